@@ -0,0 +1,29 @@
+//! What every bench uses to run a program and print how long it took
+
+use birl::context::{ Context, BIRL_GLOBAL_FUNCTION_ID };
+
+use std::io;
+use std::time::{ Duration, Instant };
+
+const RUNS : u32 = 5;
+
+/// Runs the program once, throwing away what it prints, and times only the execution
+fn run(source : &str) -> Duration {
+    let mut ctx = Context::new();
+
+    ctx.set_stdout(Some(Box::new(io::sink())));
+    ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+    ctx.add_source_string(source.to_owned()).unwrap();
+
+    let start = Instant::now();
+
+    ctx.start_program().unwrap();
+
+    start.elapsed()
+}
+
+pub fn bench(name : &str, source : &str) {
+    let total = (0..RUNS).map(|_| run(source)).sum::<Duration>();
+
+    println!("{:<20} {:>10.3} ms", name, total.as_secs_f64() * 1000.0 / f64::from(RUNS));
+}
@@ -0,0 +1,47 @@
+//! Times nested loops and conditionals. Run with `cargo bench`
+
+extern crate birl;
+
+mod common;
+
+use common::bench;
+
+// The condition is false in almost every iteration, so most of the time goes to skipping its body
+const NESTED_LOOPS : &str = "
+JAULA SHOW
+    VEM: TOTAL, 0
+    REPETE: I, 0, 300
+        REPETE: J, 0, 300
+            E ELE QUE A GENTE QUER: J, 299
+            E ELE MEMO:
+                BORA: TOTAL, TOTAL + I
+                BORA: TOTAL, TOTAL + J
+                BORA: TOTAL, TOTAL * 1
+                BORA: TOTAL, TOTAL - 0
+                BORA: TOTAL, TOTAL + 1
+                BORA: TOTAL, TOTAL - 1
+            FIM
+        FIM
+    FIM
+SAINDO DA JAULA
+";
+
+const WHILE_LOOPS : &str = "
+JAULA SHOW
+    VEM: I, 0
+    VEM: TOTAL, 0
+    ENQUANTO É MENOR: I, 300
+        VEM: J, 0
+        ENQUANTO É MENOR: J, 300
+            BORA: TOTAL, TOTAL + J
+            BORA: J, J + 1
+        FIM
+        BORA: I, I + 1
+    FIM
+SAINDO DA JAULA
+";
+
+fn main() {
+    bench("nested_loops", NESTED_LOOPS);
+    bench("while_loops", WHILE_LOOPS);
+}
@@ -0,0 +1,33 @@
+//! Times programs that mostly push and print text literals. Run with `cargo bench`
+
+extern crate birl;
+
+mod common;
+
+use common::bench;
+
+const LITERALS : &str = "
+JAULA SHOW
+    REPETE: I, 0, 3000
+        CE QUER VER: \"BIRL! \", \"SAI DE CASA \", \"COMI PRA CARALHO \"
+        CE QUER VER ISSO: \"AQUI É BODYBUILDER PORRA \", I
+    FIM
+SAINDO DA JAULA
+";
+
+const INTERPOLATION : &str = "
+JAULA FALA (N : BATATA DOCE)
+    CE QUER VER ISSO: \"{N} : É VERÃO O ANO TODO, {N * 2} : NUM VAI DAR NÃO\"
+SAINDO DA JAULA
+
+JAULA SHOW
+    REPETE: I, 0, 3000
+        E HORA DO: FALA, I
+    FIM
+SAINDO DA JAULA
+";
+
+fn main() {
+    bench("literals", LITERALS);
+    bench("interpolation", INTERPOLATION);
+}
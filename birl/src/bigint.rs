@@ -0,0 +1,393 @@
+//! Arbitrary precision integers, used when the VM is in big integer mode
+
+use parser::IntegerType;
+
+use std::cmp::Ordering;
+use std::fmt::{ Display, self };
+
+const DECIMAL_CHUNK : u32 = 1_000_000_000;
+const DECIMAL_CHUNK_DIGITS : usize = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInteger {
+    negative : bool,
+    // Little endian, base 2^32 and without zeros at the end. Zero is an empty vector
+    magnitude : Vec<u32>,
+}
+
+fn trim(magnitude : &mut Vec<u32>) {
+    while let Some(&0) = magnitude.last() {
+        magnitude.pop();
+    }
+}
+
+fn compare_magnitude(left : &[u32], right : &[u32]) -> Ordering {
+    if left.len() != right.len() {
+        return left.len().cmp(&right.len());
+    }
+
+    for (l, r) in left.iter().rev().zip(right.iter().rev()) {
+        if l != r {
+            return l.cmp(r);
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn add_magnitude(left : &[u32], right : &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(left.len().max(right.len()) + 1);
+    let mut carry = 0u64;
+
+    for i in 0..left.len().max(right.len()) {
+        let l = *left.get(i).unwrap_or(&0) as u64;
+        let r = *right.get(i).unwrap_or(&0) as u64;
+        let sum = l + r + carry;
+
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+
+    if carry > 0 {
+        result.push(carry as u32);
+    }
+
+    result
+}
+
+// Left must be greater or equal to right
+fn sub_magnitude(left : &[u32], right : &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(left.len());
+    let mut borrow = 0i64;
+
+    for (i, &l) in left.iter().enumerate() {
+        let mut diff = l as i64 - *right.get(i).unwrap_or(&0) as i64 - borrow;
+
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+
+        result.push(diff as u32);
+    }
+
+    trim(&mut result);
+
+    result
+}
+
+fn mul_magnitude(left : &[u32], right : &[u32]) -> Vec<u32> {
+    if left.is_empty() || right.is_empty() {
+        return vec![];
+    }
+
+    let mut result = vec![0u32; left.len() + right.len()];
+
+    for (i, &l) in left.iter().enumerate() {
+        let mut carry = 0u64;
+
+        for (j, &r) in right.iter().enumerate() {
+            let cur = result[i + j] as u64 + (l as u64) * (r as u64) + carry;
+            result[i + j] = cur as u32;
+            carry = cur >> 32;
+        }
+
+        let mut k = i + right.len();
+
+        while carry > 0 {
+            let cur = result[k] as u64 + carry;
+            result[k] = cur as u32;
+            carry = cur >> 32;
+            k += 1;
+        }
+    }
+
+    trim(&mut result);
+
+    result
+}
+
+// Divides in place by a small value, returning the remainder
+fn div_small(magnitude : &mut Vec<u32>, divisor : u32) -> u32 {
+    let mut remainder = 0u64;
+
+    for digit in magnitude.iter_mut().rev() {
+        let cur = (remainder << 32) | (*digit as u64);
+        *digit = (cur / divisor as u64) as u32;
+        remainder = cur % divisor as u64;
+    }
+
+    trim(magnitude);
+
+    remainder as u32
+}
+
+fn shift_left_one(magnitude : &mut Vec<u32>, bit : u32) {
+    let mut carry = bit;
+
+    for digit in magnitude.iter_mut() {
+        let next = *digit >> 31;
+        *digit = (*digit << 1) | carry;
+        carry = next;
+    }
+
+    if carry > 0 {
+        magnitude.push(carry);
+    }
+}
+
+// Schoolbook binary long division. Divisor must not be zero
+fn div_rem_magnitude(dividend : &[u32], divisor : &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut quotient = vec![0u32; dividend.len()];
+    let mut remainder = vec![];
+
+    for i in (0..dividend.len() * 32).rev() {
+        let bit = (dividend[i / 32] >> (i % 32)) & 1;
+
+        shift_left_one(&mut remainder, bit);
+
+        if compare_magnitude(&remainder, divisor) != Ordering::Less {
+            remainder = sub_magnitude(&remainder, divisor);
+            quotient[i / 32] |= 1 << (i % 32);
+        }
+    }
+
+    trim(&mut quotient);
+
+    (quotient, remainder)
+}
+
+impl BigInteger {
+    fn from_parts(negative : bool, mut magnitude : Vec<u32>) -> BigInteger {
+        trim(&mut magnitude);
+
+        BigInteger {
+            negative : negative && !magnitude.is_empty(),
+            magnitude,
+        }
+    }
+
+    pub fn from_integer(value : IntegerType) -> BigInteger {
+        let abs = value.unsigned_abs();
+
+        BigInteger::from_parts(value < 0, vec![abs as u32, (abs >> 32) as u32])
+    }
+
+    /// Returns the value as a regular integer, if it fits in one
+    pub fn to_integer(&self) -> Option<IntegerType> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+
+        let mut abs = 0u64;
+
+        for (i, &digit) in self.magnitude.iter().enumerate() {
+            abs |= (digit as u64) << (32 * i);
+        }
+
+        if self.negative {
+            if abs > IntegerType::MIN.unsigned_abs() {
+                None
+            } else {
+                Some((abs as IntegerType).wrapping_neg())
+            }
+        } else if abs > IntegerType::MAX as u64 {
+            None
+        } else {
+            Some(abs as IntegerType)
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mut result = 0f64;
+
+        for &digit in self.magnitude.iter().rev() {
+            result = result * 4294967296f64 + digit as f64;
+        }
+
+        if self.negative {
+            -result
+        } else {
+            result
+        }
+    }
+
+    /// Parses a decimal number, with an optional sign
+    pub fn parse(src : &str) -> Option<BigInteger> {
+        let (negative, digits) = if let Some(rest) = src.strip_prefix('-') {
+            (true, rest)
+        } else if let Some(rest) = src.strip_prefix('+') {
+            (false, rest)
+        } else {
+            (false, src)
+        };
+
+        if digits.is_empty() {
+            return None;
+        }
+
+        let mut magnitude = vec![];
+
+        for c in digits.chars() {
+            let digit = c.to_digit(10)?;
+
+            magnitude = mul_magnitude(&magnitude, &[10]);
+            magnitude = add_magnitude(&magnitude, &[digit]);
+        }
+
+        Some(BigInteger::from_parts(negative, magnitude))
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Bytes used by the digits
+    pub fn size_in_bytes(&self) -> usize {
+        self.magnitude.len() * ::std::mem::size_of::<u32>()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn neg(&self) -> BigInteger {
+        BigInteger::from_parts(!self.negative, self.magnitude.clone())
+    }
+
+    pub fn add(&self, other : &BigInteger) -> BigInteger {
+        if self.negative == other.negative {
+            return BigInteger::from_parts(self.negative, add_magnitude(&self.magnitude, &other.magnitude));
+        }
+
+        match compare_magnitude(&self.magnitude, &other.magnitude) {
+            Ordering::Less => BigInteger::from_parts(other.negative, sub_magnitude(&other.magnitude, &self.magnitude)),
+            _ => BigInteger::from_parts(self.negative, sub_magnitude(&self.magnitude, &other.magnitude)),
+        }
+    }
+
+    pub fn sub(&self, other : &BigInteger) -> BigInteger {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other : &BigInteger) -> BigInteger {
+        BigInteger::from_parts(self.negative != other.negative, mul_magnitude(&self.magnitude, &other.magnitude))
+    }
+
+    /// Truncating division, like the one for regular integers. None if other is zero
+    pub fn div_rem(&self, other : &BigInteger) -> Option<(BigInteger, BigInteger)> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let (quotient, remainder) = div_rem_magnitude(&self.magnitude, &other.magnitude);
+
+        Some((BigInteger::from_parts(self.negative != other.negative, quotient),
+              BigInteger::from_parts(self.negative, remainder)))
+    }
+
+    /// The absolute value written in base 2, 8, 10 or 16
+    pub fn magnitude_to_string(&self, radix : u32, uppercase : bool) -> String {
+        if self.is_zero() {
+            return "0".to_owned();
+        }
+
+        let mut magnitude = self.magnitude.clone();
+        let mut chunks = vec![];
+
+        if radix == 10 {
+            while !magnitude.is_empty() {
+                chunks.push(div_small(&mut magnitude, DECIMAL_CHUNK));
+            }
+
+            let mut result = chunks.pop().unwrap().to_string();
+
+            for chunk in chunks.iter().rev() {
+                result.push_str(format!("{:01$}", chunk, DECIMAL_CHUNK_DIGITS).as_str());
+            }
+
+            result
+        } else {
+            let mut digits = vec![];
+
+            while !magnitude.is_empty() {
+                let d = div_small(&mut magnitude, radix);
+                digits.push(std::char::from_digit(d, radix).unwrap());
+            }
+
+            let result = digits.iter().rev().collect::<String>();
+
+            if uppercase {
+                result.to_uppercase()
+            } else {
+                result
+            }
+        }
+    }
+}
+
+impl Ord for BigInteger {
+    fn cmp(&self, other : &BigInteger) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitude(&self.magnitude, &other.magnitude),
+            (true, true) => compare_magnitude(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for BigInteger {
+    fn partial_cmp(&self, other : &BigInteger) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for BigInteger {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        write!(f, "{}", self.magnitude_to_string(10, false))
+    }
+}
+
+mod tests {
+    #[test]
+    fn arithmetic() {
+        use bigint::*;
+
+        let big = |s : &str| BigInteger::parse(s).unwrap();
+
+        let max = BigInteger::from_integer(IntegerType::MAX);
+        let one = BigInteger::from_integer(1);
+
+        assert_eq!(max.add(&one).to_integer(), None);
+        assert_eq!(max.add(&one).sub(&one).to_integer(), Some(IntegerType::MAX));
+        assert_eq!(BigInteger::from_integer(IntegerType::MIN).to_integer(), Some(IntegerType::MIN));
+
+        assert_eq!(big("123456789012345678901234567890").mul(&big("-987654321098765432109876543210")).to_string(),
+                   "-121932631137021795226185032733622923332237463801111263526900");
+
+        let (q, r) = big("121932631137021795226185032733622923332237463801111263526907")
+            .div_rem(&big("987654321098765432109876543210")).unwrap();
+
+        assert_eq!(q.to_string(), "123456789012345678901234567890");
+        assert_eq!(r.to_string(), "7");
+
+        let (q, r) = big("-7").div_rem(&big("2")).unwrap();
+
+        assert_eq!((q.to_string(), r.to_string()), ("-3".to_owned(), "-1".to_owned()));
+        assert!(big("5").div_rem(&big("0")).is_none());
+
+        assert_eq!(big("-5").add(&big("5")), big("0"));
+        assert!(big("-10") < big("-9"));
+        assert!(big("100000000000000000000") > big("99999999999999999999"));
+        assert_eq!(big("255").magnitude_to_string(16, true), "FF");
+        assert_eq!(big("1000000000").to_string(), "1000000000");
+        assert!(BigInteger::parse("12a").is_none());
+    }
+}
@@ -0,0 +1,1325 @@
+//! Binary format for compiled programs, so they can be run without being compiled again.
+//!
+//! A file starts with the magic bytes, the format version, the size of the rest of the file and a checksum of it.
+//! Numbers are little endian, sizes and addresses are written as 64 bit integers and texts as their size followed
+//! by their bytes in UTF-8. Everything read is validated, so a damaged file gives an error instead of a program
+//! that breaks while running
+
+use vm::{ Code, Instruction, ComparisionRequest, DebugInfo, Block, BlockKind, MAX_FRAME_SIZE, Comparision, DynamicValue,
+          FrameState, ItemState, CoroutineState, MachineState, Suspension };
+use context::RawValue;
+use parser::TypeKind;
+use formatting::{ FormatSpec, FormatKind, Alignment, MAX_FORMAT_SIZE };
+use bigint::BigInteger;
+
+use std::convert::TryFrom;
+
+pub const BYTECODE_MAGIC : &[u8] = b"BIRLC";
+/// Changes every time the format changes. Files with a different version are rejected
+pub const BYTECODE_VERSION : u16 = 3;
+/// Extension used for compiled files
+pub const BYTECODE_EXTENSION : &str = "birlc";
+/// Snapshots have the same header as programs, with other magic bytes, and the same version
+pub const SNAPSHOT_MAGIC : &[u8] = b"BIRLS";
+/// Extension used for snapshot files
+pub const SNAPSHOT_EXTENSION : &str = "birls";
+
+// How deep lists can be nested when reading, so a crafted file can't overflow the stack
+const MAX_NESTING : usize = 1000;
+// Same for coroutines made by other coroutines in snapshots, which take a lot more of the stack to read
+const MAX_COROUTINE_NESTING : usize = 100;
+
+/// A function as known by the compiler
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionEntry {
+    pub name : String,
+    /// Code ID for source functions and plugin ID for plugins
+    pub address : usize,
+    pub parameters : Vec<TypeKind>,
+    pub is_plugin : bool,
+    /// Whether it's a GAIOLA, which gives a coroutine when called
+    pub is_coroutine : bool,
+}
+
+/// A global variable as known by the compiler
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlobalSymbol {
+    pub name : String,
+    pub address : usize,
+    pub writeable : bool,
+}
+
+/// The body of a function, along with what the VM keeps about it
+#[derive(Clone, Debug)]
+pub struct CompiledCode {
+    pub name : String,
+    pub frame_size : Option<usize>,
+    pub code : Code,
+}
+
+/// Everything needed to run a program without its source. Plugins can't be saved, so only their names and parameters
+/// are kept, and the context loading the program must have plugins with the same names and parameters
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub has_main : bool,
+    pub global_frame_size : usize,
+    pub globals : Vec<GlobalSymbol>,
+    /// Values given to global variables by the host, like the ones in modules, as address and value
+    pub global_values : Vec<(usize, RawValue)>,
+    pub functions : Vec<FunctionEntry>,
+    /// Every code, in order of ID
+    pub codes : Vec<CompiledCode>,
+}
+
+/// A program along with the state of the VM running it, so it can continue later, even in another process. Plugins are
+/// kept by name like in the program, and are bound again to the plugins with the same names when it's loaded
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub program : Program,
+    /// Whether the program got to the main function already
+    pub main_called : bool,
+    pub machine : MachineState,
+}
+
+// FNV-1a. Only meant to catch damaged files
+fn checksum(bytes : &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+struct Writer {
+    bytes : Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v : u8) {
+        self.bytes.push(v);
+    }
+
+    fn bool(&mut self, v : bool) {
+        self.u8(v as u8);
+    }
+
+    fn u64(&mut self, v : u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn usize(&mut self, v : usize) {
+        self.u64(v as u64);
+    }
+
+    fn i64(&mut self, v : i64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn f64(&mut self, v : f64) {
+        self.u64(v.to_bits());
+    }
+
+    fn str(&mut self, v : &str) {
+        self.usize(v.len());
+        self.bytes.extend_from_slice(v.as_bytes());
+    }
+
+    fn option_usize(&mut self, v : Option<usize>) {
+        match v {
+            Some(v) => {
+                self.bool(true);
+                self.usize(v);
+            }
+            None => self.bool(false),
+        }
+    }
+
+    fn type_kind(&mut self, kind : TypeKind) {
+        self.u8(match kind {
+            TypeKind::Integer => 0,
+            TypeKind::Number => 1,
+            TypeKind::Text => 2,
+            TypeKind::List => 3,
+            TypeKind::Null => 4,
+            TypeKind::Coroutine => 5,
+        });
+    }
+
+    fn raw_value(&mut self, value : &RawValue) {
+        match *value {
+            RawValue::Null => self.u8(0),
+            RawValue::Integer(i) => {
+                self.u8(1);
+                self.i64(i);
+            }
+            RawValue::Number(n) => {
+                self.u8(2);
+                self.f64(n);
+            }
+            RawValue::Text(ref t) => {
+                self.u8(3);
+                self.str(t);
+            }
+            RawValue::List(ref l) => {
+                self.u8(4);
+                self.usize(l.len());
+
+                for element in l {
+                    self.raw_value(element);
+                }
+            }
+        }
+    }
+
+    fn comparision(&mut self, req : ComparisionRequest) {
+        self.u8(match req {
+            ComparisionRequest::Equal => 0,
+            ComparisionRequest::NotEqual => 1,
+            ComparisionRequest::Less => 2,
+            ComparisionRequest::LessOrEqual => 3,
+            ComparisionRequest::More => 4,
+            ComparisionRequest::MoreOrEqual => 5,
+        });
+    }
+
+    fn format_spec(&mut self, spec : &FormatSpec) {
+        self.u64(u64::from(u32::from(spec.fill)));
+        self.u8(match spec.alignment {
+            None => 0,
+            Some(Alignment::Left) => 1,
+            Some(Alignment::Center) => 2,
+            Some(Alignment::Right) => 3,
+        });
+        self.bool(spec.sign);
+        self.bool(spec.zero_padding);
+        self.bool(spec.alternate);
+        self.option_usize(spec.width);
+        self.option_usize(spec.precision);
+        self.u8(match spec.kind {
+            FormatKind::Default => 0,
+            FormatKind::Decimal => 1,
+            FormatKind::Fixed => 2,
+            FormatKind::Exponent => 3,
+            FormatKind::Text => 4,
+            FormatKind::Hexadecimal => 5,
+            FormatKind::HexadecimalUpper => 6,
+            FormatKind::Binary => 7,
+            FormatKind::Octal => 8,
+        });
+    }
+
+    fn debug_info(&mut self, debug : &DebugInfo) {
+        self.usize(debug.lines.len());
+
+        for &(address, line) in &debug.lines {
+            self.usize(address);
+            self.usize(line);
+        }
+
+        self.usize(debug.variables.len());
+
+        for &(address, ref name, var) in &debug.variables {
+            self.usize(address);
+            self.str(name);
+            self.usize(var);
+        }
+
+        self.usize(debug.blocks.len());
+
+        for block in &debug.blocks {
+            self.usize(block.start);
+            self.usize(block.end);
+            self.u8(match block.kind {
+                BlockKind::Conditional => 0,
+                BlockKind::Loop => 1,
+            });
+        }
+    }
+
+    fn instruction(&mut self, inst : &Instruction) {
+        match *inst {
+            Instruction::PrintValue => self.u8(0),
+            Instruction::PrintValueDebug => self.u8(1),
+            Instruction::PrintNewLine => self.u8(2),
+            Instruction::FlushStdout => self.u8(3),
+            Instruction::Quit => self.u8(4),
+            Instruction::Compare => self.u8(5),
+            Instruction::Return => self.u8(6),
+            Instruction::Jump(address) => {
+                self.u8(7);
+                self.usize(address);
+            }
+            Instruction::JumpIfNot(req, address) => {
+                self.u8(8);
+                self.comparision(req);
+                self.usize(address);
+            }
+            Instruction::MakeNewFrame(id) => {
+                self.u8(9);
+                self.usize(id);
+            }
+            Instruction::SetLastFrameReady => self.u8(10),
+            Instruction::TailCall => self.u8(11),
+            Instruction::AssertCompatible(kind) => {
+                self.u8(12);
+                self.type_kind(kind);
+            }
+            Instruction::ReadInput => self.u8(13),
+            Instruction::ConvertToString => self.u8(14),
+            Instruction::ConvertToNum => self.u8(15),
+            Instruction::ConvertToInt => self.u8(16),
+            Instruction::PushValue(ref value) => {
+                self.u8(17);
+                self.raw_value(value);
+            }
+            Instruction::PushText(index) => {
+                self.u8(18);
+                self.usize(index);
+            }
+            Instruction::ReadGlobalVarFrom(address) => {
+                self.u8(19);
+                self.usize(address);
+            }
+            Instruction::WriteGlobalVarTo(address) => {
+                self.u8(20);
+                self.usize(address);
+            }
+            Instruction::ReadVarFrom(address) => {
+                self.u8(21);
+                self.usize(address);
+            }
+            Instruction::WriteVarTo(address) => {
+                self.u8(22);
+                self.usize(address);
+            }
+            Instruction::WriteVarToLast(address) => {
+                self.u8(23);
+                self.usize(address);
+            }
+            Instruction::Add => self.u8(24),
+            Instruction::Mul => self.u8(25),
+            Instruction::Div => self.u8(26),
+            Instruction::Sub => self.u8(27),
+            Instruction::BitwiseAnd => self.u8(28),
+            Instruction::BitwiseOr => self.u8(29),
+            Instruction::BitwiseXor => self.u8(30),
+            Instruction::BitwiseNot => self.u8(31),
+            Instruction::ShiftLeft => self.u8(32),
+            Instruction::ShiftRight => self.u8(33),
+            Instruction::MakeNewList => self.u8(34),
+            Instruction::IndexList => self.u8(35),
+            Instruction::AddToList => self.u8(36),
+            Instruction::InsertIntoList => self.u8(37),
+            Instruction::RemoveFromListAtIndex => self.u8(38),
+            Instruction::QueryListSize => self.u8(39),
+            Instruction::CallPlugin(address, num_args) => {
+                self.u8(40);
+                self.usize(address);
+                self.usize(num_args);
+            }
+            Instruction::StartInterpolation => self.u8(41),
+            Instruction::AppendToInterpolation(index) => {
+                self.u8(42);
+                self.usize(index);
+            }
+            Instruction::AppendValueToInterpolation(ref format) => {
+                self.u8(43);
+
+                match *format {
+                    Some(ref spec) => {
+                        self.bool(true);
+                        self.format_spec(spec);
+                    }
+                    None => self.bool(false),
+                }
+            }
+            Instruction::FinishInterpolation => self.u8(44),
+            Instruction::FormatText(num_args) => {
+                self.u8(45);
+                self.usize(num_args);
+            }
+            Instruction::Suspend => self.u8(46),
+            Instruction::MakeCoroutine => self.u8(47),
+            Instruction::ResumeCoroutine => self.u8(48),
+            Instruction::Yield => self.u8(49),
+            Instruction::QueryCoroutineDone => self.u8(50),
+        }
+    }
+    fn dynamic_value(&mut self, value : &DynamicValue) {
+        match *value {
+            DynamicValue::Null => self.u8(0),
+            DynamicValue::Integer(i) => {
+                self.u8(1);
+                self.i64(i);
+            }
+            DynamicValue::Number(n) => {
+                self.u8(2);
+                self.f64(n);
+            }
+            DynamicValue::Text(id) => {
+                self.u8(3);
+                self.u64(id);
+            }
+            DynamicValue::List(id) => {
+                self.u8(4);
+                self.u64(id);
+            }
+            DynamicValue::BigInteger(id) => {
+                self.u8(5);
+                self.u64(id);
+            }
+            DynamicValue::Coroutine(id) => {
+                self.u8(6);
+                self.u64(id);
+            }
+        }
+    }
+
+    fn dynamic_values(&mut self, values : &[DynamicValue]) {
+        self.usize(values.len());
+
+        for value in values {
+            self.dynamic_value(value);
+        }
+    }
+
+    fn frame(&mut self, frame : &FrameState) {
+        self.usize(frame.id);
+        self.dynamic_values(&frame.stack);
+        self.usize(frame.program_counter);
+
+        match frame.last_comparision {
+            Some(comparision) => {
+                self.bool(true);
+                self.u8(match comparision {
+                    Comparision::Equal => 0,
+                    Comparision::NotEqual => 1,
+                    Comparision::LessThan => 2,
+                    Comparision::MoreThan => 3,
+                });
+            }
+            None => self.bool(false),
+        }
+
+        self.bool(frame.ready);
+        self.usize(frame.num_special_items);
+        self.usize(frame.operand_base);
+    }
+
+    fn frames(&mut self, frames : &[FrameState]) {
+        self.usize(frames.len());
+
+        for frame in frames {
+            self.frame(frame);
+        }
+    }
+
+    // Big integers are kept as their decimal text
+    fn items(&mut self, items : &[(u64, ItemState)]) {
+        self.usize(items.len());
+
+        for &(id, ref item) in items {
+            self.u64(id);
+
+            match *item {
+                ItemState::Text(ref t) => {
+                    self.u8(0);
+                    self.str(t);
+                }
+                ItemState::List(ref l) => {
+                    self.u8(1);
+                    self.dynamic_values(l);
+                }
+                ItemState::BigInteger(ref b) => {
+                    self.u8(2);
+                    self.str(&b.to_string());
+                }
+                ItemState::Coroutine(ref c) => {
+                    self.u8(3);
+                    self.frames(&c.frames);
+                    self.items(&c.items);
+                    self.dynamic_values(&c.operands);
+                    self.bool(c.running);
+                    self.bool(c.done);
+                }
+            }
+        }
+    }
+
+    fn machine(&mut self, machine : &MachineState) {
+        self.frames(&machine.callstack);
+        self.dynamic_values(&machine.operands);
+        self.usize(machine.interpolations.len());
+
+        for text in &machine.interpolations {
+            self.str(text);
+        }
+
+        self.items(&machine.items);
+        self.usize(machine.num_constants);
+        self.u64(machine.next_item_id);
+        self.usize(machine.text_constants.len());
+
+        for constants in &machine.text_constants {
+            self.usize(constants.len());
+
+            for constant in constants {
+                match *constant {
+                    Some(id) => {
+                        self.bool(true);
+                        self.u64(id);
+                    }
+                    None => self.bool(false),
+                }
+            }
+        }
+
+        self.usize(machine.running_coroutines.len());
+
+        for &(id, first) in &machine.running_coroutines {
+            self.u64(id);
+            self.usize(first);
+        }
+
+        self.bool(machine.has_quit);
+
+        match machine.suspension {
+            None => self.u8(0),
+            Some(Suspension::Input) => self.u8(1),
+            Some(Suspension::Yield(ref value)) => {
+                self.u8(2);
+                self.raw_value(value);
+            }
+            Some(Suspension::Plugin(ref value)) => {
+                self.u8(3);
+                self.raw_value(value);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes : &'a [u8],
+    offset : usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len : usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.offset < len {
+            return Err("Bytecode inválido : O arquivo acaba antes do esperado".to_owned());
+        }
+
+        let slice = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("Bytecode inválido : {} não é um booleano", b)),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        let v = self.u64()?;
+
+        usize::try_from(v).map_err(|_| format!("Bytecode inválido : {} é grande demais", v))
+    }
+
+    // A size or address that must be below the limit
+    fn bounded(&mut self, limit : usize, what : &str) -> Result<usize, String> {
+        let v = self.usize()?;
+
+        if v >= limit {
+            return Err(format!("Bytecode inválido : {} {} fora do limite de {}", what, v, limit));
+        }
+
+        Ok(v)
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        Ok(self.u64()? as i64)
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?;
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Bytecode inválido : Texto com UTF-8 inválido".to_owned())
+    }
+
+    fn option_usize(&mut self) -> Result<Option<usize>, String> {
+        if self.bool()? {
+            Ok(Some(self.usize()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn type_kind(&mut self) -> Result<TypeKind, String> {
+        match self.u8()? {
+            0 => Ok(TypeKind::Integer),
+            1 => Ok(TypeKind::Number),
+            2 => Ok(TypeKind::Text),
+            3 => Ok(TypeKind::List),
+            4 => Ok(TypeKind::Null),
+            5 => Ok(TypeKind::Coroutine),
+            t => Err(format!("Bytecode inválido : Tipo {} desconhecido", t)),
+        }
+    }
+
+    fn raw_value(&mut self) -> Result<RawValue, String> {
+        self.nested_raw_value(0)
+    }
+
+    fn nested_raw_value(&mut self, depth : usize) -> Result<RawValue, String> {
+        match self.u8()? {
+            0 => Ok(RawValue::Null),
+            1 => Ok(RawValue::Integer(self.i64()?)),
+            2 => Ok(RawValue::Number(self.f64()?)),
+            3 => Ok(RawValue::Text(self.string()?)),
+            4 => {
+                if depth >= MAX_NESTING {
+                    return Err(format!("Bytecode inválido : Listas aninhadas mais de {} vezes", MAX_NESTING));
+                }
+
+                let mut elements = vec![];
+
+                for _ in 0..self.usize()? {
+                    elements.push(self.nested_raw_value(depth + 1)?);
+                }
+
+                Ok(RawValue::List(elements))
+            }
+            t => Err(format!("Bytecode inválido : Valor do tipo {} desconhecido", t)),
+        }
+    }
+
+    fn comparision(&mut self) -> Result<ComparisionRequest, String> {
+        match self.u8()? {
+            0 => Ok(ComparisionRequest::Equal),
+            1 => Ok(ComparisionRequest::NotEqual),
+            2 => Ok(ComparisionRequest::Less),
+            3 => Ok(ComparisionRequest::LessOrEqual),
+            4 => Ok(ComparisionRequest::More),
+            5 => Ok(ComparisionRequest::MoreOrEqual),
+            c => Err(format!("Bytecode inválido : Comparação {} desconhecida", c)),
+        }
+    }
+
+    fn format_spec(&mut self) -> Result<FormatSpec, String> {
+        let fill = match u32::try_from(self.u64()?).ok().and_then(::std::char::from_u32) {
+            Some(c) => c,
+            None => return Err("Bytecode inválido : Caractere de preenchimento inválido".to_owned()),
+        };
+
+        let alignment = match self.u8()? {
+            0 => None,
+            1 => Some(Alignment::Left),
+            2 => Some(Alignment::Center),
+            3 => Some(Alignment::Right),
+            a => return Err(format!("Bytecode inválido : Alinhamento {} desconhecido", a)),
+        };
+
+        let sign = self.bool()?;
+        let zero_padding = self.bool()?;
+        let alternate = self.bool()?;
+        let width = self.option_usize()?;
+        let precision = self.option_usize()?;
+
+        // Same limit as when the format is parsed
+        for size in width.iter().chain(precision.iter()) {
+            if *size > MAX_FORMAT_SIZE {
+                return Err(format!("Bytecode inválido : Largura ou precisão {} fora do limite de {}", size, MAX_FORMAT_SIZE));
+            }
+        }
+
+        let kind = match self.u8()? {
+            0 => FormatKind::Default,
+            1 => FormatKind::Decimal,
+            2 => FormatKind::Fixed,
+            3 => FormatKind::Exponent,
+            4 => FormatKind::Text,
+            5 => FormatKind::Hexadecimal,
+            6 => FormatKind::HexadecimalUpper,
+            7 => FormatKind::Binary,
+            8 => FormatKind::Octal,
+            k => return Err(format!("Bytecode inválido : Formato {} desconhecido", k)),
+        };
+
+        Ok(FormatSpec { fill, alignment, sign, zero_padding, alternate, width, precision, kind })
+    }
+
+    // Addresses in the debug info may point right after the last instruction
+    fn debug_info(&mut self, len : usize) -> Result<DebugInfo, String> {
+        let mut debug = DebugInfo::default();
+
+        for _ in 0..self.usize()? {
+            let address = self.bounded(len + 1, "Endereço de linha")?;
+
+            debug.lines.push((address, self.usize()?));
+        }
+
+        for _ in 0..self.usize()? {
+            let address = self.bounded(len + 1, "Endereço de variável")?;
+            let name = self.string()?;
+            let var = self.bounded(MAX_FRAME_SIZE, "Variável")?;
+
+            debug.variables.push((address, name, var));
+        }
+
+        for _ in 0..self.usize()? {
+            let start = self.bounded(len + 1, "Começo de bloco")?;
+            let end = self.bounded(len + 1, "Fim de bloco")?;
+            let kind = match self.u8()? {
+                0 => BlockKind::Conditional,
+                1 => BlockKind::Loop,
+                k => return Err(format!("Bytecode inválido : Tipo de bloco {} desconhecido", k)),
+            };
+
+            debug.blocks.push(Block { start, end, kind });
+        }
+
+        Ok(debug)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let inst = match self.u8()? {
+            0 => Instruction::PrintValue,
+            1 => Instruction::PrintValueDebug,
+            2 => Instruction::PrintNewLine,
+            3 => Instruction::FlushStdout,
+            4 => Instruction::Quit,
+            5 => Instruction::Compare,
+            6 => Instruction::Return,
+            7 => Instruction::Jump(self.usize()?),
+            8 => {
+                let req = self.comparision()?;
+
+                Instruction::JumpIfNot(req, self.usize()?)
+            }
+            9 => Instruction::MakeNewFrame(self.usize()?),
+            10 => Instruction::SetLastFrameReady,
+            11 => Instruction::TailCall,
+            12 => Instruction::AssertCompatible(self.type_kind()?),
+            13 => Instruction::ReadInput,
+            14 => Instruction::ConvertToString,
+            15 => Instruction::ConvertToNum,
+            16 => Instruction::ConvertToInt,
+            17 => Instruction::PushValue(self.raw_value()?),
+            18 => Instruction::PushText(self.usize()?),
+            19 => Instruction::ReadGlobalVarFrom(self.bounded(MAX_FRAME_SIZE, "Endereço")?),
+            20 => Instruction::WriteGlobalVarTo(self.bounded(MAX_FRAME_SIZE, "Endereço")?),
+            21 => Instruction::ReadVarFrom(self.bounded(MAX_FRAME_SIZE, "Endereço")?),
+            22 => Instruction::WriteVarTo(self.bounded(MAX_FRAME_SIZE, "Endereço")?),
+            23 => Instruction::WriteVarToLast(self.bounded(MAX_FRAME_SIZE, "Endereço")?),
+            24 => Instruction::Add,
+            25 => Instruction::Mul,
+            26 => Instruction::Div,
+            27 => Instruction::Sub,
+            28 => Instruction::BitwiseAnd,
+            29 => Instruction::BitwiseOr,
+            30 => Instruction::BitwiseXor,
+            31 => Instruction::BitwiseNot,
+            32 => Instruction::ShiftLeft,
+            33 => Instruction::ShiftRight,
+            34 => Instruction::MakeNewList,
+            35 => Instruction::IndexList,
+            36 => Instruction::AddToList,
+            37 => Instruction::InsertIntoList,
+            38 => Instruction::RemoveFromListAtIndex,
+            39 => Instruction::QueryListSize,
+            40 => {
+                let address = self.usize()?;
+
+                Instruction::CallPlugin(address, self.usize()?)
+            }
+            41 => Instruction::StartInterpolation,
+            42 => Instruction::AppendToInterpolation(self.usize()?),
+            43 => {
+                let format = if self.bool()? {
+                    Some(self.format_spec()?)
+                } else {
+                    None
+                };
+
+                Instruction::AppendValueToInterpolation(format)
+            }
+            44 => Instruction::FinishInterpolation,
+            45 => Instruction::FormatText(self.usize()?),
+            46 => Instruction::Suspend,
+            47 => Instruction::MakeCoroutine,
+            48 => Instruction::ResumeCoroutine,
+            49 => Instruction::Yield,
+            50 => Instruction::QueryCoroutineDone,
+            op => return Err(format!("Bytecode inválido : Instrução {} desconhecida", op)),
+        };
+
+        Ok(inst)
+    }
+    fn dynamic_value(&mut self) -> Result<DynamicValue, String> {
+        match self.u8()? {
+            0 => Ok(DynamicValue::Null),
+            1 => Ok(DynamicValue::Integer(self.i64()?)),
+            2 => Ok(DynamicValue::Number(self.f64()?)),
+            3 => Ok(DynamicValue::Text(self.u64()?)),
+            4 => Ok(DynamicValue::List(self.u64()?)),
+            5 => Ok(DynamicValue::BigInteger(self.u64()?)),
+            6 => Ok(DynamicValue::Coroutine(self.u64()?)),
+            t => Err(format!("Snapshot inválido : Valor do tipo {} desconhecido", t)),
+        }
+    }
+
+    fn dynamic_values(&mut self) -> Result<Vec<DynamicValue>, String> {
+        let mut values = vec![];
+
+        for _ in 0..self.usize()? {
+            values.push(self.dynamic_value()?);
+        }
+
+        Ok(values)
+    }
+
+    fn frame(&mut self) -> Result<FrameState, String> {
+        let id = self.usize()?;
+        let stack = self.dynamic_values()?;
+        let program_counter = self.usize()?;
+
+        let last_comparision = if self.bool()? {
+            Some(match self.u8()? {
+                0 => Comparision::Equal,
+                1 => Comparision::NotEqual,
+                2 => Comparision::LessThan,
+                3 => Comparision::MoreThan,
+                c => return Err(format!("Snapshot inválido : Resultado de comparação {} desconhecido", c)),
+            })
+        } else {
+            None
+        };
+
+        let ready = self.bool()?;
+        let num_special_items = self.usize()?;
+        let operand_base = self.usize()?;
+
+        Ok(FrameState { id, stack, program_counter, last_comparision, ready, num_special_items, operand_base })
+    }
+
+    fn frames(&mut self) -> Result<Vec<FrameState>, String> {
+        let mut frames = vec![];
+
+        for _ in 0..self.usize()? {
+            frames.push(self.frame()?);
+        }
+
+        Ok(frames)
+    }
+
+    fn items(&mut self) -> Result<Vec<(u64, ItemState)>, String> {
+        self.nested_items(0)
+    }
+
+    // The items of a coroutine are kept inside it, so they are nested when a coroutine makes another
+    fn nested_items(&mut self, depth : usize) -> Result<Vec<(u64, ItemState)>, String> {
+        let mut items = vec![];
+
+        for _ in 0..self.usize()? {
+            let id = self.u64()?;
+
+            let item = match self.u8()? {
+                0 => ItemState::Text(self.string()?),
+                1 => ItemState::List(self.dynamic_values()?),
+                2 => match BigInteger::parse(&self.string()?) {
+                    Some(b) => ItemState::BigInteger(b),
+                    None => return Err("Snapshot inválido : Inteiro grande inválido".to_owned()),
+                },
+                3 if depth >= MAX_COROUTINE_NESTING => {
+                    return Err(format!("Snapshot inválido : Gaiolas aninhadas mais de {} vezes",
+                        MAX_COROUTINE_NESTING));
+                }
+                3 => ItemState::Coroutine(CoroutineState {
+                    frames : self.frames()?,
+                    items : self.nested_items(depth + 1)?,
+                    operands : self.dynamic_values()?,
+                    running : self.bool()?,
+                    done : self.bool()?,
+                }),
+                t => return Err(format!("Snapshot inválido : Item do tipo {} desconhecido", t)),
+            };
+
+            items.push((id, item));
+        }
+
+        Ok(items)
+    }
+
+    fn machine(&mut self) -> Result<MachineState, String> {
+        let callstack = self.frames()?;
+        let operands = self.dynamic_values()?;
+        let mut interpolations = vec![];
+
+        for _ in 0..self.usize()? {
+            interpolations.push(self.string()?);
+        }
+
+        let items = self.items()?;
+        let num_constants = self.usize()?;
+        let next_item_id = self.u64()?;
+        let mut text_constants = vec![];
+
+        for _ in 0..self.usize()? {
+            let mut constants = vec![];
+
+            for _ in 0..self.usize()? {
+                constants.push(if self.bool()? { Some(self.u64()?) } else { None });
+            }
+
+            text_constants.push(constants);
+        }
+
+        let mut running_coroutines = vec![];
+
+        for _ in 0..self.usize()? {
+            running_coroutines.push((self.u64()?, self.usize()?));
+        }
+
+        let has_quit = self.bool()?;
+
+        let suspension = match self.u8()? {
+            0 => None,
+            1 => Some(Suspension::Input),
+            2 => Some(Suspension::Yield(self.raw_value()?)),
+            3 => Some(Suspension::Plugin(self.raw_value()?)),
+            s => return Err(format!("Snapshot inválido : Pausa do tipo {} desconhecida", s)),
+        };
+
+        Ok(MachineState {
+            callstack,
+            operands,
+            interpolations,
+            items,
+            num_constants,
+            next_item_id,
+            text_constants,
+            running_coroutines,
+            has_quit,
+            suspension,
+        })
+    }
+}
+
+// Puts the magic bytes, the version, the size and the checksum before the payload
+fn with_header(magic : &[u8], payload : Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 32);
+
+    bytes.extend_from_slice(magic);
+    bytes.extend_from_slice(&BYTECODE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+
+    bytes
+}
+
+// Checks the header made by with_header, giving a reader for the payload
+fn read_header<'a>(bytes : &'a [u8], magic : &[u8], wrong_magic : &str) -> Result<Reader<'a>, String> {
+    if !bytes.starts_with(magic) {
+        return Err(wrong_magic.to_owned());
+    }
+
+    let mut header = Reader { bytes, offset : magic.len() };
+
+    let version = u16::from(header.u8()?) | (u16::from(header.u8()?) << 8);
+
+    if version != BYTECODE_VERSION {
+        return Err(format!("Bytecode inválido : O arquivo é da versão {} do formato, mas só a versão {} é suportada",
+                           version, BYTECODE_VERSION));
+    }
+
+    let len = header.usize()?;
+    let expected_checksum = header.u64()?;
+    let payload = &bytes[header.offset..];
+
+    if payload.len() != len || checksum(payload) != expected_checksum {
+        return Err("Bytecode inválido : O arquivo está corrompido".to_owned());
+    }
+
+    Ok(Reader { bytes : payload, offset : 0 })
+}
+
+impl Program {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut w = Writer { bytes : vec![] };
+
+        self.write(&mut w);
+
+        with_header(BYTECODE_MAGIC, w.bytes)
+    }
+
+    fn write(&self, w : &mut Writer) {
+        w.bool(self.has_main);
+        w.usize(self.global_frame_size);
+
+        w.usize(self.globals.len());
+
+        for global in &self.globals {
+            w.str(&global.name);
+            w.usize(global.address);
+            w.bool(global.writeable);
+        }
+
+        w.usize(self.global_values.len());
+
+        for &(address, ref value) in &self.global_values {
+            w.usize(address);
+            w.raw_value(value);
+        }
+
+        w.usize(self.functions.len());
+
+        for function in &self.functions {
+            w.str(&function.name);
+            w.usize(function.address);
+            w.bool(function.is_plugin);
+            w.bool(function.is_coroutine);
+            w.usize(function.parameters.len());
+
+            for &kind in &function.parameters {
+                w.type_kind(kind);
+            }
+        }
+
+        w.usize(self.codes.len());
+
+        for compiled in &self.codes {
+            w.str(&compiled.name);
+            w.option_usize(compiled.frame_size);
+            w.usize(compiled.code.texts.len());
+
+            for text in &compiled.code.texts {
+                w.str(text);
+            }
+
+            w.usize(compiled.code.instructions.len());
+
+            for inst in &compiled.code.instructions {
+                w.instruction(inst);
+            }
+
+            w.debug_info(&compiled.code.debug);
+        }
+    }
+
+    pub fn deserialize(bytes : &[u8]) -> Result<Program, String> {
+        let mut r = read_header(bytes, BYTECODE_MAGIC, "Bytecode inválido : O arquivo não é um programa compilado do BIRL")?;
+        let program = Program::read(&mut r)?;
+
+        if r.offset != r.bytes.len() {
+            return Err("Bytecode inválido : Dados sobrando no fim do arquivo".to_owned());
+        }
+
+        Ok(program)
+    }
+
+    fn read(r : &mut Reader) -> Result<Program, String> {
+        let has_main = r.bool()?;
+        let global_frame_size = r.usize()?;
+
+        if global_frame_size > MAX_FRAME_SIZE {
+            return Err(format!("Bytecode inválido : Frame global com {} variáveis", global_frame_size));
+        }
+
+        let mut globals = vec![];
+
+        for _ in 0..r.usize()? {
+            let name = r.string()?;
+            let address = r.bounded(global_frame_size, "Endereço global")?;
+            let writeable = r.bool()?;
+
+            globals.push(GlobalSymbol { name, address, writeable });
+        }
+
+        let mut global_values = vec![];
+
+        for _ in 0..r.usize()? {
+            let address = r.bounded(global_frame_size, "Endereço global")?;
+
+            global_values.push((address, r.raw_value()?));
+        }
+
+        let mut functions = vec![];
+
+        for _ in 0..r.usize()? {
+            let name = r.string()?;
+            let address = r.usize()?;
+            let is_plugin = r.bool()?;
+            let is_coroutine = r.bool()?;
+            let mut parameters = vec![];
+
+            for _ in 0..r.usize()? {
+                parameters.push(r.type_kind()?);
+            }
+
+            functions.push(FunctionEntry { name, address, parameters, is_plugin, is_coroutine });
+        }
+
+        let mut codes = vec![];
+
+        for _ in 0..r.usize()? {
+            let name = r.string()?;
+            let frame_size = r.option_usize()?;
+
+            if let Some(size) = frame_size {
+                if size > MAX_FRAME_SIZE {
+                    return Err(format!("Bytecode inválido : Frame de \"{}\" com {} variáveis", name, size));
+                }
+            }
+
+            let mut code = Code::new();
+
+            for _ in 0..r.usize()? {
+                code.texts.push(r.string()?);
+            }
+
+            for _ in 0..r.usize()? {
+                code.instructions.push(r.instruction()?);
+            }
+
+            code.debug = r.debug_info(code.instructions.len())?;
+
+            codes.push(CompiledCode { name, frame_size, code });
+        }
+
+        let program = Program { has_main, global_frame_size, globals, global_values, functions, codes };
+
+        program.validate()?;
+
+        Ok(program)
+    }
+
+    // Checks that everything the program refers to exists
+    fn validate(&self) -> Result<(), String> {
+        use context::{ BIRL_GLOBAL_FUNCTION_ID, BIRL_MAIN_FUNCTION_ID };
+
+        if self.codes.len() <= BIRL_MAIN_FUNCTION_ID {
+            return Err("Bytecode inválido : Faltando o código global ou o da função principal".to_owned());
+        }
+
+        let num_plugins = self.functions.iter().filter(|f| f.is_plugin).map(|f| f.address + 1).max().unwrap_or(0);
+
+        for function in &self.functions {
+            if !function.is_plugin && (function.address >= self.codes.len() || function.address == BIRL_GLOBAL_FUNCTION_ID) {
+                return Err(format!("Bytecode inválido : A função \"{}\" aponta pra um código que não existe", function.name));
+            }
+            if function.is_plugin && function.is_coroutine {
+                return Err(format!("Bytecode inválido : O plugin \"{}\" não pode ser uma gaiola", function.name));
+            }
+        }
+
+        for compiled in &self.codes {
+            let code = &compiled.code;
+            let len = code.instructions.len();
+
+            for inst in &code.instructions {
+                let valid = match *inst {
+                    Instruction::Jump(address) | Instruction::JumpIfNot(_, address) => address <= len,
+                    Instruction::PushText(index) | Instruction::AppendToInterpolation(index) => index < code.texts.len(),
+                    Instruction::MakeNewFrame(id) => id < self.codes.len() && id != BIRL_GLOBAL_FUNCTION_ID,
+                    Instruction::CallPlugin(address, _) => address < num_plugins,
+                    _ => true,
+                };
+
+                if !valid {
+                    return Err(format!("Bytecode inválido : Instrução {:?} em \"{}\" aponta pra algo que não existe",
+                                       inst, compiled.name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut w = Writer { bytes : vec![] };
+
+        self.program.write(&mut w);
+        w.bool(self.main_called);
+        w.machine(&self.machine);
+
+        with_header(SNAPSHOT_MAGIC, w.bytes)
+    }
+
+    /// Only the program is validated here. The VM checks the state when it's set
+    pub fn deserialize(bytes : &[u8]) -> Result<Snapshot, String> {
+        let mut r = read_header(bytes, SNAPSHOT_MAGIC, "Snapshot inválido : O arquivo não é um snapshot do BIRL")?;
+
+        let program = Program::read(&mut r)?;
+        let main_called = r.bool()?;
+        let machine = r.machine()?;
+
+        if r.offset != r.bytes.len() {
+            return Err("Snapshot inválido : Dados sobrando no fim do arquivo".to_owned());
+        }
+
+        Ok(Snapshot { program, main_called, machine })
+    }
+}
+
+mod tests {
+    #![allow(dead_code, unused_imports)]
+
+    use bytecode::*;
+
+    fn sample() -> Program {
+        let mut main = Code::new();
+        let text = main.add_text("BIRL {}".to_owned());
+
+        main.instructions = vec![
+            Instruction::PushText(text),
+            Instruction::PushValue(RawValue::Number(1.5)),
+            Instruction::FormatText(1),
+            Instruction::StartInterpolation,
+            Instruction::AppendValueToInterpolation(Some(FormatSpec::parse("*^+08.2").unwrap())),
+            Instruction::CallPlugin(0, 1),
+            Instruction::JumpIfNot(ComparisionRequest::LessOrEqual, 7),
+        ];
+
+        main.debug.mark_line(0, 3);
+        main.debug.variables.push((1, "X".to_owned(), 1));
+        main.debug.blocks.push(Block { start : 6, end : 7, kind : BlockKind::Conditional });
+
+        Program {
+            has_main : true,
+            global_frame_size : 2,
+            globals : vec![GlobalSymbol { name : "UM".to_owned(), address : 1, writeable : false }],
+            global_values : vec![(1, RawValue::Integer(-1))],
+            functions : vec![FunctionEntry { name : "P".to_owned(), address : 0, parameters : vec![TypeKind::Text],
+                                             is_plugin : true, is_coroutine : false }],
+            codes : vec![
+                CompiledCode { name : String::new(), frame_size : Some(2), code : Code::new() },
+                CompiledCode { name : "SHOW".to_owned(), frame_size : None, code : main },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = sample();
+        let bytes = program.serialize();
+        let loaded = Program::deserialize(&bytes).unwrap();
+
+        assert_eq!(loaded.globals, program.globals);
+        assert_eq!(loaded.global_values, program.global_values);
+        assert_eq!(loaded.functions, program.functions);
+        assert_eq!(format!("{:?}", loaded.codes), format!("{:?}", program.codes));
+        assert_eq!(loaded.serialize(), bytes);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = sample().serialize();
+
+        assert!(Program::deserialize(b"CE QUER VER ISSO: 1").is_err());
+
+        // Every changed byte is caught, either by the header checks or by the checksum
+        for i in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0x20;
+
+            assert!(Program::deserialize(&damaged).is_err());
+        }
+
+        for len in 0..bytes.len() {
+            assert!(Program::deserialize(&bytes[..len]).is_err());
+        }
+
+        let mut other_version = bytes.clone();
+        other_version[BYTECODE_MAGIC.len()] += 1;
+        let err = Program::deserialize(&other_version).unwrap_err();
+        assert!(err.contains("versão"));
+
+        // A valid file that refers to things that don't exist
+        let mut program = sample();
+        program.codes[1].code.instructions.push(Instruction::PushText(5));
+        assert!(Program::deserialize(&program.serialize()).is_err());
+
+        let mut program = sample();
+        program.codes[1].code.instructions.push(Instruction::MakeNewFrame(2));
+        assert!(Program::deserialize(&program.serialize()).is_err());
+
+        let mut spec = FormatSpec::parse("10").unwrap();
+        spec.width = Some(MAX_FORMAT_SIZE + 1);
+
+        let mut program = sample();
+        program.codes[1].code.instructions.push(Instruction::AppendValueToInterpolation(Some(spec)));
+        assert!(Program::deserialize(&program.serialize()).is_err());
+    }
+
+    #[test]
+    fn rejects_deep_lists() {
+        let nested = |depth : usize| (0..depth).fold(RawValue::Integer(1), |v, _| RawValue::List(vec![v]));
+
+        let mut program = sample();
+        program.global_values[0].1 = nested(MAX_NESTING);
+        assert!(Program::deserialize(&program.serialize()).is_ok());
+
+        program.global_values[0].1 = nested(MAX_NESTING + 1);
+        let err = Program::deserialize(&program.serialize()).unwrap_err();
+        assert!(err.contains("aninhadas"));
+    }
+
+    #[test]
+    fn rejects_deep_coroutines() {
+        let coroutine = |items| ItemState::Coroutine(CoroutineState {
+            frames : vec![],
+            items,
+            operands : vec![],
+            running : false,
+            done : true,
+        });
+
+        let snapshot = |depth : usize| Snapshot {
+            program : sample(),
+            main_called : false,
+            machine : MachineState {
+                callstack : vec![],
+                operands : vec![],
+                interpolations : vec![],
+                items : (0..depth).fold(vec![], |items, id| vec![(id as u64, coroutine(items))]),
+                num_constants : 0,
+                next_item_id : depth as u64,
+                text_constants : vec![],
+                running_coroutines : vec![],
+                has_quit : false,
+                suspension : None,
+            },
+        };
+
+        assert!(Snapshot::deserialize(&snapshot(MAX_COROUTINE_NESTING).serialize()).is_ok());
+
+        let err = Snapshot::deserialize(&snapshot(MAX_COROUTINE_NESTING + 1).serialize()).unwrap_err();
+        assert!(err.contains("aninhadas"));
+    }
+}
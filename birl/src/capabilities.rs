@@ -0,0 +1,160 @@
+//! What a program can reach outside the VM, like the input, the environment or files.
+//!
+//! The compiler refuses commands that use something denied, and the VM and plugins check again when running, so a
+//! loaded bytecode program can't get around it. Everything is allowed by default
+
+use std::env;
+use std::fmt::{ Display, self };
+use std::path::{ Component, Path, PathBuf };
+
+/// Something from outside the VM that a program can use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability<'a> {
+    /// Reading the input, like FALA AÍ does
+    Stdin,
+    /// Printing anything
+    Stdout,
+    /// Reading environment variables
+    Env,
+    /// Reading or writing the file or directory at the path
+    Path(&'a Path),
+    /// Starting other programs
+    Process,
+    /// Reading the current time
+    Clock,
+}
+
+impl<'a> Display for Capability<'a> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Capability::Stdin => write!(f, "O programa não pode ler a entrada"),
+            Capability::Stdout => write!(f, "O programa não pode escrever na saída"),
+            Capability::Env => write!(f, "O programa não pode ler as variáveis de ambiente"),
+            Capability::Path(path) => write!(f, "O programa não pode mexer em \"{}\"", path.display()),
+            Capability::Process => write!(f, "O programa não pode rodar outros programas"),
+            Capability::Clock => write!(f, "O programa não pode ver as horas"),
+        }
+    }
+}
+
+/// What programs are allowed to use
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub stdin : bool,
+    pub stdout : bool,
+    pub env : bool,
+    /// Directories and files that can be used, with everything inside them. None allows every path
+    pub paths : Option<Vec<PathBuf>>,
+    pub process : bool,
+    pub clock : bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::all()
+    }
+}
+
+// Makes the path absolute and removes . and .. from it, without touching the filesystem since the path might not
+// exist yet. Symbolic links aren't followed
+fn normalize(path : &Path) -> Option<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().ok()?.join(path)
+    };
+
+    let mut normal = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => { normal.pop(); }
+            other => normal.push(other.as_os_str()),
+        }
+    }
+
+    Some(normal)
+}
+
+impl Capabilities {
+    /// Allows everything
+    pub fn all() -> Capabilities {
+        Capabilities {
+            stdin : true,
+            stdout : true,
+            env : true,
+            paths : None,
+            process : true,
+            clock : true,
+        }
+    }
+
+    /// Denies everything, even printing
+    pub fn none() -> Capabilities {
+        Capabilities {
+            stdin : false,
+            stdout : false,
+            env : false,
+            paths : Some(vec![]),
+            process : false,
+            clock : false,
+        }
+    }
+
+    /// Denies everything but printing, for running programs that can't be trusted
+    pub fn sandboxed() -> Capabilities {
+        Capabilities { stdout : true, .. Capabilities::none() }
+    }
+
+    pub fn allows(&self, capability : Capability) -> bool {
+        match capability {
+            Capability::Stdin => self.stdin,
+            Capability::Stdout => self.stdout,
+            Capability::Env => self.env,
+            Capability::Process => self.process,
+            Capability::Clock => self.clock,
+            Capability::Path(path) => match self.paths {
+                None => true,
+                Some(ref allowed) => match normalize(path) {
+                    Some(path) => allowed.iter()
+                        .filter_map(|p| normalize(p))
+                        .any(|p| path.starts_with(p)),
+                    None => false,
+                },
+            },
+        }
+    }
+
+    /// Gives a permission error if the capability is denied
+    pub fn check(&self, capability : Capability) -> Result<(), String> {
+        if self.allows(capability) {
+            Ok(())
+        } else {
+            Err(format!("Permissão negada : {}", capability))
+        }
+    }
+}
+
+mod tests {
+    #![allow(dead_code, unused_imports)]
+
+    use capabilities::*;
+
+    #[test]
+    fn paths() {
+        let caps = Capabilities { paths : Some(vec![PathBuf::from("/tmp/birl")]), .. Capabilities::none() };
+
+        assert!(caps.allows(Capability::Path(Path::new("/tmp/birl"))));
+        assert!(caps.allows(Capability::Path(Path::new("/tmp/birl/a/./b.txt"))));
+        assert!(!caps.allows(Capability::Path(Path::new("/tmp/birl/../segredo"))));
+        assert!(!caps.allows(Capability::Path(Path::new("/tmp/birlscript"))));
+        assert!(!caps.allows(Capability::Stdin));
+
+        assert!(Capabilities::all().allows(Capability::Path(Path::new("../qualquer"))));
+        assert!(!Capabilities::none().allows(Capability::Path(Path::new("/"))));
+        assert_eq!(Capabilities::sandboxed().check(Capability::Env),
+            Err("Permissão negada : O programa não pode ler as variáveis de ambiente".to_owned()));
+        assert!(Capabilities::sandboxed().check(Capability::Stdout).is_ok());
+    }
+}
@@ -1,7 +1,12 @@
 use std::collections::HashMap;
-use parser::{ Expression, ExpressionNode, FunctionParameter, Command, TypeKind, CommandArgument, MathOperator, CommandKind };
-use vm::{ Instruction, ComparisionRequest };
-use context::RawValue;
+use parser::{ Expression, ExpressionNode, FunctionParameter, Command, TypeKind, CommandArgument, MathOperator, CommandKind,
+              InterpolationPart };
+use vm::{ Instruction, ComparisionRequest, Code, Block, BlockKind, MAX_FRAME_SIZE };
+use context::{ RawValue, BIRL_RET_VAL_VAR_ADDRESS, BIRL_MAIN_FUNCTION_ID };
+use optimizer;
+use bytecode::{ FunctionEntry, GlobalSymbol };
+use formatting::Template;
+use capabilities::{ Capabilities, Capability };
 
 #[derive(Debug)]
 enum SubScopeKind {
@@ -29,11 +34,25 @@ impl SymbolEntry {
     }
 }
 
+// How a range loop moves its counter at the end of each iteration
+#[derive(Debug)]
+struct LoopIncrement {
+    counter : SymbolEntry,
+    step : SymbolEntry,
+}
+
 #[derive(Debug)]
 struct ScopeInfo {
     symbol_table : HashMap<String, SymbolEntry>,
     scope_kind : SubScopeKind,
     previous_next_var_address : usize,
+    // Address of the first instruction of the block. Loops start each iteration from it
+    start_address : usize,
+    // Jumps that leave the block, resolved to the address after it when the block ends
+    exit_jumps : Vec<usize>,
+    // Jumps to the next iteration of a loop, resolved when the loop ends
+    continue_jumps : Vec<usize>,
+    increment : Option<LoopIncrement>,
 }
 
 impl ScopeInfo {
@@ -45,6 +64,10 @@ impl ScopeInfo {
             symbol_table,
             scope_kind,
             previous_next_var_address,
+            start_address : 0,
+            exit_jumps : vec![],
+            continue_jumps : vec![],
+            increment : None,
         }
     }
 }
@@ -53,6 +76,8 @@ impl ScopeInfo {
 enum FunctionKind {
     Plugin,
     Source,
+    /// A GAIOLA, which gives a coroutine when called instead of running
+    Coroutine,
 }
 
 struct FunctionInfo {
@@ -76,7 +101,19 @@ pub struct Compiler {
     scopes : Vec<ScopeInfo>,
     functions : HashMap<String, FunctionInfo>,
     next_var_address : usize,
+    // Biggest frame needed by the function being compiled and by the global scope
+    function_frame_size : usize,
+    global_frame_size : usize,
     current_scope : ScopeKind,
+    // How much finished functions are optimized. See optimizer::optimize
+    optimization_level : u8,
+    // Source line of the command being compiled, and variables declared by it, kept in the code's debug info
+    source_line : usize,
+    declared_variables : Vec<(String, usize)>,
+    // What the commands compiled can use. Commands that use something denied don't compile
+    capabilities : Capabilities,
+    // Whether the function being compiled is a GAIOLA, the only place where SOLTA ESSE can be used
+    compiling_coroutine : bool,
 }
 
 impl Compiler {
@@ -89,25 +126,74 @@ impl Compiler {
             scopes : vec![ScopeInfo::new(SubScopeKind::Regular, 1, true)],
             functions : funcs,
             next_var_address : 1,
+            function_frame_size : 1,
+            global_frame_size : 1,
             current_scope : ScopeKind::Global,
+            optimization_level : 0,
+            source_line : 0,
+            declared_variables : vec![],
+            capabilities : Capabilities::default(),
+            compiling_coroutine : false,
         }
     }
 
+    /// Sets the source line of the next commands compiled, to be shown when they're disassembled. 0 means there's no line
+    pub fn set_source_line(&mut self, line : usize) {
+        self.source_line = line;
+    }
+
+    pub fn set_optimization_level(&mut self, level : u8) {
+        self.optimization_level = level;
+    }
+
+    pub fn set_capabilities(&mut self, capabilities : Capabilities) {
+        self.capabilities = capabilities;
+    }
+
     fn get_inst_for_op(op : MathOperator) -> Option<Instruction> {
         match op {
             MathOperator::Plus => Some(Instruction::Add),
             MathOperator::Minus => Some(Instruction::Sub),
             MathOperator::Division => Some(Instruction::Div),
             MathOperator::Multiplication => Some(Instruction::Mul),
+            MathOperator::BitwiseAnd => Some(Instruction::BitwiseAnd),
+            MathOperator::BitwiseOr => Some(Instruction::BitwiseOr),
+            MathOperator::BitwiseXor => Some(Instruction::BitwiseXor),
+            MathOperator::BitwiseNot => Some(Instruction::BitwiseNot),
+            MathOperator::ShiftLeft => Some(Instruction::ShiftLeft),
+            MathOperator::ShiftRight => Some(Instruction::ShiftRight),
             _ => None,
         }
     }
 
-    pub fn compile_expression(&self, expr : Expression, inst : &mut Vec<Instruction>) -> Result<(), String> {
+    fn compile_interpolation(&self, parts : Vec<InterpolationPart>, inst : &mut Code) -> Result<(), String> {
+        inst.push(Instruction::StartInterpolation);
+
+        for part in parts {
+            match part {
+                InterpolationPart::Text(text) => {
+                    let index = inst.add_text(text);
+
+                    inst.push(Instruction::AppendToInterpolation(index));
+                }
+                InterpolationPart::Value(expr, format) => {
+                    self.compile_expression(expr, inst)?;
+
+                    inst.push(Instruction::AppendValueToInterpolation(format));
+                }
+            }
+        }
+
+        inst.push(Instruction::FinishInterpolation);
+
+        Ok(())
+    }
 
-        inst.push(Instruction::SetFirstExpressionOperation);
+    pub fn compile_expression(&self, expr : Expression, inst : &mut Code) -> Result<(), String> {
+        // The nodes are in postfix order, so they map directly to the operand stack : values are pushed and
+        // operators pop their operands, pushing the result back. The result is left on the top of the stack
 
-        let mut is_a = expr.nodes.len() > 1;
+        let mut num_values = 0usize;
 
         for node in expr.nodes {
             match node {
@@ -119,18 +205,27 @@ impl Compiler {
                         None => unreachable!(),
                     };
 
+                    let needed = if o == MathOperator::BitwiseNot { 1 } else { 2 };
+
+                    if num_values < needed {
+                        return Err(format!("Erro interno : Operador {:?} sem valores suficientes na expressão", o));
+                    }
+
+                    num_values -= needed - 1;
+
                     inst.push(opi);
+                }
+                ExpressionNode::Value(RawValue::Text(text)) => {
+                    let index = inst.add_text(text);
+
+                    inst.push(Instruction::PushText(index));
 
-                    is_a = true;
+                    num_values += 1;
                 }
                 ExpressionNode::Value(raw) => {
-                    if is_a {
-                        inst.push(Instruction::PushValMathA(raw));
-                    } else {
-                        inst.push(Instruction::PushValMathB(raw));
-                    }
+                    inst.push(Instruction::PushValue(raw));
 
-                    is_a = !is_a;
+                    num_values += 1;
                 }
                 ExpressionNode::Symbol(s) => {
                     let info = match self.find_symbol(s.as_str()) {
@@ -138,19 +233,14 @@ impl Compiler {
                         None => return Err(format!("Variável não encontrada : {}", s)),
                     };
 
-                    if info.global {
-                        inst.push(Instruction::ReadGlobalVarFrom(info.address));
-                    } else {
-                        inst.push(Instruction::ReadVarFrom(info.address));
-                    }
+                    Compiler::compile_read(info, inst);
 
-                    if is_a {
-                        inst.push(Instruction::PushIntermediateToA);
-                    } else {
-                        inst.push(Instruction::PushIntermediateToB);
-                    }
+                    num_values += 1;
+                }
+                ExpressionNode::Interpolation(parts) => {
+                    self.compile_interpolation(parts, inst)?;
 
-                    is_a = !is_a;
+                    num_values += 1;
                 }
             }
         }
@@ -158,6 +248,51 @@ impl Compiler {
         Ok(())
     }
 
+    // Checks if the command is "BIRL: TREZE", which returns whatever the last function called returned
+    fn is_tail_call_return(&self, cmd : &Command) -> bool {
+        if self.current_scope != ScopeKind::Function || cmd.arguments.len() != 1 {
+            return false;
+        }
+
+        match cmd.arguments[0] {
+            CommandArgument::Expression(ref expr) if expr.nodes.len() == 1 => {
+                match expr.nodes[0] {
+                    ExpressionNode::Symbol(ref name) => match self.find_symbol(name.as_str()) {
+                        Some(info) => !info.global && info.address == BIRL_RET_VAL_VAR_ADDRESS,
+                        None => false,
+                    },
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    // If the last thing done was calling a function, turn the call into a tail call, so the frame is reused. Not when
+    // a block jumps to the current address, since then the call isn't always the last thing done
+    fn make_tail_call(instructions : &mut Code) -> bool {
+        match instructions.instructions.last() {
+            Some(Instruction::SetLastFrameReady) => {}
+            _ => return false,
+        }
+
+        let here = instructions.len();
+
+        let is_jump_target = instructions.instructions.iter().any(|i| match *i {
+            Instruction::Jump(address) | Instruction::JumpIfNot(_, address) => address == here,
+            _ => false,
+        });
+
+        if is_jump_target {
+            return false;
+        }
+
+        instructions.instructions.pop();
+        instructions.push(Instruction::TailCall);
+
+        true
+    }
+
     fn end_scope(&mut self, info : ScopeInfo) {
         self.next_var_address = info.previous_next_var_address;
     }
@@ -173,39 +308,130 @@ impl Compiler {
         None
     }
 
-    fn add_symbol(&mut self, name : String, writeable : bool) -> Option<SymbolEntry> {
-        let is_global = self.current_scope == ScopeKind::Global;
-        let entry = SymbolEntry::from(self.next_var_address, is_global, writeable);
+    // Takes the next free variable address, keeping track of how big the frame has to be
+    fn allocate_address(&mut self) -> Result<usize, String> {
+        let address = self.next_var_address;
+
+        if address >= MAX_FRAME_SIZE {
+            return Err(format!("Erro : Variáveis demais declaradas ao mesmo tempo. O limite é de {} por função",
+                               MAX_FRAME_SIZE - 1));
+        }
+
         self.next_var_address += 1;
 
-        match self.scopes.last_mut() {
-            Some(s) => {
-                s.symbol_table.insert(name, entry.clone());
-                Some(entry)
-            }
-            None => None,
+        let frame_size = if self.current_scope == ScopeKind::Global {
+            &mut self.global_frame_size
+        } else {
+            &mut self.function_frame_size
+        };
+
+        if *frame_size < self.next_var_address {
+            *frame_size = self.next_var_address;
         }
+
+        Ok(address)
     }
 
-    fn find_or_add_symbol(&mut self, name : &str, writeable : bool) -> Option<SymbolEntry> {
+    fn add_symbol(&mut self, name : String, writeable : bool) -> Result<SymbolEntry, String> {
         if self.scopes.is_empty() {
-            None
-        } else {
-            match self.find_symbol(name) {
-                Some(s) => return Some(s.clone()),
-                None => {},
-            }
+            return Err("Scopes é vazio".to_owned());
+        }
 
-            match self.add_symbol(name.to_owned(), writeable) {
-                Some(s) => Some(s),
-                None => None,
-            }
+        let is_global = self.current_scope == ScopeKind::Global;
+        let entry = SymbolEntry::from(self.allocate_address()?, is_global, writeable);
+
+        self.declared_variables.push((name.clone(), entry.address));
+
+        if let Some(s) = self.scopes.last_mut() {
+            s.symbol_table.insert(name, entry.clone());
+        }
+
+        Ok(entry)
+    }
+
+    fn find_or_add_symbol(&mut self, name : &str, writeable : bool) -> Result<SymbolEntry, String> {
+        match self.find_symbol(name) {
+            Some(s) => Ok(s.clone()),
+            None => self.add_symbol(name.to_owned(), writeable),
+        }
+    }
+
+    /// How many variable slots the global frame needs for everything compiled so far
+    pub fn get_global_frame_size(&self) -> usize {
+        self.global_frame_size
+    }
+
+    /// Whether nothing is being compiled right now, outside of any function or block
+    pub fn is_idle(&self) -> bool {
+        self.current_scope == ScopeKind::Global && self.scopes.len() == 1
+    }
+
+    /// Whether any function or global variable was added, besides plugins
+    pub fn has_definitions(&self) -> bool {
+        let has_functions = self.functions.values()
+            .any(|f| f.kind != FunctionKind::Plugin && f.address > BIRL_MAIN_FUNCTION_ID);
+
+        has_functions || self.global_frame_size > 1 || self.scopes[0].symbol_table.len() > 1
+    }
+
+    /// Every function and plugin added, sorted by name. The global and main functions are always there, so they're left out
+    pub fn get_function_entries(&self) -> Vec<FunctionEntry> {
+        let mut entries = self.functions.iter()
+            .filter(|&(_, f)| f.kind == FunctionKind::Plugin || f.address > BIRL_MAIN_FUNCTION_ID)
+            .map(|(name, f)| FunctionEntry {
+                name : name.clone(),
+                address : f.address,
+                parameters : f.arguments.clone(),
+                is_plugin : f.kind == FunctionKind::Plugin,
+                is_coroutine : f.kind == FunctionKind::Coroutine,
+            })
+            .collect::<Vec<FunctionEntry>>();
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        entries
+    }
+
+    /// Global variables declared so far, sorted by address
+    pub fn get_global_symbols(&self) -> Vec<GlobalSymbol> {
+        let mut symbols = self.scopes[0].symbol_table.iter()
+            .map(|(name, entry)| GlobalSymbol { name : name.clone(), address : entry.address, writeable : entry.writeable })
+            .collect::<Vec<GlobalSymbol>>();
+
+        symbols.sort_by_key(|s| s.address);
+
+        symbols
+    }
+
+    /// Adds the source functions and global variables of a program compiled before, as if it was compiled now
+    pub fn add_compiled_definitions(&mut self, functions : &[FunctionEntry], globals : &[GlobalSymbol], global_frame_size : usize)
+        -> Result<(), String>
+    {
+        if !self.is_idle() {
+            return Err("Não é possível adicionar definições enquanto algo está sendo compilado".to_owned());
+        }
+
+        for f in functions.iter().filter(|f| !f.is_plugin) {
+            let kind = if f.is_coroutine { FunctionKind::Coroutine } else { FunctionKind::Source };
+            let info = FunctionInfo::from(f.address, f.parameters.clone(), kind);
+
+            self.functions.insert(f.name.clone(), info);
         }
+
+        for g in globals {
+            self.scopes[0].symbol_table.insert(g.name.clone(), SymbolEntry::from(g.address, true, g.writeable));
+        }
+
+        self.global_frame_size = self.global_frame_size.max(global_frame_size);
+        self.next_var_address = self.global_frame_size;
+
+        Ok(())
     }
 
     fn get_function_info(&self, id : usize) -> Option<&FunctionInfo> {
+        // Plugins have their own addresses, which may be the same as a function's
         for (_, f) in &self.functions {
-            if f.address == id {
+            if f.address == id && f.kind != FunctionKind::Plugin {
                 return Some(f);
             }
         }
@@ -213,8 +439,101 @@ impl Compiler {
         None
     }
 
-    fn add_execute_while_boilerplate(&self, mut cmd : Command, instructions : &mut Vec<Instruction>) -> Result<(), String> {
-        instructions.push(Instruction::AddLoopLabel);
+    // Starts a block that is skipped when the last comparision doesn't match the request
+    fn begin_conditional(&mut self, req : ComparisionRequest, instructions : &mut Code) {
+        let is_global = self.current_scope == ScopeKind::Global;
+        let mut scope = ScopeInfo::new(SubScopeKind::ExecuteIf, self.next_var_address, is_global);
+
+        scope.start_address = instructions.len();
+        scope.exit_jumps.push(instructions.len());
+        instructions.push(Instruction::JumpIfNot(req, 0));
+
+        self.scopes.push(scope);
+    }
+
+    fn patch_jump(instructions : &mut [Instruction], index : usize, target : usize) {
+        match instructions[index] {
+            Instruction::Jump(ref mut address) => *address = target,
+            Instruction::JumpIfNot(_, ref mut address) => *address = target,
+            _ => {}
+        }
+    }
+
+    // Closes a conditional or loop block, pointing every pending jump to the right place
+    fn end_block(&mut self, mut scope : ScopeInfo, instructions : &mut Code) -> Result<(), String> {
+        if let SubScopeKind::Loop = scope.scope_kind {
+            let next_iteration = instructions.len();
+
+            for index in scope.continue_jumps.drain(..) {
+                Compiler::patch_jump(&mut instructions.instructions, index, next_iteration);
+            }
+
+            if let Some(ref increment) = scope.increment {
+                Compiler::compile_read(&increment.counter, instructions);
+                Compiler::compile_read(&increment.step, instructions);
+                instructions.push(Instruction::Add);
+                Compiler::compile_write(&increment.counter, instructions);
+            }
+
+            instructions.push(Instruction::Jump(scope.start_address));
+        }
+
+        let end = instructions.len();
+
+        for index in scope.exit_jumps.drain(..) {
+            Compiler::patch_jump(&mut instructions.instructions, index, end);
+        }
+
+        let kind = match scope.scope_kind {
+            SubScopeKind::Loop => BlockKind::Loop,
+            _ => BlockKind::Conditional,
+        };
+
+        instructions.debug.blocks.push(Block { start : scope.start_address, end, kind });
+
+        self.end_scope(scope);
+
+        Ok(())
+    }
+
+    fn compile_read(entry : &SymbolEntry, instructions : &mut Code) {
+        if entry.global {
+            instructions.push(Instruction::ReadGlobalVarFrom(entry.address));
+        } else {
+            instructions.push(Instruction::ReadVarFrom(entry.address));
+        }
+    }
+
+    fn compile_write(entry : &SymbolEntry, instructions : &mut Code) {
+        if entry.global {
+            instructions.push(Instruction::WriteGlobalVarTo(entry.address));
+        } else {
+            instructions.push(Instruction::WriteVarTo(entry.address));
+        }
+    }
+
+    // Index of the innermost loop, or of any block if loop_only is false, without leaving the function scope
+    fn find_block(&self, loop_only : bool) -> Option<usize> {
+        for (index, scope) in self.scopes.iter().enumerate().rev() {
+            match scope.scope_kind {
+                SubScopeKind::Regular => return None,
+                SubScopeKind::Loop => return Some(index),
+                SubScopeKind::ExecuteIf => if !loop_only {
+                    return Some(index);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn add_execute_while_boilerplate(&mut self, mut cmd : Command, req : ComparisionRequest, instructions : &mut Code)
+        -> Result<(), String>
+    {
+        let is_global = self.current_scope == ScopeKind::Global;
+        let mut scope = ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global);
+
+        scope.start_address = instructions.len();
 
         if let CommandArgument::Expression(expr) = cmd.arguments.remove(0) {
             self.compile_expression(expr, instructions)?;
@@ -222,9 +541,6 @@ impl Compiler {
             return Err("Argumento 1 não é expressão".to_owned());
         }
 
-        // Move result to A
-        instructions.push(Instruction::SwapMath);
-
         if let CommandArgument::Expression(expr) = cmd.arguments.remove(0) {
             self.compile_expression(expr, instructions)?;
         } else {
@@ -233,11 +549,81 @@ impl Compiler {
 
         instructions.push(Instruction::Compare);
 
+        scope.exit_jumps.push(instructions.len());
+        instructions.push(Instruction::JumpIfNot(req, 0));
+
+        self.scopes.push(scope);
+
+        Ok(())
+    }
+
+    /// Compiles the template and its values, leaving the formatted text on the operand stack
+    fn compile_format(&self, mut arguments : Vec<CommandArgument>, instructions : &mut Code) -> Result<(), String> {
+        let template = match arguments.remove(0) {
+            CommandArgument::Expression(expr) => expr,
+            _ => return Err("Erro interno : Esperado uma expressão como formato".to_owned()),
+        };
+
+        // If the template is known already, it can be checked right away
+
+        if template.nodes.len() == 1 {
+            if let ExpressionNode::Value(RawValue::Text(ref t)) = template.nodes[0] {
+                let parsed = Template::parse(t.as_str())?;
+
+                if parsed.num_arguments != arguments.len() {
+                    return Err(format!("O formato usa {} argumentos, mas {} foram passados", parsed.num_arguments, arguments.len()));
+                }
+            }
+        }
+
+        self.compile_expression(template, instructions)?;
+
+        let num_args = arguments.len();
+
+        for arg in arguments {
+            match arg {
+                CommandArgument::Expression(expr) => self.compile_expression(expr, instructions)?,
+                _ => return Err("Erro interno : Esperado uma expressão como valor pro formato".to_owned()),
+            }
+        }
+
+        instructions.push(Instruction::FormatText(num_args));
+
         Ok(())
     }
 
-    pub fn compile_command(&mut self, mut cmd : Command, instructions : &mut Vec<Instruction>)
+    // Keeps the line and the variables declared by the instructions compiled since the address
+    fn add_debug_info(&mut self, start : usize, instructions : &mut Code) {
+        if start < instructions.len() && self.source_line != 0 {
+            instructions.debug.mark_line(start, self.source_line);
+        }
+
+        for (name, address) in self.declared_variables.drain(..) {
+            instructions.debug.variables.push((start, name, address));
+        }
+    }
+
+    pub fn compile_command(&mut self, cmd : Command, instructions : &mut Code) -> Result<Option<CompilerHint>, String> {
+        let start = instructions.len();
+        let result = self.compile_single_command(cmd, instructions);
+
+        self.add_debug_info(start, instructions);
+
+        result
+    }
+
+    fn compile_single_command(&mut self, mut cmd : Command, instructions : &mut Code)
             -> Result<Option<CompilerHint>, String> {
+        match cmd.kind {
+            CommandKind::Print | CommandKind::PrintLn | CommandKind::PrintDebug | CommandKind::PrintFormatted => {
+                self.capabilities.check(Capability::Stdout)?
+            }
+            CommandKind::GetStringInput | CommandKind::GetIntegerInput | CommandKind::GetNumberInput => {
+                self.capabilities.check(Capability::Stdin)?
+            }
+            _ => {}
+        }
+
         match cmd.kind {
             CommandKind::PrintDebug => {
                 // Evaluate the single argument and print-debug it
@@ -254,7 +640,7 @@ impl Compiler {
                                 Err(e) => return Err(e),
                             };
 
-                            instructions.push(Instruction::PrintMathBDebug);
+                            instructions.push(Instruction::PrintValueDebug);
                         }
                         _ => return Err("Erro : Um argumento diferente de valor foi passado pra print. Erro interno.".to_owned()),
                     }
@@ -269,7 +655,7 @@ impl Compiler {
                                 Err(e) => return Err(e),
                             };
 
-                            instructions.push(Instruction::PrintMathB);
+                            instructions.push(Instruction::PrintValue);
                         }
                         _ => return Err("Erro : Um argumento diferente de valor foi passado pra print. Erro interno.".to_owned()),
                     }
@@ -286,7 +672,7 @@ impl Compiler {
                                 Err(e) => return Err(e),
                             };
 
-                            instructions.push(Instruction::PrintMathB);
+                            instructions.push(Instruction::PrintValue);
                         }
                         _ => return Err("Erro : Um argumento diferente de valor foi passado pra print. Erro interno.".to_owned()),
                     }
@@ -294,6 +680,32 @@ impl Compiler {
 
                 instructions.push(Instruction::PrintNewLine);
             }
+            CommandKind::PrintFormatted => {
+                self.compile_format(cmd.arguments, instructions)?;
+
+                instructions.push(Instruction::PrintValue);
+                instructions.push(Instruction::FlushStdout);
+            }
+            CommandKind::FormatText => {
+                let name = match cmd.arguments.remove(0) {
+                    CommandArgument::Name(n) => n,
+                    _ => return Err("Erro interno : Esperado um nome pro FORMATA".to_owned()),
+                };
+
+                self.compile_format(cmd.arguments, instructions)?;
+
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
+
+                if !entry.writeable {
+                    return Err(format!("Erro : A variável {} não pode ser escrita", name));
+                }
+
+                if entry.global {
+                    instructions.push(Instruction::WriteGlobalVarTo(entry.address));
+                } else {
+                    instructions.push(Instruction::WriteVarTo(entry.address));
+                }
+            }
             CommandKind::Quit => instructions.push(Instruction::Quit),
             CommandKind::Set => {
                 if cmd.arguments.len() != 2 {
@@ -347,10 +759,9 @@ impl Compiler {
                 let is_global = self.current_scope == ScopeKind::Global;
 
                 if cmd.arguments.is_empty() {
-                    // Set value to Null
-                    // To achieve this, we set both Maths to null, then copy B to the var address
+                    // The variable starts as Null
 
-                    instructions.push(Instruction::ClearMath);
+                    instructions.push(Instruction::PushValue(RawValue::Null));
                 } else {
                     let expr_arg = cmd.arguments.remove(0);
 
@@ -367,8 +778,9 @@ impl Compiler {
 
                 // Add the variable after the expression is parsed, so we can't use the variable before a value is set
 
-                let address = self.next_var_address;
-                self.next_var_address += 1;
+                let address = self.allocate_address()?;
+
+                self.declared_variables.push((name.clone(), address));
 
                 match self.scopes.last_mut() {
                     Some(s) => s.symbol_table.insert(name, SymbolEntry::from(address, is_global, true)),
@@ -382,8 +794,12 @@ impl Compiler {
                 }
             }
             CommandKind::Return => {
+                if self.is_tail_call_return(&cmd) && Compiler::make_tail_call(instructions) {
+                    return Ok(None);
+                }
+
                 if cmd.arguments.is_empty() {
-                    instructions.push(Instruction::ClearMath);
+                    instructions.push(Instruction::PushValue(RawValue::Null));
                 } else {
                     let expr_arg = cmd.arguments.remove(0);
 
@@ -413,8 +829,6 @@ impl Compiler {
                     _ => return Err(format!("Esperado uma expressão como argumento pro comando Return, encontrado {:?}", left_expr_arg)),
                 }
 
-                instructions.push(Instruction::SwapMath);
-
                 let right_expr_arg = cmd.arguments.remove(0);
 
                 match right_expr_arg {
@@ -435,69 +849,43 @@ impl Compiler {
                     None => return Err(format!("FIM fora de qualquer scope"))
                 };
 
-                match scope_info.scope_kind {
-                    SubScopeKind::ExecuteIf => instructions.push(Instruction::EndConditionalBlock),
-                    SubScopeKind::Loop => {
-                        instructions.push(Instruction::RestoreLoopLabel);
-                        instructions.push(Instruction::EndConditionalBlock);
-                        instructions.push(Instruction::PopLoopLabel);
-                    }
-                    SubScopeKind::Regular => {
-                        self.scopes.push(scope_info);
+                if let SubScopeKind::Regular = scope_info.scope_kind {
+                    self.scopes.push(scope_info);
 
-                        return Err("Erro : Usando FIM pra finalizar uma função".to_owned());
-                    }
+                    return Err("Erro : Usando FIM pra finalizar uma função".to_owned());
                 }
 
-                self.end_scope(scope_info);
+                self.end_block(scope_info, instructions)?;
 
                 return Ok(Some(CompilerHint::ScopeEnd));
             },
             CommandKind::ExecuteIfEqual => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::ExecuteIf,
-                                                self.next_var_address, is_global));
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::Equal));
+                self.begin_conditional(ComparisionRequest::Equal, instructions);
 
                 return Ok(Some(CompilerHint::ScopeStart));
             },
             CommandKind::ExecuteIfNotEqual => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::ExecuteIf,
-                                                self.next_var_address, is_global));
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::NotEqual));
+                self.begin_conditional(ComparisionRequest::NotEqual, instructions);
 
                 return Ok(Some(CompilerHint::ScopeStart));
             },
             CommandKind::ExecuteIfEqualOrGreater => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::ExecuteIf,
-                                                self.next_var_address, is_global));
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::MoreOrEqual));
+                self.begin_conditional(ComparisionRequest::MoreOrEqual, instructions);
 
                 return Ok(Some(CompilerHint::ScopeStart));
             },
             CommandKind::ExecuteIfGreater => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::ExecuteIf,
-                                                self.next_var_address, is_global));
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::More));
+                self.begin_conditional(ComparisionRequest::More, instructions);
 
                 return Ok(Some(CompilerHint::ScopeStart));
             },
             CommandKind::ExecuteIfEqualOrLess => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::ExecuteIf,
-                                                self.next_var_address, is_global));
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::LessOrEqual));
+                self.begin_conditional(ComparisionRequest::LessOrEqual, instructions);
 
                 return Ok(Some(CompilerHint::ScopeStart));
             },
             CommandKind::ExecuteIfLess => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::ExecuteIf,
-                                                self.next_var_address, is_global));
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::Less));
+                self.begin_conditional(ComparisionRequest::Less, instructions);
 
                 return Ok(Some(CompilerHint::ScopeStart));
             },
@@ -513,7 +901,7 @@ impl Compiler {
                     return Err("É HORA DO espera um nome pra função".to_owned());
                 };
 
-                if info.kind == FunctionKind::Source {
+                if info.kind != FunctionKind::Plugin {
                     instructions.push(Instruction::MakeNewFrame(info.address));
                 }
 
@@ -537,21 +925,83 @@ impl Compiler {
                         Err(e) => return Err(e)
                     };
 
-                    instructions.push(Instruction::AssertMathBCompatible(expected_type));
+                    instructions.push(Instruction::AssertCompatible(expected_type));
 
-                    if info.kind == FunctionKind::Source {
+                    if info.kind != FunctionKind::Plugin {
                         instructions.push(Instruction::WriteVarToLast(index + 1));
-                    } else {
-                        instructions.push(Instruction::PushMathBPluginArgument);
                     }
 
                     index += 1;
                 }
 
-                if info.kind == FunctionKind::Source {
-                    instructions.push(Instruction::SetLastFrameReady);
-                } else if info.kind == FunctionKind::Plugin {
-                    instructions.push(Instruction::CallPlugin(info.address, num_args));
+                match info.kind {
+                    FunctionKind::Source => instructions.push(Instruction::SetLastFrameReady),
+                    // The GAIOLA doesn't run yet, TREZE gets the coroutine
+                    FunctionKind::Coroutine => instructions.push(Instruction::MakeCoroutine),
+                    FunctionKind::Plugin => instructions.push(Instruction::CallPlugin(info.address, num_args)),
+                }
+            }
+            CommandKind::Yield => {
+                if !self.compiling_coroutine {
+                    return Err("SOLTA ESSE só pode ser usado dentro de uma GAIOLA".to_owned());
+                }
+
+                match cmd.arguments.pop() {
+                    Some(CommandArgument::Expression(expr)) => self.compile_expression(expr, instructions)?,
+                    Some(_) => return Err("Erro interno : Esperado uma expressão pro SOLTA ESSE".to_owned()),
+                    None => instructions.push(Instruction::PushValue(RawValue::Null)),
+                }
+
+                instructions.push(Instruction::Yield);
+            }
+            CommandKind::ResumeCoroutine | CommandKind::QueryCoroutineDone => {
+                let expr = match cmd.arguments.remove(0) {
+                    CommandArgument::Expression(e) => e,
+                    _ => return Err("Erro interno : Esperado uma expressão com a gaiola".to_owned()),
+                };
+
+                let name = match cmd.arguments.remove(0) {
+                    CommandArgument::Name(n) => n,
+                    _ => return Err("Erro interno : Esperado um nome pra variável".to_owned()),
+                };
+
+                self.compile_expression(expr, instructions)?;
+
+                if cmd.kind == CommandKind::ResumeCoroutine {
+                    instructions.push(Instruction::ResumeCoroutine);
+                } else {
+                    instructions.push(Instruction::QueryCoroutineDone);
+                }
+
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
+
+                if entry.global {
+                    instructions.push(Instruction::WriteGlobalVarTo(entry.address));
+                } else {
+                    instructions.push(Instruction::WriteVarTo(entry.address));
+                }
+            }
+            CommandKind::Pause => {
+                let name = match cmd.arguments.remove(0) {
+                    CommandArgument::Name(n) => n,
+                    _ => return Err("Erro interno : Esperado um nome pro PERA AÍ".to_owned()),
+                };
+
+                // The value given to the host, or Null
+                match cmd.arguments.pop() {
+                    Some(CommandArgument::Expression(expr)) => self.compile_expression(expr, instructions)?,
+                    Some(_) => return Err("Erro interno : Esperado uma expressão depois do nome".to_owned()),
+                    None => instructions.push(Instruction::PushValue(RawValue::Null)),
+                }
+
+                instructions.push(Instruction::Suspend);
+
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
+
+                if entry.global {
+                    instructions.push(Instruction::WriteGlobalVarTo(entry.address));
+                } else {
+                    instructions.push(Instruction::WriteVarTo(entry.address));
                 }
             }
             CommandKind::GetStringInput => {
@@ -562,13 +1012,9 @@ impl Compiler {
                     _ => return Err("Erro interno : Esperado um nome pra GetInput*".to_owned()),
                 };
 
-                let entry = match self.find_or_add_symbol(name.as_str(), true) {
-                    Some(e) => e,
-                    None => return Err(format!("Variável {} não encontrada", name))
-                };
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
 
                 instructions.push(Instruction::ReadInput);
-                instructions.push(Instruction::PushIntermediateToB);
 
                 if entry.global {
                     instructions.push(Instruction::WriteGlobalVarTo(entry.address));
@@ -584,15 +1030,10 @@ impl Compiler {
                     _ => return Err("Erro interno : Esperado um nome pra GetInput*".to_owned()),
                 };
 
-                let entry = match self.find_or_add_symbol(name.as_str(), true) {
-                    Some(e) => e,
-                    None => return Err(format!("Variável {} não encontrada", name))
-                };
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
 
                 instructions.push(Instruction::ReadInput);
 
-                instructions.push(Instruction::PushIntermediateToB);
-
                 instructions.push(Instruction::ConvertToInt);
 
                 if entry.global {
@@ -609,15 +1050,10 @@ impl Compiler {
                     _ => return Err("Erro interno : Esperado um nome pra GetInput*".to_owned()),
                 };
 
-                let entry = match self.find_or_add_symbol(name.as_str(), true) {
-                    Some(e) => e,
-                    None => return Err(format!("Variável {} não encontrada", name))
-                };
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
 
                 instructions.push(Instruction::ReadInput);
 
-                instructions.push(Instruction::PushIntermediateToB);
-
                 instructions.push(Instruction::ConvertToNum);
 
                 if entry.global {
@@ -645,7 +1081,6 @@ impl Compiler {
                     instructions.push(Instruction::ReadVarFrom(entry.address));
                 }
 
-                instructions.push(Instruction::PushIntermediateToB);
 
                 instructions.push(Instruction::ConvertToInt);
 
@@ -674,7 +1109,6 @@ impl Compiler {
                     instructions.push(Instruction::ReadVarFrom(entry.address));
                 }
 
-                instructions.push(Instruction::PushIntermediateToB);
 
                 instructions.push(Instruction::ConvertToNum);
 
@@ -703,7 +1137,6 @@ impl Compiler {
                     instructions.push(Instruction::ReadVarFrom(entry.address));
                 }
 
-                instructions.push(Instruction::PushIntermediateToB);
 
                 instructions.push(Instruction::ConvertToString);
 
@@ -714,57 +1147,33 @@ impl Compiler {
                 }
             }
             CommandKind::ExecuteWhileEqual => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global));
-
-                self.add_execute_while_boilerplate(cmd, instructions)?;
+                self.add_execute_while_boilerplate(cmd, ComparisionRequest::Equal, instructions)?;
 
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::Equal));
                 return Ok(Some(CompilerHint::ScopeStart));
             }
             CommandKind::ExecuteWhileNotEqual => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global));
-
-                self.add_execute_while_boilerplate(cmd, instructions)?;
+                self.add_execute_while_boilerplate(cmd, ComparisionRequest::NotEqual, instructions)?;
 
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::NotEqual));
                 return Ok(Some(CompilerHint::ScopeStart));
             }
             CommandKind::ExecuteWhileGreater => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global));
+                self.add_execute_while_boilerplate(cmd, ComparisionRequest::More, instructions)?;
 
-                self.add_execute_while_boilerplate(cmd, instructions)?;
-
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::More));
                 return Ok(Some(CompilerHint::ScopeStart));
             }
             CommandKind::ExecuteWhileEqualOrGreater => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global));
+                self.add_execute_while_boilerplate(cmd, ComparisionRequest::MoreOrEqual, instructions)?;
 
-                self.add_execute_while_boilerplate(cmd, instructions)?;
-
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::MoreOrEqual));
                 return Ok(Some(CompilerHint::ScopeStart));
             }
             CommandKind::ExecuteWhileLess => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global));
-
-                self.add_execute_while_boilerplate(cmd, instructions)?;
+                self.add_execute_while_boilerplate(cmd, ComparisionRequest::Less, instructions)?;
 
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::Less));
                 return Ok(Some(CompilerHint::ScopeStart));
             }
             CommandKind::ExecuteWhileEqualOrLess => {
-                let is_global = self.current_scope == ScopeKind::Global;
-                self.scopes.push(ScopeInfo::new(SubScopeKind::Loop, self.next_var_address, is_global));
+                self.add_execute_while_boilerplate(cmd, ComparisionRequest::LessOrEqual, instructions)?;
 
-                self.add_execute_while_boilerplate(cmd, instructions)?;
-
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::LessOrEqual));
                 return Ok(Some(CompilerHint::ScopeStart));
             }
             CommandKind::RangeLoop => {
@@ -777,21 +1186,14 @@ impl Compiler {
                     return Err("Esperado uma variável pro primeiro argumento do loop".to_owned());
                 };
 
-                let entry = match self.find_or_add_symbol(name.as_str(), true) {
-                    Some(e) => e,
-                    None => return Err(format!("Não foi possível adicionar nem encontrar a variável {}", name)),
-                };
+                let counter = self.find_or_add_symbol(name.as_str(), true)?;
 
                 // Initialize counter
 
                 if let CommandArgument::Expression(expr) = cmd.arguments.remove(0) {
                     self.compile_expression(expr, instructions)?;
 
-                    if entry.global {
-                        instructions.push(Instruction::WriteGlobalVarTo(entry.address));
-                    } else {
-                        instructions.push(Instruction::WriteVarTo(entry.address));
-                    }
+                    Compiler::compile_write(&counter, instructions);
                 } else {
                     return Err("Era esperado uma expressão pro segundo argumento de RangedLoop".to_owned());
                 }
@@ -802,39 +1204,40 @@ impl Compiler {
                     return Err("Esperado um valor final".to_owned());
                 };
 
-                // Register increment procedure
+                // The step is calculated only once and kept in a variable only the loop can see
 
                 if cmd.arguments.is_empty() {
-                    instructions.push(Instruction::PushValMathB(RawValue::Integer(1)));
+                    instructions.push(Instruction::PushValue(RawValue::Integer(1)));
                 } else {
                     if let CommandArgument::Expression(step_expr) = cmd.arguments.remove(0) {
                         self.compile_expression(step_expr, instructions)?;
                     } else {
-                        instructions.push(Instruction::PushValMathB(RawValue::Integer(1)));
+                        instructions.push(Instruction::PushValue(RawValue::Integer(1)));
                     }
                 }
 
-                // Loop starts here
+                let step = SymbolEntry::from(self.allocate_address()?, is_global, false);
 
-                instructions.push(Instruction::AddLoopLabel);
+                Compiler::compile_write(&step, instructions);
 
-                instructions.push(Instruction::RegisterIncrementOnRestore(entry.address));
+                // Loop starts here. Check if should continue
 
-                // Check if should continue
+                let start_address = instructions.len();
 
-                self.compile_expression(final_expr, instructions)?;
+                Compiler::compile_read(&counter, instructions);
 
-                if entry.global {
-                    instructions.push(Instruction::ReadGlobalVarFrom(entry.address));
-                } else {
-                    instructions.push(Instruction::ReadVarFrom(entry.address));
-                }
-
-                instructions.push(Instruction::PushIntermediateToA);
+                self.compile_expression(final_expr, instructions)?;
 
                 instructions.push(Instruction::Compare);
 
-                instructions.push(Instruction::ExecuteIf(ComparisionRequest::NotEqual));
+                let exit_jump = instructions.len();
+                instructions.push(Instruction::JumpIfNot(ComparisionRequest::NotEqual, 0));
+
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.start_address = start_address;
+                    scope.exit_jumps.push(exit_jump);
+                    scope.increment = Some(LoopIncrement { counter, step });
+                }
 
                 return Ok(Some(CompilerHint::ScopeStart));
             }
@@ -845,10 +1248,7 @@ impl Compiler {
                     return Err("MakeNewList : Esperado um nome".to_owned());
                 };
 
-                let entry = match self.find_or_add_symbol(name.as_str(), true) {
-                    Some(a) => a,
-                    None => return Err(format!("Não foi possível declarar a variável pra lista {}", name))
-                };
+                let entry = self.find_or_add_symbol(name.as_str(), true)?;
 
                 instructions.push(Instruction::MakeNewList);
 
@@ -871,10 +1271,7 @@ impl Compiler {
                     return Err("MakeNewList : Esperado um nome".to_owned());
                 };
 
-                let dest = match self.find_or_add_symbol(dest_name.as_str(), true) {
-                    Some(d) => d,
-                    None => return Err(format!("Não foi possível declarar a variável pra lista {}", dest_name))
-                };
+                let dest = self.find_or_add_symbol(dest_name.as_str(), true)?;
 
                 let list = match self.find_symbol(list_name.as_str()) {
                     Some(a) => a,
@@ -931,15 +1328,14 @@ impl Compiler {
 
                 if let Some(expr) = index {
                     self.compile_expression(expr, instructions)?;
+                    self.compile_expression(element, instructions)?;
 
-                    instructions.push(Instruction::PushMathBToSeconday);
+                    instructions.push(Instruction::InsertIntoList);
                 } else {
-                    instructions.push(Instruction::ClearSecondary);
-                }
+                    self.compile_expression(element, instructions)?;
 
-                self.compile_expression(element, instructions)?;
-
-                instructions.push(Instruction::AddToListAtIndex);
+                    instructions.push(Instruction::AddToList);
+                }
             }
             CommandKind::RemoveListElement => {
                 let name = if let CommandArgument::Name(name) = cmd.arguments.remove(0) {
@@ -988,10 +1384,7 @@ impl Compiler {
                     return Err("IndexList : Esperado um nome".to_owned())
                 };
 
-                let dest = match self.find_or_add_symbol(dest_name.as_str(), true) {
-                    Some(e) => e,
-                    None => return Err(format!("Não foi possível encontrar ou declarar a variável {}", dest_name))
-                };
+                let dest = self.find_or_add_symbol(dest_name.as_str(), true)?;
 
                 let list = match self.find_symbol(name.as_str()) {
                     Some(e) => e,
@@ -1015,33 +1408,52 @@ impl Compiler {
                 }
             }
             CommandKind::BreakScope => {
-                instructions.push(Instruction::IncreaseSkippingLevel);
+                let index = match self.find_block(false) {
+                    Some(i) => i,
+                    None => return Err("Erro : PARA AQUI fora de algum bloco condicional ou loop".to_owned()),
+                };
+
+                self.scopes[index].exit_jumps.push(instructions.len());
+                instructions.push(Instruction::Jump(0));
             }
             CommandKind::SkipNextIteration => {
-                instructions.push(Instruction::RestoreLoopLabel);
+                let index = match self.find_block(true) {
+                    Some(i) => i,
+                    None => return Err("Erro : VAI PRO PRÓXIMO fora de algum loop".to_owned()),
+                };
+
+                self.scopes[index].continue_jumps.push(instructions.len());
+                instructions.push(Instruction::Jump(0));
             }
         }
 
         Ok(None)
     }
 
-    pub fn begin_compiling_function(&mut self, address : usize, args : Vec<FunctionParameter>, name : String) -> Result<(), String> {
+    pub fn begin_compiling_function(&mut self, address : usize, args : Vec<FunctionParameter>, name : String,
+                                    coroutine : bool) -> Result<(), String> {
         let mut base_scope = ScopeInfo::new(SubScopeKind::Regular,
                                             self.next_var_address, false);
 
         self.next_var_address = 1;
+        self.function_frame_size = 1;
+        self.current_scope = ScopeKind::Function;
+        self.compiling_coroutine = coroutine;
 
         let mut args_kind = vec![];
 
         for arg in args {
             args_kind.push(arg.kind);
 
-            base_scope.symbol_table.insert(arg.name, SymbolEntry::from(self.next_var_address, false, true));
-            self.next_var_address += 1;
+            let address = self.allocate_address()?;
+
+            self.declared_variables.push((arg.name.clone(), address));
+            base_scope.symbol_table.insert(arg.name, SymbolEntry::from(address, false, true));
         }
 
-        self.current_scope = ScopeKind::Function;
-        self.functions.insert(name, FunctionInfo::from(address, args_kind, FunctionKind::Source));
+        let kind = if coroutine { FunctionKind::Coroutine } else { FunctionKind::Source };
+
+        self.functions.insert(name, FunctionInfo::from(address, args_kind, kind));
         self.scopes.push(base_scope);
 
         Ok(())
@@ -1056,20 +1468,21 @@ impl Compiler {
         }
     }
 
-    pub fn compile_global_variable(&mut self, name : String, value : RawValue, writeable : bool, instructions : &mut Vec<Instruction>) -> Result<(), String> {
+    /// Compiles the declaration of a global variable with a value given by the host, returning its address
+    pub fn compile_global_variable(&mut self, name : String, value : RawValue, writeable : bool, instructions : &mut Vec<Instruction>) -> Result<usize, String> {
         if self.current_scope != ScopeKind::Global {
             return Err("Scope atual não é o global".to_owned());
         }
 
-        let entry = match self.add_symbol(name, writeable) {
-            Some(e) => e,
-            None => return Err("Não foi possível adicionar o símbolo".to_owned())
-        };
+        let entry = self.add_symbol(name, writeable)?;
 
-        instructions.push(Instruction::PushValMathB(value));
+        // These instructions aren't kept in any code, and the name is in the global symbol table already
+        self.declared_variables.clear();
+
+        instructions.push(Instruction::PushValue(value));
         instructions.push(Instruction::WriteGlobalVarTo(entry.address));
 
-        Ok(())
+        Ok(entry.address)
     }
 
     pub fn compile_function_call(&self, id : usize, args : Vec<RawValue>, instructions : &mut Vec<Instruction>)
@@ -1108,6 +1521,11 @@ impl Compiler {
                         return Err(format!("Tipo incompatível : Função espera {:?}, foi passado Texto", expected))
                     }
                 }
+                &RawValue::List(_) => {
+                    if expected != TypeKind::List {
+                        return Err(format!("Tipo incompatível : Função espera {:?}, foi passado Lista", expected))
+                    }
+                }
                 &RawValue::Null => {
                     return Err(format!("Tipo incompatível : Passado Nulo como argumento"))
                 }
@@ -1115,8 +1533,8 @@ impl Compiler {
 
             index += 1;
 
-            instructions.push(Instruction::PushValMathB(arg));
-            Instruction::WriteVarToLast(index + 1);
+            instructions.push(Instruction::PushValue(arg));
+            instructions.push(Instruction::WriteVarToLast(index));
         }
 
         instructions.push(Instruction::SetLastFrameReady);
@@ -1124,7 +1542,8 @@ impl Compiler {
         Ok(())
     }
 
-    pub fn end_compiling_function(&mut self) -> Result<(), String> {
+    /// Finishes the current function, returning how many variable slots its frame needs
+    pub fn end_compiling_function(&mut self, instructions : &mut Code) -> Result<usize, String> {
         match self.scopes.pop() {
             Some(s) => {
                 match s.scope_kind {
@@ -1132,11 +1551,17 @@ impl Compiler {
                     _ => return Err("Fim da função encontrado, mas algum scope foi deixado aberto".to_owned()),
                 }
 
+                // Parameters of a function without any commands
+                self.add_debug_info(instructions.len(), instructions);
+
+                optimizer::optimize(instructions, self.optimization_level);
+
                 self.end_scope(s);
 
                 self.current_scope = ScopeKind::Global;
+                self.compiling_coroutine = false;
 
-                Ok(())
+                Ok(self.function_frame_size)
             }
             None => return Err("".to_owned())
         }
@@ -1,13 +1,20 @@
 //! Hosts the runtime for the birlscript language
 
-use vm::{ VirtualMachine, ExecutionStatus, PluginFunction };
-use parser::{ parse_line, TypeKind, ParserResult, IntegerType, FunctionDeclaration };
+use vm::{ VirtualMachine, ExecutionStatus, Suspension, DynamicValue, BoxedPlugin, Instruction, MemoryLimits, MemoryUsage };
+use parser::{ parse_line, has_open_text_literal, TypeKind, ParserResult, IntegerType, FunctionDeclaration };
 use compiler::{ Compiler, CompilerHint };
 use modules::*;
 use standard_lib::module_standard_library;
+use bytecode::{ Program, Snapshot, CompiledCode, FunctionEntry, GlobalSymbol };
+use disassembler::{ self, Symbols, FunctionListing };
+use capabilities::Capabilities;
+use conversion::TypedPlugin;
 
 use std::io::{ BufRead, BufReader, Write };
 use std::fs::File;
+use std::time::Instant;
+use std::collections::{ HashMap, HashSet };
+use std::any::Any;
 
 pub const BIRL_COPYRIGHT : &'static str 
     = "© 2016 - 2018 Rafael Rodrigues Nakano <lazpeng@gmail.com>";
@@ -23,6 +30,7 @@ pub const BIRL_RET_VAL_VAR_ADDRESS  : usize = 0;
 #[derive(Debug, Clone, PartialEq)]
 pub enum RawValue {
     Text(String),
+    List(Vec<RawValue>),
     Integer(IntegerType),
     Number(f64),
     Null,
@@ -34,6 +42,7 @@ impl RawValue {
             &RawValue::Integer(_) => TypeKind::Integer,
             &RawValue::Number(_) => TypeKind::Number,
             &RawValue::Text(_) => TypeKind::Text,
+            &RawValue::List(_) => TypeKind::List,
             &RawValue::Null => TypeKind::Null,
         }
     }
@@ -44,6 +53,15 @@ pub struct Context {
     has_main : bool,
     compiler : Compiler,
     current_code_id : usize,
+    // Lines read so far of a command with a multi line text literal that wasn't closed yet
+    pending_lines : String,
+    // Values given to global variables by the host, kept so they can be saved with the program
+    global_values : Vec<(usize, RawValue)>,
+    // Lines processed from the current source, and the line where the command being read started
+    line_number : usize,
+    command_line : usize,
+    // Whether the program got to the main function already, so it isn't called again when the program resumes
+    main_called : bool,
 }
 
 impl Context {
@@ -62,14 +80,27 @@ impl Context {
         let _ = vm.add_new_code(); // For global
         let _ = vm.add_new_code(); // For main
 
+        let compiler = Compiler::new();
+        let _ = vm.set_frame_size(BIRL_GLOBAL_FUNCTION_ID, compiler.get_global_frame_size());
+
         Context {
             vm,
             has_main : false,
-            compiler : Compiler::new(),
+            compiler,
             current_code_id : 0,
+            pending_lines : String::new(),
+            global_values : vec![],
+            line_number : 0,
+            command_line : 0,
+            main_called : false,
         }
     }
 
+    // The global frame is already running while new global variables are compiled, so it grows with them
+    fn update_global_frame_size(&mut self) -> Result<(), String> {
+        self.vm.set_frame_size(BIRL_GLOBAL_FUNCTION_ID, self.compiler.get_global_frame_size())
+    }
+
     fn add_function(&mut self, f : FunctionDeclaration) -> Result<(), String> {
         let is_main = f.name == BIRL_MAIN_FUNCTION;
         if is_main {
@@ -79,6 +110,9 @@ impl Context {
             if f.arguments.len() != 0 {
                 return Err("Erro : Declaração da função principal inválida : A função principal não deve pedir argumentos".to_owned());
             }
+            if f.coroutine {
+                return Err("Erro : Declaração da função principal inválida : A função principal não pode ser uma GAIOLA".to_owned());
+            }
             self.has_main = true;
         }
 
@@ -88,7 +122,8 @@ impl Context {
             self.vm.add_new_code()
         };
 
-        self.compiler.begin_compiling_function(id, f.arguments, f.name)?;
+        self.vm.set_code_name(id, f.name.clone())?;
+        self.compiler.begin_compiling_function(id, f.arguments, f.name, f.coroutine)?;
 
         self.current_code_id = id;
 
@@ -98,16 +133,83 @@ impl Context {
     pub fn set_interactive_mode(&mut self) {
         self.vm.set_interactive_mode();
     }
+
+    /// Makes integer arithmetic promote to arbitrary precision on overflow, instead of failing
+    pub fn set_big_integer_mode(&mut self, enabled : bool) {
+        self.vm.set_big_integer_mode(enabled);
+    }
     
+    /// Limits how many function calls can be active at the same time. Going past it raises a stack overflow
+    /// error, after which the context is still usable
+    pub fn set_max_call_depth(&mut self, depth : usize) {
+        self.vm.set_max_call_depth(depth);
+    }
+
+    /// Sets how much functions compiled from now on are optimized, from 0 (not at all) to
+    /// optimizer::MAX_OPTIMIZATION_LEVEL. Global code is never optimized
+    pub fn set_optimization_level(&mut self, level : u8) {
+        self.compiler.set_optimization_level(level);
+    }
+
     pub fn end_function(&mut self) -> Result<(), String>{
-        self.compiler.end_compiling_function()?;
+        let instructions = match self.vm.get_code_for(self.current_code_id) {
+            Some(i) => i,
+            None => return Err("Erro ao pegar o código para a função atual".to_owned())
+        };
+
+        let frame_size = self.compiler.end_compiling_function(instructions)?;
+
+        self.vm.set_frame_size(self.current_code_id, frame_size)?;
 
         self.current_code_id = BIRL_GLOBAL_FUNCTION_ID;
 
         Ok(())
     }
 
+    /// Checks if the last lines processed are still waiting for the end of a multi line text literal
+    pub fn has_pending_lines(&self) -> bool {
+        !self.pending_lines.is_empty()
+    }
+
+    fn check_pending_lines(&mut self) -> Result<(), String> {
+        if self.has_pending_lines() {
+            self.pending_lines.clear();
+
+            return Err("Texto de várias linhas sem o \"\"\" de fechamento no fim do código".to_owned());
+        }
+
+        Ok(())
+    }
+
     pub fn process_line(&mut self, line : &str) -> Result<Option<CompilerHint>, String> {
+        self.line_number += 1;
+
+        if !self.has_pending_lines() {
+            self.command_line = self.line_number;
+        }
+
+        // A line that opens a multi line text literal is kept until the literal is closed
+        let joined;
+
+        let line = if self.has_pending_lines() {
+            // Lines from the shell still have their line ending
+            if !self.pending_lines.ends_with('\n') {
+                self.pending_lines.push('\n');
+            }
+
+            self.pending_lines.push_str(line);
+
+            joined = ::std::mem::take(&mut self.pending_lines);
+            joined.as_str()
+        } else {
+            line
+        };
+
+        if has_open_text_literal(line) {
+            self.pending_lines = line.to_owned();
+
+            return Ok(None);
+        }
 
         let result = match parse_line(line) {
             Ok(r) => r,
@@ -122,12 +224,16 @@ impl Context {
                         None => return Err(format!("Erro ao pegar o código para a função atual"))
                     };
 
+                    self.compiler.set_source_line(self.command_line);
+
                     match self.compiler.compile_command(cmd, instructions) {
                         Ok(hint) => hint,
                         Err(e) => return Err(e)
                     }
                 };
 
+                self.update_global_frame_size()?;
+
                 Ok(hint)
             }
             ParserResult::FunctionEnd => {
@@ -147,6 +253,8 @@ impl Context {
     pub fn add_source_string(&mut self, string : String) -> Result<(), String> {
         let reader = BufReader::new(string.as_bytes());
 
+        self.line_number = 0;
+
         for line in reader.lines() {
             match line {
                 Ok(line) => {
@@ -159,7 +267,7 @@ impl Context {
             }
         }
 
-        Ok(())
+        self.check_pending_lines()
     }
 
     pub fn add_file(&mut self, filename : &str) -> Result<(), String> {
@@ -172,6 +280,8 @@ impl Context {
 
         let reader = BufReader::new(file);
 
+        self.line_number = 0;
+
         for line in reader.lines() {
             line_num += 1;
             match line {
@@ -185,10 +295,35 @@ impl Context {
             }
         }
 
-        Ok(())
+        match self.check_pending_lines() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("(Linha {}) : {:?}", line_num, e))
+        }
+    }
+
+    /// Adds a plugin, which can be a PluginFunction or a closure keeping something from the host
+    pub fn add_plugin<F>(&mut self, name : String, parameters : Vec<TypeKind>, code : F) -> Result<(), String>
+        where F : FnMut(Vec<DynamicValue>, &mut VirtualMachine) -> Result<Option<DynamicValue>, String> + 'static
+    {
+        self.add_boxed_plugin(name, parameters, Box::new(code))
     }
 
-    pub fn add_plugin(&mut self, name : String, parameters : Vec<TypeKind>, code : PluginFunction) -> Result<(), String> {
+    /// Adds an ordinary Rust function as a plugin. The parameters come from the types of its arguments, which are
+    /// converted with FromBirl, and what it returns is converted with IntoBirl
+    pub fn add_typed_plugin<F, Args>(&mut self, name : String, function : F) -> Result<(), String>
+        where F : TypedPlugin<Args>
+    {
+        self.add_boxed_plugin(name, F::parameters(), function.into_plugin())
+    }
+
+    /// Adds a plugin from a module by itself, like the ones in the standard library
+    pub fn add_module_plugin(&mut self, plugin : Plugin) -> Result<(), String> {
+        let (name, parameters, code) = plugin.into_parts();
+
+        self.add_boxed_plugin(name, parameters, code)
+    }
+
+    fn add_boxed_plugin(&mut self, name : String, parameters : Vec<TypeKind>, code : BoxedPlugin) -> Result<(), String> {
         let index = self.vm.add_new_plugin(code);
 
         self.compiler.add_plugin_function_definition(index, parameters, name)?;
@@ -196,17 +331,31 @@ impl Context {
         Ok(())
     }
 
+    /// Alias for vm.set_user_data()
+    pub fn set_user_data<T : Any>(&mut self, data : T) {
+        self.vm.set_user_data(data);
+    }
+
+    /// Alias for vm.get_user_data()
+    pub fn get_user_data<T : Any>(&mut self) -> Option<&mut T> {
+        self.vm.get_user_data()
+    }
+
     pub fn add_global_variable(&mut self, name : String, value : RawValue, writeable : bool) -> Result<(), String> {
         let mut inst = vec![];
 
-        self.compiler.compile_global_variable(name, value, writeable, &mut inst)?;
+        let address = self.compiler.compile_global_variable(name, value.clone(), writeable, &mut inst)?;
+        self.update_global_frame_size()?;
+        self.global_values.push((address, value));
 
-        for i in inst {
+        for i in &inst {
             match self.vm.run(i)? {
                 ExecutionStatus::Halt => break,
                 ExecutionStatus::Quit => return Err("VM Quitou enquanto adicionava var".to_owned()),
                 ExecutionStatus::Normal => {}
-                ExecutionStatus::Returned => return Err("VM Retornou enquanto adicionava var".to_owned())
+                ExecutionStatus::Returned => return Err("VM Retornou enquanto adicionava var".to_owned()),
+                ExecutionStatus::LimitExceeded(limit) => return Err(limit.to_string()),
+                ExecutionStatus::Suspended(_) => return Err("VM pausou enquanto adicionava var".to_owned()),
             }
         }
 
@@ -225,6 +374,9 @@ impl Context {
 
             self.add_function(decl)?;
 
+            // Modules don't come from a source file, so there's no line to show
+            self.compiler.set_source_line(0);
+
             for c in src.body {
                 let instructions = match self.vm.get_code_for(self.current_code_id) {
                     Some(i) => i,
@@ -241,18 +393,231 @@ impl Context {
         }
 
         for plg in module.plugin_functions {
-            self.add_plugin(plg.name, plg.parameters, plg.func)?;
+            self.add_module_plugin(plg)?;
         }
 
         Ok(())
     }
 
     pub fn add_standard_library(&mut self) -> Result<(), String> {
-        let m = module_standard_library();
+        let m = module_standard_library(self.vm.get_capabilities());
 
         self.add_module(m)
     }
 
+    // Everything compiled so far, as saved in the bytecode format
+    fn get_program(&self) -> Result<Program, String> {
+        if !self.compiler.is_idle() || self.has_pending_lines() {
+            return Err("Erro : Não é possível salvar o programa com uma função ou bloco sem fim".to_owned());
+        }
+
+        let mut codes = vec![];
+
+        for id in 0..self.vm.get_next_code_id() {
+            let code = match self.vm.get_code(id) {
+                Some(c) => c.clone(),
+                None => return Err(format!("Erro interno : Nenhum código com ID {}", id)),
+            };
+
+            codes.push(CompiledCode {
+                name : self.vm.get_code_name(id).unwrap_or("").to_owned(),
+                frame_size : self.vm.get_compiled_frame_size(id),
+                code,
+            });
+        }
+
+        let program = Program {
+            has_main : self.has_main,
+            global_frame_size : self.compiler.get_global_frame_size(),
+            globals : self.compiler.get_global_symbols(),
+            global_values : self.global_values.clone(),
+            functions : self.compiler.get_function_entries(),
+            codes,
+        };
+
+        Ok(program)
+    }
+
+    /// Saves everything compiled so far in the bytecode format, so it can be run later with load_bytecode
+    pub fn save_bytecode(&self) -> Result<Vec<u8>, String> {
+        Ok(self.get_program()?.serialize())
+    }
+
+    /// Loads a program saved with save_bytecode. The context must not have anything compiled yet, but the plugins the
+    /// program uses must already be added, with the same names and parameters. The global function must be running
+    pub fn load_bytecode(&mut self, bytes : &[u8]) -> Result<(), String> {
+        let program = Program::deserialize(bytes)?;
+        let global_values = self.add_program(program)?;
+
+        for (address, value) in global_values {
+            let inst = [Instruction::PushValue(value.clone()), Instruction::WriteGlobalVarTo(address)];
+
+            for i in &inst {
+                self.vm.run(i)?;
+            }
+
+            self.global_values.push((address, value));
+        }
+
+        Ok(())
+    }
+
+    /// Saves everything compiled so far along with the state of the program, so it can continue later with
+    /// load_snapshot, even in another process. The program can be running, paused or stopped by a limit, but not in
+    /// the middle of a function or block being compiled. What the host sets, like the stdout, the limits and the
+    /// capabilities, isn't saved
+    pub fn save_snapshot(&self) -> Result<Vec<u8>, String> {
+        let snapshot = Snapshot {
+            program : self.get_program()?,
+            main_called : self.main_called,
+            machine : self.vm.get_state(),
+        };
+
+        Ok(snapshot.serialize())
+    }
+
+    /// Loads a snapshot saved with save_snapshot, replacing the state of the program. Like with load_bytecode, the
+    /// context must not have anything compiled yet and the plugins must already be added, with the same names and
+    /// parameters. The global function doesn't need to be running. The program continues with resume_program
+    pub fn load_snapshot(&mut self, bytes : &[u8]) -> Result<(), String> {
+        let snapshot = Snapshot::deserialize(bytes)?;
+        let global_values = self.add_program(snapshot.program)?;
+
+        self.vm.set_state(snapshot.machine)?;
+        self.global_values = global_values;
+        self.main_called = snapshot.main_called;
+
+        Ok(())
+    }
+
+    // Adds the codes and definitions of a program, with its plugins bound to the ones with the same names. Gives
+    // back the values the program has for global variables
+    fn add_program(&mut self, program : Program) -> Result<Vec<(usize, RawValue)>, String> {
+        let is_empty = |ctx : &Context, id| ctx.vm.get_code(id).map(|c| c.is_empty()).unwrap_or(false);
+
+        if self.compiler.has_definitions() || !self.compiler.is_idle() || self.has_main || self.has_pending_lines()
+            || self.vm.get_next_code_id() != BIRL_MAIN_FUNCTION_ID + 1
+            || !is_empty(self, BIRL_GLOBAL_FUNCTION_ID) || !is_empty(self, BIRL_MAIN_FUNCTION_ID) {
+            return Err("Erro : O bytecode só pode ser carregado num contexto sem nada compilado".to_owned());
+        }
+
+        // Plugins are found by name, since their IDs depend on the order they were added. Only the ones the program
+        // calls need to be there
+
+        let mut used_plugins = HashSet::new();
+
+        for compiled in &program.codes {
+            for inst in &compiled.code.instructions {
+                if let Instruction::CallPlugin(address, _) = *inst {
+                    used_plugins.insert(address);
+                }
+            }
+        }
+
+        let local_functions = self.compiler.get_function_entries();
+        let mut plugin_ids = HashMap::new();
+
+        for function in &program.functions {
+            let local = local_functions.iter().find(|f| f.name == function.name && f.is_plugin);
+
+            match local {
+                Some(local) if !function.is_plugin => {
+                    return Err(format!("Erro : A função \"{}\" do programa tem o mesmo nome de um plugin", local.name));
+                }
+                Some(local) if local.parameters != function.parameters && used_plugins.contains(&function.address) => {
+                    return Err(format!("Erro : O plugin \"{}\" espera {:?}, mas o programa foi compilado com {:?}",
+                                       local.name, local.parameters, function.parameters));
+                }
+                Some(local) => {
+                    plugin_ids.insert(function.address, local.address);
+                }
+                None if function.is_plugin && used_plugins.contains(&function.address) => {
+                    return Err(format!("Erro : O programa usa o plugin \"{}\", que não foi adicionado", function.name));
+                }
+                None => {}
+            }
+        }
+
+        let mut codes = program.codes;
+
+        for compiled in &mut codes {
+            for inst in &mut compiled.code.instructions {
+                if let Instruction::CallPlugin(ref mut address, _) = *inst {
+                    match plugin_ids.get(address) {
+                        Some(&id) => *address = id,
+                        None => return Err(format!("Erro : Plugin com ID {} não encontrado no programa", address)),
+                    }
+                }
+            }
+        }
+
+        self.compiler.add_compiled_definitions(&program.functions, &program.globals, program.global_frame_size)?;
+
+        for (id, compiled) in codes.into_iter().enumerate() {
+            if id > BIRL_MAIN_FUNCTION_ID {
+                self.vm.add_new_code();
+            }
+
+            self.vm.set_code(id, compiled.code)?;
+            self.vm.set_code_name(id, compiled.name)?;
+
+            if let Some(size) = compiled.frame_size {
+                self.vm.set_frame_size(id, size)?;
+            }
+        }
+
+        self.has_main = program.has_main;
+        self.update_global_frame_size()?;
+
+        Ok(program.global_values)
+    }
+
+    // Names of everything compiled so far, for the disassembler
+    fn get_symbols<'a>(&'a self, entries : &'a [FunctionEntry], globals : &'a [GlobalSymbol]) -> Symbols<'a> {
+        let mut symbols = Symbols::default();
+
+        for id in 0..self.vm.get_next_code_id() {
+            if let Some(code) = self.vm.get_code(id) {
+                symbols.functions.push(FunctionListing {
+                    name : self.vm.get_code_name(id).unwrap_or(""),
+                    code,
+                    frame_size : self.vm.get_compiled_frame_size(id),
+                });
+            }
+        }
+
+        for entry in entries.iter().filter(|e| e.is_plugin) {
+            symbols.plugins.insert(entry.address, entry.name.as_str());
+        }
+
+        for global in globals {
+            symbols.globals.insert(global.address, global.name.as_str());
+        }
+
+        symbols
+    }
+
+    /// Lists the instructions of every function compiled so far, in a readable way
+    pub fn disassemble(&self) -> String {
+        let (entries, globals) = (self.compiler.get_function_entries(), self.compiler.get_global_symbols());
+
+        disassembler::disassemble_all(&self.get_symbols(&entries, &globals))
+    }
+
+    /// Lists the instructions of the function with the given name
+    pub fn disassemble_function(&self, name : &str) -> Result<String, String> {
+        let id = (0..self.vm.get_next_code_id()).find(|&id| self.vm.get_code_name(id) == Some(name));
+
+        match id {
+            Some(id) => {
+                let (entries, globals) = (self.compiler.get_function_entries(), self.compiler.get_global_symbols());
+
+                disassembler::disassemble(&self.get_symbols(&entries, &globals), id)
+            }
+            None => Err(format!("Erro : Nenhuma função com o nome \"{}\"", name)),
+        }
+    }
+
     pub fn call_function_by_id(&mut self, id : usize, args : Vec<RawValue>) -> Result<(), String> {
         let mut instructions = vec![];
 
@@ -261,7 +626,7 @@ impl Context {
             Err(e) => return Err(e),
         }
 
-        for i in instructions {
+        for i in &instructions {
             match self.vm.run(i) {
                 Ok(_) => {}
                 Err(e) => return Err(e)
@@ -271,47 +636,1311 @@ impl Context {
         Ok(())
     }
 
+    /// Limits how many more instructions the program can run. None removes the limit
+    pub fn set_fuel(&mut self, fuel : Option<u64>) {
+        self.vm.set_fuel(fuel);
+    }
+
+    /// Allows more instructions to run, so a program that ran out of fuel can be resumed
+    pub fn add_fuel(&mut self, amount : u64) {
+        self.vm.add_fuel(amount);
+    }
+
+    pub fn get_fuel(&self) -> Option<u64> {
+        self.vm.get_fuel()
+    }
+
+    /// Stops the program once the deadline passes. None removes the limit
+    pub fn set_deadline(&mut self, deadline : Option<Instant>) {
+        self.vm.set_deadline(deadline);
+    }
+
+    /// Limits how much memory texts, lists and big integers can use. Going past it is a runtime error
+    pub fn set_memory_limits(&mut self, limits : MemoryLimits) {
+        self.vm.set_memory_limits(limits);
+    }
+
+    pub fn get_memory_usage(&self) -> MemoryUsage {
+        self.vm.get_memory_usage()
+    }
+
+    /// Sets what programs can use outside the VM, like the input or files. Commands compiled from now on that use
+    /// something denied don't compile, and the VM and plugins deny them when running too. Should be set before
+    /// add_standard_library, which only reads the environment if it's allowed
+    pub fn set_capabilities(&mut self, capabilities : Capabilities) {
+        self.compiler.set_capabilities(capabilities.clone());
+        self.vm.set_capabilities(capabilities);
+    }
+
+    pub fn get_capabilities(&self) -> &Capabilities {
+        self.vm.get_capabilities()
+    }
+
     pub fn execute_next_instruction(&mut self) -> Result<ExecutionStatus, String> {
         self.vm.execute_next_instruction()
     }
 
-    pub fn start_program(&mut self) -> Result<(), String> {
-        // Global function is already running
+    /// Makes input commands pause the program instead of reading from stdin, so the host can give the line with
+    /// resume_with when it has one
+    pub fn set_suspend_on_input(&mut self, enabled : bool) {
+        self.vm.set_suspend_on_input(enabled);
+    }
+
+    /// Why the program is paused, if it is
+    pub fn get_suspension(&self) -> Option<&Suspension> {
+        self.vm.get_suspension()
+    }
 
+    /// Gives the value the paused program is waiting for, without running anything
+    pub fn give_value(&mut self, value : RawValue) -> Result<(), String> {
+        self.vm.resume_with(value)
+    }
+
+    /// Gives the value the paused program is waiting for and continues it, like resume_program
+    pub fn resume_with(&mut self, value : RawValue) -> Result<ExecutionStatus, String> {
+        self.give_value(value)?;
+
+        self.resume_program()
+    }
+
+    /// Runs the global code and then the main function. If a limit is reached, gives an error saying so, and
+    /// resume_program continues from where it stopped once the limit is raised. If the program pauses, it continues
+    /// right away with a null value
+    pub fn start_program(&mut self) -> Result<(), String> {
         loop {
-            match self.execute_next_instruction() {
-                Ok(ExecutionStatus::Normal) => {}
-                Ok(ExecutionStatus::Returned) => {}
-                Ok(ExecutionStatus::Halt) => break,
-                Ok(ExecutionStatus::Quit) => break,
-                Err(e) => return Err(e)
+            match self.resume_program()? {
+                ExecutionStatus::LimitExceeded(limit) => return Err(limit.to_string()),
+                ExecutionStatus::Suspended(_) => self.give_value(RawValue::Null)?,
+                _ => return Ok(()),
             }
         }
+    }
 
-        self.vm.unset_quit();
+    /// Runs the program until it ends, giving Halt, until a limit is reached, giving LimitExceeded, or until it
+    /// pauses, giving Suspended. Calling it again after raising the limit continues the program. A paused program
+    /// continues with resume_with
+    pub fn resume_program(&mut self) -> Result<ExecutionStatus, String> {
+        // Global function is already running
 
-        if self.has_main {
-            match self.call_function_by_id(BIRL_MAIN_FUNCTION_ID, vec![]) {
-                Ok(_) => {}
-                Err(e) => return Err(e)
-            }
+        loop {
+            match self.execute_next_instruction()? {
+                ExecutionStatus::Normal | ExecutionStatus::Returned => {}
+                ExecutionStatus::LimitExceeded(limit) => return Ok(ExecutionStatus::LimitExceeded(limit)),
+                ExecutionStatus::Suspended(suspension) => return Ok(ExecutionStatus::Suspended(suspension)),
+                ExecutionStatus::Halt | ExecutionStatus::Quit => {
+                    if self.main_called || !self.has_main {
+                        return Ok(ExecutionStatus::Halt);
+                    }
 
-            loop {
-                match self.execute_next_instruction() {
-                    Ok(ExecutionStatus::Normal) => {}
-                    Ok(ExecutionStatus::Returned) => {}
-                    Ok(ExecutionStatus::Halt) => break,
-                    Ok(ExecutionStatus::Quit) => return Ok(()),
-                    Err(e) => return Err(e)
+                    self.vm.unset_quit();
+                    self.call_function_by_id(BIRL_MAIN_FUNCTION_ID, vec![])?;
+                    self.main_called = true;
                 }
             }
         }
-
-        Ok(())
-    } 
+    }
     
     pub fn print_version() {
         println!("{}", BIRL_VERSION);
         println!("{}", BIRL_COPYRIGHT);
     }
 }
+
+mod tests {
+    #![allow(dead_code)]
+
+    use context::*;
+
+    use std::io::{ Write, self };
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs the program and returns everything it printed
+    pub fn run_program(ctx : &mut Context, source : &str) -> Result<String, String> {
+        let output = Rc::new(RefCell::new(vec![]));
+
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![])?;
+        ctx.add_source_string(source.to_owned())?;
+        ctx.start_program()?;
+
+        let result = String::from_utf8(output.borrow().clone()).unwrap();
+
+        Ok(result)
+    }
+
+    /// Runs a program with the given main function body
+    pub fn run_main(body : &str, big_integers : bool) -> Result<String, String> {
+        let mut ctx = Context::new();
+        ctx.set_big_integer_mode(big_integers);
+
+        run_program(&mut ctx, format!("JAULA SHOW\n{}\nSAINDO DA JAULA", body).as_str())
+    }
+
+    #[test]
+    fn integer_boundaries() {
+        let max = IntegerType::MAX;
+        let min = IntegerType::MIN;
+
+        assert_eq!(run_main(format!("CE QUER VER ISSO: {}", max).as_str(), false), Ok(format!("{}\n", max)));
+        let below_zero = format!("VEM: X, 0 - {}\nBORA: X, X - 1\nCE QUER VER ISSO: X", max);
+        assert_eq!(run_main(below_zero.as_str(), false), Ok(format!("{}\n", min)));
+
+        assert!(run_main(format!("CE QUER VER ISSO: {} + 1", max).as_str(), false).is_err());
+        assert!(run_main(format!("{}\nBORA: X, X - 1", below_zero).as_str(), false).is_err());
+        assert!(run_main(format!("CE QUER VER ISSO: {} * 2", max).as_str(), false).is_err());
+        assert!(run_main("CE QUER VER ISSO: 1 / 0", false).is_err());
+
+        // The lowest integer can be written, but only with the minus right before it
+        assert_eq!(run_main(format!("CE QUER VER ISSO: {}", min).as_str(), false), Ok(format!("{}\n", min)));
+        assert_eq!(run_main("CE QUER VER ISSO: -9_223_372_036_854_775_808", false), Ok(format!("{}\n", min)));
+        assert!(run_main(format!("CE QUER VER ISSO: {} - 1", min).as_str(), false).is_err());
+        assert!(run_main("CE QUER VER ISSO: 1 - 9223372036854775808", false).is_err());
+        assert!(run_main("CE QUER VER ISSO: - 9223372036854775808", false).is_err());
+        assert_eq!(run_main("CE QUER VER ISSO: -9223372036854775808.5", false), Ok("-9223372036854776000\n".to_owned()));
+
+        assert_eq!(run_main(format!("CE QUER VER ISSO: {} + 1", max).as_str(), true), Ok("9223372036854775808\n".to_owned()));
+        let bitwise = run_main(format!("CE QUER VER ISSO: ({} + 1) & 1", max).as_str(), true);
+        assert!(bitwise.unwrap_err().contains("Inteiros grandes"));
+        assert_eq!(run_main(format!("CE QUER VER ISSO: ({} + 1 - 1) & 1", max).as_str(), true), Ok("1\n".to_owned()));
+        let back_to_integer = format!("VEM: X, {} + 1\nBORA: X, X - 1\nCE QUER VER ISSO: X", max);
+        assert_eq!(run_main(back_to_integer.as_str(), true), Ok(format!("{}\n", max)));
+
+        let conversion = "VEM: X, \"-9223372036854775808\"\nMUDA PRA INTEIRO: X\nCE QUER VER ISSO: X";
+        assert_eq!(run_main(conversion, false), Ok(format!("{}\n", min)));
+
+        let conversion = "VEM: X, \"9223372036854775808\"\nMUDA PRA INTEIRO: X\nCE QUER VER ISSO: X";
+        assert!(run_main(conversion, false).is_err());
+        assert_eq!(run_main(conversion, true), Ok("9223372036854775808\n".to_owned()));
+
+        let list = "FAZ UMA LISTA: L\nPÕE ISSO AQUI: L, 1\nME DÁ ESSE: L, 0 - 1, X";
+        assert!(run_main(list, false).is_err());
+    }
+
+    #[test]
+    fn operators() {
+        let eval = |expr : &str| run_main(format!("CE QUER VER ISSO: {}", expr).as_str(), false);
+
+        assert_eq!(eval("10 - 2 - 3"), Ok("5\n".to_owned()));
+        assert_eq!(eval("2 * 3 + 4 * 5"), Ok("26\n".to_owned()));
+        assert_eq!(eval("\"a\" + \"b\" + \"c\""), Ok("abc\n".to_owned()));
+        assert_eq!(eval("0xF0 | 0x0F & 0x3C ^ 1"), Ok("253\n".to_owned()));
+        assert_eq!(eval("1 << 3 + 1"), Ok("16\n".to_owned()));
+        assert_eq!(eval("~0 >> 60"), Ok("-1\n".to_owned()));
+        assert_eq!(eval("~0xFF & 0x1FF"), Ok("256\n".to_owned()));
+
+        assert!(eval("1.0 & 1").is_err());
+        assert!(eval("1 | \"a\"").is_err());
+        assert!(eval("~1.5").is_err());
+        assert!(eval("1 << 64").is_err());
+        assert!(eval("1 >> -1").is_err());
+
+        // A '}' in a text inside an interpolation is just a '}'
+        assert_eq!(eval("\"{\"}\"}\""), Ok("}\n".to_owned()));
+        assert_eq!(eval("\"{\"}\" + \"a\"}\""), Ok("}a\n".to_owned()));
+        assert_eq!(eval("\"{\"{{}}\"}{\"{2}}}\"}\""), Ok("{}2}\n".to_owned()));
+
+        let compare = "E ELE QUE A GENTE QUER: 6, 2 * 3\nE ELE MEMO:\nCE QUER VER ISSO: \"igual\"\nFIM";
+        assert_eq!(run_main(compare, false), Ok("igual\n".to_owned()));
+    }
+
+    #[test]
+    fn tail_calls() {
+        use vm::Instruction;
+
+        let source = "
+JAULA JUNTA (N : BATATA DOCE, TEXTO : FIBRA)
+    E ELE QUE A GENTE QUER: N, 0
+    E ELE MEMO:
+        BIRL: TEXTO
+    FIM
+    E HORA DO: JUNTA, N - 1, TEXTO + \"!\"
+    BIRL: TREZE
+SAINDO DA JAULA
+
+JAULA SOMA (N : BATATA DOCE, TOTAL : BATATA DOCE)
+    E ELE QUE A GENTE QUER: N, 0
+    E ELE MEMO:
+        BIRL: TOTAL
+    FIM
+    E HORA DO: SOMA, N - 1, TOTAL + N
+    BIRL: TREZE
+SAINDO DA JAULA
+
+JAULA SHOW
+    E HORA DO: JUNTA, 5, \"BIRL\"
+    CE QUER VER ISSO: TREZE
+    E HORA DO: SOMA, 50000, 0
+    CE QUER VER ISSO: TREZE
+SAINDO DA JAULA";
+
+        let mut ctx = Context::new();
+
+        assert_eq!(run_program(&mut ctx, source), Ok("BIRL!!!!!\n1250025000\n".to_owned()));
+
+        // Both functions reuse their frame, but the main function's calls aren't in tail position
+        for id in 2..4 {
+            let code = ctx.vm.get_code_for(id).unwrap();
+
+            assert!(code.instructions.iter().any(|i| matches!(i, Instruction::TailCall)));
+            assert!(!code.instructions.iter().any(|i| matches!(i, Instruction::SetLastFrameReady)));
+        }
+
+        let main = ctx.vm.get_code_for(BIRL_MAIN_FUNCTION_ID).unwrap();
+        assert!(!main.instructions.iter().any(|i| matches!(i, Instruction::TailCall)));
+    }
+
+    #[test]
+    fn tail_call_after_block() {
+        let source = "
+JAULA SETE
+    BIRL: 7
+SAINDO DA JAULA
+
+JAULA TALVEZ (N : BATATA DOCE)
+    E HORA DO: SETE
+    E ELE QUE A GENTE QUER: N, 1
+    E ELE MEMO:
+        E HORA DO: SETE
+    FIM
+    BIRL: TREZE
+SAINDO DA JAULA
+
+JAULA TALVEZ_SEM_RETORNO (N : BATATA DOCE)
+    E ELE QUE A GENTE QUER: N, 1
+    E ELE MEMO:
+        E HORA DO: SETE
+    FIM
+SAINDO DA JAULA
+
+JAULA SO_CHAMA
+    E HORA DO: SETE
+SAINDO DA JAULA
+
+JAULA CHAMA_E_VEM
+    E HORA DO: SETE
+    VEM: X, 1
+SAINDO DA JAULA
+
+JAULA SHOW
+    E HORA DO: TALVEZ, 0
+    CE QUER VER ISSO: TREZE
+    E HORA DO: TALVEZ, 1
+    CE QUER VER ISSO: TREZE
+    E HORA DO: TALVEZ_SEM_RETORNO, 1
+    CE QUER VER ISSO: TREZE
+    E HORA DO: SO_CHAMA
+    CE QUER VER ISSO: TREZE
+    E HORA DO: CHAMA_E_VEM
+    CE QUER VER ISSO: TREZE
+SAINDO DA JAULA";
+
+        let mut ctx = Context::new();
+
+        // The call inside the block isn't the last thing done when the block is skipped, so it can't be a tail call.
+        // Only BIRL: TREZE returns what the call returned, wherever the call is
+        assert_eq!(run_program(&mut ctx, source), Ok("7\n7\n<Null>\n<Null>\n<Null>\n".to_owned()));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let source = |depth : usize| format!("
+JAULA DESCE (N : BATATA DOCE)
+    E ELE QUE A GENTE QUER: N, 0
+    E ELE MEMO:
+        BIRL: 0
+    FIM
+    E HORA DO: DESCE, N - 1
+    BIRL: TREZE + 1
+SAINDO DA JAULA
+
+JAULA SHOW
+    E HORA DO: DESCE, {}
+    CE QUER VER ISSO: TREZE
+SAINDO DA JAULA", depth);
+
+        let mut ctx = Context::new();
+        ctx.set_max_call_depth(50);
+
+        let error = run_program(&mut ctx, source(100).as_str()).unwrap_err();
+
+        assert!(error.starts_with("Estouro de pilha"));
+        assert!(error.contains("\"DESCE\""));
+        assert!(error.contains("profundidade 51"));
+        assert_eq!(ctx.vm.get_call_depth(), 0);
+
+        let mut ctx = Context::new();
+        ctx.set_max_call_depth(50);
+
+        // SHOW plus 49 calls to DESCE
+        assert_eq!(run_program(&mut ctx, source(48).as_str()), Ok("48\n".to_owned()));
+    }
+
+    #[test]
+    fn frame_sizes() {
+        use vm::MAX_FRAME_SIZE;
+
+        let mut many = String::from("JAULA MUITAS (A : BATATA DOCE)\n");
+
+        for i in 0..200 {
+            many.push_str(format!("VEM: V{}, A + {}\n", i, i).as_str());
+        }
+
+        many.push_str("BIRL: V199\nSAINDO DA JAULA\n\nJAULA SHOW\nE HORA DO: MUITAS, 1\nCE QUER VER ISSO: TREZE\n\
+                       VEM: X, TREZE\nSAINDO DA JAULA");
+
+        let mut ctx = Context::new();
+
+        assert_eq!(run_program(&mut ctx, many.as_str()), Ok("200\n".to_owned()));
+        // The return value, the argument and the variables
+        assert_eq!(ctx.vm.get_frame_size(2), 202);
+        assert_eq!(ctx.vm.get_frame_size(BIRL_MAIN_FUNCTION_ID), 2);
+
+        let mut ctx = Context::new();
+        ctx.process_line("JAULA DEMAIS").unwrap();
+
+        for i in 1..MAX_FRAME_SIZE {
+            ctx.process_line(format!("VEM: V{}, {}", i, i).as_str()).unwrap();
+        }
+
+        match ctx.process_line("VEM: ULTIMA, 0") {
+            Err(e) => assert!(e.contains("Variáveis demais")),
+            Ok(_) => panic!("Passou do limite de variáveis sem erro"),
+        }
+    }
+
+    #[test]
+    fn control_flow() {
+        // A false condition skips everything inside it, including other blocks
+        let nested = "VEM: X, 1
+E ELE QUE A GENTE QUER: X, 2
+E ELE MEMO:
+    E ELE QUE A GENTE QUER: X, X
+    E ELE MEMO:
+        CE QUER VER ISSO: \"dentro\"
+    FIM
+    CE QUER VER ISSO: \"depois\"
+FIM
+CE QUER VER ISSO: \"fim\"";
+        assert_eq!(run_main(nested, false), Ok("fim\n".to_owned()));
+
+        let loops = "REPETE: I, 0, 3
+    REPETE: J, 10, 0, 0 - 5
+        CE QUER VER: I * 100 + J
+        CE QUER VER: \" \"
+    FIM
+FIM
+CE QUER VER ISSO: \"\"
+VEM: N, 0
+ENQUANTO É MENOR: N, 10
+    BORA: N, N + 1
+    CE QUER VER: N
+FIM
+CE QUER VER ISSO: \"\"
+REPETE: I, 0, 6
+    E ELE QUE A GENTE QUER: I, 2
+    E ELE MEMO:
+        VAI PRO PRÓXIMO
+    FIM
+    CE QUER VER: I
+FIM
+CE QUER VER ISSO: \"\"
+REPETE: I, 0, 100
+    CE QUER VER: I
+    PARA AQUI
+FIM";
+        assert_eq!(run_main(loops, false), Ok("10 5 110 105 210 205 \n12345678910\n01345\n0".to_owned()));
+
+        assert!(run_main("PARA AQUI", false).is_err());
+        assert!(run_main("E ELE QUE A GENTE QUER: 1, 1\nE ELE MEMO:\nVAI PRO PRÓXIMO\nFIM", false).is_err());
+    }
+
+    use vm::DynamicValue;
+
+    fn digits(arguments : Vec<DynamicValue>, _ : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+        let mut total = 0;
+
+        for arg in arguments {
+            match arg {
+                DynamicValue::Integer(i) => total = total * 10 + i,
+                _ => return Err("Esperado um inteiro".to_owned()),
+            }
+        }
+
+        Ok(Some(DynamicValue::Integer(total)))
+    }
+
+    #[test]
+    fn operand_stack() {
+        let eval = |expr : &str| run_main(format!("CE QUER VER ISSO: {}", expr).as_str(), false);
+
+        // Interpolated texts are evaluated in place, between the other values of the expression
+        assert_eq!(eval("\"a\" + \"{1 + 2}\" + \"b\" + \"{2 * 3}\""), Ok("a3b6\n".to_owned()));
+        assert_eq!(eval("(1 + 2) * (3 + 4) - 10 / (1 + 1)"), Ok("16\n".to_owned()));
+
+        let list = "FAZ UMA LISTA: L
+PÕE ISSO AQUI: L, 1
+PÕE ISSO AQUI: L, 3
+PÕE ISSO AQUI: L, 2, 1
+PÕE ISSO AQUI: L, 4, 10
+ME DÁ ESSE: L, 1 + 1, X
+CE QUER VER ISSO: L, \" \", X";
+        assert_eq!(run_main(list, false), Ok("(Lista) [ 1, 2, 3, 4 ] 3\n".to_owned()));
+
+        // Plugins receive their arguments in the order they were passed
+        let mut ctx = Context::new();
+        ctx.add_plugin("DIGITOS".to_owned(), vec![TypeKind::Integer; 3], digits).unwrap();
+
+        let source = "JAULA SHOW\nE HORA DO: DIGITOS, 1, 2, 3\nCE QUER VER ISSO: TREZE\nSAINDO DA JAULA";
+        assert_eq!(run_program(&mut ctx, source), Ok("123\n".to_owned()));
+    }
+
+    #[test]
+    fn returned_lists() {
+        let source = |body : &str| format!("
+JAULA FAZ
+    FAZ UMA LISTA: L
+    FAZ UMA LISTA: M
+    PÕE ISSO AQUI: M, 1
+{}
+    BIRL: L
+SAINDO DA JAULA
+
+JAULA SHOW
+    E HORA DO: FAZ
+    CE QUER VER ISSO: TREZE
+SAINDO DA JAULA", body);
+
+        // The same list can be in more than one place, but a list can't have itself inside
+        let shared = source("    PÕE ISSO AQUI: L, M\n    PÕE ISSO AQUI: L, M");
+        assert_eq!(run_program(&mut Context::new(), shared.as_str()), Ok("(Lista) [ [ 1 ], [ 1 ] ]\n".to_owned()));
+
+        let cyclic = source("    PÕE ISSO AQUI: M, L\n    PÕE ISSO AQUI: L, M");
+        assert!(run_program(&mut Context::new(), cyclic.as_str()).unwrap_err().contains("tem ela mesma dentro"));
+    }
+
+    #[test]
+    fn multi_line_texts() {
+        let output = Rc::new(RefCell::new(vec![]));
+
+        let mut ctx = Context::new();
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+
+        // Like in the shell, where every line still ends with its '\n'
+        for line in &["JAULA SHOW\n", "CE QUER VER ISSO: \"\"\"\n", "a\n", "b\"\"\", r\"\"\"c\n", "d\"\"\"\n", "SAINDO DA JAULA\n"] {
+            ctx.process_line(line).unwrap();
+        }
+
+        ctx.start_program().unwrap();
+
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "a\nbc\nd\n");
+
+        let source = "JAULA SHOW\nCE QUER VER ISSO: \"\"\"a\nb\"\"\"\nSAINDO DA JAULA";
+        assert_eq!(run_program(&mut Context::new(), source), Ok("a\nb\n".to_owned()));
+    }
+
+    #[test]
+    fn text_constants() {
+        use vm::Instruction;
+
+        let source = "
+JAULA SHOW
+    REPETE: I, 0, 3
+        VEM: X, \"BIRL\"
+        BORA: X, X + \"!\"
+        CE QUER VER ISSO: X, \" \", \"BIRL\", \" {I}!\"
+    FIM
+SAINDO DA JAULA";
+
+        let mut ctx = Context::new();
+
+        // Changing a variable that holds a literal doesn't change the literal
+        assert_eq!(run_program(&mut ctx, source), Ok("BIRL! BIRL 0!\nBIRL! BIRL 1!\nBIRL! BIRL 2!\n".to_owned()));
+
+        let main = ctx.vm.get_code_for(BIRL_MAIN_FUNCTION_ID).unwrap();
+        assert_eq!(main.texts, vec!["BIRL", "!", " "]);
+        assert!(!main.instructions.iter().any(|i| matches!(i, Instruction::PushValue(RawValue::Text(_)))));
+    }
+
+    #[test]
+    fn optimization_levels() {
+        use optimizer::MAX_OPTIMIZATION_LEVEL;
+
+        let programs = [
+            "CE QUER VER ISSO: 2 * 60 * 60, \" \", 10 - 2 - 3, \" \", 1.5 * 2, \" \", \"a\" + \"b\" + \"{1 + 1}\", \" \", ~0xFF & 0x1FF",
+            "CE QUER VER ISSO: 9223372036854775807 + 1",
+            "CE QUER VER ISSO: 1 / (2 - 2)",
+            "VEM: X, 5
+ENQUANTO É MENOR: 0, X
+    BORA: X, X - 1
+    E ELE QUE A GENTE QUER: X, 2 + 1
+    E ELE MEMO:
+        VAI PRO PRÓXIMO
+    FIM
+    CE QUER VER: X
+    E ELE QUE A GENTE QUER: X, 1
+    E ELE MEMO:
+        PARA AQUI
+    FIM
+FIM
+BIRL: X
+CE QUER VER ISSO: \"nunca\"",
+        ];
+
+        for program in programs.iter() {
+            let results = (0..MAX_OPTIMIZATION_LEVEL + 1).map(|level| {
+                let mut ctx = Context::new();
+                ctx.set_optimization_level(level);
+
+                let source = format!("JAULA SHOW\n{}\nSAINDO DA JAULA", program);
+                let result = run_program(&mut ctx, source.as_str());
+                let size = ctx.vm.get_code_for(BIRL_MAIN_FUNCTION_ID).unwrap().len();
+
+                (result, size)
+            }).collect::<Vec<_>>();
+
+            for &(ref result, size) in &results[1..] {
+                assert_eq!(*result, results[0].0);
+                assert!(size <= results[0].1);
+            }
+        }
+
+        let mut ctx = Context::new();
+        ctx.set_optimization_level(MAX_OPTIMIZATION_LEVEL);
+
+        assert_eq!(run_program(&mut ctx, "JAULA SHOW\nCE QUER VER ISSO: 2 * 60 * 60\nSAINDO DA JAULA"), Ok("7200\n".to_owned()));
+        assert_eq!(ctx.vm.get_code_for(BIRL_MAIN_FUNCTION_ID).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn bytecode() {
+        let source = "
+JAULA DOBRO (N : BATATA DOCE)
+    BIRL: N * BASE
+SAINDO DA JAULA
+
+JAULA SHOW
+    REPETE: I, 1, 4
+        E HORA DO: DOBRO, I
+        VEM: D, TREZE
+        E HORA DO: DIGITOS, I, D
+        CE QUER VER ISSO: \"{D} \", TREZE, \" {CUMPADE}\"
+    FIM
+SAINDO DA JAULA";
+
+        let start = |ctx : &mut Context| {
+            ctx.add_plugin("DIGITOS".to_owned(), vec![TypeKind::Integer; 2], digits).unwrap();
+            ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        };
+
+        let mut ctx = Context::new();
+        ctx.set_optimization_level(1);
+        start(&mut ctx);
+        ctx.add_global_variable("BASE".to_owned(), RawValue::Integer(2), false).unwrap();
+        ctx.add_global_variable("CUMPADE".to_owned(), RawValue::Text("BIRL".to_owned()), false).unwrap();
+        ctx.add_source_string(source.to_owned()).unwrap();
+
+        let bytes = ctx.save_bytecode().unwrap();
+
+        // The plugin gets another ID in the new context, since something else was added before it
+        let mut loaded = Context::new();
+        loaded.add_plugin("OUTRO".to_owned(), vec![], digits).unwrap();
+        start(&mut loaded);
+        loaded.load_bytecode(&bytes).unwrap();
+
+        // What was loaded can be saved again
+        let mut reloaded = Context::new();
+        start(&mut reloaded);
+        reloaded.load_bytecode(&loaded.save_bytecode().unwrap()).unwrap();
+
+        let expected = "2 12 BIRL\n4 24 BIRL\n6 36 BIRL\n";
+        assert_eq!(run_program(&mut ctx, ""), Ok(expected.to_owned()));
+        assert_eq!(run_program(&mut loaded, ""), Ok(expected.to_owned()));
+        assert_eq!(run_program(&mut reloaded, ""), Ok(expected.to_owned()));
+
+        // Only a context without anything compiled can load it
+        let mut used = Context::new();
+        start(&mut used);
+        used.add_source_string("VEM: X, 1".to_owned()).unwrap();
+        assert!(used.load_bytecode(&bytes).is_err());
+
+        let mut without_plugin = Context::new();
+        without_plugin.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        assert!(without_plugin.load_bytecode(&bytes).is_err());
+    }
+
+    #[test]
+    fn disassembly() {
+        let source = "VEM: LIMITE, 3
+
+JAULA SHOW
+    REPETE: I, 0, LIMITE
+        E ELE QUE A GENTE QUER: I, 1
+        E ELE MEMO:
+            VEM: X, \"BIRL\"
+            CE QUER VER ISSO: X
+        FIM
+    FIM
+    VEM: Y, 2 * 60
+SAINDO DA JAULA";
+
+        for level in 0..3 {
+            let mut ctx = Context::new();
+            ctx.set_optimization_level(level);
+            ctx.add_source_string(source.to_owned()).unwrap();
+
+            let listing = ctx.disassemble_function("SHOW").unwrap();
+            let lines = listing.lines().map(str::trim).collect::<Vec<&str>>();
+            let position = |text : &str| lines.iter().position(|l| *l == text).unwrap();
+
+            assert!(lines[0].starts_with("== SHOW (ID 1"));
+            assert!(position("; linha 4") < position("; começo da repetição"));
+            assert!(position("; começo da repetição") < position("; linha 5"));
+            assert!(position("; começo da condição") < position("; fim da condição"));
+            assert!(position("; fim da condição") < position("; fim da repetição"));
+            assert!(position("; fim da repetição") < position("; linha 11"));
+
+            // Names of variables, including the ones in blocks that share an address after the block ends
+            assert!(lines.iter().any(|l| l.contains("ReadGlobalVarFrom(1)") && l.ends_with("; LIMITE")));
+            assert!(lines.iter().any(|l| l.contains("WriteVarTo(") && l.ends_with("; X")));
+            assert!(lines.iter().any(|l| l.contains("WriteVarTo(") && l.ends_with("; Y")));
+            assert!(lines.iter().any(|l| l.contains("PushText(0)") && l.ends_with("; \"BIRL\"")));
+        }
+
+        let ctx = Context::new();
+        assert!(ctx.disassemble_function("NADA").is_err());
+        assert!(ctx.disassemble().contains("== código global (ID 0"));
+    }
+
+    #[test]
+    fn execution_limits() {
+        use vm::ExecutionLimit;
+        use std::time::{ Instant, Duration };
+
+        let forever = "JAULA SHOW\nENQUANTO É MENOR: 0, 1\nFIM\nSAINDO DA JAULA";
+
+        let mut ctx = Context::new();
+        ctx.set_fuel(Some(1000));
+        let err = run_program(&mut ctx, forever).unwrap_err();
+        assert!(err.contains("Limite excedido"));
+        assert_eq!(ctx.get_fuel(), Some(0));
+
+        // Running out of fuel stops before an instruction, so topping up and resuming gives the same result
+        let source = "VEM: TOTAL, 0
+JAULA SHOW
+    REPETE: I, 0, 20
+        BORA: TOTAL, TOTAL + I
+        CE QUER VER: I, \" \"
+    FIM
+    CE QUER VER ISSO: TOTAL
+SAINDO DA JAULA";
+
+        let expected = run_program(&mut Context::new(), source).unwrap();
+
+        let mut ctx = Context::new();
+        let output = Rc::new(RefCell::new(vec![]));
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(source.to_owned()).unwrap();
+        ctx.set_fuel(Some(0));
+
+        let mut resumes = 0;
+
+        loop {
+            match ctx.resume_program().unwrap() {
+                ExecutionStatus::LimitExceeded(ExecutionLimit::Fuel) => ctx.add_fuel(7),
+                ExecutionStatus::Halt => break,
+                status => panic!("{:?}", status),
+            }
+
+            resumes += 1;
+        }
+
+        assert!(resumes > 10);
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), expected);
+
+        // A deadline in the past stops right away, and the program continues once it's removed
+        let mut ctx = Context::new();
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(forever.to_owned()).unwrap();
+        ctx.set_deadline(Some(Instant::now()));
+        assert!(matches!(ctx.resume_program(), Ok(ExecutionStatus::LimitExceeded(ExecutionLimit::Deadline))));
+
+        ctx.set_deadline(Some(Instant::now() + Duration::from_millis(50)));
+        assert!(matches!(ctx.resume_program(), Ok(ExecutionStatus::LimitExceeded(ExecutionLimit::Deadline))));
+
+        ctx.set_deadline(None);
+        ctx.set_fuel(Some(100));
+        assert!(matches!(ctx.resume_program(), Ok(ExecutionStatus::LimitExceeded(ExecutionLimit::Fuel))));
+    }
+
+    #[test]
+    fn memory_limits() {
+        use vm::{ MemoryLimits, LIST_ELEMENT_SIZE };
+
+        let limited = |max_bytes, max_items| {
+            let mut ctx = Context::new();
+            ctx.set_memory_limits(MemoryLimits { max_bytes, max_items });
+
+            ctx
+        };
+
+        let growing_list = "JAULA SHOW\nFAZ UMA LISTA: L\nENQUANTO É MENOR: 0, 1\nPÕE ISSO AQUI: L, 1\nFIM\nSAINDO DA JAULA";
+        let err = run_program(&mut limited(Some(100 * LIST_ELEMENT_SIZE), None), growing_list).unwrap_err();
+        assert!(err.contains("Limite excedido"));
+
+        let doubling_text = "JAULA SHOW\nVEM: T, \"BIRL\"\nENQUANTO É MENOR: 0, 1\nBORA: T, T + T\nFIM\nSAINDO DA JAULA";
+        let mut ctx = limited(Some(1 << 20), None);
+        let err = run_program(&mut ctx, doubling_text).unwrap_err();
+        assert!(err.contains("Limite excedido"));
+        assert!(ctx.get_memory_usage().bytes <= 1 << 20);
+
+        // Formats are checked before the padding is made
+        for format in &["\"%4000.1f\", 1.0", "\"%2000d%2000d\", 1, 2", "\"{1:4000}\""] {
+            let source = format!("JAULA SHOW\nCE QUER VER FORMATADO: {}\nSAINDO DA JAULA", format);
+            let err = run_program(&mut limited(Some(2500), None), source.as_str()).unwrap_err();
+            assert!(err.contains("Limite excedido"), "{}", err);
+        }
+
+        let many_items = "JAULA SHOW\nFAZ UMA LISTA: L\nENQUANTO É MENOR: 0, 1\nPÕE ISSO AQUI: L, \"A\" + \"B\"\nFIM\nSAINDO DA JAULA";
+        assert!(run_program(&mut limited(None, Some(50)), many_items).unwrap_err().contains("Limite excedido"));
+
+        // What a function uses is given back when it returns, so calling it many times stays under the limit
+        let source = "
+JAULA ENCHE (N : BATATA DOCE)
+    FAZ UMA LISTA: L
+    REPETE: I, 0, N
+        PÕE ISSO AQUI: L, I
+    FIM
+    TIRA ESSE: L, 0
+    CE QUER VER ISSO: L
+SAINDO DA JAULA
+
+JAULA SHOW
+    REPETE: I, 0, 100
+        E HORA DO: ENCHE, 50
+    FIM
+SAINDO DA JAULA";
+
+        let mut ctx = limited(Some(60 * LIST_ELEMENT_SIZE + 1000), Some(100));
+        let output = run_program(&mut ctx, source).unwrap();
+        assert_eq!(output.lines().count(), 100);
+        assert!(output.starts_with("(Lista) [ 1, 2,"));
+
+        let usage = ctx.get_memory_usage();
+        assert!(usage.bytes < 100 && usage.items < 10, "{:?}", usage);
+    }
+
+    #[test]
+    fn capabilities() {
+        use capabilities::Capability;
+
+        fn clock(_ : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+            vm.check_capability(Capability::Clock)?;
+
+            Ok(Some(DynamicValue::Integer(1)))
+        }
+
+        let sandboxed = || {
+            let mut ctx = Context::new();
+            ctx.set_capabilities(Capabilities::sandboxed());
+            ctx.add_plugin("HORAS".to_owned(), vec![], clock).unwrap();
+
+            ctx
+        };
+
+        let reads_input = "JAULA SHOW\nFALA AÍ: NOME\nSAINDO DA JAULA";
+        let err = run_program(&mut sandboxed(), reads_input).unwrap_err();
+        assert!(err.contains("Permissão negada : O programa não pode ler a entrada"), "{}", err);
+
+        let err = run_program(&mut sandboxed(), "JAULA SHOW\nE HORA DO: HORAS\nSAINDO DA JAULA").unwrap_err();
+        assert!(err.contains("Permissão negada : O programa não pode ver as horas"), "{}", err);
+
+        // The environment isn't read, so CUMPADE is always the same
+        let stdlib = module_standard_library(&Capabilities::sandboxed());
+        let user = stdlib.global_variables.iter().find(|v| v.name == "CUMPADE").unwrap();
+        assert_eq!(user.value, RawValue::Text("CUMPADE".to_owned()));
+
+        let mut ctx = Context::new();
+        ctx.set_capabilities(Capabilities { stdout : false, .. Capabilities::all() });
+        assert!(run_program(&mut ctx, "JAULA SHOW\nCE QUER VER: 1\nSAINDO DA JAULA").unwrap_err().contains("Permissão negada"));
+
+        // Bytecode doesn't go through the compiler, so the VM denies it when running
+        let mut ctx = Context::new();
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(reads_input.to_owned()).unwrap();
+        let bytes = ctx.save_bytecode().unwrap();
+
+        let mut loaded = Context::new();
+        loaded.set_capabilities(Capabilities::sandboxed());
+        let _ = loaded.set_stdin(Some(Box::new(io::Cursor::new(b"BIRL\n".to_vec()))));
+        loaded.load_bytecode(&bytes).unwrap();
+        loaded.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        assert!(loaded.start_program().unwrap_err().contains("Permissão negada"));
+    }
+
+    #[test]
+    fn suspension() {
+        fn ask(_ : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+            vm.suspend(RawValue::Text("PRONTO?".to_owned()));
+
+            Ok(Some(DynamicValue::Integer(0)))
+        }
+
+        let source = "
+JAULA SHOW
+    FALA UM INTEIRO: N
+    VEM: TOTAL, 0
+    REPETE: I, 0, N
+        PERA AÍ: X, I * 10
+        BORA: TOTAL, TOTAL + X
+    FIM
+    E HORA DO: PERGUNTA
+    CE QUER VER ISSO: TOTAL, \" \", TREZE
+SAINDO DA JAULA";
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut ctx = Context::new();
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+        ctx.set_suspend_on_input(true);
+        ctx.add_plugin("PERGUNTA".to_owned(), vec![], ask).unwrap();
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(source.to_owned()).unwrap();
+
+        assert!(ctx.resume_with(RawValue::Null).is_err());
+        assert_eq!(ctx.resume_program().unwrap(), ExecutionStatus::Suspended(Suspension::Input));
+        // Nothing runs until the value is given
+        assert_eq!(ctx.resume_program().unwrap(), ExecutionStatus::Suspended(Suspension::Input));
+
+        let mut status = ctx.resume_with(RawValue::Text("3".to_owned())).unwrap();
+        let mut given = vec![];
+
+        while let ExecutionStatus::Suspended(Suspension::Yield(RawValue::Integer(i))) = status {
+            given.push(i);
+            status = ctx.resume_with(RawValue::Integer(i + 1)).unwrap();
+        }
+
+        assert_eq!(given, vec![0, 10, 20]);
+        assert_eq!(status, ExecutionStatus::Suspended(Suspension::Plugin(RawValue::Text("PRONTO?".to_owned()))));
+        assert_eq!(ctx.resume_with(RawValue::Integer(7)).unwrap(), ExecutionStatus::Halt);
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "33 7\n");
+
+        // Without anyone to give values, the program continues with null
+        let output = run_program(&mut Context::new(), "JAULA SHOW\nPERA AÍ: X, 1\nCE QUER VER ISSO: X\nSAINDO DA JAULA");
+        assert_eq!(output.unwrap(), "<Null>\n");
+    }
+
+    #[test]
+    fn coroutines() {
+        let source = "
+GAIOLA FIBONACCI
+    VEM: A, 0
+    VEM: B, 1
+    ENQUANTO É MENOR: 0, 1
+        SOLTA ESSE: A
+        VEM: C, A + B
+        BORA: A, B
+        BORA: B, C
+    FIM
+SAINDO DA GAIOLA
+GAIOLA PALAVRAS (FRASE : FIBRA)
+    VEM: ATUAL, \"\"
+    REPETE: I, 0, 3
+        BORA: ATUAL, ATUAL + FRASE
+        SOLTA ESSE: ATUAL
+    FIM
+    BIRL: \"FIM\"
+SAINDO DA GAIOLA
+JAULA SHOW
+    É HORA DO: FIBONACCI
+    VEM: F, TREZE
+    REPETE: I, 0, 8
+        ME DÁ O PRÓXIMO: F, X
+        CE QUER VER: X, \" \"
+    FIM
+    CE QUER VER ISSO
+    É HORA DO: PALAVRAS, \"ha\"
+    VEM: P, TREZE
+    JÁ ACABOU: P, ACABOU
+    ENQUANTO É MEMO: ACABOU, 0
+        ME DÁ O PRÓXIMO: P, X
+        CE QUER VER ISSO: X
+        JÁ ACABOU: P, ACABOU
+    FIM
+SAINDO DA JAULA";
+
+        let output = run_program(&mut Context::new(), source);
+        assert_eq!(output.unwrap(), "0 1 1 2 3 5 8 13 \nha\nhaha\nhahaha\nFIM\n");
+
+        // Asking a finished coroutine for more is an error
+        let finished = "GAIOLA G\nSOLTA ESSE: 1\nSAINDO DA GAIOLA\nJAULA SHOW\nÉ HORA DO: G\nVEM: X, TREZE\n\
+                        ME DÁ O PRÓXIMO: X, Y\nME DÁ O PRÓXIMO: X, Y\nME DÁ O PRÓXIMO: X, Y\nSAINDO DA JAULA";
+        assert!(run_program(&mut Context::new(), finished).is_err());
+
+        // A coroutine can't leave the function that made it
+        let returned = "GAIOLA G\nSOLTA ESSE: 1\nSAINDO DA GAIOLA\nJAULA F\nÉ HORA DO: G\nBIRL: TREZE\nSAINDO DA JAULA\n\
+                        JAULA SHOW\nÉ HORA DO: F\nSAINDO DA JAULA";
+        assert!(run_program(&mut Context::new(), returned).is_err());
+
+        // Nor go into a list or a global, where other functions could get it
+        let in_list = "GAIOLA G\nSOLTA ESSE: 1\nSAINDO DA GAIOLA\nJAULA SHOW\nÉ HORA DO: G\nFAZ UMA LISTA: L\n\
+                       PÕE ISSO AQUI: L, TREZE\nSAINDO DA JAULA";
+        assert!(run_program(&mut Context::new(), in_list).unwrap_err().contains("não pode ser posta numa lista"));
+
+        let in_global = "GAIOLA G\nSOLTA ESSE: 1\nSAINDO DA GAIOLA\nVEM: GLOBAL, 0\nJAULA SHOW\nÉ HORA DO: G\n\
+                         BORA: GLOBAL, TREZE\nSAINDO DA JAULA";
+        assert!(run_program(&mut Context::new(), in_global).unwrap_err().contains("variável global"));
+
+        let outside = "JAULA SHOW\nSOLTA ESSE: 1\nSAINDO DA JAULA";
+        assert!(Context::new().add_source_string(outside.to_owned()).is_err());
+
+        // The example in the documentation
+        let doc = include_str!("../DOC.md");
+        let start = doc.find("GAIOLA CONTAGEM").unwrap();
+        let end = start + doc[start..].find("SAINDO DA GAIOLA").unwrap() + "SAINDO DA GAIOLA".len();
+
+        let example = format!("{}\nJAULA SHOW\n\
+                               É HORA DO: CONTAGEM, 3\nVEM: C, TREZE\n\
+                               REPETE: I, 0, 3\nME DÁ O PRÓXIMO: C, X\nCE QUER VER: X\nFIM\n\
+                               SAINDO DA JAULA", &doc[start..end]);
+        assert_eq!(run_program(&mut Context::new(), example.as_str()), Ok("012".to_owned()));
+    }
+
+    #[test]
+    fn plugin_state() {
+        use std::cell::Cell;
+        use modules::{ Module, Plugin };
+
+        struct Config {
+            greeting : String,
+            calls : usize,
+        }
+
+        fn greet(_ : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+            let greeting = match vm.get_user_data::<Config>() {
+                Some(config) => {
+                    config.calls += 1;
+                    config.greeting.clone()
+                }
+                None => return Err("Sem configuração".to_owned()),
+            };
+
+            vm.print_string(&greeting)?;
+
+            Ok(None)
+        }
+
+        let total = Rc::new(Cell::new(0));
+        let counter = total.clone();
+
+        let mut module = Module::new("CONTA".to_owned());
+        module.plugin_functions.push(Plugin::new("SOMA".to_owned(), vec![TypeKind::Integer], move |args, _| {
+            if let DynamicValue::Integer(i) = args[0] {
+                counter.set(counter.get() + i);
+            }
+
+            Ok(Some(DynamicValue::Integer(counter.get())))
+        }));
+
+        let mut ctx = Context::new();
+        ctx.set_user_data(Config { greeting : "BIRL ".to_owned(), calls : 0 });
+        ctx.add_plugin("SAUDA".to_owned(), vec![], greet).unwrap();
+        ctx.add_module(module).unwrap();
+
+        ctx.add_module_plugin(Plugin::from_fn("OLA".to_owned(), vec![], greet)).unwrap();
+
+        let source = "JAULA SHOW\nREPETE: I, 1, 4\nÉ HORA DO: SOMA, I\nÉ HORA DO: SAUDA\nFIM\nÉ HORA DO: OLA\nCE QUER VER ISSO: TREZE\nSAINDO DA JAULA";
+        let output = run_program(&mut ctx, source);
+
+        assert_eq!(output.unwrap(), "BIRL BIRL BIRL BIRL 6\n");
+        assert_eq!(total.get(), 6);
+        assert_eq!(ctx.get_user_data::<Config>().map(|c| c.calls), Some(4));
+        assert!(ctx.get_user_data::<String>().is_none());
+    }
+
+    #[test]
+    fn plugin_values() {
+        fn words(args : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+            let text = vm.get_text(args[0])?.to_owned();
+            let mut values = vec![];
+
+            for word in text.split_whitespace() {
+                values.push(vm.new_text(word.to_uppercase())?);
+            }
+
+            Ok(Some(vm.new_list(values)?))
+        }
+
+        fn join(args : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+            let mut parts = vec![];
+
+            for element in vm.get_list(args[0])? {
+                parts.push(match vm.dynamic_to_raw(**element)? {
+                    RawValue::Text(t) => t,
+                    v => format!("{:?}", v),
+                });
+            }
+
+            Ok(Some(vm.new_text(parts.join("-"))?))
+        }
+
+        let mut ctx = Context::new();
+        ctx.add_plugin("PALAVRAS".to_owned(), vec![TypeKind::Text], words).unwrap();
+        ctx.add_plugin("JUNTA".to_owned(), vec![TypeKind::List], join).unwrap();
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+
+        let numbers = RawValue::List(vec![RawValue::Integer(1), RawValue::Text("dois".to_owned())]);
+        ctx.add_global_variable("NUMEROS".to_owned(), numbers.clone(), false).unwrap();
+
+        let source = "
+JAULA SHOW
+    É HORA DO: PALAVRAS, \"hora do show\"
+    VEM: L, TREZE
+    PÕE ISSO AQUI: L, 13
+    É HORA DO: JUNTA, L
+    CE QUER VER ISSO: TREZE
+    É HORA DO: JUNTA, NUMEROS
+    CE QUER VER ISSO: TREZE
+    PERA AÍ: X, NUMEROS
+    PÕE ISSO AQUI: L, L
+    É HORA DO: JUNTA, L
+SAINDO DA JAULA";
+
+        ctx.add_source_string(source.to_owned()).unwrap();
+        ctx.set_suspend_on_input(true);
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+
+        assert_eq!(ctx.resume_program().unwrap(), ExecutionStatus::Suspended(Suspension::Yield(numbers)));
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "HORA-DO-SHOW-Integer(13)\nInteger(1)-dois\n");
+
+        // A list with itself inside can't be given to the host
+        let error = ctx.resume_with(RawValue::Null).unwrap_err();
+        assert!(error.contains("tem ela mesma dentro"));
+    }
+
+    #[test]
+    fn typed_plugins() {
+        use std::collections::BTreeMap;
+
+        fn count(words : Vec<String>) -> BTreeMap<String, i64> {
+            let mut counts = BTreeMap::new();
+
+            for word in words {
+                *counts.entry(word).or_insert(0) += 1;
+            }
+
+            counts
+        }
+
+        let mut ctx = Context::new();
+        ctx.add_typed_plugin("CONTA".to_owned(), count).unwrap();
+        ctx.add_typed_plugin("MEDIA".to_owned(), |a : f64, b : f64| (a + b) / 2.0).unwrap();
+        ctx.add_typed_plugin("POSITIVO".to_owned(), |n : u32| n).unwrap();
+        ctx.add_typed_plugin("ACHA".to_owned(), |list : Vec<String>, word : String| {
+            list.iter().position(|w| *w == word)
+        }).unwrap();
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+
+        let source = "
+JAULA SHOW
+    FAZ UMA LISTA: L
+    PÕE ISSO AQUI: L, \"oi\"
+    PÕE ISSO AQUI: L, \"tchau\"
+    PÕE ISSO AQUI: L, \"oi\"
+    É HORA DO: CONTA, L
+    CE QUER VER ISSO: TREZE
+    É HORA DO: MEDIA, 3, 4.5
+    CE QUER VER ISSO: TREZE
+    É HORA DO: ACHA, L, \"tchau\"
+    CE QUER VER ISSO: TREZE
+    É HORA DO: ACHA, L, \"nada\"
+    CE QUER VER ISSO: TREZE
+    É HORA DO: POSITIVO, -1
+SAINDO DA JAULA";
+
+        ctx.add_source_string(source.to_owned()).unwrap();
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+
+        assert_eq!(ctx.start_program(), Err("O número -1 não cabe em u32".to_owned()));
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "(Lista) [ [ \"oi\", 2 ], [ \"tchau\", 1 ] ]\n3.75\n1\n<Null>\n");
+
+        // A type made of itself, reading a list that has itself inside
+        use conversion::FromBirl;
+
+        struct Tree(Vec<Tree>);
+
+        impl FromBirl for Tree {
+            fn kind() -> TypeKind {
+                TypeKind::List
+            }
+
+            fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<Tree, String> {
+                Vec::from_birl(value, vm).map(Tree)
+            }
+
+            fn from_nested_birl(value : DynamicValue, vm : &VirtualMachine, parents : &mut HashSet<u64>)
+                -> Result<Tree, String>
+            {
+                Vec::from_nested_birl(value, vm, parents).map(Tree)
+            }
+        }
+
+        fn height(tree : &Tree) -> usize {
+            1 + tree.0.iter().map(height).max().unwrap_or(0)
+        }
+
+        let mut ctx = Context::new();
+        ctx.add_typed_plugin("ALTURA".to_owned(), |tree : Tree| height(&tree)).unwrap();
+
+        let source = "
+JAULA SHOW
+    FAZ UMA LISTA: L
+    FAZ UMA LISTA: M
+    PÕE ISSO AQUI: L, M
+    PÕE ISSO AQUI: L, M
+    É HORA DO: ALTURA, L
+    CE QUER VER ISSO: TREZE
+    PÕE ISSO AQUI: M, L
+    É HORA DO: ALTURA, L
+SAINDO DA JAULA";
+
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(source.to_owned()).unwrap();
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+
+        assert!(ctx.start_program().unwrap_err().contains("tem ela mesma dentro"));
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "2\n");
+    }
+
+    #[test]
+    fn typed_big_integers() {
+        use bigint::BigInteger;
+
+        let source = "
+JAULA SHOW
+    É HORA DO: MAXIMO
+    CE QUER VER ISSO: TREZE
+    VEM: X, TREZE
+    É HORA DO: DOBRO, X
+    CE QUER VER ISSO: TREZE
+    É HORA DO: METADE, X
+    CE QUER VER ISSO: TREZE
+    É HORA DO: PEQUENO, X
+SAINDO DA JAULA";
+
+        let context = |big_integers : bool| {
+            let mut ctx = Context::new();
+            ctx.set_big_integer_mode(big_integers);
+            ctx.add_typed_plugin("MAXIMO".to_owned(), || u64::MAX).unwrap();
+            ctx.add_typed_plugin("DOBRO".to_owned(), |n : BigInteger| n.add(&n)).unwrap();
+            ctx.add_typed_plugin("METADE".to_owned(), |n : u64| n / 2).unwrap();
+            ctx.add_typed_plugin("PEQUENO".to_owned(), |n : i32| n).unwrap();
+            ctx
+        };
+
+        let mut ctx = context(true);
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(source.to_owned()).unwrap();
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+
+        assert_eq!(ctx.start_program(), Err("O número 18446744073709551615 não cabe em i32".to_owned()));
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(),
+            "18446744073709551615\n36893488147419103230\n9223372036854775807\n");
+
+        // Without the big integer mode, a number that doesn't fit is an overflow
+        let error = run_program(&mut context(false), source).unwrap_err();
+        assert!(error.contains("Estouro de inteiro"));
+    }
+
+    #[test]
+    fn snapshots() {
+        use vm::ExecutionLimit;
+
+        fn double(args : Vec<DynamicValue>, _ : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+            match args[0] {
+                DynamicValue::Integer(i) => Ok(Some(DynamicValue::Integer(i * 2))),
+                _ => Err("Esperado um inteiro".to_owned()),
+            }
+        }
+
+        let source = "
+VEM: NOME, \"BIRL\"
+GAIOLA CONTA
+    REPETE: I, 0, 100
+        SOLTA ESSE: I
+    FIM
+SAINDO DA GAIOLA
+JAULA SHOW
+    FAZ UMA LISTA: L
+    É HORA DO: CONTA
+    VEM: G, TREZE
+    REPETE: I, 0, 20
+        ME DÁ O PRÓXIMO: G, X
+        É HORA DO: DOBRO, X
+        PÕE ISSO AQUI: L, \"{NOME} {TREZE}\"
+    FIM
+    FALA O TAMANHO: L, N
+    ME DÁ ESSE: L, N - 1, ÚLTIMO
+    FALA AÍ: ENTRADA
+    CE QUER VER ISSO: N, \" \", ÚLTIMO, \" \", ENTRADA
+SAINDO DA JAULA";
+
+        let new_context = |output : &Rc<RefCell<Vec<u8>>>| {
+            let mut ctx = Context::new();
+            let _ = ctx.set_stdout(Some(Box::new(SharedOutput(output.clone()))));
+            ctx.set_suspend_on_input(true);
+            ctx.add_plugin("DOBRO".to_owned(), vec![TypeKind::Integer], double).unwrap();
+            ctx
+        };
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut ctx = new_context(&output);
+        ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]).unwrap();
+        ctx.add_source_string(source.to_owned()).unwrap();
+        ctx.set_fuel(Some(200));
+
+        assert_eq!(ctx.resume_program().unwrap(), ExecutionStatus::LimitExceeded(ExecutionLimit::Fuel));
+
+        // Continues in a new context, which doesn't even have the global function running
+        let bytes = ctx.save_snapshot().unwrap();
+        let mut restored = new_context(&output);
+        restored.load_snapshot(&bytes).unwrap();
+
+        assert_eq!(restored.resume_program().unwrap(), ExecutionStatus::Suspended(Suspension::Input));
+
+        // Paused programs can be saved too
+        let bytes = restored.save_snapshot().unwrap();
+        let mut restored = new_context(&output);
+        restored.load_snapshot(&bytes).unwrap();
+
+        assert_eq!(restored.resume_with(RawValue::Text("oi".to_owned())).unwrap(), ExecutionStatus::Halt);
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "20 BIRL 38 oi\n");
+
+        // Plugins are found by name
+        let mut missing = Context::new();
+        assert_eq!(missing.load_snapshot(&bytes), Err("Erro : O programa usa o plugin \"DOBRO\", que não foi adicionado".to_owned()));
+        assert!(Context::new().load_bytecode(&bytes).is_err());
+    }
+}
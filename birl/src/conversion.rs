@@ -0,0 +1,419 @@
+//! Conversion between Rust values and the values of the VM, so plugins can be ordinary typed functions
+//!
+//! Maps don't exist in BirlScript, so they are lists of pairs, each one a list with the key and the value
+
+use parser::{ IntegerType, TypeKind };
+use vm::{ VirtualMachine, DynamicValue, BoxedPlugin };
+use context::RawValue;
+use bigint::BigInteger;
+
+use std::collections::{ HashMap, HashSet, BTreeMap };
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::fmt::Display;
+
+/// A Rust value that can be made from a value of the VM
+pub trait FromBirl : Sized {
+    /// The type the compiler checks when this is a plugin parameter
+    fn kind() -> TypeKind;
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<Self, String>;
+
+    /// Like from_birl, for a value inside the lists with the given ids, which are still being read. Lists use it so one
+    /// that has itself inside is an error instead of being read forever, and types made of lists, like a tree, should
+    /// pass the ids on to the lists inside them
+    fn from_nested_birl(value : DynamicValue, vm : &VirtualMachine, _parents : &mut HashSet<u64>)
+        -> Result<Self, String>
+    {
+        Self::from_birl(value, vm)
+    }
+}
+
+/// A Rust value that can be given to the VM. Texts and lists are made like in vm.new_text and vm.new_list
+pub trait IntoBirl {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String>;
+}
+
+fn incompatible(expected : &str, value : DynamicValue) -> String {
+    let found = match value {
+        DynamicValue::Integer(_) => "um Inteiro",
+        DynamicValue::BigInteger(_) => "um Inteiro grande",
+        DynamicValue::Number(_) => "um Número",
+        DynamicValue::Text(_) => "um Texto",
+        DynamicValue::List(_) => "uma Lista",
+        DynamicValue::Coroutine(_) => "uma Gaiola",
+        DynamicValue::Null => "Null",
+    };
+
+    format!("Tipo incompatível : Esperado {}, encontrado {}", expected, found)
+}
+
+// Marks the list as being read, so finding it again inside itself is an error. The id is taken out by the caller
+fn start_reading_list(value : DynamicValue, parents : &mut HashSet<u64>) -> Result<Option<u64>, String> {
+    let id = match value {
+        DynamicValue::List(id) => id,
+        _ => return Ok(None),
+    };
+
+    if parents.insert(id) {
+        Ok(Some(id))
+    } else {
+        Err("Tipo incompatível : Uma lista que tem ela mesma dentro não pode ser lida".to_owned())
+    }
+}
+
+macro_rules! integer_conversion {
+    ($($t:ty),*) => {
+        $(
+            impl FromBirl for $t {
+                fn kind() -> TypeKind {
+                    TypeKind::Integer
+                }
+
+                // Big integers that fit are fine too
+                fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<$t, String> {
+                    let doesnt_fit = |i : &dyn Display| format!("O número {} não cabe em {}", i, stringify!($t));
+
+                    match value {
+                        DynamicValue::Integer(i) => <$t>::try_from(i).map_err(|_| doesnt_fit(&i)),
+                        DynamicValue::BigInteger(_) => {
+                            let integer = vm.get_integer(value)?;
+
+                            integer.to_string().parse::<$t>().map_err(|_| doesnt_fit(&integer))
+                        }
+                        v => Err(incompatible("um Inteiro", v)),
+                    }
+                }
+            }
+
+            // Numbers too big for a regular integer are big integers, if the VM is in the big integer mode
+            impl IntoBirl for $t {
+                fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+                    match IntegerType::try_from(self) {
+                        Ok(i) => Ok(DynamicValue::Integer(i)),
+                        Err(_) => match BigInteger::parse(&self.to_string()) {
+                            Some(b) => vm.new_integer(b),
+                            None => Err(format!("Erro interno : {} não é um inteiro", self)),
+                        },
+                    }
+                }
+            }
+        )*
+    }
+}
+
+integer_conversion!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromBirl for BigInteger {
+    fn kind() -> TypeKind {
+        TypeKind::Integer
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<BigInteger, String> {
+        vm.get_integer(value)
+    }
+}
+
+impl IntoBirl for BigInteger {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        vm.new_integer(self)
+    }
+}
+
+impl FromBirl for f64 {
+    fn kind() -> TypeKind {
+        TypeKind::Number
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<f64, String> {
+        match value {
+            DynamicValue::Number(n) => Ok(n),
+            DynamicValue::Integer(i) => Ok(i as f64),
+            DynamicValue::BigInteger(_) => Ok(vm.get_integer(value)?.to_f64()),
+            v => Err(incompatible("um Número", v)),
+        }
+    }
+}
+
+impl IntoBirl for f64 {
+    fn into_birl(self, _ : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        Ok(DynamicValue::Number(self))
+    }
+}
+
+impl FromBirl for f32 {
+    fn kind() -> TypeKind {
+        TypeKind::Number
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<f32, String> {
+        f64::from_birl(value, vm).map(|n| n as f32)
+    }
+}
+
+impl IntoBirl for f32 {
+    fn into_birl(self, _ : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        Ok(DynamicValue::Number(f64::from(self)))
+    }
+}
+
+impl FromBirl for String {
+    fn kind() -> TypeKind {
+        TypeKind::Text
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<String, String> {
+        vm.get_text(value).map(|t| t.to_owned())
+    }
+}
+
+impl IntoBirl for String {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        vm.new_text(self)
+    }
+}
+
+/// Only this way, since a borrowed text from the VM wouldn't let the plugin use it mutably. Use String to receive texts
+impl IntoBirl for &str {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        vm.new_text(self.to_owned())
+    }
+}
+
+impl<T : FromBirl> FromBirl for Vec<T> {
+    fn kind() -> TypeKind {
+        TypeKind::List
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<Vec<T>, String> {
+        Vec::from_nested_birl(value, vm, &mut HashSet::new())
+    }
+
+    fn from_nested_birl(value : DynamicValue, vm : &VirtualMachine, parents : &mut HashSet<u64>)
+        -> Result<Vec<T>, String>
+    {
+        let reading = start_reading_list(value, parents)?;
+
+        let result = vm.get_list(value)?.iter()
+            .map(|v| T::from_nested_birl(**v, vm, parents))
+            .collect::<Result<Vec<T>, String>>();
+
+        if let Some(id) = reading {
+            parents.remove(&id);
+        }
+
+        result
+    }
+}
+
+impl<T : IntoBirl> IntoBirl for Vec<T> {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        let mut values = Vec::with_capacity(self.len());
+
+        for value in self {
+            values.push(value.into_birl(vm)?);
+        }
+
+        vm.new_list(values)
+    }
+}
+
+/// Null is None. The compiler never lets Null be passed to a plugin, so this is for return values and list elements
+impl<T : FromBirl> FromBirl for Option<T> {
+    fn kind() -> TypeKind {
+        T::kind()
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<Option<T>, String> {
+        match value {
+            DynamicValue::Null => Ok(None),
+            v => T::from_birl(v, vm).map(Some),
+        }
+    }
+
+    fn from_nested_birl(value : DynamicValue, vm : &VirtualMachine, parents : &mut HashSet<u64>)
+        -> Result<Option<T>, String>
+    {
+        match value {
+            DynamicValue::Null => Ok(None),
+            v => T::from_nested_birl(v, vm, parents).map(Some),
+        }
+    }
+}
+
+impl<T : IntoBirl> IntoBirl for Option<T> {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        match self {
+            Some(v) => v.into_birl(vm),
+            None => Ok(DynamicValue::Null),
+        }
+    }
+}
+
+/// An error stops the program, like an error returned by a plugin
+impl<T : IntoBirl> IntoBirl for Result<T, String> {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        self.and_then(|v| v.into_birl(vm))
+    }
+}
+
+/// A plugin returning nothing gives Null
+impl IntoBirl for () {
+    fn into_birl(self, _ : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        Ok(DynamicValue::Null)
+    }
+}
+
+impl IntoBirl for DynamicValue {
+    fn into_birl(self, _ : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        Ok(self)
+    }
+}
+
+impl IntoBirl for RawValue {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        vm.raw_to_dynamic(&self)
+    }
+}
+
+fn pairs_from_birl<K, V>(value : DynamicValue, vm : &VirtualMachine, parents : &mut HashSet<u64>)
+    -> Result<Vec<(K, V)>, String> where K : FromBirl, V : FromBirl
+{
+    let reading = start_reading_list(value, parents)?;
+    let mut pairs = vec![];
+
+    for pair in vm.get_list(value)? {
+        match vm.get_list(**pair)? {
+            [key, value] => {
+                pairs.push((K::from_nested_birl(**key, vm, parents)?, V::from_nested_birl(**value, vm, parents)?));
+            }
+            p => return Err(format!("Esperado um par com chave e valor, encontrada uma lista com {} elementos",
+                p.len())),
+        }
+    }
+
+    if let Some(id) = reading {
+        parents.remove(&id);
+    }
+
+    Ok(pairs)
+}
+
+fn pairs_into_birl<K, V, I>(pairs : I, vm : &mut VirtualMachine) -> Result<DynamicValue, String>
+    where K : IntoBirl, V : IntoBirl, I : Iterator<Item = (K, V)>
+{
+    let mut values = vec![];
+
+    for (key, value) in pairs {
+        let pair = vec![key.into_birl(vm)?, value.into_birl(vm)?];
+
+        values.push(vm.new_list(pair)?);
+    }
+
+    vm.new_list(values)
+}
+
+impl<K : FromBirl + Eq + Hash, V : FromBirl> FromBirl for HashMap<K, V> {
+    fn kind() -> TypeKind {
+        TypeKind::List
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<HashMap<K, V>, String> {
+        HashMap::from_nested_birl(value, vm, &mut HashSet::new())
+    }
+
+    fn from_nested_birl(value : DynamicValue, vm : &VirtualMachine, parents : &mut HashSet<u64>)
+        -> Result<HashMap<K, V>, String>
+    {
+        pairs_from_birl(value, vm, parents).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+/// The pairs come in no particular order. Use a BTreeMap to have them ordered by key
+impl<K : IntoBirl, V : IntoBirl> IntoBirl for HashMap<K, V> {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        pairs_into_birl(self.into_iter(), vm)
+    }
+}
+
+impl<K : FromBirl + Ord, V : FromBirl> FromBirl for BTreeMap<K, V> {
+    fn kind() -> TypeKind {
+        TypeKind::List
+    }
+
+    fn from_birl(value : DynamicValue, vm : &VirtualMachine) -> Result<BTreeMap<K, V>, String> {
+        BTreeMap::from_nested_birl(value, vm, &mut HashSet::new())
+    }
+
+    fn from_nested_birl(value : DynamicValue, vm : &VirtualMachine, parents : &mut HashSet<u64>)
+        -> Result<BTreeMap<K, V>, String>
+    {
+        pairs_from_birl(value, vm, parents).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+impl<K : IntoBirl, V : IntoBirl> IntoBirl for BTreeMap<K, V> {
+    fn into_birl(self, vm : &mut VirtualMachine) -> Result<DynamicValue, String> {
+        pairs_into_birl(self.into_iter(), vm)
+    }
+}
+
+/// A Rust function that can be a plugin, with Args being the tuple of its parameters. Implemented for functions and
+/// closures with up to 6 parameters that are FromBirl and returning something that is IntoBirl
+///
+/// ```
+/// use birl::context::Context;
+///
+/// fn repeat(text : String, times : usize) -> String {
+///     text.repeat(times)
+/// }
+///
+/// let mut ctx = Context::new();
+///
+/// ctx.add_typed_plugin("REPETE_TEXTO".to_owned(), repeat).unwrap();
+/// ctx.add_typed_plugin("MAIOR".to_owned(), |list : Vec<f64>| list.into_iter().fold(None, |max : Option<f64>, n| {
+///     Some(max.map_or(n, |m| m.max(n)))
+/// })).unwrap();
+/// ```
+pub trait TypedPlugin<Args> {
+    /// The types of the parameters, given to the compiler
+    fn parameters() -> Vec<TypeKind>;
+
+    fn into_plugin(self) -> BoxedPlugin;
+}
+
+macro_rules! typed_plugin {
+    ($($arg:ident),*) => {
+        impl<Func, Ret, $($arg),*> TypedPlugin<($($arg,)*)> for Func
+            where Func : FnMut($($arg),*) -> Ret + 'static, Ret : IntoBirl, $($arg : FromBirl),*
+        {
+            fn parameters() -> Vec<TypeKind> {
+                vec![$($arg::kind()),*]
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_plugin(mut self) -> BoxedPlugin {
+                let num_params = Self::parameters().len();
+
+                Box::new(move |args : Vec<DynamicValue>, vm : &mut VirtualMachine| {
+                    if args.len() != num_params {
+                        return Err(format!("Esperados {} argumentos, mas {} foram passados", num_params, args.len()));
+                    }
+
+                    let mut args = args.into_iter();
+                    $(let $arg = $arg::from_birl(args.next().unwrap(), vm)?;)*
+
+                    Ok(Some(self($($arg),*).into_birl(vm)?))
+                })
+            }
+        }
+    }
+}
+
+typed_plugin!();
+typed_plugin!(A);
+typed_plugin!(A, B);
+typed_plugin!(A, B, C);
+typed_plugin!(A, B, C, D);
+typed_plugin!(A, B, C, D, E);
+typed_plugin!(A, B, C, D, E, F);
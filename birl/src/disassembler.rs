@@ -0,0 +1,231 @@
+//! Readable listings of compiled functions, to see what the compiler made of the source.
+//!
+//! Each instruction is shown with its address, indented by the blocks it's in, and followed by the name of what it
+//! refers to, like the variable it reads or the function it calls. The source line of the instructions is shown
+//! before the first instruction of each line
+
+use vm::{ Code, Instruction, Block, BlockKind };
+use context::{ BIRL_GLOBAL_FUNCTION_ID, BIRL_MAIN_FUNCTION_ID, BIRL_MAIN_FUNCTION, BIRL_RET_VAL_VAR_ADDRESS };
+
+use std::collections::HashMap;
+
+/// A compiled function, as seen by the disassembler
+pub struct FunctionListing<'a> {
+    pub name : &'a str,
+    pub code : &'a Code,
+    pub frame_size : Option<usize>,
+}
+
+/// Everything instructions can refer to, so the listing can show names instead of addresses
+#[derive(Default)]
+pub struct Symbols<'a> {
+    /// Every function, in order of code ID
+    pub functions : Vec<FunctionListing<'a>>,
+    /// Plugin names by plugin ID
+    pub plugins : HashMap<usize, &'a str>,
+    /// Global variable names by address
+    pub globals : HashMap<usize, &'a str>,
+}
+
+// Column where the names after the instructions start
+const NAME_COLUMN : usize = 40;
+const INDENT : &str = "  ";
+
+fn block_name(kind : BlockKind) -> &'static str {
+    match kind {
+        BlockKind::Conditional => "condição",
+        BlockKind::Loop => "repetição",
+    }
+}
+
+fn function_name(symbols : &Symbols, id : usize) -> String {
+    match symbols.functions.get(id) {
+        Some(f) if !f.name.is_empty() => f.name.to_owned(),
+        _ if id == BIRL_GLOBAL_FUNCTION_ID => "código global".to_owned(),
+        _ if id == BIRL_MAIN_FUNCTION_ID => BIRL_MAIN_FUNCTION.to_owned(),
+        _ => format!("#{}", id),
+    }
+}
+
+fn local_name(code : &Code, address : usize, var : usize) -> Option<&str> {
+    if var == BIRL_RET_VAL_VAR_ADDRESS {
+        return Some("TREZE");
+    }
+
+    code.debug.variable_at(address, var)
+}
+
+fn global_name<'a>(symbols : &'a Symbols, id : usize, address : usize, var : usize) -> Option<&'a str> {
+    let global_code = symbols.functions.get(BIRL_GLOBAL_FUNCTION_ID).map(|f| f.code);
+
+    // Variables declared inside blocks in the global code aren't in the global symbol table anymore
+    match global_code {
+        Some(code) if id == BIRL_GLOBAL_FUNCTION_ID => local_name(code, address, var)
+            .or_else(|| symbols.globals.get(&var).cloned()),
+        Some(code) => symbols.globals.get(&var).cloned()
+            .or_else(|| local_name(code, code.len(), var)),
+        None => symbols.globals.get(&var).cloned(),
+    }
+}
+
+// The name of what the instruction refers to, if it refers to something. last_frame is the code ID of the last
+// frame made, which WriteVarToLast writes to
+fn describe(symbols : &Symbols, id : usize, address : usize, inst : &Instruction, last_frame : Option<usize>)
+    -> Option<String>
+{
+    let code = symbols.functions[id].code;
+
+    match *inst {
+        Instruction::ReadVarFrom(var) | Instruction::WriteVarTo(var) => local_name(code, address, var).map(str::to_owned),
+        Instruction::ReadGlobalVarFrom(var) | Instruction::WriteGlobalVarTo(var) => {
+            global_name(symbols, id, address, var).map(str::to_owned)
+        }
+        // Parameters are declared before the first instruction of the function
+        Instruction::WriteVarToLast(var) => last_frame
+            .and_then(|frame| symbols.functions.get(frame))
+            .and_then(|f| local_name(f.code, 0, var))
+            .map(str::to_owned),
+        Instruction::MakeNewFrame(frame) => Some(function_name(symbols, frame)),
+        Instruction::CallPlugin(plugin, _) => symbols.plugins.get(&plugin).map(|&name| name.to_owned()),
+        Instruction::PushText(index) | Instruction::AppendToInterpolation(index) => {
+            code.texts.get(index).map(|t| format!("{:?}", t))
+        }
+        _ => None,
+    }
+}
+
+/// Lists the instructions of the function with the code ID
+pub fn disassemble(symbols : &Symbols, id : usize) -> Result<String, String> {
+    let function = match symbols.functions.get(id) {
+        Some(f) => f,
+        None => return Err(format!("Nenhuma função com ID {}", id)),
+    };
+
+    let code = function.code;
+    let mut listing = format!("== {} (ID {}", function_name(symbols, id), id);
+
+    if let Some(size) = function.frame_size {
+        listing.push_str(format!(", {} variáveis", size).as_str());
+    }
+
+    listing.push_str(format!(", {} instruções) ==\n", code.len()).as_str());
+
+    // Blocks whose instructions were all removed by the optimizer aren't shown
+    let blocks = code.debug.blocks.iter().filter(|b| b.start < b.end).collect::<Vec<&Block>>();
+    let mut depth = 0usize;
+    let mut last_frame = None;
+
+    for address in 0..code.len() + 1 {
+        let mut ending = blocks.iter().filter(|b| b.end == address).collect::<Vec<_>>();
+        ending.sort_by_key(|b| ::std::cmp::Reverse(b.start));
+
+        for block in ending {
+            depth = depth.saturating_sub(1);
+            listing.push_str(format!("      {}; fim da {}\n", INDENT.repeat(depth), block_name(block.kind)).as_str());
+        }
+
+        let inst = match code.instructions.get(address) {
+            Some(i) => i,
+            None => break,
+        };
+
+        let mut starting = blocks.iter().filter(|b| b.start == address).collect::<Vec<_>>();
+        starting.sort_by_key(|b| ::std::cmp::Reverse(b.end));
+
+        for block in starting {
+            listing.push_str(format!("      {}; começo da {}\n", INDENT.repeat(depth), block_name(block.kind)).as_str());
+            depth += 1;
+        }
+
+        if let Some(&(_, line)) = code.debug.lines.iter().find(|&&(start, _)| start == address) {
+            listing.push_str(format!("      {}; linha {}\n", INDENT.repeat(depth), line).as_str());
+        }
+
+        let text = format!("{:4}  {}{:?}", address, INDENT.repeat(depth), inst);
+
+        match describe(symbols, id, address, inst, last_frame) {
+            Some(name) => listing.push_str(format!("{:width$} ; {}\n", text, name, width = NAME_COLUMN).as_str()),
+            None => listing.push_str(format!("{}\n", text).as_str()),
+        }
+
+        if let Instruction::MakeNewFrame(frame) = *inst {
+            last_frame = Some(frame);
+        }
+    }
+
+    Ok(listing)
+}
+
+/// Lists the instructions of every function, in order of code ID
+pub fn disassemble_all(symbols : &Symbols) -> String {
+    (0..symbols.functions.len())
+        .filter_map(|id| disassemble(symbols, id).ok())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+mod tests {
+    #![allow(dead_code, unused_imports)]
+
+    use disassembler::*;
+    use vm::ComparisionRequest;
+    use context::RawValue;
+
+    #[test]
+    fn listing() {
+        let mut function = Code::new();
+        function.instructions = vec![Instruction::ReadVarFrom(1), Instruction::Return];
+        function.debug.variables.push((0, "N".to_owned(), 1));
+        function.debug.mark_line(0, 2);
+
+        let mut main = Code::new();
+        let text = main.add_text("BIRL".to_owned());
+        main.instructions = vec![
+            Instruction::PushValue(RawValue::Integer(1)),
+            Instruction::WriteVarTo(1),
+            Instruction::ReadVarFrom(1),
+            Instruction::PushValue(RawValue::Integer(3)),
+            Instruction::Compare,
+            Instruction::JumpIfNot(ComparisionRequest::Less, 11),
+            Instruction::MakeNewFrame(2),
+            Instruction::ReadVarFrom(1),
+            Instruction::WriteVarToLast(1),
+            Instruction::SetLastFrameReady,
+            Instruction::Jump(2),
+            Instruction::PushText(text),
+            Instruction::CallPlugin(0, 1),
+        ];
+        main.debug.variables.push((0, "I".to_owned(), 1));
+        main.debug.mark_line(0, 5);
+        main.debug.mark_line(6, 6);
+        main.debug.mark_line(11, 8);
+        main.debug.blocks.push(Block { start : 2, end : 11, kind : BlockKind::Loop });
+
+        let global = Code::new();
+        let mut symbols = Symbols::default();
+        symbols.functions.push(FunctionListing { name : "", code : &global, frame_size : Some(1) });
+        symbols.functions.push(FunctionListing { name : "SHOW", code : &main, frame_size : Some(2) });
+        symbols.functions.push(FunctionListing { name : "DOBRO", code : &function, frame_size : Some(2) });
+        symbols.plugins.insert(0, "FALA");
+
+        let listing = disassemble(&symbols, 1).unwrap();
+        let lines = listing.lines().map(str::trim_end).collect::<Vec<&str>>();
+
+        assert_eq!(lines[0], "== SHOW (ID 1, 2 variáveis, 13 instruções) ==");
+        assert_eq!(lines[1], "      ; linha 5");
+        assert!(lines[3].starts_with("   1  WriteVarTo(1)") && lines[3].ends_with("; I"));
+        assert_eq!(lines[4], "      ; começo da repetição");
+        assert!(lines[5].starts_with("   2    ReadVarFrom(1)"));
+        assert!(listing.contains("MakeNewFrame(2)") && lines.iter().any(|l| l.contains("MakeNewFrame(2)") && l.ends_with("; DOBRO")));
+        assert!(lines.iter().any(|l| l.contains("WriteVarToLast(1)") && l.ends_with("; N")));
+        assert!(lines.iter().any(|l| l.contains("PushText(0)") && l.ends_with("; \"BIRL\"")));
+        assert!(lines.iter().any(|l| l.contains("CallPlugin(0, 1)") && l.ends_with("; FALA")));
+
+        let end = lines.iter().position(|l| l.trim() == "; fim da repetição").unwrap();
+        assert!(lines[end - 1].contains("Jump(2)"));
+        assert!(lines[end + 2].starts_with("  11  PushText(0)"));
+
+        assert!(disassemble(&symbols, 3).is_err());
+        assert!(disassemble_all(&symbols).contains("== código global (ID 0, 1 variáveis, 0 instruções) =="));
+    }
+}
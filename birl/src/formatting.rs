@@ -0,0 +1,562 @@
+//! Formatting of values used by text interpolation and by formatted output
+
+use parser::IntegerType;
+use bigint::BigInteger;
+
+/// Biggest width or precision accepted, so a format can't ask for a gigantic text
+pub const MAX_FORMAT_SIZE : usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatKind {
+    /// Whatever is the default for the value
+    Default,
+    /// d : Integers only
+    Decimal,
+    /// f : Numbers with fixed precision (6 if none is given)
+    Fixed,
+    /// e : Numbers in scientific notation
+    Exponent,
+    /// s : Any value, converted to text
+    Text,
+    /// x and X : Integers in hexadecimal
+    Hexadecimal,
+    HexadecimalUpper,
+    /// b : Integers in binary
+    Binary,
+    /// o : Integers in octal
+    Octal,
+}
+
+impl FormatKind {
+    fn from_char(c : char) -> Option<FormatKind> {
+        match c {
+            'v' => Some(FormatKind::Default),
+            'd' | 'i' => Some(FormatKind::Decimal),
+            'f' => Some(FormatKind::Fixed),
+            'e' => Some(FormatKind::Exponent),
+            's' => Some(FormatKind::Text),
+            'x' => Some(FormatKind::Hexadecimal),
+            'X' => Some(FormatKind::HexadecimalUpper),
+            'b' => Some(FormatKind::Binary),
+            'o' => Some(FormatKind::Octal),
+            _ => None,
+        }
+    }
+
+    fn is_integer_only(self) -> bool {
+        matches!(self, FormatKind::Decimal | FormatKind::Hexadecimal | FormatKind::HexadecimalUpper |
+                       FormatKind::Binary | FormatKind::Octal)
+    }
+
+    fn is_numeric(self) -> bool {
+        !matches!(self, FormatKind::Default | FormatKind::Text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatSpec {
+    pub fill : char,
+    pub alignment : Option<Alignment>,
+    /// Always print the sign of numbers
+    pub sign : bool,
+    /// Pad numbers with zeros after the sign
+    pub zero_padding : bool,
+    /// Prefix hexadecimal, binary and octal values with 0x, 0b or 0o
+    pub alternate : bool,
+    pub width : Option<usize>,
+    pub precision : Option<usize>,
+    pub kind : FormatKind,
+}
+
+impl FormatSpec {
+    fn new() -> FormatSpec {
+        FormatSpec {
+            fill : ' ',
+            alignment : None,
+            sign : false,
+            zero_padding : false,
+            alternate : false,
+            width : None,
+            precision : None,
+            kind : FormatKind::Default,
+        }
+    }
+
+    /// Parses a specifier in the form [[preenchimento]alinhamento][+][#][0][largura][.precisão][tipo],
+    /// where the alignment is one of <, ^ or > and the type one of v, d, f, e, s, x, X, b or o
+    pub fn parse(src : &str) -> Result<FormatSpec, String> {
+        let chars = src.chars().collect::<Vec<char>>();
+        let mut spec = FormatSpec::new();
+        let mut offset = 0usize;
+
+        if chars.len() > 1 {
+            if let Some(a) = get_alignment(chars[1]) {
+                spec.fill = chars[0];
+                spec.alignment = Some(a);
+                offset = 2;
+            }
+        }
+
+        if spec.alignment.is_none() && !chars.is_empty() {
+            if let Some(a) = get_alignment(chars[0]) {
+                spec.alignment = Some(a);
+                offset = 1;
+            }
+        }
+
+        if offset < chars.len() && chars[offset] == '+' {
+            spec.sign = true;
+            offset += 1;
+        }
+
+        if offset < chars.len() && chars[offset] == '#' {
+            spec.alternate = true;
+            offset += 1;
+        }
+
+        if offset < chars.len() && chars[offset] == '0' {
+            spec.zero_padding = true;
+            offset += 1;
+        }
+
+        spec.width = read_size(src, &chars, &mut offset, "largura")?;
+
+        if offset < chars.len() && chars[offset] == '.' {
+            offset += 1;
+
+            spec.precision = match read_size(src, &chars, &mut offset, "precisão")? {
+                Some(p) => Some(p),
+                None => return Err(format!("Formato inválido \"{}\" : Esperado a precisão depois do '.'", src)),
+            };
+        }
+
+        if offset < chars.len() {
+            if let Some(kind) = FormatKind::from_char(chars[offset]) {
+                spec.kind = kind;
+                offset += 1;
+            }
+        }
+
+        if offset < chars.len() {
+            return Err(format!("Formato inválido \"{}\" : Caractere inesperado '{}'", src, chars[offset]));
+        }
+
+        Ok(spec)
+    }
+
+    /// Most bytes the width and the precision can add to a value, so the VM can check there's room before formatting
+    pub fn reserved_bytes(&self) -> usize {
+        let width = self.width.unwrap_or(0).saturating_mul(self.fill.len_utf8());
+
+        width.saturating_add(self.precision.unwrap_or(0))
+    }
+
+    pub fn format_integer(&self, value : IntegerType) -> Result<String, String> {
+        let (negative, digits) = match self.kind {
+            FormatKind::Fixed | FormatKind::Exponent => return self.format_number(value as f64),
+            FormatKind::Default | FormatKind::Decimal if self.precision.is_some() => return self.format_number(value as f64),
+            FormatKind::Text => return self.format_text(value.to_string().as_str()),
+            // Like in C, negative values are shown in two's complement
+            FormatKind::Hexadecimal => (false, self.with_prefix("0x", format!("{:x}", value))),
+            FormatKind::HexadecimalUpper => (false, self.with_prefix("0x", format!("{:X}", value))),
+            FormatKind::Binary => (false, self.with_prefix("0b", format!("{:b}", value))),
+            FormatKind::Octal => (false, self.with_prefix("0o", format!("{:o}", value))),
+            FormatKind::Default | FormatKind::Decimal => (value < 0, value.unsigned_abs().to_string()),
+        };
+
+        Ok(self.pad_numeric(negative, digits))
+    }
+
+    pub fn format_big_integer(&self, value : &BigInteger) -> Result<String, String> {
+        let digits = match self.kind {
+            FormatKind::Fixed | FormatKind::Exponent => return self.format_number(value.to_f64()),
+            FormatKind::Default | FormatKind::Decimal if self.precision.is_some() => return self.format_number(value.to_f64()),
+            FormatKind::Text => return self.format_text(value.to_string().as_str()),
+            // There's no two's complement for big integers, so the sign is kept
+            FormatKind::Hexadecimal => self.with_prefix("0x", value.magnitude_to_string(16, false)),
+            FormatKind::HexadecimalUpper => self.with_prefix("0x", value.magnitude_to_string(16, true)),
+            FormatKind::Binary => self.with_prefix("0b", value.magnitude_to_string(2, false)),
+            FormatKind::Octal => self.with_prefix("0o", value.magnitude_to_string(8, false)),
+            FormatKind::Default | FormatKind::Decimal => value.magnitude_to_string(10, false),
+        };
+
+        Ok(self.pad_numeric(value.is_negative(), digits))
+    }
+
+    pub fn format_number(&self, value : f64) -> Result<String, String> {
+        if self.kind.is_integer_only() {
+            return Err(format!("O formato {:?} só pode ser usado com Inteiros, mas foi passado o Número {}", self.kind, value));
+        }
+
+        let digits = match (self.kind, self.precision) {
+            (FormatKind::Text, _) => return self.format_text(value.to_string().as_str()),
+            (FormatKind::Exponent, Some(p)) => format!("{:.*e}", p, value.abs()),
+            (FormatKind::Exponent, None) => format!("{:e}", value.abs()),
+            (FormatKind::Fixed, None) => format!("{:.6}", value.abs()),
+            (_, Some(p)) => format!("{:.*}", p, value.abs()),
+            (_, None) => format!("{}", value.abs()),
+        };
+
+        Ok(self.pad_numeric(value.is_sign_negative() && value != 0.0, digits))
+    }
+
+    pub fn format_text(&self, value : &str) -> Result<String, String> {
+        if self.kind.is_numeric() {
+            return Err(format!("O formato {:?} só pode ser usado com números, mas foi passado o texto \"{}\"", self.kind, value));
+        }
+
+        let text = match self.precision {
+            Some(p) => value.chars().take(p).collect::<String>(),
+            None => value.to_owned(),
+        };
+
+        Ok(self.pad(text, Alignment::Left))
+    }
+
+    fn with_prefix(&self, prefix : &str, digits : String) -> String {
+        if self.alternate {
+            format!("{}{}", prefix, digits)
+        } else {
+            digits
+        }
+    }
+
+    fn pad_numeric(&self, negative : bool, digits : String) -> String {
+        let sign = if negative {
+            "-"
+        } else if self.sign {
+            "+"
+        } else {
+            ""
+        };
+
+        if self.zero_padding {
+            let width = self.width.unwrap_or(0);
+            let len = sign.chars().count() + digits.chars().count();
+
+            // Zeros go after the sign and the 0x prefix, if any
+            let prefix_len = if self.alternate && digits.starts_with('0') && digits.len() > 1 {
+                match digits.as_bytes()[1] {
+                    b'x' | b'b' | b'o' => 2,
+                    _ => 0,
+                }
+            } else {
+                0
+            };
+
+            let mut result = String::from(sign);
+            result.push_str(&digits[..prefix_len]);
+
+            for _ in len..width {
+                result.push('0');
+            }
+
+            result.push_str(&digits[prefix_len..]);
+
+            result
+        } else {
+            self.pad(format!("{}{}", sign, digits), Alignment::Right)
+        }
+    }
+
+    fn pad(&self, text : String, default_alignment : Alignment) -> String {
+        let width = match self.width {
+            Some(w) => w,
+            None => return text,
+        };
+
+        let len = text.chars().count();
+
+        if len >= width {
+            return text;
+        }
+
+        let missing = width - len;
+
+        let (before, after) = match self.alignment.unwrap_or(default_alignment) {
+            Alignment::Left => (0, missing),
+            Alignment::Right => (missing, 0),
+            Alignment::Center => (missing / 2, missing - missing / 2),
+        };
+
+        let mut result = String::with_capacity(text.len() + missing);
+
+        for _ in 0..before {
+            result.push(self.fill);
+        }
+
+        result.push_str(text.as_str());
+
+        for _ in 0..after {
+            result.push(self.fill);
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePiece {
+    Literal(String),
+    /// Index of the argument and how to format it
+    Argument(usize, FormatSpec),
+}
+
+/// A printf-style template, like "%s tem %5.2f%%"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub pieces : Vec<TemplatePiece>,
+    pub num_arguments : usize,
+}
+
+impl Template {
+    /// Parses a template where each placeholder follows the form %[posição$][flags][largura][.precisão]tipo.
+    /// The position starts at 1 and flags are any of -, +, 0 and #. Use %% for a literal %
+    pub fn parse(src : &str) -> Result<Template, String> {
+        let chars = src.chars().collect::<Vec<char>>();
+        let mut pieces = vec![];
+        let mut literal = String::new();
+        let mut next_argument = 0usize;
+        let mut num_arguments = 0usize;
+        let mut offset = 0usize;
+
+        while offset < chars.len() {
+            let cur = chars[offset];
+            offset += 1;
+
+            if cur != '%' {
+                literal.push(cur);
+                continue;
+            }
+
+            if offset < chars.len() && chars[offset] == '%' {
+                literal.push('%');
+                offset += 1;
+                continue;
+            }
+
+            if !literal.is_empty() {
+                pieces.push(TemplatePiece::Literal(literal));
+                literal = String::new();
+            }
+
+            let (index, spec) = Template::parse_placeholder(src, &chars, &mut offset)?;
+
+            let index = match index {
+                Some(i) => i,
+                None => {
+                    next_argument += 1;
+                    next_argument - 1
+                }
+            };
+
+            if index + 1 > num_arguments {
+                num_arguments = index + 1;
+            }
+
+            pieces.push(TemplatePiece::Argument(index, spec));
+        }
+
+        if !literal.is_empty() {
+            pieces.push(TemplatePiece::Literal(literal));
+        }
+
+        Ok(Template { pieces, num_arguments })
+    }
+
+    fn parse_placeholder(src : &str, chars : &[char], offset : &mut usize) -> Result<(Option<usize>, FormatSpec), String> {
+        let mut spec = FormatSpec::new();
+        let mut index = None;
+
+        // Either a position or the width. If a $ follows, it's a position
+        let start = *offset;
+
+        if let Some(n) = read_number(chars, offset)? {
+            if *offset < chars.len() && chars[*offset] == '$' {
+                if n == 0 {
+                    return Err(format!("Formato inválido \"{}\" : As posições começam em 1", src));
+                }
+
+                index = Some(n - 1);
+                *offset += 1;
+            } else {
+                *offset = start;
+            }
+        }
+
+        // Like in C, everything is aligned to the right unless - is used
+        spec.alignment = Some(Alignment::Right);
+
+        while *offset < chars.len() {
+            match chars[*offset] {
+                '-' => spec.alignment = Some(Alignment::Left),
+                '+' => spec.sign = true,
+                '0' => spec.zero_padding = true,
+                '#' => spec.alternate = true,
+                _ => break,
+            }
+
+            *offset += 1;
+        }
+
+        spec.width = read_size(src, chars, offset, "largura")?;
+
+        if *offset < chars.len() && chars[*offset] == '.' {
+            *offset += 1;
+
+            spec.precision = match read_size(src, chars, offset, "precisão")? {
+                Some(p) => Some(p),
+                None => return Err(format!("Formato inválido \"{}\" : Esperado a precisão depois do '.'", src)),
+            };
+        }
+
+        if *offset >= chars.len() {
+            return Err(format!("Formato inválido \"{}\" : O último % não tem um tipo", src));
+        }
+
+        spec.kind = match FormatKind::from_char(chars[*offset]) {
+            Some(k) => k,
+            None => return Err(format!("Formato inválido \"{}\" : Tipo desconhecido '{}'", src, chars[*offset])),
+        };
+
+        *offset += 1;
+
+        Ok((index, spec))
+    }
+}
+
+fn get_alignment(c : char) -> Option<Alignment> {
+    match c {
+        '<' => Some(Alignment::Left),
+        '^' => Some(Alignment::Center),
+        '>' => Some(Alignment::Right),
+        _ => None,
+    }
+}
+
+fn read_number(chars : &[char], offset : &mut usize) -> Result<Option<usize>, String> {
+    let start = *offset;
+
+    while *offset < chars.len() && chars[*offset].is_ascii_digit() {
+        *offset += 1;
+    }
+
+    if start == *offset {
+        return Ok(None);
+    }
+
+    let digits = chars[start..*offset].iter().collect::<String>();
+
+    match digits.parse::<usize>() {
+        Ok(n) => Ok(Some(n)),
+        Err(_) => Err(format!("Número muito grande no formato : {}", digits)),
+    }
+}
+
+// A width or precision, which can't be above MAX_FORMAT_SIZE
+fn read_size(src : &str, chars : &[char], offset : &mut usize, what : &str) -> Result<Option<usize>, String> {
+    let start = *offset;
+
+    match read_number(chars, offset) {
+        Ok(Some(n)) if n > MAX_FORMAT_SIZE => {}
+        Ok(n) => return Ok(n),
+        Err(_) => {}
+    }
+
+    let digits = chars[start..*offset].iter().collect::<String>();
+
+    Err(format!("Formato inválido \"{}\" : A {} {} passa do limite de {}", src, what, digits, MAX_FORMAT_SIZE))
+}
+
+mod tests {
+    #[test]
+    fn specs() {
+        use formatting::*;
+
+        let spec = FormatSpec::parse("*^+08.2").unwrap();
+
+        assert_eq!(spec.fill, '*');
+        assert_eq!(spec.alignment, Some(Alignment::Center));
+        assert!(spec.sign);
+        assert!(spec.zero_padding);
+        assert_eq!(spec.width, Some(8));
+        assert_eq!(spec.precision, Some(2));
+
+        assert_eq!(FormatSpec::parse("#010x").unwrap().kind, FormatKind::Hexadecimal);
+
+        assert!(FormatSpec::parse(".").is_err());
+        assert!(FormatSpec::parse("5q").is_err());
+
+        assert_eq!(FormatSpec::parse("4096.4096").unwrap().width, Some(MAX_FORMAT_SIZE));
+        assert!(FormatSpec::parse("4097").unwrap_err().starts_with("Formato inválido"));
+        assert!(FormatSpec::parse(".99999999999999999999999").unwrap_err().starts_with("Formato inválido"));
+    }
+
+    #[test]
+    fn values() {
+        use formatting::*;
+
+        let fmt = |spec : &str| FormatSpec::parse(spec).unwrap();
+
+        assert_eq!(fmt(".2").format_number(1.23456).unwrap(), "1.23");
+        assert_eq!(fmt("6").format_integer(-42).unwrap(), "   -42");
+        assert_eq!(fmt("06").format_integer(-42).unwrap(), "-00042");
+        assert_eq!(fmt("+").format_integer(7).unwrap(), "+7");
+        assert_eq!(fmt("<5").format_integer(7).unwrap(), "7    ");
+        assert_eq!(fmt("-^7").format_text("BIRL").unwrap(), "-BIRL--");
+        assert_eq!(fmt(".3").format_text("BAMBAM").unwrap(), "BAM");
+        assert_eq!(fmt("8.1").format_integer(2).unwrap(), "     2.0");
+        assert_eq!(fmt("x").format_integer(-1).unwrap(), "ffffffffffffffff");
+        assert_eq!(fmt("").format_integer(IntegerType::MIN).unwrap(), "-9223372036854775808");
+        assert_eq!(fmt("x").format_integer(255).unwrap(), "ff");
+        assert_eq!(fmt("#06X").format_integer(255).unwrap(), "0x00FF");
+        assert_eq!(fmt("#b").format_integer(5).unwrap(), "0b101");
+        assert_eq!(fmt("o").format_integer(8).unwrap(), "10");
+        assert_eq!(fmt("f").format_number(1.5).unwrap(), "1.500000");
+        assert_eq!(fmt(".2e").format_number(1234.5).unwrap(), "1.23e3");
+
+        assert!(fmt("x").format_number(1.5).is_err());
+        assert!(fmt("d").format_text("BIRL").is_err());
+    }
+
+    #[test]
+    fn templates() {
+        use formatting::*;
+
+        let template = Template::parse("%s tem %-5d|%2$05.1f%%").unwrap();
+
+        assert_eq!(template.num_arguments, 2);
+        assert_eq!(template.pieces.len(), 6);
+        assert_eq!(template.pieces[0], TemplatePiece::Argument(0, {
+            let mut spec = FormatSpec::parse(">").unwrap();
+            spec.kind = FormatKind::Text;
+            spec
+        }));
+        assert_eq!(template.pieces[5], TemplatePiece::Literal("%".to_owned()));
+
+        match template.pieces[4] {
+            TemplatePiece::Argument(1, ref spec) => {
+                assert!(spec.zero_padding);
+                assert_eq!(spec.width, Some(5));
+                assert_eq!(spec.precision, Some(1));
+                assert_eq!(spec.kind, FormatKind::Fixed);
+            }
+            ref p => panic!("Esperado o segundo argumento, encontrado {:?}", p),
+        }
+
+        assert!(Template::parse("100%").is_err());
+        assert!(Template::parse("%q").is_err());
+        assert!(Template::parse("%0$d").is_err());
+
+        assert!(Template::parse("%4096.4096f").is_ok());
+        assert!(Template::parse("%999999999999999999.1f").unwrap_err().starts_with("Formato inválido"));
+        assert!(Template::parse("%.4097s").unwrap_err().starts_with("Formato inválido"));
+    }
+}
@@ -2,5 +2,12 @@ pub mod parser;
 pub mod context;
 pub mod vm;
 pub mod compiler;
+pub mod optimizer;
+pub mod bytecode;
+pub mod disassembler;
 pub mod modules;
 pub mod standard_lib;
+pub mod formatting;
+pub mod bigint;
+pub mod capabilities;
+pub mod conversion;
\ No newline at end of file
@@ -1,6 +1,7 @@
 use context::RawValue;
-use vm::PluginFunction;
+use vm::{ VirtualMachine, DynamicValue, BoxedPlugin, PluginFunction };
 use parser::{ Command, TypeKind, FunctionParameter };
+use conversion::TypedPlugin;
 
 pub struct GlobalVariable {
     pub name : String,
@@ -18,20 +19,42 @@ impl GlobalVariable {
     }
 }
 
+/// Made with one of the constructors, since the function is kept boxed
 pub struct Plugin {
     pub name : String,
     pub parameters : Vec<TypeKind>,
-    pub func : PluginFunction,
+    func : BoxedPlugin,
 }
 
 impl Plugin {
-    pub fn new(name : String, parameters : Vec<TypeKind>, func : PluginFunction) -> Plugin {
+    /// The function can be a PluginFunction or a closure, like in Context::add_plugin
+    pub fn new<F>(name : String, parameters : Vec<TypeKind>, func : F) -> Plugin
+        where F : FnMut(Vec<DynamicValue>, &mut VirtualMachine) -> Result<Option<DynamicValue>, String> + 'static
+    {
         Plugin {
             name,
             parameters,
-            func
+            func : Box::new(func),
         }
     }
+
+    /// Same as Plugin::new with a PluginFunction, for code that used to build the Plugin with one in `func`
+    pub fn from_fn(name : String, parameters : Vec<TypeKind>, func : PluginFunction) -> Plugin {
+        Plugin::new(name, parameters, func)
+    }
+
+    /// Like Context::add_typed_plugin
+    pub fn typed<F, Args>(name : String, func : F) -> Plugin where F : TypedPlugin<Args> {
+        Plugin {
+            name,
+            parameters : F::parameters(),
+            func : func.into_plugin(),
+        }
+    }
+
+    pub fn into_parts(self) -> (String, Vec<TypeKind>, BoxedPlugin) {
+        (self.name, self.parameters, self.func)
+    }
 }
 
 pub struct SourceFunction {
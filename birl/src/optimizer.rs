@@ -0,0 +1,384 @@
+//! Optimization passes over the instructions of compiled functions. Every pass keeps the behavior of the code the same,
+//! including the errors it gives, so anything that may fail at runtime is left for the VM
+
+use vm::{ Code, Instruction };
+use context::RawValue;
+use parser::IntegerType;
+
+use std::collections::HashSet;
+
+/// The highest optimization level. Anything above it works the same
+pub const MAX_OPTIMIZATION_LEVEL : u8 = 2;
+
+/// Optimizes the code of a function. Level 0 leaves it as is, level 1 folds constant expressions and level 2 also
+/// shortens chains of jumps and removes instructions that can never run
+pub fn optimize(code : &mut Code, level : u8) {
+    if level >= 1 {
+        fold_constants(code);
+    }
+
+    if level >= 2 {
+        thread_jumps(code);
+
+        while remove_dead_code(code) {}
+    }
+}
+
+/// A value known while compiling
+#[derive(Clone, Debug, PartialEq)]
+enum Constant {
+    Integer(IntegerType),
+    Number(f64),
+    Text(String),
+}
+
+fn jump_target(inst : &Instruction) -> Option<usize> {
+    match *inst {
+        Instruction::Jump(address) | Instruction::JumpIfNot(_, address) => Some(address),
+        _ => None,
+    }
+}
+
+fn set_jump_target(inst : &mut Instruction, target : usize) {
+    match *inst {
+        Instruction::Jump(ref mut address) | Instruction::JumpIfNot(_, ref mut address) => *address = target,
+        _ => {}
+    }
+}
+
+fn jump_targets(instructions : &[Instruction]) -> HashSet<usize> {
+    instructions.iter().filter_map(jump_target).collect()
+}
+
+fn as_constant(code : &Code, inst : &Instruction) -> Option<Constant> {
+    match *inst {
+        Instruction::PushValue(RawValue::Integer(i)) => Some(Constant::Integer(i)),
+        Instruction::PushValue(RawValue::Number(n)) => Some(Constant::Number(n)),
+        Instruction::PushText(index) => code.texts.get(index).map(|t| Constant::Text(t.clone())),
+        _ => None,
+    }
+}
+
+fn push_constant(code : &mut Code, value : Constant) -> Instruction {
+    match value {
+        Constant::Integer(i) => Instruction::PushValue(RawValue::Integer(i)),
+        Constant::Number(n) => Instruction::PushValue(RawValue::Number(n)),
+        Constant::Text(t) => Instruction::PushText(code.add_text(t)),
+    }
+}
+
+// Mirrors the arithmetic in the VM for the cases that can't fail. Anything else is left for runtime
+fn fold_binary(inst : &Instruction, left : &Constant, right : &Constant) -> Option<Constant> {
+    use self::Constant::*;
+
+    match (left, right) {
+        (Integer(l), Integer(r)) => {
+            let (l, r) = (*l, *r);
+            let result = match *inst {
+                Instruction::Add => l.checked_add(r),
+                Instruction::Sub => l.checked_sub(r),
+                Instruction::Mul => l.checked_mul(r),
+                Instruction::Div if r != 0 => l.checked_div(r),
+                Instruction::BitwiseAnd => Some(l & r),
+                Instruction::BitwiseOr => Some(l | r),
+                Instruction::BitwiseXor => Some(l ^ r),
+                Instruction::ShiftLeft | Instruction::ShiftRight if r >= 0 && r < IntegerType::BITS as IntegerType => {
+                    if let Instruction::ShiftLeft = *inst { Some(l << r) } else { Some(l >> r) }
+                }
+                _ => None,
+            };
+
+            result.map(Integer)
+        }
+        (Integer(_), Number(_)) | (Number(_), Integer(_)) | (Number(_), Number(_)) => {
+            let as_number = |c : &Constant| match *c {
+                Integer(i) => i as f64,
+                Number(n) => n,
+                Text(_) => unreachable!(),
+            };
+
+            let (l, r) = (as_number(left), as_number(right));
+
+            match *inst {
+                Instruction::Add => Some(Number(l + r)),
+                Instruction::Sub => Some(Number(l - r)),
+                Instruction::Mul => Some(Number(l * r)),
+                Instruction::Div => Some(Number(l / r)),
+                _ => None,
+            }
+        }
+        (Text(l), Text(r)) => match *inst {
+            Instruction::Add => Some(Text(format!("{}{}", l, r))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn fold_unary(inst : &Instruction, value : &Constant) -> Option<Constant> {
+    match *value {
+        Constant::Integer(i) if matches!(*inst, Instruction::BitwiseNot) => Some(Constant::Integer(!i)),
+        _ => None,
+    }
+}
+
+/// Replaces the instructions of the code, keeping jumps pointed at the same instructions. Each instruction comes with
+/// the address it had before, and a jump to a removed address goes to the next instruction that was kept
+fn rebuild(code : &mut Code, kept : Vec<(usize, Instruction)>) {
+    let old_len = code.instructions.len();
+    let mut new_address = vec![kept.len(); old_len + 1];
+    let mut next = kept.len();
+
+    for address in (0..old_len).rev() {
+        while next > 0 && kept[next - 1].0 >= address {
+            next -= 1;
+        }
+
+        new_address[address] = next;
+    }
+
+    code.debug.relocate(&new_address);
+
+    code.instructions = kept.into_iter()
+        .map(|(_, mut inst)| {
+            if let Some(target) = jump_target(&inst) {
+                set_jump_target(&mut inst, new_address[target.min(old_len)]);
+            }
+
+            inst
+        })
+        .collect();
+}
+
+/// Evaluates operations whose operands are all constants, pushing the result instead. Operations that would give an
+/// error, like an overflow or a division by zero, are kept so the error still happens when they run
+pub fn fold_constants(code : &mut Code) {
+    let targets = jump_targets(&code.instructions);
+    let instructions = code.instructions.clone();
+    let mut kept : Vec<(usize, Instruction)> = Vec::with_capacity(instructions.len());
+
+    for (address, inst) in instructions.into_iter().enumerate() {
+        let len = kept.len();
+
+        // Only fold when nothing jumps into the middle of the operation
+        let folded = match inst {
+            _ if targets.contains(&address) => None,
+            Instruction::BitwiseNot if len >= 1 && !targets.contains(&kept[len - 1].0) => {
+                as_constant(code, &kept[len - 1].1)
+                    .and_then(|value| fold_unary(&inst, &value))
+                    .map(|result| (1, result))
+            }
+            Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::BitwiseAnd |
+            Instruction::BitwiseOr | Instruction::BitwiseXor | Instruction::ShiftLeft |
+            Instruction::ShiftRight if len >= 2 && !targets.contains(&kept[len - 1].0) => {
+                match (as_constant(code, &kept[len - 2].1), as_constant(code, &kept[len - 1].1)) {
+                    (Some(left), Some(right)) => fold_binary(&inst, &left, &right).map(|result| (2, result)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match folded {
+            Some((num_operands, result)) => {
+                kept.truncate(len - num_operands + 1);
+
+                let push = push_constant(code, result);
+                kept[len - num_operands].1 = push;
+            }
+            None => kept.push((address, inst)),
+        }
+    }
+
+    rebuild(code, kept);
+    remove_unused_texts(code);
+}
+
+/// Removes texts from the pool that no instruction uses anymore, like the operands of a folded concatenation, so they
+/// don't end up in bytecode and snapshots
+pub fn remove_unused_texts(code : &mut Code) {
+    let mut new_index = vec![None; code.texts.len()];
+
+    for inst in &code.instructions {
+        match *inst {
+            Instruction::PushText(index) | Instruction::AppendToInterpolation(index) if index < new_index.len() => {
+                new_index[index] = Some(0);
+            }
+            _ => {}
+        }
+    }
+
+    if new_index.iter().all(|i| i.is_some()) {
+        return;
+    }
+
+    let texts = ::std::mem::take(&mut code.texts);
+
+    for (index, text) in texts.into_iter().enumerate() {
+        if new_index[index].is_some() {
+            new_index[index] = Some(code.texts.len());
+            code.texts.push(text);
+        }
+    }
+
+    for inst in &mut code.instructions {
+        match *inst {
+            Instruction::PushText(ref mut index) | Instruction::AppendToInterpolation(ref mut index) => {
+                if let Some(Some(new)) = new_index.get(*index) {
+                    *index = *new;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Jumps to another unconditional jump go straight to where the last one goes
+pub fn thread_jumps(code : &mut Code) {
+    let len = code.instructions.len();
+
+    for address in 0..len {
+        let mut target = match jump_target(&code.instructions[address]) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        // Limited so a loop made only of jumps doesn't hang the compiler
+        for _ in 0..len {
+            match code.instructions.get(target) {
+                Some(&Instruction::Jump(next)) if next != target => target = next,
+                _ => break,
+            }
+        }
+
+        set_jump_target(&mut code.instructions[address], target);
+    }
+}
+
+/// Removes instructions after a Return, Quit, TailCall or unconditional jump that nothing jumps to, and jumps to the
+/// instruction right after them. Returns whether something was removed
+pub fn remove_dead_code(code : &mut Code) -> bool {
+    let targets = jump_targets(&code.instructions);
+    let instructions = code.instructions.clone();
+    let len = instructions.len();
+    let mut kept = Vec::with_capacity(len);
+    let mut reachable = true;
+
+    for (address, inst) in instructions.into_iter().enumerate() {
+        if targets.contains(&address) {
+            reachable = true;
+        }
+
+        if !reachable {
+            continue;
+        }
+
+        match inst {
+            Instruction::Jump(target) if target == address + 1 => continue,
+            Instruction::Jump(_) | Instruction::Return | Instruction::Quit | Instruction::TailCall => reachable = false,
+            _ => {}
+        }
+
+        kept.push((address, inst));
+    }
+
+    if kept.len() == len {
+        return false;
+    }
+
+    rebuild(code, kept);
+
+    true
+}
+
+mod tests {
+    #![allow(dead_code, unused_imports)]
+
+    use optimizer::*;
+    use vm::ComparisionRequest;
+
+    fn code_from(instructions : Vec<Instruction>) -> Code {
+        let mut code = Code::new();
+        code.instructions = instructions;
+
+        code
+    }
+
+    fn int(i : IntegerType) -> Instruction {
+        Instruction::PushValue(RawValue::Integer(i))
+    }
+
+    #[test]
+    fn folding() {
+        // (2 * 60 * 60) ~ 0, then a print
+        let mut code = code_from(vec![int(2), int(60), Instruction::Mul, int(60), Instruction::Mul, int(0),
+                                      Instruction::BitwiseNot, Instruction::BitwiseXor, Instruction::PrintValue]);
+        fold_constants(&mut code);
+        assert_eq!(format!("{:?}", code.instructions), format!("{:?}", vec![int(!7200), Instruction::PrintValue]));
+
+        let mut code = code_from(vec![Instruction::PushText(0), Instruction::PushText(1), Instruction::Add]);
+        code.texts = vec!["BIRL".to_owned(), "!".to_owned()];
+        fold_constants(&mut code);
+        assert_eq!(code.texts, vec!["BIRL!"]);
+        assert_eq!(format!("{:?}", code.instructions), format!("{:?}", vec![Instruction::PushText(0)]));
+
+        // Only the texts that are still used are kept, and the instructions point to where they went
+        let mut code = code_from(vec![Instruction::PushText(0), Instruction::PushText(1), Instruction::Add,
+                                      Instruction::PrintValue, Instruction::PushText(2), Instruction::PushText(1),
+                                      Instruction::AppendToInterpolation(3)]);
+        code.texts = vec!["BIRL".to_owned(), "!".to_owned(), "X".to_owned(), "Y".to_owned()];
+        fold_constants(&mut code);
+        assert_eq!(code.texts, vec!["!", "X", "Y", "BIRL!"]);
+        assert_eq!(format!("{:?}", code.instructions), format!("{:?}", vec![
+            Instruction::PushText(3), Instruction::PrintValue, Instruction::PushText(1), Instruction::PushText(0),
+            Instruction::AppendToInterpolation(2)
+        ]));
+
+        // Errors are left for runtime
+        let failing = vec![
+            vec![int(IntegerType::MAX), int(1), Instruction::Add],
+            vec![int(1), int(0), Instruction::Div],
+            vec![int(1), int(64), Instruction::ShiftLeft],
+            vec![Instruction::PushValue(RawValue::Number(1.5)), int(1), Instruction::BitwiseAnd],
+            vec![Instruction::PushText(0), int(1), Instruction::Sub],
+        ];
+
+        for instructions in failing {
+            let mut code = code_from(instructions.clone());
+            code.texts = vec!["BIRL".to_owned()];
+            fold_constants(&mut code);
+            assert_eq!(format!("{:?}", code.instructions), format!("{:?}", instructions));
+        }
+
+        // Something jumps between the operands, so the operation doesn't always use both constants
+        let jumping = vec![int(1), Instruction::JumpIfNot(ComparisionRequest::Equal, 3), int(2), int(3), Instruction::Add];
+        let mut code = code_from(jumping.clone());
+        fold_constants(&mut code);
+        assert_eq!(format!("{:?}", code.instructions), format!("{:?}", jumping));
+    }
+
+    #[test]
+    fn jumps_and_dead_code() {
+        let mut code = code_from(vec![
+            /* 0 */ Instruction::JumpIfNot(ComparisionRequest::Equal, 2),
+            /* 1 */ Instruction::Jump(5),
+            /* 2 */ int(1), int(2), Instruction::Add,
+            /* 5 */ Instruction::PrintValue,
+            /* 6 */ Instruction::Jump(7),
+            /* 7 */ Instruction::Return,
+            /* 8 */ Instruction::PrintNewLine,
+            /* 9 */ Instruction::Jump(1),
+        ]);
+
+        optimize(&mut code, MAX_OPTIMIZATION_LEVEL);
+
+        // Jumps to the start of the folded expression land on its result
+        assert_eq!(format!("{:?}", code.instructions), format!("{:?}", vec![
+            Instruction::JumpIfNot(ComparisionRequest::Equal, 2),
+            Instruction::Jump(3),
+            int(3),
+            Instruction::PrintValue,
+            Instruction::Return,
+        ]));
+    }
+}
@@ -1,20 +1,21 @@
 use context::RawValue;
+use formatting::FormatSpec;
 
-#[cfg(target_pointer_width = "64")]
+/// Integers are always 64 bits, no matter the platform, so programs behave the same everywhere
 pub type IntegerType = i64;
 
-#[cfg(target_pointer_width = "32")]
-pub type IntegerType = i32;
-
 const COMMENT_CHARACTER : char = '#';
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeyPhrase {
     FunctionStart,
+    CoroutineStart,
     FunctionEnd,
     PrintLn,
     Print,
     PrintDebug,
+    PrintFormatted,
+    FormatText,
     Quit,
     Return,
     Declare,
@@ -52,13 +53,18 @@ pub enum KeyPhrase {
     IndexList,
     BreakScope,
     SkipNextIteration,
+    Pause,
+    Yield,
+    ResumeCoroutine,
+    QueryCoroutineDone,
 }
 
 impl KeyPhrase {
     pub fn matches(src : &str) -> Option<KeyPhrase> {
         match src {
             "JAULA" => Some(KeyPhrase::FunctionStart),
-            "SAINDO DA JAULA" => Some(KeyPhrase::FunctionEnd),
+            "GAIOLA" => Some(KeyPhrase::CoroutineStart),
+            "SAINDO DA JAULA" | "SAINDO DA GAIOLA" => Some(KeyPhrase::FunctionEnd),
             "BIRL" => Some(KeyPhrase::Return),
             "NUM VAI DA NAO" |
             "NUM VAI DÁ NAO" |
@@ -68,6 +74,9 @@ impl KeyPhrase {
             "CÊ QUER VER" => Some(KeyPhrase::Print),
             "CE QUER VER ISSO" |
             "CÊ QUER VER ISSO" => Some(KeyPhrase::PrintLn),
+            "CE QUER VER FORMATADO" |
+            "CÊ QUER VER FORMATADO" => Some(KeyPhrase::PrintFormatted),
+            "FORMATA" => Some(KeyPhrase::FormatText),
             "VEM" => Some(KeyPhrase::Declare),
             "BORA" => Some(KeyPhrase::Set),
             "TRAPÉZIO DESCENDENTE" | "TRAPEZIO DESCENDENTE" => Some(KeyPhrase::TypeNum),
@@ -104,6 +113,10 @@ impl KeyPhrase {
             "ME DA ESSE" | "ME DÁ ESSE" => Some(KeyPhrase::IndexList),
             "PARA AQUI" => Some(KeyPhrase::BreakScope),
             "VAI PRO PROXIMO" | "VAI PRO PRÓXIMO" => Some(KeyPhrase::SkipNextIteration),
+            "PERA AI" | "PERA AÍ" => Some(KeyPhrase::Pause),
+            "SOLTA ESSE" => Some(KeyPhrase::Yield),
+            "ME DA O PROXIMO" | "ME DÁ O PRÓXIMO" | "ME DA O PRÓXIMO" | "ME DÁ O PROXIMO" => Some(KeyPhrase::ResumeCoroutine),
+            "JA ACABOU" | "JÁ ACABOU" => Some(KeyPhrase::QueryCoroutineDone),
             _ => None,
         }
     }
@@ -117,6 +130,12 @@ pub enum MathOperator {
     Multiplication,
     ParenthesisLeft,
     ParenthesisRight,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    BitwiseNot,
+    ShiftLeft,
+    ShiftRight,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -125,11 +144,19 @@ pub enum PunctuationKind {
     Comma,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum TextFragment {
+    Literal(String),
+    /// Source of the interpolated expression and an optional format
+    Placeholder(String, Option<FormatSpec>),
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Command(KeyPhrase),
     Symbol(String),
     Text(String),
+    InterpolatedText(Vec<TextFragment>),
     Number(f64),
     Integer(IntegerType),
     Operator(MathOperator),
@@ -147,6 +174,10 @@ fn get_op(c : char) -> Option<MathOperator> {
         '*' => Some(MathOperator::Multiplication),
         '(' => Some(MathOperator::ParenthesisLeft),
         ')' => Some(MathOperator::ParenthesisRight),
+        '&' => Some(MathOperator::BitwiseAnd),
+        '|' => Some(MathOperator::BitwiseOr),
+        '^' => Some(MathOperator::BitwiseXor),
+        '~' => Some(MathOperator::BitwiseNot),
         _ => None,
     }
 }
@@ -171,104 +202,441 @@ fn get_digit(c : char) -> Option<u8> {
     }
 }
 
+// Reads digits of the given radix, allowing single underscores between them. Returns the digits without the underscores
+fn digit_sequence(input : &[char], offset : &mut usize, radix : u32, literal : &mut String) -> Result<String, String> {
+    let mut digits = String::new();
+    let mut last_was_separator = false;
+
+    while *offset < input.len() {
+        let cur = input[*offset];
+
+        if cur == '_' {
+            if digits.is_empty() || last_was_separator {
+                return Err(format!("Literal de número inválido \"{}_\" : '_' só pode aparecer entre dígitos", literal));
+            }
+
+            last_was_separator = true;
+        } else if cur.is_digit(radix) {
+            digits.push(cur);
+            last_was_separator = false;
+        } else {
+            break;
+        }
+
+        literal.push(cur);
+        *offset += 1;
+    }
+
+    if last_was_separator {
+        return Err(format!("Literal de número inválido \"{}\" : '_' só pode aparecer entre dígitos", literal));
+    }
+
+    Ok(digits)
+}
+
+// Makes sure the literal isn't directly followed by something like a letter, as in "12abc"
+fn check_number_end(input : &[char], offset : usize, literal : &str) -> Result<(), String> {
+    if offset < input.len() {
+        let cur = input[offset];
+
+        if cur.is_alphanumeric() || cur == '_' || cur == '.' {
+            return Err(format!("Literal de número inválido \"{}{}\" : Caractere '{}' inesperado", literal, cur, cur));
+        }
+    }
+
+    Ok(())
+}
+
+fn radix_number_token(input : &[char], offset : &mut usize, radix : u32, prefix : &str) -> Result<Token, String> {
+    let name = if radix == 16 { "hexadecimal" } else { "binário" };
+    let mut literal = prefix.to_owned();
+
+    let digits = digit_sequence(input, offset, radix, &mut literal)?;
+
+    if let Some(c) = input.get(*offset) {
+        if c.is_alphanumeric() {
+            return Err(format!("Literal {} inválido \"{}{}\" : '{}' não é um dígito {}", name, literal, c, c, name));
+        }
+    }
+
+    if digits.is_empty() {
+        return Err(format!("Literal {} \"{}\" sem nenhum dígito", name, literal));
+    }
+
+    check_number_end(input, *offset, literal.as_str())?;
+
+    match IntegerType::from_str_radix(digits.as_str(), radix) {
+        Ok(i) => Ok(Token::Integer(i)),
+        Err(_) => Err(format!("Literal de inteiro \"{}\" muito grande. O máximo é {}", literal, IntegerType::MAX)),
+    }
+}
+
 fn number_token(input : &[char], offset : &mut usize, first : char) -> Result<Token, String> {
+    if first == '0' {
+        match input.get(*offset) {
+            Some('x') | Some('X') | Some('b') | Some('B') => {
+                let prefix = format!("0{}", input[*offset]);
+                let radix = if prefix == "0x" || prefix == "0X" { 16 } else { 2 };
 
+                *offset += 1;
+
+                return radix_number_token(input, offset, radix, prefix.as_str());
+            }
+            _ => {}
+        }
+    }
+
+    // Offset points to the character after the first one, so start from it
+    *offset -= 1;
+
+    let mut literal = String::new();
+    // What is given to the standard library, so the float is correctly rounded
+    let mut clean = String::new();
     let mut is_int = true;
-    let mut int_val = 0 as IntegerType;
-    let mut num_val = 0f64;
-    let mut digits_after_dot = 0;
 
-    if first == '.' {
+    clean.push_str(digit_sequence(input, offset, 10, &mut literal)?.as_str());
+
+    if input.get(*offset) == Some(&'.') {
         is_int = false;
-        digits_after_dot = 1;
+        literal.push('.');
+        clean.push('.');
+        *offset += 1;
+
+        if input.get(*offset) == Some(&'_') {
+            return Err(format!("Literal de número inválido \"{}_\" : '_' só pode aparecer entre dígitos", literal));
+        }
+
+        clean.push_str(digit_sequence(input, offset, 10, &mut literal)?.as_str());
+
+        if input.get(*offset) == Some(&'.') {
+            return Err(format!("Literal de número inválido \"{}.\" : Dois pontos aparecem no literal de número", literal));
+        }
+
+        if clean == "." {
+            return Err("Literal de número inválido \".\" : Nenhum dígito".to_owned());
+        }
+    }
+
+    if let Some(&e) = input.get(*offset) {
+        if e == 'e' || e == 'E' {
+            is_int = false;
+            literal.push(e);
+            clean.push('e');
+            *offset += 1;
+
+            if let Some(&sign) = input.get(*offset) {
+                if sign == '+' || sign == '-' {
+                    literal.push(sign);
+                    clean.push(sign);
+                    *offset += 1;
+                }
+            }
+
+            let exponent = digit_sequence(input, offset, 10, &mut literal)?;
+
+            if exponent.is_empty() {
+                return Err(format!("Literal de número inválido \"{}\" : O expoente não tem nenhum dígito", literal));
+            }
+
+            clean.push_str(exponent.as_str());
+        }
+    }
+
+    check_number_end(input, *offset, literal.as_str())?;
+
+    if is_int {
+        match clean.parse::<IntegerType>() {
+            Ok(i) => Ok(Token::Integer(i)),
+            Err(_) => Err(format!("Literal de inteiro \"{}\" muito grande. O máximo é {}", literal, IntegerType::MAX)),
+        }
     } else {
-        int_val = match get_digit(first) {
-            Some(d) => d as IntegerType,
-            None => return Err("Internal error : First char to number_token is not a digit or a dot".to_owned()),
-        };
+        match clean.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Token::Number(n)),
+            Ok(_) => Err(format!("Literal de número \"{}\" grande demais", literal)),
+            Err(_) => Err(format!("Literal de número inválido \"{}\"", literal)),
+        }
     }
+}
+
+fn placeholder_fragment(input : &[char], offset : &mut usize) -> Result<TextFragment, String> {
+    // The offset is right after the opening brace. Look for the closing one, ignoring anything
+    // inside text literals that may be part of the expression
+
+    let mut source = String::new();
+    let mut format_start = None;
+    let mut in_text = false;
+    let mut last_was_escape = false;
+    // Placeholders opened by a text inside this one, so their '}' isn't taken as a literal
+    let mut inner_placeholders = 0usize;
 
     loop {
         if *offset >= input.len() {
-            break;
+            return Err("Interpolação sem o '}' de fechamento no texto".to_owned());
         }
 
         let cur = input[*offset];
+        *offset += 1;
 
-        if cur == COMMENT_CHARACTER {
-            break;
-        }
-
-        if cur == '.' {
-            if !is_int {
-                return Err(String::from("Dois pontos aparecem no literal de número"));
-            } else {
-                is_int = false;
-                num_val = int_val as f64;
-                digits_after_dot = 1;
+        if in_text {
+            if last_was_escape {
+                last_was_escape = false;
+            } else if cur == '\\' {
+                last_was_escape = true;
+            } else if cur == '\"' {
+                in_text = false;
+            } else if cur == '}' && inner_placeholders > 0 {
+                inner_placeholders -= 1;
+            } else if (cur == '{' || cur == '}') && input.get(*offset) == Some(&cur) {
+                *offset += 1;
+                source.push(cur);
+            } else if cur == '{' {
+                inner_placeholders += 1;
+            } else if cur == '}' {
+                // The source is lexed again later, so a lone '}' in the text has to be doubled to stay literal
+                source.push(cur);
             }
         } else {
             match cur {
-                '0'..='9' => {
-                    let digit = get_digit(cur).unwrap();
-
-                    if is_int {
-                        int_val *= 10;
-                        int_val += digit as IntegerType;
-                    } else {
-                        let diff = 0.1f64.powi(digits_after_dot);
-                        num_val += diff * (digit as f64);
-                        digits_after_dot += 1;
-                    }
+                '}' => break,
+                '\"' => in_text = true,
+                ':' if format_start.is_none() => {
+                    format_start = Some(source.len());
+                    continue;
                 }
-                _ => break,
+                '{' => return Err("'{' inesperado dentro de uma interpolação".to_owned()),
+                _ => {}
             }
         }
 
+        source.push(cur);
+    }
+
+    let (expr, format) = match format_start {
+        Some(index) => {
+            let spec = FormatSpec::parse(&source[index..])?;
+            (source[..index].trim().to_owned(), Some(spec))
+        }
+        None => (source.trim().to_owned(), None),
+    };
+
+    if expr.is_empty() {
+        return Err("Interpolação vazia no texto".to_owned());
+    }
+
+    Ok(TextFragment::Placeholder(expr, format))
+}
+
+fn hex_escape(input : &[char], offset : &mut usize, num_digits : usize, escape : &str) -> Result<u32, String> {
+    let mut value = 0u32;
+
+    for i in 0..num_digits {
+        match input.get(*offset).and_then(|c| c.to_digit(16)) {
+            Some(d) => value = value * 16 + d,
+            None => return Err(format!("Escape \"\\{}\" inválido : Esperado {} dígitos hexadecimais, encontrado {}", escape, num_digits, i)),
+        }
+
         *offset += 1;
     }
 
-    if is_int {
-        Ok(Token::Integer(int_val))
-    } else {
-        Ok(Token::Number(num_val))
+    Ok(value)
+}
+
+// Reads what comes after the backslash
+fn escape_sequence(input : &[char], offset : &mut usize, escape : char) -> Result<char, String> {
+    match escape {
+        '\\' | '\"' | '\'' | '{' | '}' => Ok(escape),
+        't' => Ok('\t'),
+        'n' => Ok('\n'),
+        'r' => Ok('\r'),
+        '0' => Ok('\0'),
+        'x' => {
+            let value = hex_escape(input, offset, 2, "x")?;
+
+            if value > 0x7F {
+                return Err(format!("Escape \"\\x{:02X}\" inválido : \\x só aceita valores até 7F. Use \\u{{{:X}}} pra outros caracteres",
+                                   value, value));
+            }
+
+            Ok(value as u8 as char)
+        }
+        'u' => {
+            if input.get(*offset) != Some(&'{') {
+                return Err("Escape \"\\u\" inválido : Esperado um '{' com o código do caractere, como em \\u{1F4AA}".to_owned());
+            }
+
+            *offset += 1;
+
+            let mut code = String::new();
+
+            loop {
+                match input.get(*offset) {
+                    Some('}') => break,
+                    Some(&c) if c.is_ascii_hexdigit() && code.len() < 6 => code.push(c),
+                    Some(&c) if c.is_ascii_hexdigit() => return Err(format!("Escape \"\\u{{{}...\" inválido : Máximo de 6 dígitos", code)),
+                    Some(&c) => return Err(format!("Escape \"\\u{{{}{}\" inválido : '{}' não é um dígito hexadecimal", code, c, c)),
+                    None => return Err(format!("Escape \"\\u{{{}\" sem o '}}' de fechamento", code)),
+                }
+
+                *offset += 1;
+            }
+
+            *offset += 1;
+
+            if code.is_empty() {
+                return Err("Escape \"\\u{}\" inválido : Nenhum dígito".to_owned());
+            }
+
+            let value = u32::from_str_radix(code.as_str(), 16).unwrap();
+
+            match ::std::char::from_u32(value) {
+                Some(c) => Ok(c),
+                None => Err(format!("Escape \"\\u{{{}}}\" inválido : {:X} não é um caractere unicode válido", code, value)),
+            }
+        }
+        _ => Err(format!("Escape desconhecido \"\\{}\" no texto. Use \"\\\\\" pra uma barra literal", escape)),
     }
 }
 
-fn text_token(input : &[char], offset : &mut usize) -> Result<Token, String> {
+// Checks if there are triple quotes starting right before the offset
+fn is_triple_quote(input : &[char], offset : usize) -> bool {
+    input.get(offset) == Some(&'\"') && input.get(offset + 1) == Some(&'\"')
+}
+
+// Checks if the closing quotes are at the offset, which is right after the first quote. In multi line
+// texts, quotes before the last three are part of the text
+fn is_text_end(input : &[char], offset : usize, multi_line : bool) -> bool {
+    !multi_line || (is_triple_quote(input, offset) && input.get(offset + 2) != Some(&'\"'))
+}
+
+/// Reads a text literal. Raw texts have no escapes nor interpolation, and multi line texts are
+/// delimited by triple quotes and may contain line breaks
+fn text_token(input : &[char], offset : &mut usize, raw : bool, multi_line : bool) -> Result<Token, String> {
     let mut content = String::new();
+    let mut fragments = vec![];
 
-    let mut last_was_escape = false;
+    // A line break right after the opening quotes isn't part of the text
+    if multi_line && input.get(*offset) == Some(&'\n') {
+        *offset += 1;
+    }
 
     loop {
         if *offset >= input.len() {
-            break;
+            return Err(if multi_line {
+                "Texto de várias linhas sem o \"\"\" de fechamento".to_owned()
+            } else {
+                "Texto sem as aspas de fechamento".to_owned()
+            });
         }
 
         let cur = input[*offset];
         *offset += 1;
 
-        if last_was_escape {
-            match cur {
-                '\\' |
-                '\"' => content.push(cur),
-                't' => content.push('\t'),
-                'n' => content.push('\n'),
-                'r' => content.push('\r'),
-                _ => {} // warn?
+        if cur == '\"' && is_text_end(input, *offset, multi_line) {
+            if multi_line {
+                *offset += 2;
             }
 
-            last_was_escape = false;
-        } else {
-            match cur {
-                '\"' => break,
-                '\\' => last_was_escape = true,
-                _ => content.push(cur),
+            break;
+        }
+
+        if cur == '\n' && !multi_line {
+            return Err("Texto sem as aspas de fechamento. Use \"\"\" pra textos de várias linhas".to_owned());
+        }
+
+        if raw {
+            content.push(cur);
+            continue;
+        }
+
+        match cur {
+            '\\' => {
+                let escape = match input.get(*offset) {
+                    Some(&c) => c,
+                    None => return Err("Texto termina com uma '\\' sem escape".to_owned()),
+                };
+
+                *offset += 1;
+
+                content.push(escape_sequence(input, offset, escape)?);
+            }
+            '{' if *offset < input.len() && input[*offset] == '{' => {
+                *offset += 1;
+                content.push('{');
+            }
+            '}' if *offset < input.len() && input[*offset] == '}' => {
+                *offset += 1;
+                content.push('}');
+            }
+            '{' => {
+                if !content.is_empty() {
+                    fragments.push(TextFragment::Literal(content));
+                    content = String::new();
+                }
+
+                fragments.push(placeholder_fragment(input, offset)?);
+            }
+            '}' => return Err("'}' sem um '{' correspondente no texto. Use }} pra um '}' literal".to_owned()),
+            _ => content.push(cur),
+        }
+    }
+
+    if fragments.is_empty() {
+        return Ok(Token::Text(content));
+    }
+
+    if !content.is_empty() {
+        fragments.push(TextFragment::Literal(content));
+    }
+
+    Ok(Token::InterpolatedText(fragments))
+}
+
+/// Checks if the line ends inside a multi line text literal, meaning the next lines are part of it
+pub fn has_open_text_literal(src : &str) -> bool {
+    let chars = src.chars().collect::<Vec<char>>();
+    let mut offset = 0usize;
+    let mut previous = ' ';
+
+    while offset < chars.len() {
+        let cur = chars[offset];
+        offset += 1;
+
+        if cur == COMMENT_CHARACTER {
+            return false;
+        }
+
+        if cur == '\"' {
+            let raw = (previous == 'r' || previous == 'R') && (offset < 3 || !chars[offset - 3].is_alphanumeric());
+            let multi_line = is_triple_quote(&chars, offset);
+
+            if multi_line {
+                offset += 2;
+            }
+
+            loop {
+                if offset >= chars.len() {
+                    return multi_line;
+                }
+
+                let c = chars[offset];
+                offset += 1;
+
+                if c == '\\' && !raw {
+                    offset += 1;
+                } else if c == '\"' && is_text_end(&chars, offset, multi_line) {
+                    if multi_line {
+                        offset += 2;
+                    }
+
+                    break;
+                }
             }
         }
+
+        previous = cur;
     }
 
-    Ok(Token::Text(content))
+    false
 }
 
 fn symbol_token(input : &[char], offset : &mut usize, first : char) -> Result<Token, String> {
@@ -348,6 +716,10 @@ pub fn next_token(input : &[char], offset : &mut usize) -> Result<Token, String>
     }
 
     loop {
+        if *offset >= input.len() {
+            return Ok(Token::None);
+        }
+
         if input[*offset] != ' ' && input[*offset] != '\t' {
             break;
         }
@@ -370,6 +742,16 @@ pub fn next_token(input : &[char], offset : &mut usize) -> Result<Token, String>
         return Ok(Token::Operator(op));
     }
 
+    if first_char == '<' || first_char == '>' {
+        if input.get(*offset) == Some(&first_char) {
+            *offset += 1;
+
+            return Ok(Token::Operator(if first_char == '<' { MathOperator::ShiftLeft } else { MathOperator::ShiftRight }));
+        }
+
+        return Err(format!("Operador inválido '{}'. Use '{}{}' pro deslocamento de bits", first_char, first_char, first_char));
+    }
+
     if let Some(p) = get_ponct(first_char) {
         return Ok(Token::Punctuation(p));
     }
@@ -383,7 +765,25 @@ pub fn next_token(input : &[char], offset : &mut usize) -> Result<Token, String>
     }
 
     if first_char == '\"' {
-        return text_token(input, offset);
+        let multi_line = is_triple_quote(input, *offset);
+
+        if multi_line {
+            *offset += 2;
+        }
+
+        return text_token(input, offset, false, multi_line);
+    }
+
+    if (first_char == 'r' || first_char == 'R') && input.get(*offset) == Some(&'\"') {
+        *offset += 1;
+
+        let multi_line = is_triple_quote(input, *offset);
+
+        if multi_line {
+            *offset += 2;
+        }
+
+        return text_token(input, offset, true, multi_line);
     }
 
     symbol_token(input, offset, first_char)
@@ -396,6 +796,8 @@ pub enum TypeKind {
     Text,
     List,
     Null,
+    /// Made by calling a GAIOLA
+    Coroutine,
 }
 
 impl TypeKind {
@@ -405,6 +807,7 @@ impl TypeKind {
             KeyPhrase::TypeNum => Some(TypeKind::Number),
             KeyPhrase::TypeStr => Some(TypeKind::Text),
             KeyPhrase::TypeList => Some(TypeKind::List),
+            KeyPhrase::CoroutineStart => Some(TypeKind::Coroutine),
             _ => None,
         }
     }
@@ -429,22 +832,32 @@ impl FunctionParameter {
 pub struct FunctionDeclaration {
     pub name : String,
     pub arguments : Vec<FunctionParameter>,
+    /// Declared with GAIOLA. Calling it makes a coroutine instead of running it
+    pub coroutine : bool,
 }
 
 impl FunctionDeclaration {
     pub fn from(name : String) -> FunctionDeclaration {
         FunctionDeclaration {
             name,
-            arguments: vec![]
+            arguments: vec![],
+            coroutine : false,
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum InterpolationPart {
+    Text(String),
+    Value(Expression, Option<FormatSpec>),
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ExpressionNode {
     Value(RawValue),
     Symbol(String),
     Operator(MathOperator),
+    Interpolation(Vec<InterpolationPart>),
 }
 
 #[derive(Debug, PartialEq)]
@@ -469,6 +882,8 @@ pub enum CommandKind {
     Print,
     PrintLn,
     PrintDebug,
+    PrintFormatted,
+    FormatText,
     Declare,
     Set,
     Compare,
@@ -500,6 +915,10 @@ pub enum CommandKind {
     IndexList,
     BreakScope,
     SkipNextIteration,
+    Pause,
+    Yield,
+    ResumeCoroutine,
+    QueryCoroutineDone,
 }
 
 impl CommandKind {
@@ -508,6 +927,8 @@ impl CommandKind {
             KeyPhrase::Print => Some(CommandKind::Print),
             KeyPhrase::PrintLn => Some(CommandKind::PrintLn),
             KeyPhrase::PrintDebug => Some(CommandKind::PrintDebug),
+            KeyPhrase::PrintFormatted => Some(CommandKind::PrintFormatted),
+            KeyPhrase::FormatText => Some(CommandKind::FormatText),
             KeyPhrase::Return => Some(CommandKind::Return),
             KeyPhrase::Quit => Some(CommandKind::Quit),
             KeyPhrase::Declare => Some(CommandKind::Declare),
@@ -541,6 +962,10 @@ impl CommandKind {
             KeyPhrase::IndexList => Some(CommandKind::IndexList),
             KeyPhrase::BreakScope => Some(CommandKind::BreakScope),
             KeyPhrase::SkipNextIteration => Some(CommandKind::SkipNextIteration),
+            KeyPhrase::Pause => Some(CommandKind::Pause),
+            KeyPhrase::Yield => Some(CommandKind::Yield),
+            KeyPhrase::ResumeCoroutine => Some(CommandKind::ResumeCoroutine),
+            KeyPhrase::QueryCoroutineDone => Some(CommandKind::QueryCoroutineDone),
             _ => None,
         }
     }
@@ -579,6 +1004,13 @@ impl CommandInfo {
                                                       vec![CommandArgumentKind::Expression]),
             CommandKind::PrintDebug => CommandInfo::from(1, 1,
                                                          vec![CommandArgumentKind::Expression]),
+            CommandKind::PrintFormatted => CommandInfo::from(1, -1,
+                                                             vec![CommandArgumentKind::Expression]),
+            CommandKind::FormatText => {
+                // First is the destination, second the template and the rest are the values
+                CommandInfo::from(2, -1, vec![CommandArgumentKind::Name,
+                                              CommandArgumentKind::Expression])
+            }
             CommandKind::Declare => {
                 CommandInfo::from(1, 2, vec![CommandArgumentKind::Name,
                                              CommandArgumentKind::Expression])
@@ -643,6 +1075,15 @@ impl CommandInfo {
                     CommandArgumentKind::Name])
             }
             CommandKind::BreakScope | CommandKind::SkipNextIteration => CommandInfo::from(0, 0, vec![]),
+            CommandKind::Pause => {
+                // First is where the value given back is stored, second (optional) is the value given to the host
+                CommandInfo::from(1, 2, vec![CommandArgumentKind::Name, CommandArgumentKind::Expression])
+            }
+            CommandKind::Yield => CommandInfo::from(0, 1, vec![CommandArgumentKind::Expression]),
+            CommandKind::ResumeCoroutine | CommandKind::QueryCoroutineDone => {
+                // First is the coroutine, second is where the result is stored
+                CommandInfo::from(2, 2, vec![CommandArgumentKind::Expression, CommandArgumentKind::Name])
+            }
         }
     }
 }
@@ -695,7 +1136,7 @@ fn parse_parameter(src : &[char], offset : &mut usize) -> Result<Option<Function
     Ok(Some(FunctionParameter::from(name, kind)))
 }
 
-fn parse_function(src : &[char], offset : &mut usize) -> Result<ParserResult, String> {
+fn parse_function(src : &[char], offset : &mut usize, coroutine : bool) -> Result<ParserResult, String> {
 
     // Next token is the function name
 
@@ -710,6 +1151,7 @@ fn parse_function(src : &[char], offset : &mut usize) -> Result<ParserResult, St
     };
 
     let mut func = FunctionDeclaration::from(name);
+    func.coroutine = coroutine;
 
     match next_token(src, offset) {
         Ok(t) => {
@@ -750,310 +1192,158 @@ fn parse_function(src : &[char], offset : &mut usize) -> Result<ParserResult, St
     Ok(ParserResult::FunctionStart(func))
 }
 
-fn parse_sub_expression(src : &[char], offset : &mut usize, expr : &mut Expression, root : bool) -> Result<(), String> {
-
-    let mut last_was_value;
+fn parse_interpolation(fragments : Vec<TextFragment>, expr : &mut Expression) -> Result<ExpressionNode, String> {
+    let mut parts = vec![];
 
-    let mut dummy_offset = *offset;
+    for fragment in fragments {
+        match fragment {
+            TextFragment::Literal(text) => parts.push(InterpolationPart::Text(text)),
+            TextFragment::Placeholder(source, format) => {
+                let chars = source.chars().collect::<Vec<char>>();
+                let mut offset = 0usize;
 
-    let first = match next_token(src, &mut dummy_offset) {
-        Ok(t) => t,
-        Err(e) => return Err(e),
-    };
+                let inner = parse_expression(&chars, &mut offset)?;
 
-    let mut nodes = vec![];
-
-    let mut values : Vec<ExpressionNode> = vec![];
-    let mut operations : Vec<MathOperator> = vec![];
-    let mut last_was_important = false;
-
-    match first {
-        Token::Comment | Token::None => return Ok(()),
-        Token::Integer(i) => {
-            last_was_value = true;
-
-            if last_was_important {
-                last_was_important = false;
-
-                match values.pop() {
-                    Some(v) => nodes.push(v),
-                    None => {}
+                match next_token(&chars, &mut offset)? {
+                    Token::None | Token::NewLine => {}
+                    t => return Err(format!("Esperado o fim da interpolação \"{}\", encontrado {:?}", source, t)),
                 }
 
-                let op = match operations.pop() {
-                    Some(v) => v,
-                    None => return Err("Operations tá vazio".to_owned()),
-                };
-
-                nodes.push(ExpressionNode::Value(RawValue::Integer(i)));
-                nodes.push(ExpressionNode::Operator(op));
-            } else {
-                values.push(ExpressionNode::Value(RawValue::Integer(i)));
-            }
-        }
-        Token::Number(n) => {
-            last_was_value = true;
-
-            if last_was_important {
-                last_was_important = false;
-
-                match values.pop() {
-                    Some(v) => nodes.push(v),
-                    None => {}
+                if inner.nodes.is_empty() {
+                    return Err("Interpolação vazia no texto".to_owned());
                 }
 
-                let op = match operations.pop() {
-                    Some(v) => v,
-                    None => return Err("Operations tá vazio".to_owned()),
-                };
-
-                nodes.push(ExpressionNode::Value(RawValue::Number(n)));
-                nodes.push(ExpressionNode::Operator(op));
-            } else {
-                values.push(ExpressionNode::Value(RawValue::Number(n)));
-            }
-        }
-        Token::Text(t) => {
-            last_was_value = true;
-
-            values.push(ExpressionNode::Value(RawValue::Text(t)));
-        }
-        Token::NewLine => return Ok(()),
-        Token::Symbol(s) => {
-            last_was_value = true;
-
-            if !expr.has_symbols {
-                expr.has_symbols = true;
-            }
-
-            if last_was_important {
-                last_was_important = false;
-
-                match values.pop() {
-                    Some(v) => nodes.push(v),
-                    None => {}
+                if inner.has_symbols {
+                    expr.has_symbols = true;
                 }
 
-                let op = match operations.pop() {
-                    Some(v) => v,
-                    None => return Err("Operations tá vazio".to_owned()),
-                };
-
-                nodes.push(ExpressionNode::Symbol(s));
-                nodes.push(ExpressionNode::Operator(op));
-            } else {
-                values.push(ExpressionNode::Symbol(s));
+                parts.push(InterpolationPart::Value(inner, format));
             }
         }
-        Token::Operator(MathOperator::ParenthesisLeft) => {
-            last_was_value = true;
-
-            match parse_sub_expression(src, &mut dummy_offset, expr, false) {
-                Ok(_) => {}
-                Err(e) => return Err(e)
-            };
-        }
-        Token::Operator(MathOperator::ParenthesisRight) => {
-            *offset = dummy_offset;
-
-            return Ok(());
-        },
-        Token::Operator(o) => {
-            match o {
-                MathOperator::Plus | MathOperator::Minus => {
-                    // Add a zero before this
-                    values.push(ExpressionNode::Value(RawValue::Integer(0)));
-                    operations.push(o);
-                },
-                _ => return Err(format!("Scope ou expressão começa com o operator unário inválido {:?}", o)),
-            }
-
-            last_was_value = false;
-        }
-        Token::Punctuation(p) => {
-            match p {
-                PunctuationKind::Comma if root => {
-                    // Ok
-
-                    return Ok(());
-                }
-                _ => return Err(format!("A expressão deveria começar com um valor ou operador unário, mas começa com {:?}", p)),
-            }
-        }
-        _ => return Err(format!("Esperado um valor ou operador na expressão, encontrado {:?}", first)),
     }
 
-    *offset = dummy_offset;
-
-    loop {
-        if *offset >= src.len() {
-            break;
-        }
-
-        let current = match next_token(src, &mut dummy_offset) {
-            Ok(t) => t,
-            Err(e) => return Err(e)
-        };
-
-        match current {
-            Token::None | Token::Comment => break,
-            Token::Integer(i) => {
-                if last_was_value {
-                    return Err("Dois valores seguidos na expressão".to_owned());
-                }
-
-                last_was_value = true;
+    Ok(ExpressionNode::Interpolation(parts))
+}
 
-                if last_was_important {
-                    last_was_important = false;
+// How tightly a binary operator binds. Operators with higher precedence are executed first
+fn binary_precedence(op : MathOperator) -> Option<u8> {
+    match op {
+        MathOperator::BitwiseOr => Some(1),
+        MathOperator::BitwiseXor => Some(2),
+        MathOperator::BitwiseAnd => Some(3),
+        MathOperator::ShiftLeft | MathOperator::ShiftRight => Some(4),
+        MathOperator::Plus | MathOperator::Minus => Some(5),
+        MathOperator::Multiplication | MathOperator::Division => Some(6),
+        _ => None,
+    }
+}
 
-                    match values.pop() {
-                        Some(v) => nodes.push(v),
-                        None => {}
-                    }
+// The lowest integer only fits with its minus, so when it comes right after one, both are read together. Anything
+// else is left for the lexer
+fn min_integer_literal(src : &[char], offset : &mut usize) -> bool {
+    let mut end = *offset;
+    let mut literal = String::new();
 
-                    let op = match operations.pop() {
-                        Some(v) => v,
-                        None => return Err("Operations tá vazio".to_owned()),
-                    };
+    let digits = match digit_sequence(src, &mut end, 10, &mut literal) {
+        Ok(d) => d,
+        Err(_) => return false,
+    };
 
-                    nodes.push(ExpressionNode::Value(RawValue::Integer(i)));
-                    nodes.push(ExpressionNode::Operator(op));
-                } else {
-                    values.push(ExpressionNode::Value(RawValue::Integer(i)));
-                }
-            }
-            Token::Number(n) => {
-                if last_was_value {
-                    return Err("Dois valores seguidos na expressão".to_owned());
-                }
+    if digits != IntegerType::MIN.unsigned_abs().to_string() || check_number_end(src, end, &literal).is_err() {
+        return false;
+    }
 
-                last_was_value = true;
+    *offset = end;
 
-                if last_was_important {
-                    last_was_important = false;
+    true
+}
 
-                    match values.pop() {
-                        Some(v) => nodes.push(v),
-                        None => {}
-                    }
+// Parses a single value, a sub expression between parenthesis or an unary operator followed by its operand
+fn parse_operand(src : &[char], offset : &mut usize, expr : &mut Expression) -> Result<(), String> {
+    let token = next_token(src, offset)?;
 
-                    let op = match operations.pop() {
-                        Some(v) => v,
-                        None => return Err("Operations tá vazio".to_owned()),
-                    };
+    match token {
+        Token::Integer(i) => expr.nodes.push(ExpressionNode::Value(RawValue::Integer(i))),
+        Token::Number(n) => expr.nodes.push(ExpressionNode::Value(RawValue::Number(n))),
+        Token::Text(t) => expr.nodes.push(ExpressionNode::Value(RawValue::Text(t))),
+        Token::InterpolatedText(fragments) => {
+            let node = parse_interpolation(fragments, expr)?;
 
-                    nodes.push(ExpressionNode::Value(RawValue::Number(n)));
-                    nodes.push(ExpressionNode::Operator(op));
-                } else {
-                    values.push(ExpressionNode::Value(RawValue::Number(n)));
-                }
-            }
-            Token::Text(t) => {
-                if last_was_value {
-                    return Err("Dois valores seguidos na expressão".to_owned());
-                }
+            expr.nodes.push(node);
+        }
+        Token::Symbol(s) => {
+            expr.has_symbols = true;
 
-                last_was_value = true;
+            expr.nodes.push(ExpressionNode::Symbol(s));
+        }
+        Token::Operator(MathOperator::ParenthesisLeft) => {
+            parse_binary_expression(src, offset, expr, 0)?;
 
-                values.push(ExpressionNode::Value(RawValue::Text(t)));
+            match next_token(src, offset)? {
+                Token::Operator(MathOperator::ParenthesisRight) => {}
+                Token::None | Token::NewLine | Token::Comment => return Err("Parêntesis aberto sem o ')' correspondente na expressão".to_owned()),
+                t => return Err(format!("Esperado um operador ou ')' na expressão, encontrado {:?}", t)),
             }
-            Token::Symbol(s) => {
-                if last_was_value {
-                    return Err("Dois valores seguidos na expressão".to_owned());
+        }
+        Token::Operator(op) => {
+            match op {
+                MathOperator::Minus if min_integer_literal(src, offset) => {
+                    expr.nodes.push(ExpressionNode::Value(RawValue::Integer(IntegerType::MIN)));
                 }
+                MathOperator::Plus | MathOperator::Minus => {
+                    // Add a zero before this
+                    expr.nodes.push(ExpressionNode::Value(RawValue::Integer(0)));
 
-                last_was_value = true;
+                    parse_operand(src, offset, expr)?;
 
-                if !expr.has_symbols {
-                    expr.has_symbols = true;
+                    expr.nodes.push(ExpressionNode::Operator(op));
                 }
+                MathOperator::BitwiseNot => {
+                    parse_operand(src, offset, expr)?;
 
-                if last_was_important {
-                    last_was_important = false;
-
-                    match values.pop() {
-                        Some(v) => nodes.push(v),
-                        None => {}
-                    }
-
-                    let op = match operations.pop() {
-                        Some(v) => v,
-                        None => return Err("Operations tá vazio".to_owned()),
-                    };
-
-                    nodes.push(ExpressionNode::Symbol(s));
-                    nodes.push(ExpressionNode::Operator(op));
-                } else {
-                    values.push(ExpressionNode::Symbol(s));
+                    expr.nodes.push(ExpressionNode::Operator(op));
                 }
+                MathOperator::ParenthesisRight => return Err("Esperado um valor na expressão, encontrado ')'".to_owned()),
+                _ => return Err(format!("Expressão começa com o operador {:?}, que não é unário", op)),
             }
-            Token::Operator(MathOperator::ParenthesisLeft) => {
-                last_was_value = true;
+        }
+        Token::None | Token::NewLine | Token::Comment => return Err("Expressão termina com um operador".to_owned()),
+        Token::Punctuation(p) => return Err(format!("Esperado um valor na expressão, encontrado {:?}", p)),
+        _ => return Err(format!("Esperado um valor ou operador na expressão, encontrado {:?}", token)),
+    }
 
-                match parse_sub_expression(src, &mut dummy_offset, expr, false) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e)
-                };
-            }
-            Token::Operator(MathOperator::ParenthesisRight) => {
-                *offset = dummy_offset;
-
-                break
-            },
-            Token::Operator(o) => {
-                if !last_was_value {
-                    return Err("Dois operadores seguidos na expressão".to_owned());
-                }
+    Ok(())
+}
 
-                last_was_value = false;
+// Precedence climbing. Nodes are added in postfix order, e.g. 1 + 2 * 3 becomes 1 2 3 * +
+fn parse_binary_expression(src : &[char], offset : &mut usize, expr : &mut Expression, min_precedence : u8) -> Result<(), String> {
+    parse_operand(src, offset, expr)?;
 
-                last_was_important = match o {
-                    MathOperator::Plus | MathOperator::Minus => false,
-                    _ => true,
-                };
+    loop {
+        let mut peek_offset = *offset;
+
+        let op = match next_token(src, &mut peek_offset)? {
+            Token::Operator(MathOperator::ParenthesisRight) => break,
+            Token::Operator(MathOperator::BitwiseNot) => return Err("O operador '~' só pode aparecer antes de um valor".to_owned()),
+            Token::Operator(MathOperator::ParenthesisLeft) |
+            Token::Integer(_) | Token::Number(_) | Token::Text(_) |
+            Token::InterpolatedText(_) | Token::Symbol(_) => return Err("Dois valores seguidos na expressão".to_owned()),
+            Token::Operator(op) => op,
+            // Let the caller see the end of the expression or the comma
+            _ => break,
+        };
 
-                operations.push(o);
-            }
-            Token::Punctuation(p) => {
-                match p {
-                    PunctuationKind::Comma if root => {
-                        // Ok. Do not set offset to dummy_offset, since we want the lower calls and the parser to see the comma
+        let precedence = binary_precedence(op).unwrap();
 
-                        break;
-                    }
-                    _ => return Err(format!("Erro: {:?} no meio da expressão", p)),
-                }
-            }
-            Token::NewLine => break,
-            _ => return Err(format!("Esperado um valor ou operador na expressão, encontrado {:?}", current)),
+        if precedence < min_precedence {
+            break;
         }
 
-        *offset = dummy_offset;
-    }
-
-    if !last_was_value {
-        return Err("Expressão termina com um operador".to_owned());
-    }
-
-    for node in nodes {
-        expr.nodes.push(node);
-    }
+        *offset = peek_offset;
 
-    if values.len() == operations.len() {
-        // Ok
-    } else if operations.len() == values.len() - 1 {
-        let first = values.remove(0);
+        // All binary operators are left associative, so the right side only takes operators that bind tighter
+        parse_binary_expression(src, offset, expr, precedence + 1)?;
 
-        expr.nodes.push(first);
-    } else {
-        return Err(format!("Formatação inválida da expressão resultado. Lenv : {}. lenO: {}", values.len(), operations.len()));
-    }
-
-    for _ in 0..values.len() {
-        let val = values.remove(0);
-        let op = operations.remove(0);
-        expr.nodes.push(val);
         expr.nodes.push(ExpressionNode::Operator(op));
     }
 
@@ -1063,10 +1353,23 @@ fn parse_sub_expression(src : &[char], offset : &mut usize, expr : &mut Expressi
 fn parse_expression(src : &[char], offset : &mut usize) -> Result<Expression, String> {
     let mut expr = Expression::new();
 
-    match parse_sub_expression(src, offset, &mut expr, true) {
-        Ok(_) => Ok(expr),
-        Err(e) => Err(e),
+    let mut peek_offset = *offset;
+
+    match next_token(src, &mut peek_offset)? {
+        Token::None | Token::NewLine | Token::Comment => return Ok(expr),
+        Token::Punctuation(PunctuationKind::Comma) => return Ok(expr),
+        _ => {}
     }
+
+    parse_binary_expression(src, offset, &mut expr, 0)?;
+
+    let mut peek_offset = *offset;
+
+    if let Token::Operator(MathOperator::ParenthesisRight) = next_token(src, &mut peek_offset)? {
+        return Err("')' sem o '(' correspondente na expressão".to_owned());
+    }
+
+    Ok(expr)
 }
 
 fn parse_command(src : &[char], offset : &mut usize, kp : KeyPhrase) -> Result<ParserResult, String> {
@@ -1202,11 +1505,13 @@ pub fn parse_line(src : &str) -> Result<ParserResult, String> {
         Token::Command(kp) => {
             match kp {
                 KeyPhrase::FunctionEnd => Ok(ParserResult::FunctionEnd),
-                KeyPhrase::FunctionStart => parse_function(&chars, &mut offset),
+                KeyPhrase::FunctionStart => parse_function(&chars, &mut offset, false),
+                KeyPhrase::CoroutineStart => parse_function(&chars, &mut offset, true),
                 _ => parse_command(&chars, &mut offset, kp),
             }
         }
-        Token::Text(_) | Token::Number(_) | Token::Integer(_) | Token::Operator(MathOperator::ParenthesisLeft) => {
+        Token::Text(_) | Token::InterpolatedText(_) | Token::Number(_) | Token::Integer(_) |
+        Token::Operator(MathOperator::ParenthesisLeft) => {
             offset = 0;
             parse_command(&chars, &mut offset, KeyPhrase::PrintDebug)
         }
@@ -1295,6 +1600,46 @@ mod tests {
 
             assert_eq!(tok, expected);
         }
+
+        {
+            let src = format!("{}", IntegerType::MAX);
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            assert_eq!(next_token(&chars, &mut offset), Ok(Token::Integer(9_223_372_036_854_775_807)));
+        }
+
+        {
+            let src = format!("{}0", IntegerType::MAX);
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            assert!(next_token(&chars, &mut offset).is_err());
+        }
+
+        let lex = |src : &str| {
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            next_token(&chars, &mut offset)
+        };
+
+        assert_eq!(lex("0x1F"), Ok(Token::Integer(31)));
+        assert_eq!(lex("0XfF_ff"), Ok(Token::Integer(0xffff)));
+        assert_eq!(lex("0b1010"), Ok(Token::Integer(10)));
+        assert_eq!(lex("1_000_000"), Ok(Token::Integer(1000000)));
+        assert_eq!(lex("0.3"), Ok(Token::Number(0.3)));
+        assert_eq!(lex("0.1"), Ok(Token::Number(0.1)));
+        assert_eq!(lex(".5"), Ok(Token::Number(0.5)));
+        assert_eq!(lex("1e-9"), Ok(Token::Number(1e-9)));
+        assert_eq!(lex("2.5E+3"), Ok(Token::Number(2500.0)));
+        assert_eq!(lex("1_0.2_5"), Ok(Token::Number(10.25)));
+        assert_eq!(lex("0x7FFF_FFFF_FFFF_FFFF"), Ok(Token::Integer(IntegerType::MAX)));
+
+        for malformed in &["0x", "0b102", "0xG", "1__0", "1_", "1e", "1e+", "1.2.3", "12abc", "1_.5", "1._5",
+                           "0x8000_0000_0000_0000", "1e999"] {
+            assert!(lex(malformed).is_err(), "{} should not be valid", malformed);
+        }
     }
 
     #[test]
@@ -1315,6 +1660,120 @@ mod tests {
         let expected = Token::Text("test string".to_owned());
 
         assert_eq!(tok, expected);
+
+        let lex = |src : &str| {
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            next_token(&chars, &mut offset)
+        };
+
+        let text = |s : &str| Ok(Token::Text(s.to_owned()));
+
+        assert_eq!(lex(r#""\u{1F4AA}\x41\0\t""#), text("\u{1F4AA}A\0\t"));
+        assert_eq!(lex(r#"r"C:\pasta\{nome}""#), text(r"C:\pasta\{nome}"));
+        assert_eq!(lex("\"\"\"\nprimeira\n\"segunda\"\"\"\""), text("primeira\n\"segunda\""));
+        assert_eq!(lex("r\"\"\"a\\n\nb\"\"\""), text("a\\n\nb"));
+        assert_eq!(lex("\"\""), text(""));
+
+        for malformed in &[r#""\q""#, r#""\x80""#, r#""\x4""#, r#""\u{D800}""#, r#""\u{}""#, r#""\u41""#, "\"aberto", "\"a\nb\""] {
+            assert!(lex(malformed).is_err(), "{} should not be valid", malformed);
+        }
+
+        assert!(has_open_text_literal("CE QUER VER: \"\"\"começo"));
+        assert!(has_open_text_literal("CE QUER VER: r\"\"\"começo \\"));
+        assert!(!has_open_text_literal("CE QUER VER: \"\"\"tudo\"\"\", \"a\\\"b\""));
+        assert!(!has_open_text_literal("CE QUER VER: X # \"\"\""));
+    }
+
+    #[test]
+    fn interpolated_text_tokens() {
+        use parser::*;
+        use formatting::FormatSpec;
+
+        {
+            let src = "\"FATORIAL DE {NUMERO} É {TREZE:>5}{{}}\"";
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            let tok = match next_token(&chars, &mut offset) {
+                Ok(t) => t,
+                Err(e) => panic!("{}", e),
+            };
+
+            let expected = Token::InterpolatedText(vec![
+                TextFragment::Literal("FATORIAL DE ".to_owned()),
+                TextFragment::Placeholder("NUMERO".to_owned(), None),
+                TextFragment::Literal(" É ".to_owned()),
+                TextFragment::Placeholder("TREZE".to_owned(), Some(FormatSpec::parse(">5").unwrap())),
+                TextFragment::Literal("{}".to_owned()),
+            ]);
+
+            assert_eq!(tok, expected);
+        }
+
+        {
+            let src = "\"{\"}\" + X}\"";
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            let tok = match next_token(&chars, &mut offset) {
+                Ok(t) => t,
+                Err(e) => panic!("{}", e),
+            };
+
+            let expected = Token::InterpolatedText(vec![
+                TextFragment::Placeholder("\"}}\" + X".to_owned(), None),
+            ]);
+
+            assert_eq!(tok, expected);
+        }
+
+        for src in &["\"{}\"", "\"{X\"", "\"}\"", "\"{X:?}\"", "\"{1:99999999999}\""] {
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            assert!(next_token(&chars, &mut offset).is_err(), "{} deveria dar erro", src);
+        }
+
+        for src in &["CE QUER VER ISSO: \"{\"}\"}\"", "CE QUER VER ISSO: \"{\"}\" + X}\"", "CE QUER VER ISSO: \"{\"{X}}}\"}\""] {
+            if let Err(e) = parse_line(src) {
+                panic!("{} deu erro : {}", src, e);
+            }
+        }
+
+        match parse_line("CE QUER VER ISSO: \"{X + }\"") {
+            Err(_) => {}
+            Ok(res) => panic!("Era esperado um erro, recebido {:?}", res),
+        }
+    }
+
+    #[test]
+    fn expressions() {
+        use parser::*;
+
+        let parse = |src : &str| {
+            let chars = src.chars().collect::<Vec<char>>();
+            let mut offset = 0usize;
+
+            parse_expression(&chars, &mut offset).map(|e| e.nodes)
+        };
+
+        let int = |i| ExpressionNode::Value(RawValue::Integer(i));
+        let op = ExpressionNode::Operator;
+
+        assert_eq!(parse("10 - 2 - 3"), Ok(vec![int(10), int(2), op(MathOperator::Minus), int(3), op(MathOperator::Minus)]));
+        assert_eq!(parse("1 + 2 * 3"), Ok(vec![int(1), int(2), int(3), op(MathOperator::Multiplication), op(MathOperator::Plus)]));
+        assert_eq!(parse("1 | 2 ^ 3 & 4"), Ok(vec![int(1), int(2), int(3), int(4), op(MathOperator::BitwiseAnd),
+                                                   op(MathOperator::BitwiseXor), op(MathOperator::BitwiseOr)]));
+        assert_eq!(parse("1 << 2 + 3"), Ok(vec![int(1), int(2), int(3), op(MathOperator::Plus), op(MathOperator::ShiftLeft)]));
+        assert_eq!(parse("(1 >> 2) & ~3"), Ok(vec![int(1), int(2), op(MathOperator::ShiftRight), int(3),
+                                                   op(MathOperator::BitwiseNot), op(MathOperator::BitwiseAnd)]));
+        assert_eq!(parse("-2 * 3"), Ok(vec![int(0), int(2), op(MathOperator::Minus), int(3), op(MathOperator::Multiplication)]));
+
+        for malformed in &["1 +", "(1 + 2", "1 + 2)", "1 2", "1 ~ 2", "* 2", "1 < 2", "1 >>> 2"] {
+            assert!(parse(malformed).is_err(), "{} should not be valid", malformed);
+        }
     }
 
     #[test]
@@ -2,6 +2,7 @@ use std::env;
 
 use modules::*;
 use context::RawValue;
+use capabilities::{ Capabilities, Capability };
 
 mod plugins {
     use vm::PluginFunction;
@@ -13,13 +14,21 @@ mod plugins {
     }
 }
 
-pub fn module_standard_library() -> Module {
+/// The standard library, with only what the capabilities allow. CUMPADE is the user's name only if the environment
+/// can be read
+pub fn module_standard_library(capabilities : &Capabilities) -> Module {
     let mut module = Module::new("PADRÃO".to_owned());
 
+    let user = if capabilities.allows(Capability::Env) {
+        env::var("USER").ok()
+    } else {
+        None
+    };
+
     let vars = vec!
     [
         ("UM".to_owned(), RawValue::Integer(1)),
-        ("CUMPADE".to_owned(), RawValue::Text(env::var("USER").unwrap_or("CUMPADE".to_owned()))),
+        ("CUMPADE".to_owned(), RawValue::Text(user.unwrap_or("CUMPADE".to_owned()))),
         ("FRANGO".to_owned(), RawValue::Null),
     ];
 
@@ -1,12 +1,32 @@
-use parser::{ TypeKind, IntegerType };
+use parser::{ TypeKind, IntegerType, MathOperator };
 use context::RawValue;
+use formatting::{ FormatSpec, Template, TemplatePiece };
+use bigint::BigInteger;
+use capabilities::{ Capabilities, Capability };
 
 use std::io::{ Write, BufRead };
+use std::any::Any;
 use std::fmt::{ Display, self };
+use std::convert::TryFrom;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::Instant;
 
 const STACK_DEFAULT_SIZE : usize = 128;
+/// Biggest frame a function can have. Address 0 is used by the return value, so this is one more than
+/// the number of variables
+pub const MAX_FRAME_SIZE : usize = 65_536;
+/// Default limit for how many function calls can be active at the same time
+pub const DEFAULT_MAX_CALL_DEPTH : usize = 10_000;
+/// Bytes counted for each element of a list when limiting memory
+pub const LIST_ELEMENT_SIZE : usize = ::std::mem::size_of::<Box<DynamicValue>>() + ::std::mem::size_of::<DynamicValue>();
+// Reading the clock is slow compared to an instruction, so the deadline is only checked once every this many
+const DEADLINE_CHECK_INTERVAL : u32 = 1024;
 
 pub type PluginFunction = fn (arguments : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String>;
+/// A plugin that can keep its own state, like a closure holding something from the host. Plugin functions are boxed
+/// into one when they're added
+pub type BoxedPlugin = Box<dyn FnMut(Vec<DynamicValue>, &mut VirtualMachine) -> Result<Option<DynamicValue>, String>>;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Comparision {
@@ -41,16 +61,61 @@ pub enum DynamicValue {
     Number(f64),
     Text(u64),
     List(u64),
+    /// An integer that doesn't fit in IntegerType. Only created in big integer mode
+    BigInteger(u64),
+    /// Made by calling a GAIOLA
+    Coroutine(u64),
     Null,
 }
 
 #[derive(Debug)]
 pub enum SpecialItemData {
     Text(String),
-    List(Vec<Box<DynamicValue>>)
+    List(Vec<Box<DynamicValue>>),
+    BigInteger(BigInteger),
+    Coroutine(Coroutine),
+}
+
+/// A function that runs a bit at a time, stopping at each SOLTA ESSE. While it isn't running, it keeps the frames it
+/// was running, along with their special items and operands
+#[derive(Debug)]
+pub struct Coroutine {
+    frames : Vec<FunctionFrame>,
+    items : Vec<SpecialItem>,
+    // Operand base of the frames are relative to the first one, since the operand stack changes between runs
+    operands : Vec<DynamicValue>,
+    running : bool,
+    done : bool,
+}
+
+/// A copy of a value that doesn't depend on the special storage, so it can outlive the frame that owned it
+enum DetachedValue {
+    Plain(DynamicValue),
+    Text(String),
+    List(Vec<DetachedValue>),
+    BigInteger(BigInteger),
 }
 
 impl SpecialItemData {
+    /// Bytes counted for the item when limiting memory. Texts count their length, lists count LIST_ELEMENT_SIZE for
+    /// each element, without what the elements point to, and big integers count their digits
+    pub fn size_in_bytes(&self) -> usize {
+        match *self {
+            SpecialItemData::Text(ref t) => t.len(),
+            SpecialItemData::List(ref l) => l.len() * LIST_ELEMENT_SIZE,
+            SpecialItemData::BigInteger(ref b) => b.size_in_bytes(),
+            SpecialItemData::Coroutine(ref c) => c.items.iter().map(|i| i.data.size_in_bytes()).sum(),
+        }
+    }
+
+    /// Items counted for the item when limiting memory : itself and the ones kept by a coroutine
+    pub fn num_items(&self) -> usize {
+        match *self {
+            SpecialItemData::Coroutine(ref c) => 1 + c.items.iter().map(|i| i.data.num_items()).sum::<usize>(),
+            _ => 1,
+        }
+    }
+
     pub fn try_into_str(&self) -> Option<&str> {
         match self {
             &SpecialItemData::Text(ref s) => Some(s.as_str()),
@@ -86,10 +151,31 @@ pub struct SpecialItem {
     item_id : u64,
 }
 
+/// Limits on how much the special storage can hold. None is no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryLimits {
+    /// Bytes of every item together, as counted by SpecialItemData::size_in_bytes
+    pub max_bytes : Option<usize>,
+    pub max_items : Option<usize>,
+}
+
+/// How much the special storage holds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    pub bytes : usize,
+    pub items : usize,
+}
+
 #[derive(Debug)]
 pub struct SpecialStorage {
     items : Vec<SpecialItem>,
     next_item_id : u64,
+    // Constants are kept at the bottom, below the items of every frame, so they're never removed
+    num_constants : usize,
+    limits : MemoryLimits,
+    // Sum of the sizes and counts of every item
+    used_bytes : usize,
+    used_items : usize,
 }
 
 impl SpecialStorage {
@@ -97,10 +183,89 @@ impl SpecialStorage {
         SpecialStorage {
             items : vec![],
             next_item_id : 0,
+            num_constants : 0,
+            limits : MemoryLimits::default(),
+            used_bytes : 0,
+            used_items : 0,
         }
     }
 
-    fn add(&mut self, data : SpecialItemData) -> u64 {
+    pub fn get_usage(&self) -> MemoryUsage {
+        MemoryUsage { bytes : self.used_bytes, items : self.used_items }
+    }
+
+    pub fn get_limits(&self) -> MemoryLimits {
+        self.limits
+    }
+
+    /// Only applies to what is added from now on, so the storage may already be past the new limits
+    pub fn set_limits(&mut self, limits : MemoryLimits) {
+        self.limits = limits;
+    }
+
+    /// Checks if the given number of bytes and items can be added without passing the limits
+    pub fn check_room(&self, bytes : usize, items : usize) -> Result<(), String> {
+        if let Some(max) = self.limits.max_bytes {
+            if self.used_bytes.saturating_add(bytes) > max {
+                return Err(format!("Limite excedido : O programa passou do limite de memória de {} bytes", max));
+            }
+        }
+
+        if let Some(max) = self.limits.max_items {
+            if self.used_items.saturating_add(items) > max {
+                return Err(format!("Limite excedido : O programa passou do limite de {} textos, listas e inteiros \
+                                    grandes ao mesmo tempo", max));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Counts bytes added to an item that is already stored, like an element added to a list. The caller checks
+    // if there's room first
+    fn grow(&mut self, bytes : usize) {
+        self.used_bytes += bytes;
+    }
+
+    fn shrink(&mut self, bytes : usize) {
+        self.used_bytes = self.used_bytes.saturating_sub(bytes);
+    }
+
+    fn count(&mut self, data : &SpecialItemData) -> Result<(), String> {
+        let (size, num) = (data.size_in_bytes(), data.num_items());
+        self.check_room(size, num)?;
+        self.used_bytes += size;
+        self.used_items += num;
+
+        Ok(())
+    }
+
+    fn uncount(&mut self, data : &SpecialItemData) {
+        self.shrink(data.size_in_bytes());
+        self.used_items = self.used_items.saturating_sub(data.num_items());
+    }
+
+    // Adds an item that lives as long as the storage itself
+    fn add_constant(&mut self, data : SpecialItemData) -> Result<u64, String> {
+        self.count(&data)?;
+
+        let item_id = self.next_item_id;
+        self.next_item_id += 1;
+
+        let item = SpecialItem {
+            data,
+            item_id
+        };
+
+        self.items.insert(self.num_constants, item);
+        self.num_constants += 1;
+
+        Ok(item_id)
+    }
+
+    fn add(&mut self, data : SpecialItemData) -> Result<u64, String> {
+        self.count(&data)?;
+
         let item_id = self.next_item_id;
         self.next_item_id += 1;
 
@@ -111,7 +276,7 @@ impl SpecialStorage {
 
         self.items.push(item);
 
-        item_id
+        Ok(item_id)
     }
 
     fn remove_top(&mut self, num : usize) -> Result<(), String> {
@@ -120,13 +285,81 @@ impl SpecialStorage {
         } else {
 
             for _ in 0..num {
-                let _ = self.items.pop().expect("Impossível. Erro no pop em remove_top");
+                let item = self.items.pop().expect("Impossível. Erro no pop em remove_top");
+                self.uncount(&item.data);
             }
 
             Ok(())
         }
     }
 
+    // Removes the last num items, except the ones in keep. Returns how many were kept
+    fn retain_top(&mut self, num : usize, keep : &HashSet<u64>) -> Result<usize, String> {
+        if self.items.len() < num {
+            return Err("retain_top : Número pra remover é maior que o número guardado".to_owned());
+        }
+
+        let start = self.items.len() - num;
+        let top = self.items.split_off(start);
+        let mut kept = 0usize;
+
+        for item in top {
+            if keep.contains(&item.item_id) {
+                self.items.push(item);
+                kept += 1;
+            } else {
+                self.uncount(&item.data);
+            }
+        }
+
+        Ok(kept)
+    }
+
+    // Removes the last num items and gives them back, to be added again inside a coroutine
+    fn take_top(&mut self, num : usize) -> Result<Vec<SpecialItem>, String> {
+        if self.items.len() < num {
+            return Err("take_top : Número pra remover é maior que o número guardado".to_owned());
+        }
+
+        let start = self.items.len() - num;
+        let top = self.items.split_off(start);
+
+        for item in &top {
+            self.uncount(&item.data);
+        }
+
+        Ok(top)
+    }
+
+    // Moves the last num items into the coroutine, where they're still counted
+    fn park(&mut self, coroutine : u64, num : usize) -> Result<(), String> {
+        if self.items.len() < num {
+            return Err("park : Número pra guardar é maior que o número guardado".to_owned());
+        }
+
+        let start = self.items.len() - num;
+        let top = self.items.split_off(start);
+
+        match self.get_mut(coroutine) {
+            Some(SpecialItemData::Coroutine(ref mut c)) => c.items = top,
+            _ => return Err("Erro interno : ID inválida pra gaiola".to_owned()),
+        }
+
+        Ok(())
+    }
+
+    // Moves the items of the coroutine back to the top
+    fn unpark(&mut self, coroutine : u64) -> Result<(), String> {
+        let items = match self.get_mut(coroutine) {
+            Some(SpecialItemData::Coroutine(ref mut c)) => ::std::mem::take(&mut c.items),
+            _ => return Err("Erro interno : ID inválida pra gaiola".to_owned()),
+        };
+
+        self.items.extend(items);
+
+        Ok(())
+    }
+
     fn get_ref(&self, id : u64) -> Option<&SpecialItemData> {
         for e in &self.items {
             if e.item_id == id {
@@ -148,162 +381,1037 @@ impl SpecialStorage {
     }
 }
 
+/// The compiled body of a function
+#[derive(Clone, Debug, Default)]
+pub struct Code {
+    pub instructions : Vec<Instruction>,
+    /// Text literals used by the function, referred to by their index. Each one is only copied into the
+    /// special storage the first time it's used
+    pub texts : Vec<String>,
+    /// Where the instructions came from in the source. Not needed to run them
+    pub debug : DebugInfo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockKind {
+    Conditional,
+    Loop,
+}
+
+/// A conditional or loop block in the source, from its first instruction up to the one after its last
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start : usize,
+    pub end : usize,
+    pub kind : BlockKind,
+}
+
+/// Source information about a function's instructions, used to show them in a readable way
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    /// Source line of the instructions starting at each address, sorted by address
+    pub lines : Vec<(usize, usize)>,
+    /// Variables declared in the function, as the address of the instruction where they were declared, their name
+    /// and their variable address, sorted by instruction address
+    pub variables : Vec<(usize, String, usize)>,
+    pub blocks : Vec<Block>,
+}
+
+impl DebugInfo {
+    /// Marks the instructions from the address on as coming from the line
+    pub fn mark_line(&mut self, address : usize, line : usize) {
+        match self.lines.last_mut() {
+            Some(&mut (_, last)) if last == line => return,
+            Some(last) if last.0 == address => {
+                last.1 = line;
+
+                return;
+            }
+            _ => {}
+        }
+
+        self.lines.push((address, line));
+    }
+
+    /// The source line of the instruction at the address
+    pub fn line_at(&self, address : usize) -> Option<usize> {
+        self.lines.iter().rev().find(|&&(start, _)| start <= address).map(|&(_, line)| line)
+    }
+
+    /// The name of the variable at the variable address, as seen by the instruction at the address. Blocks reuse
+    /// addresses after they end, so it's the last one declared before the instruction
+    pub fn variable_at(&self, address : usize, var_address : usize) -> Option<&str> {
+        self.variables.iter().rev()
+            .find(|v| v.0 <= address && v.2 == var_address)
+            .map(|v| v.1.as_str())
+    }
+
+    /// Moves everything to new instruction addresses, given the new address of each old one
+    pub fn relocate(&mut self, new_address : &[usize]) {
+        let moved = |address : usize| new_address[address.min(new_address.len() - 1)];
+        let mut lines : Vec<(usize, usize)> = vec![];
+
+        for &(address, line) in &self.lines {
+            let address = moved(address);
+
+            // A line with all of its instructions removed is replaced by the next one
+            if let Some(last) = lines.last_mut() {
+                if last.0 == address {
+                    last.1 = line;
+                    continue;
+                }
+            }
+
+            lines.push((address, line));
+        }
+
+        self.lines = lines;
+
+        for var in &mut self.variables {
+            var.0 = moved(var.0);
+        }
+
+        for block in &mut self.blocks {
+            block.start = moved(block.start);
+            block.end = moved(block.end);
+        }
+    }
+}
+
+impl Code {
+    pub fn new() -> Code {
+        Code::default()
+    }
+
+    pub fn push(&mut self, inst : Instruction) {
+        self.instructions.push(inst);
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// Adds a text to the pool, returning its index. Repeated texts share the same index
+    pub fn add_text(&mut self, text : String) -> usize {
+        match self.texts.iter().position(|t| *t == text) {
+            Some(index) => index,
+            None => {
+                self.texts.push(text);
+
+                self.texts.len() - 1
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
-struct LoopLabel {
-    start_pc : usize,
-    index_address : Option<usize>,
-    stepping : DynamicValue,
+pub struct FunctionFrame {
+    id : usize,
+    stack : Vec<DynamicValue>,
+    program_counter : usize,
+    last_comparision : Option<Comparision>,
+    next_address : usize,
+    ready : bool,
+    stack_size : usize,
+    // Number of special items allocated
+    num_special_items : usize,
+    // Size of the operand stack when the function started, restored when it returns
+    operand_base : usize,
+}
+
+impl FunctionFrame {
+    pub fn new(id : usize, stack_size : usize) -> FunctionFrame {
+        FunctionFrame {
+            id,
+            stack : vec![DynamicValue::Null; stack_size],
+            program_counter : 0,
+            last_comparision : None,
+            next_address : 0usize,
+            ready : false,
+            stack_size,
+            num_special_items : 0,
+            operand_base : 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExecutionStatus {
+    Normal,
+    Quit,
+    Returned,
+    Halt,
+    /// The next instruction wasn't run because a limit was reached. It runs once the limit is raised
+    LimitExceeded(ExecutionLimit),
+    /// The program paused and waits for a value from the host, given with resume_with
+    Suspended(Suspension),
+}
+
+/// Why the program paused
+#[derive(Clone, Debug, PartialEq)]
+pub enum Suspension {
+    /// An input command wants a line, when input suspends the program. The value given back is the line read
+    Input,
+    /// PERA AÍ paused the program, giving the value. The value given back is stored in the command's variable
+    Yield(RawValue),
+    /// A plugin asked to pause with suspend, giving the value. The value given back is what the plugin returns
+    Plugin(RawValue),
+}
+
+/// Limits the host can set on how much a program runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutionLimit {
+    /// Every instruction uses one unit of fuel
+    Fuel,
+    Deadline,
+}
+
+impl Display for ExecutionLimit {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionLimit::Fuel => write!(f, "Limite excedido : O programa rodou todas as instruções permitidas"),
+            ExecutionLimit::Deadline => write!(f, "Limite excedido : O programa passou do tempo permitido"),
+        }
+    }
+}
+
+/// A frame as kept in a MachineState
+#[derive(Clone, Debug)]
+pub struct FrameState {
+    pub id : usize,
+    pub stack : Vec<DynamicValue>,
+    pub program_counter : usize,
+    pub last_comparision : Option<Comparision>,
+    pub ready : bool,
+    pub num_special_items : usize,
+    pub operand_base : usize,
+}
+
+/// A special item as kept in a MachineState
+#[derive(Clone, Debug)]
+pub enum ItemState {
+    Text(String),
+    List(Vec<DynamicValue>),
+    BigInteger(BigInteger),
+    Coroutine(CoroutineState),
+}
+
+/// A coroutine as kept in a MachineState, with what it keeps while it isn't running
+#[derive(Clone, Debug)]
+pub struct CoroutineState {
+    pub frames : Vec<FrameState>,
+    pub items : Vec<(u64, ItemState)>,
+    pub operands : Vec<DynamicValue>,
+    pub running : bool,
+    pub done : bool,
+}
+
+/// Everything the VM keeps about the program it runs, so the program can continue in another VM with the same codes.
+/// What the host sets, like the stdout, the plugins, the modes, the limits and the capabilities, isn't part of it
+#[derive(Clone, Debug)]
+pub struct MachineState {
+    pub callstack : Vec<FrameState>,
+    pub operands : Vec<DynamicValue>,
+    pub interpolations : Vec<String>,
+    /// Special items from the bottom of the storage up, with their IDs. The first num_constants are the texts of the
+    /// codes' pools
+    pub items : Vec<(u64, ItemState)>,
+    pub num_constants : usize,
+    pub next_item_id : u64,
+    /// ID of the item holding each text of each code's pool, for the texts already used
+    pub text_constants : Vec<Vec<Option<u64>>>,
+    /// Coroutines running, with the callstack index of their first frame
+    pub running_coroutines : Vec<(u64, usize)>,
+    pub has_quit : bool,
+    pub suspension : Option<Suspension>,
+}
+
+impl FunctionFrame {
+    fn to_state(&self) -> FrameState {
+        FrameState {
+            id : self.id,
+            stack : self.stack.clone(),
+            program_counter : self.program_counter,
+            last_comparision : self.last_comparision,
+            ready : self.ready,
+            num_special_items : self.num_special_items,
+            operand_base : self.operand_base,
+        }
+    }
+
+    fn from_state(state : FrameState) -> FunctionFrame {
+        FunctionFrame {
+            id : state.id,
+            stack_size : state.stack.len(),
+            stack : state.stack,
+            program_counter : state.program_counter,
+            last_comparision : state.last_comparision,
+            next_address : 0,
+            ready : state.ready,
+            num_special_items : state.num_special_items,
+            operand_base : state.operand_base,
+        }
+    }
 }
 
-impl LoopLabel {
-    fn new(start_pc : usize) -> LoopLabel {
-        LoopLabel {
-            start_pc,
-            index_address : None,
-            stepping : DynamicValue::Null,
+impl SpecialItem {
+    fn to_state(&self) -> (u64, ItemState) {
+        let state = match self.data {
+            SpecialItemData::Text(ref t) => ItemState::Text(t.clone()),
+            SpecialItemData::List(ref l) => ItemState::List(l.iter().map(|v| **v).collect()),
+            SpecialItemData::BigInteger(ref b) => ItemState::BigInteger(b.clone()),
+            SpecialItemData::Coroutine(ref c) => ItemState::Coroutine(CoroutineState {
+                frames : c.frames.iter().map(|f| f.to_state()).collect(),
+                items : c.items.iter().map(|i| i.to_state()).collect(),
+                operands : c.operands.clone(),
+                running : c.running,
+                done : c.done,
+            }),
+        };
+
+        (self.item_id, state)
+    }
+
+    fn from_state(item_id : u64, state : ItemState) -> SpecialItem {
+        let data = match state {
+            ItemState::Text(t) => SpecialItemData::Text(t),
+            ItemState::List(l) => SpecialItemData::List(l.into_iter().map(Box::new).collect()),
+            ItemState::BigInteger(b) => SpecialItemData::BigInteger(b),
+            ItemState::Coroutine(c) => SpecialItemData::Coroutine(Coroutine {
+                frames : c.frames.into_iter().map(FunctionFrame::from_state).collect(),
+                items : c.items.into_iter().map(|(id, i)| SpecialItem::from_state(id, i)).collect(),
+                operands : c.operands,
+                running : c.running,
+                done : c.done,
+            }),
+        };
+
+        SpecialItem { data, item_id }
+    }
+}
+
+pub struct Registers {
+    default_stack_size : usize,
+    has_quit : bool,
+    is_interactive : bool,
+    big_integers : bool,
+    max_call_depth : usize,
+    next_code_index : usize,
+    next_plugin_index : usize,
+    // How many instructions can still run. None is no limit
+    fuel : Option<u64>,
+    deadline : Option<Instant>,
+    // Instructions left until the deadline is checked again
+    deadline_countdown : u32,
+    // Whether input commands pause the program instead of reading from stdin
+    suspend_on_input : bool,
+    // Why the program is paused, if it is. No instruction runs until the host gives it a value
+    suspension : Option<Suspension>,
+    // Value given by the plugin being called, if it asked to pause
+    suspend_request : Option<RawValue>,
+}
+
+impl Registers {
+    fn default() -> Registers {
+        Registers {
+            default_stack_size : STACK_DEFAULT_SIZE,
+            has_quit : false,
+            is_interactive : false,
+            big_integers : false,
+            max_call_depth : DEFAULT_MAX_CALL_DEPTH,
+            next_code_index : 0,
+            next_plugin_index : 0,
+            fuel : None,
+            deadline : None,
+            deadline_countdown : 0,
+            suspend_on_input : false,
+            suspension : None,
+            suspend_request : None,
+        }
+    }
+}
+
+pub struct VirtualMachine {
+    registers : Registers,
+    callstack : Vec<FunctionFrame>,
+    stdout: Option<Box<dyn Write>>,
+    stdin:  Option<Box<dyn BufRead>>,
+    // Shared so an instruction can be run while borrowed from its code
+    code : Vec<Rc<Code>>,
+    // Special storage ids of the texts in each code's pool, filled as they're first used
+    text_constants : Vec<Vec<Option<u64>>>,
+    // Name of the function each code belongs to, used on error messages
+    code_names : Vec<String>,
+    // How many variable slots the frames for each code need. None uses the default stack size
+    frame_sizes : Vec<Option<usize>>,
+    // Taken out while the plugin runs, since it gets the VM
+    plugins : Vec<Option<BoxedPlugin>>,
+    special_storage : SpecialStorage,
+    // Values being worked on. Instructions take their operands from the top and push their results back
+    operand_stack : Vec<DynamicValue>,
+    // Texts being built by interpolations
+    interpolation_stack : Vec<String>,
+    capabilities : Capabilities,
+    // Coroutines running, the innermost last, with the callstack index of their first frame
+    running_coroutines : Vec<(u64, usize)>,
+    // Whatever the host gave for plugins to use
+    user_data : Option<Box<dyn Any>>,
+}
+
+macro_rules! vm_write{
+    ($out:expr,$($arg:tt)*) => ({
+        if let Some(output) = $out.as_mut(){
+            write!(output, $($arg)*)
+                .map_err(|what| format!("Deu pra escrever não cumpade: {:?}", what))
+        }else{
+            Ok(())
+        }
+    })
+}
+
+// Converts an index of a list to usize. Doesn't check if it's in bounds
+fn list_index(index : IntegerType) -> Result<usize, String> {
+    if index < 0 {
+        return Err(format!("Erro : Índice negativo pra lista : {}", index));
+    }
+
+    match usize::try_from(index) {
+        Ok(i) => Ok(i),
+        Err(_) => Err(format!("Erro : Índice grande demais pra lista : {}", index)),
+    }
+}
+
+impl VirtualMachine {
+    pub fn new() -> VirtualMachine {
+        VirtualMachine {
+            registers : Registers::default(),
+            callstack : vec![],
+            stdout: None,
+            stdin: None,
+            code : vec![],
+            text_constants : vec![],
+            code_names : vec![],
+            frame_sizes : vec![],
+            plugins : vec![],
+            special_storage : SpecialStorage::new(),
+            operand_stack : vec![],
+            interpolation_stack : vec![],
+            capabilities : Capabilities::default(),
+            running_coroutines : vec![],
+            user_data : None,
+        }
+    }
+
+    fn add_special_item(&mut self, frame_index : usize, data : SpecialItemData) -> Result<u64, String> {
+        if self.callstack.len() <= frame_index {
+            return Err("add_special_item : Index é inválido".to_owned());
+        }
+
+        let id = self.special_storage.add(data)?;
+        self.callstack[frame_index].num_special_items += 1;
+
+        Ok(id)
+    }
+
+    /// For plugins : makes a new text, kept by the function running until it returns. A plugin can return it or
+    /// put it in a list
+    ///
+    /// ```
+    /// use birl::vm::{ VirtualMachine, DynamicValue };
+    ///
+    /// fn greet(args : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+    ///     let name = vm.get_text(args[0])?.to_owned();
+    ///
+    ///     Ok(Some(vm.new_text(format!("Bora, {}!", name))?))
+    /// }
+    /// ```
+    pub fn new_text(&mut self, text : String) -> Result<DynamicValue, String> {
+        let index = self.get_last_ready_index().unwrap_or(0);
+
+        Ok(DynamicValue::Text(self.add_special_item(index, SpecialItemData::Text(text))?))
+    }
+
+    /// For plugins : makes a new list with the values, kept by the function running like in new_text
+    ///
+    /// ```
+    /// use birl::vm::{ VirtualMachine, DynamicValue };
+    ///
+    /// fn range(args : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+    ///     let end = match args[0] {
+    ///         DynamicValue::Integer(i) => i,
+    ///         _ => return Err("Esperado um inteiro".to_owned()),
+    ///     };
+    ///
+    ///     let values = (0..end).map(DynamicValue::Integer).collect();
+    ///
+    ///     Ok(Some(vm.new_list(values)?))
+    /// }
+    /// ```
+    pub fn new_list(&mut self, values : Vec<DynamicValue>) -> Result<DynamicValue, String> {
+        let index = self.get_last_ready_index().unwrap_or(0);
+        let list = values.into_iter().map(Box::new).collect();
+
+        Ok(DynamicValue::List(self.add_special_item(index, SpecialItemData::List(list))?))
+    }
+
+    /// The text a value points to
+    pub fn get_text(&self, value : DynamicValue) -> Result<&str, String> {
+        match value {
+            DynamicValue::Text(id) => match self.special_storage.get_ref(id) {
+                Some(SpecialItemData::Text(ref t)) => Ok(t.as_str()),
+                Some(_) => Err("Erro interno : DynamicValue é texto, mas o id aponta pra outra coisa".to_owned()),
+                None => Err(format!("Texto com ID {} não encontrado", id)),
+            },
+            v => Err(format!("Tipo incompatível : Esperado um texto, encontrado {}", self.kind_name(v))),
+        }
+    }
+
+    /// The elements of the list a value points to
+    ///
+    /// ```
+    /// use birl::vm::{ VirtualMachine, DynamicValue };
+    ///
+    /// fn join(args : Vec<DynamicValue>, vm : &mut VirtualMachine) -> Result<Option<DynamicValue>, String> {
+    ///     let mut parts = vec![];
+    ///
+    ///     for element in vm.get_list(args[0])? {
+    ///         parts.push(vm.get_text(**element)?.to_owned());
+    ///     }
+    ///
+    ///     Ok(Some(vm.new_text(parts.join(" "))?))
+    /// }
+    /// ```
+    pub fn get_list(&self, value : DynamicValue) -> Result<&[Box<DynamicValue>], String> {
+        match value {
+            DynamicValue::List(id) => match self.special_storage.get_ref(id) {
+                Some(SpecialItemData::List(ref l)) => Ok(l.as_slice()),
+                Some(_) => Err("Erro interno : DynamicValue é uma lista, mas o id aponta pra outra coisa".to_owned()),
+                None => Err(format!("Lista com ID {} não encontrada", id)),
+            },
+            v => Err(format!("Tipo incompatível : Esperado uma lista, encontrado {}", self.kind_name(v))),
+        }
+    }
+
+    /// For plugins : makes an integer, kept as a big integer if it doesn't fit in a regular one. Outside of the big
+    /// integer mode, that's an overflow error
+    pub fn new_integer(&mut self, value : BigInteger) -> Result<DynamicValue, String> {
+        if !self.registers.big_integers && value.to_integer().is_none() {
+            return Err(format!("Estouro de inteiro : {} não cabe em um inteiro de {} bits. \
+                                Use o modo de inteiros grandes pra trabalhar com valores maiores",
+                               value, IntegerType::BITS));
+        }
+
+        self.store_big_integer(value)
+    }
+
+    /// The integer a value has, be it a regular or a big one
+    pub fn get_integer(&self, value : DynamicValue) -> Result<BigInteger, String> {
+        match self.as_big_integer(value)? {
+            Some(b) => Ok(b),
+            None => Err(format!("Tipo incompatível : Esperado um inteiro, encontrado {}", self.kind_name(value))),
+        }
+    }
+
+    /// Makes a value the VM can use from a value of the host. Texts and lists are made like in new_text and new_list
+    pub fn raw_to_dynamic(&mut self, val : &RawValue) -> Result<DynamicValue, String> {
+        match *val {
+            RawValue::Text(ref t) => self.new_text(t.clone()),
+            RawValue::List(ref l) => {
+                let mut values = Vec::with_capacity(l.len());
+
+                for element in l {
+                    values.push(self.raw_to_dynamic(element)?);
+                }
+
+                self.new_list(values)
+            }
+            RawValue::Number(n) => Ok(DynamicValue::Number(n)),
+            RawValue::Integer(i) => Ok(DynamicValue::Integer(i)),
+            RawValue::Null => Ok(DynamicValue::Null),
+        }
+    }
+
+    /// Copies a value to give to the host, along with the texts and lists it points to. Big integers and coroutines
+    /// can't be given
+    pub fn dynamic_to_raw(&self, val : DynamicValue) -> Result<RawValue, String> {
+        self.nested_dynamic_to_raw(val, &mut HashSet::new())
+    }
+
+    // Like detach_nested_value, parents are the lists being copied, so a list that has itself inside is an error
+    fn nested_dynamic_to_raw(&self, val : DynamicValue, parents : &mut HashSet<u64>) -> Result<RawValue, String> {
+        match val {
+            DynamicValue::Integer(i) => Ok(RawValue::Integer(i)),
+            DynamicValue::Number(n) => Ok(RawValue::Number(n)),
+            DynamicValue::Null => Ok(RawValue::Null),
+            DynamicValue::Text(_) => Ok(RawValue::Text(self.get_text(val)?.to_owned())),
+            DynamicValue::List(id) => {
+                if !parents.insert(id) {
+                    return Err("Tipo incompatível : Uma lista que tem ela mesma dentro não pode ser copiada".to_owned());
+                }
+
+                let mut elements = vec![];
+
+                for element in self.get_list(val)? {
+                    elements.push(self.nested_dynamic_to_raw(**element, parents)?);
+                }
+
+                parents.remove(&id);
+
+                Ok(RawValue::List(elements))
+            }
+            DynamicValue::BigInteger(_) => Err("Tipo incompatível : Não dá pra passar um inteiro grande pro host".to_owned()),
+            DynamicValue::Coroutine(_) => Err("Tipo incompatível : Não dá pra passar uma gaiola pro host".to_owned()),
+        }
+    }
+
+    pub fn set_interactive_mode(&mut self) {
+        self.registers.is_interactive = true;
+    }
+
+    /// When enabled, integers that overflow are promoted to big integers instead of raising an error
+    pub fn set_big_integer_mode(&mut self, enabled : bool) {
+        self.registers.big_integers = enabled;
+    }
+
+    /// Sets how many function calls can be active at the same time before a stack overflow error
+    pub fn set_max_call_depth(&mut self, depth : usize) {
+        self.registers.max_call_depth = depth;
+    }
+
+    /// Limits how many more instructions can run. None removes the limit
+    pub fn set_fuel(&mut self, fuel : Option<u64>) {
+        self.registers.fuel = fuel;
+    }
+
+    /// Allows more instructions to run, after the fuel ran out or before. Does nothing if there's no limit
+    pub fn add_fuel(&mut self, amount : u64) {
+        if let Some(ref mut fuel) = self.registers.fuel {
+            *fuel = fuel.saturating_add(amount);
+        }
+    }
+
+    /// How many more instructions can run, or None if there's no limit
+    pub fn get_fuel(&self) -> Option<u64> {
+        self.registers.fuel
+    }
+
+    /// Stops running instructions after the given time. None removes the limit. Instructions that wait, like
+    /// reading input or a plugin that takes long, aren't interrupted, so the deadline is only noticed after them
+    pub fn set_deadline(&mut self, deadline : Option<Instant>) {
+        self.registers.deadline = deadline;
+        self.registers.deadline_countdown = 0;
+    }
+
+    pub fn get_deadline(&self) -> Option<Instant> {
+        self.registers.deadline
+    }
+
+    // Checks the limits before running an instruction, using up one unit of fuel if it can run
+    fn check_limits(&mut self) -> Option<ExecutionLimit> {
+        if let Some(deadline) = self.registers.deadline {
+            if self.registers.deadline_countdown == 0 {
+                if Instant::now() >= deadline {
+                    return Some(ExecutionLimit::Deadline);
+                }
+
+                self.registers.deadline_countdown = DEADLINE_CHECK_INTERVAL;
+            }
+
+            self.registers.deadline_countdown -= 1;
+        }
+
+        if let Some(ref mut fuel) = self.registers.fuel {
+            if *fuel == 0 {
+                return Some(ExecutionLimit::Fuel);
+            }
+
+            *fuel -= 1;
+        }
+
+        None
+    }
+
+    /// Sets what the program can use outside the VM. Printing and reading input are checked by the VM, the rest is up
+    /// to the plugins, with check_capability
+    pub fn set_capabilities(&mut self, capabilities : Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn get_capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Gives a permission error if the program isn't allowed to use the capability
+    pub fn check_capability(&self, capability : Capability) -> Result<(), String> {
+        self.capabilities.check(capability)
+    }
+
+    /// When enabled, input commands pause the program with Suspension::Input instead of reading from stdin, and the
+    /// host gives the line with resume_with
+    pub fn set_suspend_on_input(&mut self, enabled : bool) {
+        self.registers.suspend_on_input = enabled;
+    }
+
+    /// For plugins : pauses the program once the plugin returns, giving the value to the host. The value the host
+    /// gives back with resume_with replaces what the plugin returned
+    pub fn suspend(&mut self, value : RawValue) {
+        self.registers.suspend_request = Some(value);
+    }
+
+    /// Why the program is paused, if it is
+    pub fn get_suspension(&self) -> Option<&Suspension> {
+        self.registers.suspension.as_ref()
+    }
+
+    /// Gives the value the paused program is waiting for, so it can continue
+    pub fn resume_with(&mut self, value : RawValue) -> Result<(), String> {
+        if self.registers.suspension.is_none() {
+            return Err("Erro : O programa não está esperando nenhum valor".to_owned());
+        }
+
+        let value = self.raw_to_dynamic(&value)?;
+
+        match self.registers.suspension.take() {
+            Some(Suspension::Plugin(_)) => {
+                let index = self.callstack.len() - 1;
+                self.write_to(value, index, 0)?;
+            }
+            _ => self.operand_stack.push(value),
+        }
+
+        Ok(())
+    }
+
+    fn suspend_with(&mut self, suspension : Suspension) -> ExecutionStatus {
+        self.registers.suspension = Some(suspension.clone());
+
+        ExecutionStatus::Suspended(suspension)
+    }
+
+    pub fn get_max_call_depth(&self) -> usize {
+        self.registers.max_call_depth
+    }
+
+    pub fn get_call_depth(&self) -> usize {
+        self.callstack.len().saturating_sub(1)
+    }
+
+    /// Copies the state of the program being run, so it can be saved and continued later with set_state
+    pub fn get_state(&self) -> MachineState {
+        MachineState {
+            callstack : self.callstack.iter().map(|f| f.to_state()).collect(),
+            operands : self.operand_stack.clone(),
+            interpolations : self.interpolation_stack.clone(),
+            items : self.special_storage.items.iter().map(|i| i.to_state()).collect(),
+            num_constants : self.special_storage.num_constants,
+            next_item_id : self.special_storage.next_item_id,
+            text_constants : self.text_constants.clone(),
+            running_coroutines : self.running_coroutines.clone(),
+            has_quit : self.registers.has_quit,
+            suspension : self.registers.suspension.clone(),
+        }
+    }
+
+    // Checks that a saved frame can run with the codes of this VM
+    fn check_frame_state(&self, frame : &FrameState, num_operands : usize) -> Result<(), String> {
+        if frame.id >= self.code.len() {
+            return Err(format!("Estado inválido : Nenhum código com ID {}", frame.id));
+        }
+
+        // The program counter can be past the end, which returns from the function
+        if frame.stack.len() > MAX_FRAME_SIZE || frame.operand_base > num_operands {
+            return Err(format!("Estado inválido : Um frame de \"{}\" não combina com o código", self.code_names[frame.id]));
+        }
+
+        Ok(())
+    }
+
+    fn check_item_state(&self, id : u64, item : &ItemState, next_item_id : u64) -> Result<(), String> {
+        if id >= next_item_id {
+            return Err(format!("Estado inválido : Item com ID {} depois da próxima ID", id));
+        }
+
+        if let ItemState::Coroutine(ref c) = *item {
+            for frame in &c.frames {
+                self.check_frame_state(frame, c.operands.len())?;
+            }
+
+            if c.frames.iter().map(|f| f.num_special_items).sum::<usize>() != c.items.len() {
+                return Err("Estado inválido : Os itens de uma gaiola não combinam com os frames dela".to_owned());
+            }
+
+            for &(id, ref item) in &c.items {
+                self.check_item_state(id, item, next_item_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the state of the program with one given by get_state, from a VM with the same codes. The memory
+    /// limits of this VM apply to the items of the state
+    pub fn set_state(&mut self, state : MachineState) -> Result<(), String> {
+        if state.text_constants.len() != self.code.len() {
+            return Err(format!("Estado inválido : O estado é de {} códigos, mas a VM tem {}",
+                               state.text_constants.len(), self.code.len()));
+        }
+
+        for frame in &state.callstack {
+            self.check_frame_state(frame, state.operands.len())?;
+        }
+
+        for &(id, ref item) in &state.items {
+            self.check_item_state(id, item, state.next_item_id)?;
+        }
+
+        let num_items = state.num_constants + state.callstack.iter().map(|f| f.num_special_items).sum::<usize>();
+
+        if num_items != state.items.len() {
+            return Err("Estado inválido : Os itens não combinam com os frames".to_owned());
+        }
+
+        if state.running_coroutines.iter().any(|&(_, first)| first >= state.callstack.len()) {
+            return Err("Estado inválido : Gaiola rodando fora da callstack".to_owned());
+        }
+
+        if let Some(Suspension::Plugin(_)) = state.suspension {
+            if state.callstack.is_empty() {
+                return Err("Estado inválido : Plugin pausado sem nenhuma função em execução".to_owned());
+            }
+        }
+
+        let mut storage = SpecialStorage::new();
+        storage.set_limits(self.special_storage.get_limits());
+
+        for (id, item) in state.items {
+            let item = SpecialItem::from_state(id, item);
+
+            storage.count(&item.data)?;
+            storage.items.push(item);
+        }
+
+        storage.num_constants = state.num_constants;
+        storage.next_item_id = state.next_item_id;
+
+        self.special_storage = storage;
+        self.callstack = state.callstack.into_iter().map(FunctionFrame::from_state).collect();
+        self.operand_stack = state.operands;
+        self.interpolation_stack = state.interpolations;
+        self.text_constants = state.text_constants;
+        self.running_coroutines = state.running_coroutines;
+        self.registers.has_quit = state.has_quit;
+        self.registers.suspension = state.suspension;
+        self.registers.suspend_request = None;
+
+        Ok(())
+    }
+
+    // Drops every frame but the global one, so the VM can keep running after an unrecoverable error
+    fn unwind_to_global(&mut self) -> Result<(), String> {
+        // Coroutines that were running lose their frames, so they can't run again
+        while let Some((id, _)) = self.running_coroutines.pop() {
+            if let Some(SpecialItemData::Coroutine(ref mut c)) = self.special_storage.get_mut(id) {
+                c.running = false;
+                c.done = true;
+            }
+        }
+
+        while self.callstack.len() > 1 {
+            if let Some(frame) = self.callstack.pop() {
+                self.special_storage.remove_top(frame.num_special_items)?;
+            }
+        }
+
+        self.operand_stack.clear();
+        self.interpolation_stack.clear();
+
+        Ok(())
+    }
+
+    fn stack_overflow(&mut self, id : usize) -> String {
+        let depth = self.callstack.len();
+        let name = match self.code_names.get(id) {
+            Some(n) if ! n.is_empty() => n.clone(),
+            _ => format!("#{}", id),
+        };
+
+        if let Err(e) = self.unwind_to_global() {
+            return e;
+        }
+
+        format!("Estouro de pilha : A chamada pra \"{}\" chegou na profundidade {}, passando do limite de {} \
+                 chamadas ao mesmo tempo", name, depth, self.registers.max_call_depth)
+    }
+
+    fn detach_value(&self, val : DynamicValue) -> Result<DetachedValue, String> {
+        self.detach_nested_value(val, &mut HashSet::new())
+    }
+
+    // The lists being copied are kept in parents, so a list that has itself inside is an error instead of copying
+    // forever. The same list can still be in more than one place
+    fn detach_nested_value(&self, val : DynamicValue, parents : &mut HashSet<u64>) -> Result<DetachedValue, String> {
+        match val {
+            DynamicValue::Text(id) => match self.special_storage.get_ref(id) {
+                Some(SpecialItemData::Text(ref t)) => Ok(DetachedValue::Text(t.clone())),
+                Some(_) => Err("Erro interno : DynamicValue é texto, mas o id aponta pra outra coisa".to_owned()),
+                None => Err(format!("Texto com ID {} não encontrado", id)),
+            },
+            DynamicValue::List(id) => match self.special_storage.get_ref(id) {
+                Some(SpecialItemData::List(ref list)) => {
+                    if !parents.insert(id) {
+                        return Err("Tipo incompatível : Uma lista que tem ela mesma dentro não pode ser copiada".to_owned());
+                    }
+
+                    let mut items = Vec::with_capacity(list.len());
+
+                    for item in list {
+                        items.push(self.detach_nested_value(**item, parents)?);
+                    }
+
+                    parents.remove(&id);
+
+                    Ok(DetachedValue::List(items))
+                }
+                Some(_) => Err("Erro interno : DynamicValue é uma lista, mas o id aponta pra outra coisa".to_owned()),
+                None => Err(format!("Lista com ID {} não encontrada", id)),
+            },
+            DynamicValue::BigInteger(id) => Ok(DetachedValue::BigInteger(self.get_big_integer(id)?)),
+            // The coroutine keeps frames and items that can't be copied
+            DynamicValue::Coroutine(_) => Err("Tipo incompatível : Uma gaiola não pode sair da função que fez ela".to_owned()),
+            _ => Ok(DetachedValue::Plain(val)),
         }
     }
-}
 
-#[derive(Debug)]
-pub struct FunctionFrame {
-    id : usize,
-    stack : Vec<DynamicValue>,
-    program_counter : usize,
-    last_comparision : Option<Comparision>,
-    next_address : usize,
-    ready : bool,
-    skip_level : u32,
-    stack_size : usize,
-    // Number of special items allocated
-    num_special_items : usize,
-    label_stack : Vec<LoopLabel>,
-}
+    fn attach_value(&mut self, frame_index : usize, val : DetachedValue) -> Result<DynamicValue, String> {
+        match val {
+            DetachedValue::Plain(v) => Ok(v),
+            DetachedValue::Text(t) => Ok(DynamicValue::Text(self.add_special_item(frame_index, SpecialItemData::Text(t))?)),
+            DetachedValue::BigInteger(b) => {
+                Ok(DynamicValue::BigInteger(self.add_special_item(frame_index, SpecialItemData::BigInteger(b))?))
+            }
+            DetachedValue::List(items) => {
+                let mut list = Vec::with_capacity(items.len());
 
-impl FunctionFrame {
-    pub fn new(id : usize, stack_size : usize) -> FunctionFrame {
-        FunctionFrame {
-            id,
-            stack : vec![DynamicValue::Null; stack_size],
-            program_counter : 0,
-            last_comparision : None,
-            next_address : 0usize,
-            ready : false,
-            skip_level : 0,
-            stack_size,
-            label_stack : vec![],
-            num_special_items : 0,
+                for item in items {
+                    list.push(Box::new(self.attach_value(frame_index, item)?));
+                }
+
+                Ok(DynamicValue::List(self.add_special_item(frame_index, SpecialItemData::List(list))?))
+            }
         }
     }
-}
-
-#[derive(Clone, Debug)]
-pub enum ExecutionStatus {
-    Normal,
-    Quit,
-    Returned,
-    Halt,
-}
 
-pub struct Registers {
-    math_a : DynamicValue,
-    math_b : DynamicValue,
-    intermediate : DynamicValue,
-    first_operation : bool,
-    secondary : DynamicValue,
-    default_stack_size : usize,
-    has_quit : bool,
-    is_interactive : bool,
-    next_code_index : usize,
-    next_plugin_index : usize,
-}
+    // Adds the IDs of every special item reachable from the value
+    fn collect_special_ids(&self, val : DynamicValue, ids : &mut HashSet<u64>) {
+        match val {
+            DynamicValue::Text(id) | DynamicValue::BigInteger(id) | DynamicValue::Coroutine(id) => {
+                ids.insert(id);
+            }
+            DynamicValue::List(id) => {
+                if !ids.insert(id) {
+                    return;
+                }
 
-impl Registers {
-    fn default() -> Registers {
-        Registers {
-            math_a : DynamicValue::Null,
-            math_b : DynamicValue::Null,
-            secondary : DynamicValue::Null,
-            intermediate : DynamicValue::Null,
-            first_operation : false,
-            default_stack_size : STACK_DEFAULT_SIZE,
-            has_quit : false,
-            is_interactive : false,
-            next_code_index : 0,
-            next_plugin_index : 0,
+                if let Some(SpecialItemData::List(ref list)) = self.special_storage.get_ref(id) {
+                    for item in list {
+                        self.collect_special_ids(**item, ids);
+                    }
+                }
+            }
+            _ => {}
         }
     }
-}
-
-pub struct VirtualMachine {
-    registers : Registers,
-    callstack : Vec<FunctionFrame>,
-    stdout: Option<Box<dyn Write>>,
-    stdin:  Option<Box<dyn BufRead>>,
-    code : Vec<Vec<Instruction>>,
-    plugins : Vec<PluginFunction>,
-    special_storage : SpecialStorage,
-    plugin_argument_stack : Vec<DynamicValue>,
-}
 
-macro_rules! vm_write{
-    ($out:expr,$($arg:tt)*) => ({
-        if let Some(output) = $out.as_mut(){
-            write!(output, $($arg)*)
-                .map_err(|what| format!("Deu pra escrever não cumpade: {:?}", what))
-        }else{
-            Ok(())
+    fn get_big_integer(&self, id : u64) -> Result<BigInteger, String> {
+        match self.special_storage.get_ref(id) {
+            Some(SpecialItemData::BigInteger(ref b)) => Ok(b.clone()),
+            Some(_) => Err("Erro interno : DynamicValue é um inteiro grande, mas o id aponta pra outra coisa".to_owned()),
+            None => Err(format!("Inteiro grande com ID {} não encontrado", id)),
         }
-    })
-}
+    }
 
-impl VirtualMachine {
-    pub fn new() -> VirtualMachine {
-        VirtualMachine {
-            registers : Registers::default(),
-            callstack : vec![],
-            stdout: None,
-            stdin: None,
-            code : vec![],
-            plugins : vec![],
-            special_storage : SpecialStorage::new(),
-            plugin_argument_stack : vec![]
+    fn as_big_integer(&self, val : DynamicValue) -> Result<Option<BigInteger>, String> {
+        match val {
+            DynamicValue::Integer(i) => Ok(Some(BigInteger::from_integer(i))),
+            DynamicValue::BigInteger(id) => Ok(Some(self.get_big_integer(id)?)),
+            _ => Ok(None),
         }
     }
 
-    fn add_special_item(&mut self, frame_index : usize, data : SpecialItemData) -> Result<u64, String> {
-        if self.callstack.len() <= frame_index {
-            return Err("add_special_item : Index é inválido".to_owned());
+    /// Stores the value as a regular integer if it fits in one, or as a big integer otherwise
+    fn store_big_integer(&mut self, value : BigInteger) -> Result<DynamicValue, String> {
+        if let Some(i) = value.to_integer() {
+            return Ok(DynamicValue::Integer(i));
         }
 
-        self.callstack[frame_index].num_special_items += 1;
+        let index = match self.get_last_ready_index() {
+            Some(i) => i,
+            None => return Err("Nenhuma função em execução".to_owned())
+        };
+
+        let id = self.add_special_item(index, SpecialItemData::BigInteger(value))?;
 
-        Ok(self.special_storage.add(data))
+        Ok(DynamicValue::BigInteger(id))
     }
 
-    fn raw_to_dynamic(&mut self, val : RawValue) -> Result<DynamicValue, String> {
-        match val {
-            RawValue::Text(t) => {
-                let parent_index = match self.get_last_ready_index() {
-                    Some(s) => s,
-                    None => 0,
+    // Arithmetic where at least one of the values is a big integer, or an integer operation overflowed
+    fn big_integer_operation(&mut self, op : MathOperator, left : DynamicValue, right : DynamicValue) -> Result<DynamicValue, String> {
+        let l = self.as_big_integer(left)?;
+        let r = self.as_big_integer(right)?;
+
+        match (l, r) {
+            (Some(l), Some(r)) => {
+                let result = match op {
+                    MathOperator::Plus => l.add(&r),
+                    MathOperator::Minus => l.sub(&r),
+                    MathOperator::Multiplication => l.mul(&r),
+                    MathOperator::Division => match l.div_rem(&r) {
+                        Some((quotient, _)) => quotient,
+                        None => return Err("Divisão por zero".to_owned()),
+                    },
+                    _ => return Err(format!("Erro interno : Operação {:?} inválida pra inteiros grandes", op)),
                 };
 
-                let id = match self.add_special_item(parent_index, SpecialItemData::Text(t)) {
-                    Ok(id) => id,
-                    Err(e) => return Err(e)
+                self.store_big_integer(result)
+            }
+            _ => {
+                // The other is a Number
+                let l = self.conv_to_num(left)?;
+                let r = self.conv_to_num(right)?;
+
+                let result = match op {
+                    MathOperator::Plus => l + r,
+                    MathOperator::Minus => l - r,
+                    MathOperator::Multiplication => l * r,
+                    MathOperator::Division => l / r,
+                    _ => return Err(format!("Erro interno : Operação {:?} inválida pra números", op)),
                 };
 
-                Ok(DynamicValue::Text(id))
-            },
-            RawValue::Number(n) => Ok(DynamicValue::Number(n)),
-            RawValue::Integer(i) => Ok(DynamicValue::Integer(i)),
-            RawValue::Null => Ok(DynamicValue::Null),
+                Ok(DynamicValue::Number(result))
+            }
         }
     }
 
-    pub fn set_interactive_mode(&mut self) {
-        self.registers.is_interactive = true;
+    fn integer_overflow(&mut self, op : MathOperator, left : IntegerType, right : IntegerType) -> Result<DynamicValue, String> {
+        if self.registers.big_integers {
+            return self.big_integer_operation(op, DynamicValue::Integer(left), DynamicValue::Integer(right));
+        }
+
+        let symbol = match op {
+            MathOperator::Plus => "+",
+            MathOperator::Minus => "-",
+            MathOperator::Multiplication => "*",
+            MathOperator::Division => "/",
+            _ => "?",
+        };
+
+        Err(format!("Estouro de inteiro : O resultado de {} {} {} não cabe em um inteiro de {} bits. \
+                     Use o modo de inteiros grandes pra trabalhar com valores maiores",
+                    left, symbol, right, IntegerType::BITS))
     }
 
     pub fn execute_next_instruction(&mut self) -> Result<ExecutionStatus, String> {
@@ -325,26 +1433,29 @@ impl VirtualMachine {
             return Err("ID atual pra função é inválida".to_owned());
         }
 
-        let instruction = {
-            let code = &self.code[id];
+        if let Some(ref suspension) = self.registers.suspension {
+            return Ok(ExecutionStatus::Suspended(suspension.clone()));
+        }
 
-            if code.len() <= pc {
-                if self.callstack.len() == 1 && self.registers.is_interactive {
-                    return Ok(ExecutionStatus::Halt);
-                } else {
-                    Instruction::Return
-                }
-            } else {
-                code[pc].clone()
-            }
-        };
+        let code = self.code[id].clone();
+
+        if code.len() <= pc && self.callstack.len() == 1 && self.registers.is_interactive {
+            return Ok(ExecutionStatus::Halt);
+        }
+
+        if let Some(limit) = self.check_limits() {
+            return Ok(ExecutionStatus::LimitExceeded(limit));
+        }
 
         match self.increment_pc() {
             Ok(_) => {}
             Err(e) => return Err(e),
         }
 
-        self.run(instruction)
+        match code.instructions.get(pc) {
+            Some(inst) => self.run(inst),
+            None => self.run(&Instruction::Return),
+        }
     }
 
     pub fn set_stdout(&mut self, write: Option<Box<dyn Write>>) -> Option<Box<dyn Write>>{
@@ -357,13 +1468,6 @@ impl VirtualMachine {
         mem::replace(&mut self.stdin, read)
     } 
 
-    pub fn get_current_skip_level(&self) -> u32 {
-        match self.get_last_ready_ref() {
-            Some(f) => f.skip_level,
-            None => 0,
-        }
-    }
-
     fn get_last_ready_ref(&self) -> Option<&FunctionFrame> {
         let callstack = &self.callstack;
         for frame in callstack.into_iter().rev() {
@@ -403,33 +1507,129 @@ impl VirtualMachine {
         self.registers.next_plugin_index
     }
 
-    pub fn get_code_for(&mut self, id : usize) -> Option<&mut Vec<Instruction>> {
+    pub fn get_code_for(&mut self, id : usize) -> Option<&mut Code> {
         if self.code.len() <= id {
             None
         } else {
-            Some(&mut self.code[id])
+            Some(Rc::make_mut(&mut self.code[id]))
         }
     }
 
     pub fn add_new_code(&mut self) -> usize {
         let id = self.registers.next_code_index;
         self.registers.next_code_index += 1;
-        self.code.push(vec![]);
+        self.code.push(Rc::new(Code::new()));
+        self.text_constants.push(vec![]);
+        self.code_names.push(String::new());
+        self.frame_sizes.push(None);
 
         id
     }
 
-    pub fn add_new_plugin(&mut self, plugin : PluginFunction) -> usize {
+    /// Sets how many variable slots frames for the code need. Frames already running for it are grown if needed
+    pub fn set_frame_size(&mut self, id : usize, size : usize) -> Result<(), String> {
+        if size > MAX_FRAME_SIZE {
+            return Err(format!("set_frame_size : Tamanho {} passa do limite de {}", size, MAX_FRAME_SIZE));
+        }
+
+        match self.frame_sizes.get_mut(id) {
+            Some(s) => *s = Some(size),
+            None => return Err(format!("set_frame_size : Nenhum código com ID {}", id)),
+        }
+
+        for frame in self.callstack.iter_mut().filter(|f| f.id == id) {
+            if frame.stack.len() < size {
+                frame.stack.resize(size, DynamicValue::Null);
+                frame.stack_size = size;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_frame_size(&self, id : usize) -> usize {
+        match self.frame_sizes.get(id) {
+            Some(&Some(size)) => size,
+            _ => self.registers.default_stack_size,
+        }
+    }
+
+    pub fn get_code(&self, id : usize) -> Option<&Code> {
+        self.code.get(id).map(|c| &**c)
+    }
+
+    /// Replaces the whole code, forgetting the texts of the old one
+    pub fn set_code(&mut self, id : usize, code : Code) -> Result<(), String> {
+        if self.code.len() <= id {
+            return Err(format!("set_code : Nenhum código com ID {}", id));
+        }
+
+        self.code[id] = Rc::new(code);
+        self.text_constants[id].clear();
+
+        Ok(())
+    }
+
+    pub fn get_code_name(&self, id : usize) -> Option<&str> {
+        self.code_names.get(id).map(|n| n.as_str())
+    }
+
+    /// The frame size calculated by the compiler for the code, if it was set
+    pub fn get_compiled_frame_size(&self, id : usize) -> Option<usize> {
+        match self.frame_sizes.get(id) {
+            Some(&size) => size,
+            None => None,
+        }
+    }
+
+    pub fn set_code_name(&mut self, id : usize, name : String) -> Result<(), String> {
+        match self.code_names.get_mut(id) {
+            Some(n) => *n = name,
+            None => return Err(format!("set_code_name : Nenhum código com ID {}", id)),
+        }
+
+        Ok(())
+    }
+
+    pub fn add_new_plugin(&mut self, plugin : BoxedPlugin) -> usize {
         let id = self.get_next_plugin_id();
         self.registers.next_plugin_index += 1;
-        self.plugins.push(plugin);
+        self.plugins.push(Some(plugin));
 
         id
     }
+
+    /// Gives the plugins something from the host, like a database connection or a configuration, replacing what
+    /// was given before
+    pub fn set_user_data<T : Any>(&mut self, data : T) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// What the host gave with set_user_data, if it's of the type asked
+    pub fn get_user_data<T : Any>(&mut self) -> Option<&mut T> {
+        match self.user_data {
+            Some(ref mut data) => data.downcast_mut::<T>(),
+            None => None,
+        }
+    }
     pub fn get_registers(&self) -> &Registers {
         &self.registers
     }
 
+    /// Limits how much memory texts, lists and big integers can use. Going past it is a runtime error
+    pub fn set_memory_limits(&mut self, limits : MemoryLimits) {
+        self.special_storage.set_limits(limits);
+    }
+
+    pub fn get_memory_limits(&self) -> MemoryLimits {
+        self.special_storage.get_limits()
+    }
+
+    /// How much memory texts, lists and big integers are using right now
+    pub fn get_memory_usage(&self) -> MemoryUsage {
+        self.special_storage.get_usage()
+    }
+
     pub fn get_special_storage_ref(&self) -> &SpecialStorage {
         &self.special_storage
     }
@@ -456,9 +1656,9 @@ impl VirtualMachine {
                     false
                 }
             }
-            DynamicValue::Integer(_) | DynamicValue::Number(_) => {
+            DynamicValue::Integer(_) | DynamicValue::Number(_) | DynamicValue::BigInteger(_) => {
                 match right {
-                    DynamicValue::Integer(_) | DynamicValue::Number(_) => true,
+                    DynamicValue::Integer(_) | DynamicValue::Number(_) | DynamicValue::BigInteger(_) => true,
                     _ => false,
                 }
             }
@@ -466,6 +1666,60 @@ impl VirtualMachine {
         }
     }
 
+    fn kind_name(&self, val : DynamicValue) -> &'static str {
+        match val {
+            DynamicValue::Integer(_) => "um Inteiro",
+            DynamicValue::BigInteger(_) => "um Inteiro grande",
+            DynamicValue::Number(_) => "um Número",
+            DynamicValue::Text(_) => "um Texto",
+            DynamicValue::List(_) => "uma Lista",
+            DynamicValue::Coroutine(_) => "uma Gaiola",
+            DynamicValue::Null => "Null",
+        }
+    }
+
+    fn bitwise_operation(&self, inst : &Instruction, left : DynamicValue, right : DynamicValue) -> Result<DynamicValue, String> {
+        let symbol = match *inst {
+            Instruction::BitwiseAnd => "&",
+            Instruction::BitwiseOr => "|",
+            Instruction::BitwiseXor => "^",
+            Instruction::ShiftLeft => "<<",
+            Instruction::ShiftRight => ">>",
+            _ => return Err(format!("Erro interno : {:?} não é uma operação bit a bit", inst)),
+        };
+
+        let (l, r) = match (left, right) {
+            (DynamicValue::Integer(l), DynamicValue::Integer(r)) => (l, r),
+            (DynamicValue::BigInteger(_), _) | (_, DynamicValue::BigInteger(_)) => {
+                return Err(format!("O operador {} não funciona com Inteiros grandes, só com os que cabem em 64 bits", symbol));
+            }
+            (DynamicValue::Integer(_), _) => {
+                return Err(format!("O operador {} só funciona com Inteiros, mas o lado direito é {}", symbol, self.kind_name(right)));
+            }
+            _ => return Err(format!("O operador {} só funciona com Inteiros, mas o lado esquerdo é {}", symbol, self.kind_name(left))),
+        };
+
+        let result = match *inst {
+            Instruction::BitwiseAnd => l & r,
+            Instruction::BitwiseOr => l | r,
+            Instruction::BitwiseXor => l ^ r,
+            _ => {
+                if r < 0 || r >= IntegerType::BITS as IntegerType {
+                    return Err(format!("Deslocamento inválido : {} {} {}. O deslocamento deve ser de 0 a {}",
+                                       l, symbol, r, IntegerType::BITS - 1));
+                }
+
+                if let Instruction::ShiftLeft = *inst {
+                    l << r
+                } else {
+                    l >> r
+                }
+            }
+        };
+
+        Ok(DynamicValue::Integer(result))
+    }
+
     fn add_values(&mut self, left : DynamicValue, right : DynamicValue) -> Result<DynamicValue, String> {
         if ! VirtualMachine::is_compatible(left, right) {
             return Err(format!("Add : Os valores não são compatíveis : {:?} e {:?}", left, right));
@@ -474,7 +1728,11 @@ impl VirtualMachine {
         match left {
             DynamicValue::Integer(l_i) => {
                 match right {
-                    DynamicValue::Integer(r_i) => Ok(DynamicValue::Integer(l_i + r_i)),
+                    DynamicValue::Integer(r_i) => match l_i.checked_add(r_i) {
+                        Some(v) => Ok(DynamicValue::Integer(v)),
+                        None => self.integer_overflow(MathOperator::Plus, l_i, r_i),
+                    },
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Plus, left, right),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number((l_i as f64) + r_n)),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
@@ -483,9 +1741,11 @@ impl VirtualMachine {
                 match right {
                     DynamicValue::Integer(r_i) => Ok(DynamicValue::Number(l_n + (r_i as f64))),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number(l_n + r_n)),
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Plus, left, right),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
             }
+            DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Plus, left, right),
             DynamicValue::Text(l_t) => {
                 match right {
                     DynamicValue::Text(r_t) => {
@@ -494,33 +1754,27 @@ impl VirtualMachine {
                         let mut result = String::new();
 
                         {
-                            let left_v = match self.special_storage.get_ref(r_t) {
+                            let left_v = match self.special_storage.get_ref(l_t) {
                                 Some(s) => match s {
                                     &SpecialItemData::Text(ref s) => s,
                                     _ => return Err(format!("Erro interno : DynamicValue é texto, mas o id aponta pra outra coisa"))
                                 },
-                                None => return Err(format!("Add w/ Text : Id {} não encontrada.", r_t))
+                                None => return Err(format!("Add w/ Text : Id {} não encontrada.", l_t))
                             };
 
-                            // remove right node
-                            let right_v = match self.special_storage.get_ref(l_t) {
+                            let right_v = match self.special_storage.get_ref(r_t) {
                                 Some(s) => match s {
                                     &SpecialItemData::Text(ref s) => s,
                                     _ => return Err(format!("Erro interno : DynamicValue é texto, mas o id aponta pra outra coisa"))
                                 },
-                                None => return Err(format!("Add w/ Text : Id {} não encontrada.", l_t))
+                                None => return Err(format!("Add w/ Text : Id {} não encontrada.", r_t))
                             };
 
-                            if self.registers.first_operation {
-                                result.push_str(right_v);
-                                result.push_str(left_v);
-
-                                self.registers.first_operation = false;
-                            } else {
-                                result.push_str(left_v);
-                                result.push_str(right_v);
+                            // Checked before building the text, so a text that keeps doubling fails before using the memory
+                            self.special_storage.check_room(left_v.len() + right_v.len(), 1)?;
 
-                            }
+                            result.push_str(left_v);
+                            result.push_str(right_v);
                         }
 
                         let parent_index = match self.get_last_ready_index() {
@@ -578,6 +1832,7 @@ impl VirtualMachine {
                 }
             }
             DynamicValue::Null => Ok(DynamicValue::Null),
+            DynamicValue::Coroutine(_) => Err("Operação não suportada em gaiolas".to_owned()),
         }
     }
 
@@ -589,7 +1844,11 @@ impl VirtualMachine {
         match left {
             DynamicValue::Integer(l_i) => {
                 match right {
-                    DynamicValue::Integer(r_i) => Ok(DynamicValue::Integer(l_i - r_i)),
+                    DynamicValue::Integer(r_i) => match l_i.checked_sub(r_i) {
+                        Some(v) => Ok(DynamicValue::Integer(v)),
+                        None => self.integer_overflow(MathOperator::Minus, l_i, r_i),
+                    },
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Minus, left, right),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number((l_i as f64) - r_n)),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
@@ -598,12 +1857,15 @@ impl VirtualMachine {
                 match right {
                     DynamicValue::Integer(r_i) => Ok(DynamicValue::Number(l_n - (r_i as f64))),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number(l_n - r_n)),
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Minus, left, right),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
             }
+            DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Minus, left, right),
             DynamicValue::Text(_) => return Err("Operação inválida em texto : -".to_owned()),
             DynamicValue::Null => Ok(DynamicValue::Null),
-            DynamicValue::List(_) => return Err("Operação não suportada em listas".to_owned())
+            DynamicValue::List(_) => Err("Operação não suportada em listas".to_owned()),
+            DynamicValue::Coroutine(_) => Err("Operação não suportada em gaiolas".to_owned()),
         }
     }
 
@@ -615,7 +1877,11 @@ impl VirtualMachine {
         match left {
             DynamicValue::Integer(l_i) => {
                 match right {
-                    DynamicValue::Integer(r_i) => Ok(DynamicValue::Integer(l_i * r_i)),
+                    DynamicValue::Integer(r_i) => match l_i.checked_mul(r_i) {
+                        Some(v) => Ok(DynamicValue::Integer(v)),
+                        None => self.integer_overflow(MathOperator::Multiplication, l_i, r_i),
+                    },
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Multiplication, left, right),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number((l_i as f64) * r_n)),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
@@ -624,12 +1890,15 @@ impl VirtualMachine {
                 match right {
                     DynamicValue::Integer(r_i) => Ok(DynamicValue::Number(l_n * (r_i as f64))),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number(l_n * r_n)),
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Multiplication, left, right),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
             }
+            DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Multiplication, left, right),
             DynamicValue::Text(_) => return Err("Operação inválida em texto : *".to_owned()),
             DynamicValue::Null => Ok(DynamicValue::Null),
-            DynamicValue::List(_) => return Err("Operação não suportada em listas".to_owned())
+            DynamicValue::List(_) => Err("Operação não suportada em listas".to_owned()),
+            DynamicValue::Coroutine(_) => Err("Operação não suportada em gaiolas".to_owned()),
         }
     }
 
@@ -641,7 +1910,12 @@ impl VirtualMachine {
         match left {
             DynamicValue::Integer(l_i) => {
                 match right {
-                    DynamicValue::Integer(r_i) => Ok(DynamicValue::Integer(l_i / r_i)),
+                    DynamicValue::Integer(0) => Err("Divisão por zero".to_owned()),
+                    DynamicValue::Integer(r_i) => match l_i.checked_div(r_i) {
+                        Some(v) => Ok(DynamicValue::Integer(v)),
+                        None => self.integer_overflow(MathOperator::Division, l_i, r_i),
+                    },
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Division, left, right),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number((l_i as f64) / r_n)),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
@@ -650,12 +1924,15 @@ impl VirtualMachine {
                 match right {
                     DynamicValue::Integer(r_i) => Ok(DynamicValue::Number(l_n / (r_i as f64))),
                     DynamicValue::Number(r_n) => Ok(DynamicValue::Number(l_n / r_n)),
+                    DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Division, left, right),
                     _ => return Err("Incompatível. Não deveria chegar aqui.".to_owned()),
                 }
             }
+            DynamicValue::BigInteger(_) => self.big_integer_operation(MathOperator::Division, left, right),
             DynamicValue::Text(_) => return Err("Operação inválida em texto : /".to_owned()),
             DynamicValue::Null => Ok(DynamicValue::Null),
-            DynamicValue::List(_) => return Err("Operação não suportada em listas".to_owned())
+            DynamicValue::List(_) => Err("Operação não suportada em listas".to_owned()),
+            DynamicValue::Coroutine(_) => Err("Operação não suportada em gaiolas".to_owned()),
         }
     }
 
@@ -694,6 +1971,7 @@ impl VirtualMachine {
                         }
                     }
                     DynamicValue::Number(r_n) => comp_numbers(l_i as f64, r_n),
+                    DynamicValue::BigInteger(_) => self.compare_big_integers(left, right)?,
                     _ => Comparision::NotEqual
                 }
             }
@@ -705,6 +1983,16 @@ impl VirtualMachine {
                     DynamicValue::Integer(r_i) => {
                         comp_numbers(l_n, r_i as f64)
                     }
+                    DynamicValue::BigInteger(id) => {
+                        comp_numbers(l_n, self.get_big_integer(id)?.to_f64())
+                    }
+                    _ => Comparision::NotEqual,
+                }
+            }
+            DynamicValue::BigInteger(id) => {
+                match right {
+                    DynamicValue::Integer(_) | DynamicValue::BigInteger(_) => self.compare_big_integers(left, right)?,
+                    DynamicValue::Number(r_n) => comp_numbers(self.get_big_integer(id)?.to_f64(), r_n),
                     _ => Comparision::NotEqual,
                 }
             }
@@ -784,11 +2072,29 @@ impl VirtualMachine {
                     _ => Comparision::NotEqual,
                 }
             }
+            // Only the same coroutine is equal
+            DynamicValue::Coroutine(left_id) => {
+                match right {
+                    DynamicValue::Coroutine(right_id) if left_id == right_id => Comparision::Equal,
+                    _ => Comparision::NotEqual,
+                }
+            }
         };
 
         Ok(comp)
     }
 
+    fn compare_big_integers(&self, left : DynamicValue, right : DynamicValue) -> Result<Comparision, String> {
+        let l = self.as_big_integer(left)?;
+        let r = self.as_big_integer(right)?;
+
+        match l.cmp(&r) {
+            ::std::cmp::Ordering::Equal => Ok(Comparision::Equal),
+            ::std::cmp::Ordering::Less => Ok(Comparision::LessThan),
+            ::std::cmp::Ordering::Greater => Ok(Comparision::MoreThan),
+        }
+    }
+
     fn set_last_comparision(&mut self, comp : Comparision) -> Result<(), String> {
         if self.callstack.is_empty() {
             return Err("Callstack tá vazia. Provavelmente é erro interno".to_owned());
@@ -837,24 +2143,6 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn increase_skip_level(&mut self) -> Result<(), String> {
-        match self.get_last_ready_mut() {
-            Some(f) => f.skip_level += 1,
-            None => return Err("Nenhuma função ready em execução".to_owned())
-        }
-
-        Ok(())
-    }
-
-    fn decrease_skip_level(&mut self) -> Result<(), String> {
-        match self.get_last_ready_mut() {
-            Some(f) => f.skip_level -= 1,
-            None => return Err("Nenhuma função ready em execução".to_owned())
-        }
-
-        Ok(())
-    }
-
     fn read_from_id(&mut self, index : usize, address : usize) -> Result<DynamicValue, String> {
         if self.callstack.len() < index {
             return Err(format!("Index out of bounds for read : {}", index));
@@ -922,7 +2210,9 @@ impl VirtualMachine {
             }
             DynamicValue::Integer(i) => Ok(format!("{}", i)),
             DynamicValue::Number(n) => Ok(format!("{}", n)),
+            DynamicValue::BigInteger(id) => Ok(self.get_big_integer(id)?.to_string()),
             DynamicValue::Null => Ok(String::from("<Null>")),
+            DynamicValue::Coroutine(_) => Ok(String::from("<Gaiola>")),
             DynamicValue::List(id) => {
                 let list = match self.special_storage.get_ref(id) {
                     Some(SpecialItemData::List(ref list)) => list.clone(),
@@ -967,7 +2257,53 @@ impl VirtualMachine {
         }
     }
 
-    fn conv_to_int(&mut self, val : DynamicValue) -> Result<IntegerType, String> {
+    fn format_value(&mut self, val : DynamicValue, format : Option<&FormatSpec>) -> Result<String, String> {
+        let spec = match format {
+            Some(s) => s,
+            None => return self.conv_to_string(val),
+        };
+
+        self.special_storage.check_room(spec.reserved_bytes(), 0)?;
+
+        match val {
+            DynamicValue::Integer(i) => spec.format_integer(i),
+            DynamicValue::Number(n) => spec.format_number(n),
+            DynamicValue::BigInteger(id) => spec.format_big_integer(&self.get_big_integer(id)?),
+            _ => {
+                let text = self.conv_to_string(val)?;
+
+                spec.format_text(text.as_str())
+            }
+        }
+    }
+
+    /// Formats the values according to a printf-style template (see formatting::Template)
+    pub fn format_template(&mut self, template : &str, arguments : &[DynamicValue]) -> Result<String, String> {
+        let template = Template::parse(template)?;
+
+        if template.num_arguments != arguments.len() {
+            return Err(format!("O formato usa {} argumentos, mas {} foram passados", template.num_arguments, arguments.len()));
+        }
+
+        let mut result = String::new();
+
+        for piece in template.pieces {
+            match piece {
+                TemplatePiece::Literal(text) => result.push_str(text.as_str()),
+                TemplatePiece::Argument(index, spec) => {
+                    self.special_storage.check_room(result.len().saturating_add(spec.reserved_bytes()), 0)?;
+
+                    let text = self.format_value(arguments[index], Some(&spec))?;
+
+                    result.push_str(text.as_str());
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn conv_to_int(&mut self, val : DynamicValue) -> Result<DynamicValue, String> {
         match val {
             DynamicValue::Text(t) => {
                 let text = match self.special_storage.get_ref(t) {
@@ -978,17 +2314,39 @@ impl VirtualMachine {
                     None => return Err("Invalid text id".to_owned())
                 };
 
-                let i = match text.parse::<IntegerType>() {
-                    Ok(i) => i,
-                    Err(_) => return Err(format!("Não foi possível converter \"{}\" pra Int", text))
-                };
+                let error = format!("Não foi possível converter \"{}\" pra Int", text);
+
+                match text.trim().parse::<IntegerType>() {
+                    Ok(i) => Ok(DynamicValue::Integer(i)),
+                    Err(_) if self.registers.big_integers => match BigInteger::parse(text.trim()) {
+                        Some(b) => self.store_big_integer(b),
+                        None => Err(error),
+                    },
+                    Err(_) => Err(error),
+                }
+            }
+            DynamicValue::Number(n) => {
+                if n.is_nan() || n.is_infinite() {
+                    return Err(format!("Não foi possível converter {} pra Int", n));
+                }
 
-                Ok(i)
+                if n >= IntegerType::MIN as f64 && n < -(IntegerType::MIN as f64) {
+                    Ok(DynamicValue::Integer(n as IntegerType))
+                } else if self.registers.big_integers {
+                    // Goes through the text representation so no digits are lost
+                    match BigInteger::parse(format!("{:.0}", n.trunc()).as_str()) {
+                        Some(b) => self.store_big_integer(b),
+                        None => Err(format!("Não foi possível converter {} pra Int", n)),
+                    }
+                } else {
+                    Err(format!("Estouro de inteiro : {} não cabe em um Int", n))
+                }
             }
-            DynamicValue::Number(n) => Ok(n as IntegerType),
-            DynamicValue::Integer(i) => Ok(i),
+            DynamicValue::Integer(i) => Ok(DynamicValue::Integer(i)),
+            DynamicValue::BigInteger(id) => Ok(DynamicValue::BigInteger(id)),
             DynamicValue::Null => return Err("Convert : <Null>".to_owned()),
-            DynamicValue::List(_) => return Err("Não é possível converter uma lista pra inteiro".to_owned())
+            DynamicValue::List(_) => Err("Não é possível converter uma lista pra inteiro".to_owned()),
+            DynamicValue::Coroutine(_) => Err("Não é possível converter uma gaiola pra inteiro".to_owned()),
         }
     }
 
@@ -1012,8 +2370,10 @@ impl VirtualMachine {
             }
             DynamicValue::Number(n) => Ok(n),
             DynamicValue::Integer(i) => Ok(i as f64),
+            DynamicValue::BigInteger(id) => Ok(self.get_big_integer(id)?.to_f64()),
             DynamicValue::Null => return Err("Convert : <Null>".to_owned()),
-            DynamicValue::List(_) => return Err("Não é possível converter uma lista pra número".to_owned())
+            DynamicValue::List(_) => Err("Não é possível converter uma lista pra número".to_owned()),
+            DynamicValue::Coroutine(_) => Err("Não é possível converter uma gaiola pra número".to_owned()),
         }
     }
 
@@ -1047,13 +2407,22 @@ impl VirtualMachine {
     }
 
     pub fn print_string(&mut self, s : &str) -> Result<(), String> {
+        self.check_capability(Capability::Stdout)?;
+
         vm_write!(self.stdout, "{}", s)
     }
 
     pub fn print_value(&mut self, val : DynamicValue) -> Result<(), String> {
+        self.check_capability(Capability::Stdout)?;
+
         match val {
             DynamicValue::Integer(i) => vm_write!(self.stdout, "{}", i)?,
             DynamicValue::Number(n) => vm_write!(self.stdout, "{}", n)?,
+            DynamicValue::BigInteger(id) => {
+                let value = self.get_big_integer(id)?;
+
+                vm_write!(self.stdout, "{}", value)?
+            }
             DynamicValue::Text(t) => {
                 let t = match self.special_storage.get_ref(t) {
                     Some(s) => match s {
@@ -1073,26 +2442,97 @@ impl VirtualMachine {
                 vm_write!(self.stdout, "(Lista) {}", string)?;
             }
             DynamicValue::Null => vm_write!(self.stdout, "<Null>")?,
+            DynamicValue::Coroutine(_) => vm_write!(self.stdout, "<Gaiola>")?,
         }
 
         Ok(())
     }
 
-    pub fn run(&mut self, inst : Instruction) -> Result<ExecutionStatus, String> {
-        if self.get_current_skip_level() > 0 {
-            if let Instruction::EndConditionalBlock = inst {
-                self.decrease_skip_level()?;
-            }
+    fn pop_operand(&mut self) -> Result<DynamicValue, String> {
+        match self.operand_stack.pop() {
+            Some(v) => Ok(v),
+            None => Err("Erro interno : A pilha de operandos está vazia".to_owned()),
+        }
+    }
+
+    fn peek_operand(&self) -> Result<DynamicValue, String> {
+        match self.operand_stack.last() {
+            Some(v) => Ok(*v),
+            None => Err("Erro interno : A pilha de operandos está vazia".to_owned()),
+        }
+    }
+
+    // Pops the two sides of a binary operation, returning them as (left, right)
+    fn pop_operand_pair(&mut self) -> Result<(DynamicValue, DynamicValue), String> {
+        let right = self.pop_operand()?;
+        let left = self.pop_operand()?;
+
+        Ok((left, right))
+    }
+
+    fn pop_coroutine_id(&mut self) -> Result<u64, String> {
+        match self.pop_operand()? {
+            DynamicValue::Coroutine(id) => Ok(id),
+            v => Err(format!("Tipo incompatível : Esperado uma gaiola, encontrado {}", self.kind_name(v))),
+        }
+    }
+
+    fn pop_list_id(&mut self) -> Result<u64, String> {
+        match self.pop_operand()? {
+            DynamicValue::List(id) => Ok(id),
+            _ => Err("A variável não é uma lista".to_owned()),
+        }
+    }
+
+    fn pop_list_index(&mut self) -> Result<usize, String> {
+        match self.pop_operand()? {
+            DynamicValue::Integer(i) => list_index(i),
+            v => Err(format!("Esperado um índice na forma de um inteiro, encontrado {:?}", v)),
+        }
+    }
+
+    // Id of the special item holding a text from the current function's pool, adding it the first time
+    fn text_constant(&mut self, index : usize) -> Result<u64, String> {
+        let id = match self.get_current_id() {
+            Some(i) => i,
+            None => return Err("Nenhuma função em execução".to_owned())
+        };
+
+        if let Some(&Some(item)) = self.text_constants[id].get(index) {
+            return Ok(item);
+        }
 
-            return Ok(ExecutionStatus::Normal);
+        let text = match self.code[id].texts.get(index) {
+            Some(t) => t.clone(),
+            None => return Err(format!("Erro interno : Texto constante {} não existe", index))
+        };
+
+        let item = self.special_storage.add_constant(SpecialItemData::Text(text))?;
+
+        let constants = &mut self.text_constants[id];
+
+        if constants.len() <= index {
+            constants.resize(index + 1, None);
         }
 
-        match inst {
-            Instruction::EndConditionalBlock => {},
-            Instruction::PrintMathBDebug => {
-                match self.registers.math_b {
+        constants[index] = Some(item);
+
+        Ok(item)
+    }
+
+    pub fn run(&mut self, inst : &Instruction) -> Result<ExecutionStatus, String> {
+        match *inst {
+            Instruction::PrintValueDebug => {
+                self.check_capability(Capability::Stdout)?;
+
+                match self.pop_operand()? {
                     DynamicValue::Integer(i) => vm_write!(self.stdout, "(Integer) {}\n", i)?,
                     DynamicValue::Number(n) => vm_write!(self.stdout, "(Number) {}\n", n)?,
+                    DynamicValue::BigInteger(id) => {
+                        let value = self.get_big_integer(id)?;
+
+                        vm_write!(self.stdout, "(Integer) {}\n", value)?
+                    }
                     DynamicValue::Text(t) => {
                         let t = match self.special_storage.get_ref(t) {
                             Some(s) => match s {
@@ -1105,6 +2545,7 @@ impl VirtualMachine {
                         vm_write!(self.stdout, "(Text) \"{}\"\n", t)?
                     }
                     DynamicValue::Null => vm_write!(self.stdout, "<Null>\n")?,
+                    DynamicValue::Coroutine(_) => vm_write!(self.stdout, "<Gaiola>\n")?,
                     DynamicValue::List(id) => {
                         let string = match self.conv_to_string(DynamicValue::List(id)) {
                             Ok(s) => s,
@@ -1116,12 +2557,14 @@ impl VirtualMachine {
 
                 self.flush_stdout();
             }
-            Instruction::PrintMathB => {
-                let val = self.registers.math_b;
+            Instruction::PrintValue => {
+                let val = self.pop_operand()?;
 
                 self.print_value(val)?;
             }
             Instruction::PrintNewLine => {
+                self.check_capability(Capability::Stdout)?;
+
                 vm_write!(self.stdout, "\n")?
             }
             Instruction::Quit => {
@@ -1133,10 +2576,9 @@ impl VirtualMachine {
                 self.flush_stdout();
             }
             Instruction::Compare => {
-                let result = match self.compare(self.registers.math_a, self.registers.math_b) {
-                    Ok(c) => c,
-                    Err(e) => return Err(e),
-                };
+                let (left, right) = self.pop_operand_pair()?;
+
+                let result = self.compare(left, right)?;
 
                 match self.set_last_comparision(result) {
                     Ok(_) => {}
@@ -1150,13 +2592,48 @@ impl VirtualMachine {
                     return Ok(ExecutionStatus::Quit);
                 }
 
+                let base = match self.get_last_ready_ref() {
+                    Some(f) => f.operand_base,
+                    None => return Err("Erro no return : Nenhuma função em execução".to_owned())
+                };
+
+                // A function that ends without BIRL returns nothing
+                let value = if self.operand_stack.len() > base {
+                    self.pop_operand()?
+                } else {
+                    DynamicValue::Null
+                };
+
+                self.operand_stack.truncate(base);
+
+                // The returned value may live in the storage of the frame being removed, so copy it before
+                let returned = self.detach_value(value)?;
+                let ends_coroutine = match self.running_coroutines.last() {
+                    Some(&(_, first)) => first == self.callstack.len() - 1,
+                    None => false,
+                };
+
                 match self.callstack.pop() {
                     Some(frame) => self.special_storage.remove_top(frame.num_special_items)?,
                     None => return Err("Erro no return : Nenhuma função em execução".to_owned())
                 }
 
                 let index = self.callstack.len() - 1;
-                let val = self.registers.math_b;
+                let val = self.attach_value(index, returned)?;
+
+                // What a coroutine returns is the last value it gives, to whoever resumed it
+                if ends_coroutine {
+                    if let Some((id, _)) = self.running_coroutines.pop() {
+                        if let Some(SpecialItemData::Coroutine(ref mut c)) = self.special_storage.get_mut(id) {
+                            c.running = false;
+                            c.done = true;
+                        }
+                    }
+
+                    self.operand_stack.push(val);
+
+                    return Ok(ExecutionStatus::Returned);
+                }
                 match self.write_to(val, index, 0) {
                     Ok(_) => {}
                     Err(e) => return Err(e)
@@ -1164,33 +2641,71 @@ impl VirtualMachine {
 
                 return Ok(ExecutionStatus::Returned);
             }
-            Instruction::ExecuteIf(req) => {
-                if self.get_current_skip_level() > 0 {
-                    self.increase_skip_level()?;
-                } else {
-                    if ! self.last_comparision_matches(req)? {
-                        self.increase_skip_level()?;
-                    }
+            Instruction::Jump(address) => {
+                self.set_current_pc(address)?;
+            }
+            Instruction::JumpIfNot(req, address) => {
+                if ! self.last_comparision_matches(req)? {
+                    self.set_current_pc(address)?;
                 }
             }
             Instruction::MakeNewFrame(id) => {
                 // Add a new, not ready frame to the callstack
 
-                let frame = FunctionFrame::new(id, self.registers.default_stack_size);
+                if self.callstack.len() > self.registers.max_call_depth {
+                    return Err(self.stack_overflow(id));
+                }
+
+                let frame = FunctionFrame::new(id, self.get_frame_size(id));
 
                 self.callstack.push(frame);
             }
             Instruction::SetLastFrameReady => {
                 // Set the last frame to ready
 
-                if ! self.callstack.is_empty() {
-                    self.callstack.last_mut().unwrap().ready = true;
-                } else {
-                    return Err("Callstack vazia".to_owned());
+                let base = self.operand_stack.len();
+
+                match self.callstack.last_mut() {
+                    Some(f) => {
+                        f.ready = true;
+                        f.operand_base = base;
+                    }
+                    None => return Err("Callstack vazia".to_owned()),
+                }
+            }
+            Instruction::TailCall => {
+                // The frame being prepared takes the place of the current one, since there's nothing
+                // left to do in it. Its return value goes straight to the caller of the current function
+
+                if self.callstack.len() < 3 {
+                    return Err("TailCall : Não há uma função em execução pra ser substituída".to_owned());
                 }
+
+                let mut frame = self.callstack.pop().unwrap();
+
+                if frame.ready {
+                    return Err("TailCall : O último frame já está pronto".to_owned());
+                }
+
+                let current = self.callstack.pop().unwrap();
+
+                // Special items of the current frame that were passed as arguments must stay alive
+                let mut used = HashSet::new();
+
+                for val in &frame.stack {
+                    self.collect_special_ids(*val, &mut used);
+                }
+
+                let total = current.num_special_items + frame.num_special_items;
+                frame.num_special_items = self.special_storage.retain_top(total, &used)?;
+                frame.ready = true;
+                frame.operand_base = current.operand_base;
+
+                self.operand_stack.truncate(current.operand_base);
+                self.callstack.push(frame);
             }
-            Instruction::AssertMathBCompatible(kind) => {
-                let v = self.registers.math_b;
+            Instruction::AssertCompatible(kind) => {
+                let v = self.peek_operand()?;
 
                 match v {
                     DynamicValue::Null => return Err("Tipo incompatível : Null".to_owned()),
@@ -1201,7 +2716,7 @@ impl VirtualMachine {
                             return Err("Tipo incompatível : Texto".to_owned());
                         }
                     }
-                    DynamicValue::Integer(_) => {
+                    DynamicValue::Integer(_) | DynamicValue::BigInteger(_) => {
                         if kind == TypeKind::Integer || kind == TypeKind::Number {
                             // Ok
                         } else {
@@ -1222,9 +2737,20 @@ impl VirtualMachine {
                             return Err("Tipo incompatível : Lista".to_owned());
                         }
                     }
+                    DynamicValue::Coroutine(_) => {
+                        if kind != TypeKind::Coroutine {
+                            return Err("Tipo incompatível : Gaiola".to_owned());
+                        }
+                    }
                 }
             }
             Instruction::ReadInput => {
+                self.check_capability(Capability::Stdin)?;
+
+                if self.registers.suspend_on_input {
+                    return Ok(self.suspend_with(Suspension::Input));
+                }
+
                 let line = if let Some(ref mut input) = self.stdin.as_mut(){
                     let mut line = String::new();
                     match input.read_line(&mut line) {
@@ -1243,37 +2769,41 @@ impl VirtualMachine {
                     None => return Err("Nenhuma função em execução".to_owned())
                 };
 
-                if let Some(line) = line {
+                let value = if let Some(line) = line {
                     let id = match self.add_special_item(parent_index, SpecialItemData::Text(line)) {
                         Ok(id) => id,
                         Err(e) => return Err(e)
                     };
 
-                    self.registers.intermediate = DynamicValue::Text(id);
-                }
+                    DynamicValue::Text(id)
+                } else {
+                    DynamicValue::Null
+                };
+
+                self.operand_stack.push(value);
             }
             Instruction::ConvertToNum => {
-                let val = self.registers.math_b;
+                let val = self.pop_operand()?;
 
                 let v = match self.conv_to_num(val) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = DynamicValue::Number(v);
+                self.operand_stack.push(DynamicValue::Number(v));
             }
             Instruction::ConvertToInt => {
-                let val = self.registers.math_b;
+                let val = self.pop_operand()?;
 
                 let v = match self.conv_to_int(val) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = DynamicValue::Integer(v);
+                self.operand_stack.push(v);
             }
             Instruction::ConvertToString => {
-                let val = self.registers.math_b;
+                let val = self.pop_operand()?;
 
                 let id = if let DynamicValue::Text(id) = val {
                     id
@@ -1294,37 +2824,32 @@ impl VirtualMachine {
                     }
                 };
 
-                self.registers.math_b = DynamicValue::Text(id);
-            }
-            Instruction::PushValMathA(val) => {
-                match self.raw_to_dynamic(val) {
-                    Ok(v) => self.registers.math_a = v,
-                    Err(e) => return Err(e)
-                }
+                self.operand_stack.push(DynamicValue::Text(id));
             }
-            Instruction::PushValMathB(val) => {
+            Instruction::PushValue(ref val) => {
                 match self.raw_to_dynamic(val) {
-                    Ok(v) => self.registers.math_b = v,
+                    Ok(v) => self.operand_stack.push(v),
                     Err(e) => return Err(e)
                 }
             }
-            Instruction::PushIntermediateToA => {
-                self.registers.math_a = self.registers.intermediate;
-            }
-            Instruction::PushIntermediateToB => {
-                self.registers.math_b = self.registers.intermediate;
-            }
             Instruction::ReadGlobalVarFrom(addr) => {
                 let val = match self.read_from_id(0, addr) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.intermediate = val;
+                self.operand_stack.push(val);
             }
             Instruction::WriteGlobalVarTo(addr) => {
                 let index = 0;
-                let val = self.registers.math_b;
+                let val = self.pop_operand()?;
+
+                // Only the global code itself can keep a coroutine it made in a global, since the others can't use it
+                if let DynamicValue::Coroutine(_) = val {
+                    if self.callstack.len() > 1 {
+                        return Err("Tipo incompatível : Uma gaiola não pode ser guardada numa variável global".to_owned());
+                    }
+                }
 
                 match self.write_to(val, index, addr) {
                     Ok(_) => {}
@@ -1342,7 +2867,7 @@ impl VirtualMachine {
                     Err(e) => return Err(e)
                 };
 
-                self.registers.intermediate = val;
+                self.operand_stack.push(val);
             }
             Instruction::WriteVarTo(addr) => {
                 let index = match self.get_last_ready_index() {
@@ -1350,7 +2875,7 @@ impl VirtualMachine {
                     None => return Err("Nenhuma função pronta em execução".to_owned()),
                 };
 
-                let val = self.registers.math_b;
+                let val = self.pop_operand()?;
 
                 match self.write_to(val, index, addr) {
                     Ok(_) => {}
@@ -1359,7 +2884,7 @@ impl VirtualMachine {
             }
             Instruction::WriteVarToLast(addr) => {
                 let index = self.callstack.len() - 1;
-                let val = self.registers.math_b;
+                let val = self.pop_operand()?;
 
                 match self.write_to(val, index, addr) {
                     Ok(_) => {}
@@ -1367,136 +2892,57 @@ impl VirtualMachine {
                 }
             }
             Instruction::Add => {
-                let left = self.registers.math_a;
-                let right = self.registers.math_b;
+                let (left, right) = self.pop_operand_pair()?;
                 let res = match self.add_values(left, right) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = res;
+                self.operand_stack.push(res);
             }
             Instruction::Mul => {
-                let left = self.registers.math_a;
-                let right = self.registers.math_b;
+                let (left, right) = self.pop_operand_pair()?;
                 let res = match self.mul_values(left, right) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = res;
+                self.operand_stack.push(res);
             }
             Instruction::Div => {
-                let left = self.registers.math_a;
-                let right = self.registers.math_b;
+                let (left, right) = self.pop_operand_pair()?;
                 let res = match self.div_values(left, right) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = res;
+                self.operand_stack.push(res);
             }
             Instruction::Sub => {
-                let left = self.registers.math_a;
-                let right = self.registers.math_b;
+                let (left, right) = self.pop_operand_pair()?;
                 let res = match self.sub_values(left, right) {
                     Ok(v) => v,
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = res;
+                self.operand_stack.push(res);
             }
-            Instruction::SwapMath => {
-                let tmp = self.registers.math_b;
-                self.registers.math_b = self.registers.math_a;
-                self.registers.math_a = tmp;
-            }
-            Instruction::ClearMath => {
-                self.registers.math_a = DynamicValue::Null;
-                self.registers.math_b = DynamicValue::Null;
-                self.registers.intermediate = DynamicValue::Null;
-            }
-            Instruction::AddLoopLabel => {
-                let next_pc = match self.get_current_pc() {
-                    Some(p) => p,
-                    None => return Err("Nenhuma função em execução".to_owned())
-                };
+            Instruction::BitwiseAnd | Instruction::BitwiseOr | Instruction::BitwiseXor |
+            Instruction::ShiftLeft | Instruction::ShiftRight => {
+                let (left, right) = self.pop_operand_pair()?;
+                let res = self.bitwise_operation(inst, left, right)?;
 
-                match self.get_last_ready_mut() {
-                    Some(f) => f.label_stack.push(LoopLabel::new(next_pc)),
-                    None => return Err("Nenhuma função em execução".to_owned())
-                }
+                self.operand_stack.push(res);
             }
-            Instruction::RestoreLoopLabel => {
-                let (mut address, mut step) = (None, DynamicValue::Null);
-
-                let pc = match self.get_last_ready_ref() {
-                    Some(f) => {
-                        let label = match f.label_stack.last() {
-                            Some(l) => l,
-                            None => return Err("Restore : Nenhuma label disponível".to_owned())
-                        };
-
-                        if let Some(addr) = label.index_address {
-                            address = Some(addr);
-                            step = label.stepping;
-                        }
+            Instruction::BitwiseNot => {
+                let val = self.pop_operand()?;
 
-                        label.start_pc
-                    }
-                    None => return Err("Nenhuma função em execução".to_owned())
+                let res = match val {
+                    DynamicValue::Integer(i) => DynamicValue::Integer(!i),
+                    _ => return Err(format!("O operador ~ só funciona com Inteiros, mas foi usado com {}", self.kind_name(val))),
                 };
 
-                self.set_current_pc(pc)?;
-
-                if let Some(address) = address {
-                    let index = match self.get_last_ready_index() {
-                        Some(i) => i,
-                        None => return Err("Nenhuma função pronta em execução".to_owned()),
-                    };
-
-                    let current = self.read_from_id(index, address)?;
-
-                    let result = self.add_values(current, step)?;
-
-                    match self.write_to(result, index, address) {
-                        Ok(_) => {}
-                        Err(e) => return Err(e)
-                    }
-                }
-            }
-            Instruction::PopLoopLabel => {
-                match self.get_last_ready_mut() {
-                    Some(f) => {
-                        match f.label_stack.pop() {
-                            Some(_) => {}
-                            None => return Err("Não havia nenhuma label pra remover".to_owned())
-                        }
-                    }
-                    None => return Err("Nenhuma função em execução".to_owned())
-                }
-            }
-            Instruction::RegisterIncrementOnRestore(address) => {
-                // Since this instruction is right after AddLabel, this is going to be executed each iteration
-                // and since we don't want that, we'll also increment the PC on the label
-
-                let stepping = self.registers.math_b;
-
-                match self.get_last_ready_mut() {
-                    Some(s) => match s.label_stack.last_mut() {
-                        Some(l) => {
-                            l.stepping = stepping;
-                            l.index_address = Some(address);
-                            // As explained above
-                            l.start_pc += 1;
-                        }
-                        None => return Err("Função atual não tem nenhuma label".to_owned()),
-                    }
-                    None => return Err("Nenhuma função em execução".to_owned())
-                };
-            }
-            Instruction::SetFirstExpressionOperation => {
-                self.registers.first_operation = true;
+                self.operand_stack.push(res);
             }
             Instruction::MakeNewList => {
                 let index = match self.get_last_ready_index() {
@@ -1509,106 +2955,80 @@ impl VirtualMachine {
                     Err(e) => return Err(e)
                 };
 
-                self.registers.math_b = DynamicValue::List(data);
+                self.operand_stack.push(DynamicValue::List(data));
             }
             Instruction::IndexList => {
-                let index = if let DynamicValue::Integer(i) = self.registers.math_b {
-                    i
-                } else {
-                    return Err(format!("Esperado um índice na forma de um inteiro, encontrado {:?}", self.registers.math_b))
-                };
-
-                let value = {
-                    if let DynamicValue::List(id) = self.registers.intermediate {
-                        match self.special_storage.get_ref(id) {
-                            Some(SpecialItemData::List(ref d)) => {
-                                if index as usize >= d.len() {
-                                    return Err(format!("Erro : Index depois do final da lista. Tamanho da lista : {}", d.len()));
-                                }
+                let index = self.pop_list_index()?;
+                let id = self.pop_list_id()?;
 
-                                *d[index as usize]
-                            }
-                            Some(_) => return Err(format!("Erro interno : DynamicValue é uma lista, mas o item na memória não")),
-                            None => return Err("Erro interno : ID inválida".to_owned())
+                let value = match self.special_storage.get_ref(id) {
+                    Some(SpecialItemData::List(ref d)) => {
+                        if index >= d.len() {
+                            return Err(format!("Erro : Index depois do final da lista. Tamanho da lista : {}", d.len()));
                         }
-                    } else {
-                        return Err(format!("Variável passada não é uma lista"));
+
+                        *d[index]
                     }
+                    Some(_) => return Err("Erro interno : DynamicValue é uma lista, mas o item na memória não".to_owned()),
+                    None => return Err("Erro interno : ID inválida".to_owned())
                 };
 
-                self.registers.math_b = value;
+                self.operand_stack.push(value);
             }
-            Instruction::AddToListAtIndex => {
-                let index = if let DynamicValue::Integer(val) = self.registers.secondary {
-                    Some(val)
+            Instruction::AddToList | Instruction::InsertIntoList => {
+                let value = self.pop_operand()?;
+
+                if let DynamicValue::Coroutine(_) = value {
+                    return Err("Tipo incompatível : Uma gaiola não pode ser posta numa lista".to_owned());
+                }
+
+                let index = if let Instruction::InsertIntoList = *inst {
+                    Some(self.pop_list_index()?)
                 } else {
                     None
                 };
 
-                let value = self.registers.math_b;
+                let list_id = self.pop_list_id()?;
 
-                let list_id = if let DynamicValue::List(id) = self.registers.intermediate {
-                    id
-                } else {
-                    return Err(format!("AddListToIndex : A variável não é uma lista"));
-                };
+                self.special_storage.check_room(LIST_ELEMENT_SIZE, 0)?;
 
-                let list = match self.special_storage.get_mut(list_id) {
-                    Some(l) => match l {
-                        SpecialItemData::List(ref mut list) => list,
-                        _ => return Err("Item especial com a ID passada não é uma lista".to_owned())
-                    }
-                    None => return Err("ID da lista não encontrada".to_owned())
-                };
+                {
+                    let list = match self.special_storage.get_mut(list_id) {
+                        Some(l) => match l {
+                            SpecialItemData::List(ref mut list) => list,
+                            _ => return Err("Item especial com a ID passada não é uma lista".to_owned())
+                        }
+                        None => return Err("ID da lista não encontrada".to_owned())
+                    };
 
-                if let Some(i) = index {
-                    if i as usize >= list.len() {
-                        list.push(Box::new(value));
-                    } else {
-                        list.insert(i as usize, Box::new(value));
+                    match index {
+                        Some(i) if i < list.len() => list.insert(i, Box::new(value)),
+                        _ => list.push(Box::new(value)),
                     }
-                } else {
-                    list.push(Box::new(value));
                 }
-            }
-            Instruction::ClearSecondary => {
-                self.registers.secondary = DynamicValue::Null;
-            }
-            Instruction::PushMathBToSeconday => {
-                let val = self.registers.math_b;
-                self.registers.secondary = val;
+
+                self.special_storage.grow(LIST_ELEMENT_SIZE);
             }
             Instruction::RemoveFromListAtIndex => {
-                let index = if let DynamicValue::Integer(i) = self.registers.math_b {
-                    i
-                } else {
-                    return Err(format!("Esperado um inteiro como índice pra lista, encontrado {:?}", self.registers.math_b));
-                };
-
-                let id = if let DynamicValue::List(id) = self.registers.intermediate {
-                    id
-                } else {
-                    return Err("A variável não é uma lista".to_owned());
-                };
+                let index = self.pop_list_index()?;
+                let id = self.pop_list_id()?;
 
                 match self.special_storage.get_mut(id) {
                     Some(SpecialItemData::List(ref mut list)) => {
-                        if index as usize >= list.len() {
+                        if index >= list.len() {
                             return Err(format!("Erro : Index maior que a lista. Tamanho da lista : {}", list.len()));
                         }
 
-                        list.remove(index as usize);
+                        list.remove(index);
                     }
                     Some(_) => return Err("Erro interno : DynamicValue é uma lista mas o valor na memória não".to_owned()),
                     None => return Err("Erro interno : ID não encontrada".to_owned())
                 }
+
+                self.special_storage.shrink(LIST_ELEMENT_SIZE);
             }
             Instruction::QueryListSize => {
-                let id = if let DynamicValue::List(id) = self.registers.intermediate {
-                    id
-                } else {
-                    return Err("QueryListSize : Variável não é uma lista".to_owned());
-                };
+                let id = self.pop_list_id()?;
 
                 let list = match self.special_storage.get_ref(id) {
                     Some(l) => match l {
@@ -1620,43 +3040,225 @@ impl VirtualMachine {
 
                 let val = DynamicValue::Integer(list.len() as IntegerType);
 
-                self.registers.math_b = val;
+                self.operand_stack.push(val);
             }
             Instruction::CallPlugin(address, num) => {
-                if address > self.plugins.len() {
+                if address >= self.plugins.len() {
                     return Err("CallPlugin : Endereço inválido".to_owned());
                 }
 
-                let plugin = self.plugins[address];
-
-                if num > self.plugin_argument_stack.len() {
+                if num > self.operand_stack.len() {
                     return Err(format!("CallPlugin : Número de argumentos maior que a quantidade de argumentos disponíveis"));
                 }
 
-                let mut args = Vec::with_capacity(num);
+                let mut plugin = match self.plugins[address].take() {
+                    Some(p) => p,
+                    None => return Err("CallPlugin : O plugin chamou ele mesmo".to_owned()),
+                };
 
-                for _ in 0..num {
-                    let val = match self.plugin_argument_stack.pop() {
-                        Some(v) => v,
-                        None => unreachable!()
-                    };
+                let start = self.operand_stack.len() - num;
+                let args = self.operand_stack.split_off(start);
 
-                    args.push(val);
-                }
+                let result = plugin(args, self);
+                self.plugins[address] = Some(plugin);
 
-                let result = plugin(args, self)?;
+                let request = self.registers.suspend_request.take();
 
-                if let Some(value) = result {
+                if let Some(value) = result? {
                     let index = self.callstack.len() - 1;
                     self.write_to(value, index, 0)?;
                 }
+
+                if let Some(value) = request {
+                    return Ok(self.suspend_with(Suspension::Plugin(value)));
+                }
+            }
+            Instruction::MakeCoroutine => {
+                // The frame prepared by the call doesn't run now, it's kept in the coroutine along with its items
+
+                let mut frame = match self.callstack.pop() {
+                    Some(f) => f,
+                    None => return Err("Callstack vazia".to_owned()),
+                };
+
+                if frame.ready || self.callstack.is_empty() {
+                    return Err("MakeCoroutine : Nenhuma função sendo preparada".to_owned());
+                }
+
+                frame.ready = true;
+                frame.operand_base = 0;
+
+                let items = self.special_storage.take_top(frame.num_special_items)?;
+                let coroutine = Coroutine { frames : vec![frame], items, operands : vec![], running : false, done : false };
+
+                let index = self.callstack.len() - 1;
+                let id = self.add_special_item(index, SpecialItemData::Coroutine(coroutine))?;
+                self.write_to(DynamicValue::Coroutine(id), index, 0)?;
+            }
+            Instruction::ResumeCoroutine => {
+                let id = self.pop_coroutine_id()?;
+
+                let num_frames = match self.special_storage.get_ref(id) {
+                    Some(SpecialItemData::Coroutine(ref c)) if c.done => return Err("Erro : A gaiola já acabou".to_owned()),
+                    Some(SpecialItemData::Coroutine(ref c)) if c.running => {
+                        return Err("Erro : A gaiola já está rodando".to_owned())
+                    }
+                    Some(SpecialItemData::Coroutine(ref c)) => c.frames.len(),
+                    _ => return Err("Erro interno : ID inválida pra gaiola".to_owned()),
+                };
+
+                if self.callstack.len() + num_frames > self.registers.max_call_depth + 1 {
+                    let code = match self.special_storage.get_ref(id) {
+                        Some(SpecialItemData::Coroutine(ref c)) => c.frames.first().map(|f| f.id).unwrap_or(0),
+                        _ => 0,
+                    };
+
+                    return Err(self.stack_overflow(code));
+                }
+
+                let (frames, operands) = match self.special_storage.get_mut(id) {
+                    Some(SpecialItemData::Coroutine(ref mut c)) => {
+                        c.running = true;
+                        (::std::mem::take(&mut c.frames), ::std::mem::take(&mut c.operands))
+                    }
+                    _ => return Err("Erro interno : ID inválida pra gaiola".to_owned()),
+                };
+
+                self.special_storage.unpark(id)?;
+                self.running_coroutines.push((id, self.callstack.len()));
+
+                let base = self.operand_stack.len();
+
+                for mut frame in frames {
+                    frame.operand_base += base;
+                    self.callstack.push(frame);
+                }
+
+                self.operand_stack.extend(operands);
+            }
+            Instruction::Yield => {
+                let (id, first) = match self.running_coroutines.last() {
+                    Some(&r) => r,
+                    None => return Err("Erro : SOLTA ESSE só pode ser usado numa gaiola".to_owned()),
+                };
+
+                let value = self.pop_operand()?;
+                let value = self.detach_value(value)?;
+
+                let mut frames = self.callstack.split_off(first);
+                let base = frames.first().map(|f| f.operand_base).unwrap_or(0).min(self.operand_stack.len());
+                let operands = self.operand_stack.split_off(base);
+                let num_items = frames.iter().map(|f| f.num_special_items).sum();
+
+                for frame in &mut frames {
+                    frame.operand_base -= base;
+                }
+
+                self.special_storage.park(id, num_items)?;
+
+                if let Some(SpecialItemData::Coroutine(ref mut c)) = self.special_storage.get_mut(id) {
+                    c.frames = frames;
+                    c.operands = operands;
+                    c.running = false;
+                }
+
+                self.running_coroutines.pop();
+
+                let index = self.callstack.len() - 1;
+                let value = self.attach_value(index, value)?;
+                self.operand_stack.push(value);
+            }
+            Instruction::QueryCoroutineDone => {
+                let id = self.pop_coroutine_id()?;
+
+                let done = match self.special_storage.get_ref(id) {
+                    Some(SpecialItemData::Coroutine(ref c)) => c.done,
+                    _ => return Err("Erro interno : ID inválida pra gaiola".to_owned()),
+                };
+
+                self.operand_stack.push(DynamicValue::Integer(if done { 1 } else { 0 }));
+            }
+            Instruction::Suspend => {
+                let value = self.pop_operand()?;
+                let value = self.dynamic_to_raw(value)?;
+
+                return Ok(self.suspend_with(Suspension::Yield(value)));
+            }
+            Instruction::StartInterpolation => {
+                self.interpolation_stack.push(String::new());
+            }
+            Instruction::PushText(index) => {
+                let id = self.text_constant(index)?;
+
+                self.operand_stack.push(DynamicValue::Text(id));
+            }
+            Instruction::AppendToInterpolation(index) => {
+                let id = match self.get_current_id() {
+                    Some(i) => i,
+                    None => return Err("Nenhuma função em execução".to_owned())
+                };
+
+                let text = match self.code[id].texts.get(index) {
+                    Some(t) => t,
+                    None => return Err(format!("Erro interno : Texto constante {} não existe", index))
+                };
+
+                match self.interpolation_stack.last_mut() {
+                    Some(s) => s.push_str(text.as_str()),
+                    None => return Err("Nenhuma interpolação em andamento".to_owned())
+                }
+            }
+            Instruction::AppendValueToInterpolation(ref format) => {
+                let val = self.pop_operand()?;
+                let text = self.format_value(val, format.as_ref())?;
+
+                match self.interpolation_stack.last_mut() {
+                    Some(s) => s.push_str(text.as_str()),
+                    None => return Err("Nenhuma interpolação em andamento".to_owned())
+                }
             }
-            Instruction::PushMathBPluginArgument => {
-                let val = self.registers.math_b;
-                self.plugin_argument_stack.push(val);
+            Instruction::FinishInterpolation => {
+                let text = match self.interpolation_stack.pop() {
+                    Some(s) => s,
+                    None => return Err("Nenhuma interpolação em andamento".to_owned())
+                };
+
+                let index = match self.get_last_ready_index() {
+                    Some(i) => i,
+                    None => return Err("Nenhuma função em execução".to_owned())
+                };
+
+                let id = self.add_special_item(index, SpecialItemData::Text(text))?;
+
+                self.operand_stack.push(DynamicValue::Text(id));
             }
-            Instruction::IncreaseSkippingLevel => {
-                self.increase_skip_level()?;
+            Instruction::FormatText(num) => {
+                if num >= self.operand_stack.len() {
+                    return Err("FormatText : Número de argumentos maior que a quantidade de argumentos disponíveis".to_owned());
+                }
+
+                let start = self.operand_stack.len() - num;
+                let arguments = self.operand_stack.split_off(start);
+
+                let template = match self.pop_operand()? {
+                    DynamicValue::Text(id) => match self.special_storage.get_ref(id) {
+                        Some(SpecialItemData::Text(ref t)) => t.clone(),
+                        Some(_) => return Err("Erro interno : DynamicValue é texto, mas o id aponta pra outra coisa".to_owned()),
+                        None => return Err(format!("FormatText : Não foi encontrado texto com ID {}", id)),
+                    },
+                    v => return Err(format!("O formato deve ser um texto, mas foi passado {:?}", v)),
+                };
+
+                let text = self.format_template(template.as_str(), &arguments)?;
+
+                let index = match self.get_last_ready_index() {
+                    Some(i) => i,
+                    None => return Err("Nenhuma função em execução".to_owned())
+                };
+
+                let id = self.add_special_item(index, SpecialItemData::Text(text))?;
+
+                self.operand_stack.push(DynamicValue::Text(id));
             }
         }
 
@@ -1664,74 +3266,101 @@ impl VirtualMachine {
     }
 }
 
+/// Instructions work on the operand stack : they pop the values they need from its top and push their results back.
+/// For binary operations, the left side is pushed first
 #[derive(Clone, Debug)]
 pub enum Instruction {
-    PrintMathB,
-    PrintMathBDebug,
+    /// Pop a value and print it
+    PrintValue,
+    /// Pop a value and print it along with its type
+    PrintValueDebug,
     PrintNewLine,
     FlushStdout,
     Quit,
+    /// Pop two values and save how the left one compares to the right one
     Compare,
+    /// Pop the returned value, if there's one, and return to the caller
     Return,
-    EndConditionalBlock,
-    ExecuteIf(ComparisionRequest),
+    /// Continue the execution from the given address of the current function
+    Jump(usize),
+    /// Jump to the given address if the last comparision doesn't match the request
+    JumpIfNot(ComparisionRequest, usize),
     MakeNewFrame(usize),
     SetLastFrameReady,
-    // For use when pushing arguments for a function. Check if the value on the top of the main stack
-    // has a compatible type
-    AssertMathBCompatible(TypeKind),
-    // Get a line of input and put it at the top of the main stack
+    /// Like SetLastFrameReady, but the new frame replaces the current one, which is discarded
+    TailCall,
+    // For use when pushing arguments for a function. Check if the value on the top of the operand stack
+    // has a compatible type, without removing it
+    AssertCompatible(TypeKind),
+    // Get a line of input and push it
     ReadInput,
-    // Turn the main stack top into string
+    // Turn the value on the top into string
     ConvertToString,
-    // Turn the main stack top into num
+    // Turn the value on the top into num
     ConvertToNum,
-    // Turn the main stack top into int
+    // Turn the value on the top into int
     ConvertToInt,
-    PushValMathA(RawValue),
-    PushValMathB(RawValue),
-    PushIntermediateToA,
-    PushIntermediateToB,
-    PushMathBToSeconday,
-    ClearSecondary,
-    // Values are read to the intermediate register
+    /// Push a value given by the host. Literals in the source use PushText for texts
+    PushValue(RawValue),
+    /// Push the text at the given index in the current function's pool
+    PushText(usize),
+    // Reads push the value of the variable
     ReadGlobalVarFrom(usize),
-    // When writing, values are read from the math b register
+    // Writes pop the value to be written
     WriteGlobalVarTo(usize),
     ReadVarFrom(usize),
     WriteVarTo(usize),
     WriteVarToLast(usize),
-    SwapMath,
-    ClearMath,
     Add,
     Mul,
     Div,
     Sub,
-    /// Saves the current PC so when the loop ends it can return to it's beginning
-    AddLoopLabel,
-    /// Return to a previous saved loop label
-    RestoreLoopLabel,
-    /// Remove a previously saved label
-    PopLoopLabel,
-    /// Retrieve the increment value from MathB and write it on every Restore to the specified address
-    RegisterIncrementOnRestore(usize),
-    /// Set the register to denote this is the first operation on the expression
-    SetFirstExpressionOperation,
-    /// Create a new list and put the result at MathB
+    /// left & right. Integers only
+    BitwiseAnd,
+    /// left | right. Integers only
+    BitwiseOr,
+    /// left ^ right. Integers only
+    BitwiseXor,
+    /// ~value. Integers only
+    BitwiseNot,
+    /// left << right. Integers only
+    ShiftLeft,
+    /// left >> right, keeping the sign. Integers only
+    ShiftRight,
+    /// Create a new list and push it
     MakeNewList,
-    /// Index a list with the ID from the intermediate register and the index from MathB, and put the result in MathB
+    /// Pop an index and a list, and push the element at the index
     IndexList,
-    /// Add the result in MathB to the list in the intermediate register, using the index at the secondary register
-    /// if the secondary register is Null, the element is placed on the back of the list
-    AddToListAtIndex,
-    /// Remove the element at the index located in MathB from the list in the intermediate register
+    /// Pop a value and a list, and put the value on the back of the list
+    AddToList,
+    /// Pop a value, an index and a list, and put the value at the index. If the index is after the end, the value
+    /// is placed on the back of the list
+    InsertIntoList,
+    /// Pop an index and a list, and remove the element at the index
     RemoveFromListAtIndex,
-    /// Query the list from the intermediate address and write its size to the MathB
+    /// Pop a list and push its size
     QueryListSize,
-    /// Call a plugin function with a number of arguments to pop from the stack
+    /// Call a plugin function, popping the given number of arguments
     CallPlugin(usize, usize),
-    /// Push the value in MathB to the Plugin Argument stack
-    PushMathBPluginArgument,
-    /// Increase the skipping level
-    IncreaseSkippingLevel,
+    /// Pop a value and pause the program, giving the value to the host. The value the host gives back is pushed
+    Suspend,
+    /// Take the frame being prepared out of the callstack and keep it in a new coroutine, which is written to the
+    /// return value of the current frame
+    MakeCoroutine,
+    /// Pop a coroutine and run it from where it stopped. The value it gives or returns is pushed
+    ResumeCoroutine,
+    /// Pop a value and stop the running coroutine, giving the value to whoever resumed it
+    Yield,
+    /// Pop a coroutine and push 1 if it ended, or 0 if it can still run
+    QueryCoroutineDone,
+    /// Start building a new interpolated text
+    StartInterpolation,
+    /// Append the text at the given index in the current function's pool to the text being interpolated
+    AppendToInterpolation(usize),
+    /// Pop a value and append it to the text being interpolated as text, using the format if there's one
+    AppendValueToInterpolation(Option<FormatSpec>),
+    /// Finish the text being interpolated and push it
+    FinishInterpolation,
+    /// Pop a number of arguments and the template below them, and push the formatted text
+    FormatText(usize),
 }
@@ -6,7 +6,8 @@ use birl::context::Context;
 use birl::compiler::CompilerHint;
 use birl::context::BIRL_GLOBAL_FUNCTION_ID;
 
-fn start_interactive_console(add_stdlib : bool) {
+fn start_interactive_console(add_stdlib : bool, big_integers : bool, max_call_depth : Option<usize>,
+                             optimization_level : u8, sandboxed : bool, memory_limit : Option<usize>) {
 	/* Print heading info. */
 	eprintln!("O SHELL QUE CONSTRÓI FIBRA. VERSÃO {}", birl::context::BIRL_VERSION);
 	eprintln!("BIRL  © 2018, RAFAEL RODRIGUES NAKANO.");
@@ -29,6 +30,24 @@ fn start_interactive_console(add_stdlib : bool) {
 	let mut c = Context::new();
 
     c.set_interactive_mode();
+    c.set_big_integer_mode(big_integers);
+
+    if let Some(depth) = max_call_depth {
+        c.set_max_call_depth(depth);
+    }
+
+    c.set_optimization_level(optimization_level);
+    c.set_memory_limits(birl::vm::MemoryLimits { max_bytes : memory_limit, max_items : None });
+
+    if sandboxed {
+        c.set_capabilities(birl::capabilities::Capabilities::sandboxed());
+    }
+
+    /* Everything typed is compiled into the global function, so it has to be running */
+    if let Err(e) = c.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]) {
+        eprintln!("Erro fatal : Não foi possível iniciar o contexto : {}", e);
+        return;
+    }
 
 	/* Bind the Context interpreter to standard IO */
 	let _ = c.set_stdin({
@@ -56,7 +75,9 @@ fn start_interactive_console(add_stdlib : bool) {
 	let mut prompt = BufReader::new(stdin());
     let mut scope_level = 0usize;
 	loop{
-        if scope_level == 0 {
+        if c.has_pending_lines() {
+            eprint!("..");
+        } else if scope_level == 0 {
             eprint!("> ");
         } else {
             eprint!(">>");
@@ -78,6 +99,12 @@ fn start_interactive_console(add_stdlib : bool) {
 			}
 		}
 
+        /* Shell commands start with ':' and aren't passed to the interpreter */
+        if !c.has_pending_lines() && line.trim_start().starts_with(':') {
+            run_shell_command(&c, line.trim());
+            continue;
+        }
+
         match c.process_line(&line) {
             Ok(None) => {}
             Ok(Some(hint)) => {
@@ -98,6 +125,12 @@ fn start_interactive_console(add_stdlib : bool) {
                         return;
                     }
                     Ok(Es::Halt) => break,
+                    /* Nobody else to give a value to PERA AÍ, so it continues with null */
+                    Ok(Es::Suspended(_)) => {
+                        if let Err(e) = c.give_value(birl::context::RawValue::Null) {
+                            eprintln!("{}", e);
+                        }
+                    }
                     Ok(_) => {}
                     Err(e) => {
                         eprintln!("{}", e);
@@ -112,6 +145,26 @@ fn start_interactive_console(add_stdlib : bool) {
 		.expect("Could not flush io::stdout().");
 }
 
+fn run_shell_command(c : &Context, command : &str) {
+    let mut parts = command.splitn(2, char::is_whitespace);
+    let name = parts.next().unwrap_or("");
+    let argument = parts.next().map(str::trim).unwrap_or("");
+
+    match name {
+        ":desmontar" | ":d" => {
+            if argument.is_empty() {
+                println!("{}", c.disassemble());
+            } else {
+                match c.disassemble_function(argument) {
+                    Ok(listing) => println!("{}", listing),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+        _ => eprintln!("Comando \"{}\" não existe. Os comandos do shell são: :desmontar [função]", name),
+    }
+}
+
 fn print_help() {
 	Context::print_version();
 
@@ -126,6 +179,25 @@ fn print_help() {
               um arquivo.");
 	println!("\t-i ou --interativo\t\t\t\t: Inicia um console interativo pra rodar códigos");
     println!("\t-p ou --sem-padrão\t\t\t\t: Não adiciona as definições da biblioteca padrão");
+    println!("\t-g ou --inteiros-grandes\t\t\t: Inteiros que estouram viram inteiros de precisão arbitrária \
+              ao invés de dar erro");
+    println!("\t-c ou --chamadas [numero]\t\t\t: Limite de chamadas de função ativas ao mesmo tempo. \
+              Passando disso dá estouro de pilha (padrão {})", birl::vm::DEFAULT_MAX_CALL_DEPTH);
+    println!("\t-O ou --otimizar [nivel]\t\t\t: Otimiza as funções compiladas, de 0 (nada) a {}. \
+              1 calcula as expressões constantes e {} também tira código que nunca roda (padrão 0)",
+             birl::optimizer::MAX_OPTIMIZATION_LEVEL, birl::optimizer::MAX_OPTIMIZATION_LEVEL);
+    println!("\t-L ou --instrucoes [numero]\t\t\t: Para o programa depois de rodar esse tanto de instruções. \
+              Não vale no console interativo");
+    println!("\t-T ou --tempo [milissegundos]\t\t: Para o programa depois de rodar por esse tempo. \
+              Não vale no console interativo");
+    println!("\t-M ou --memoria [bytes]\t\t\t: Limite de memória pra textos, listas e inteiros grandes");
+    println!("\t-S ou --isolado\t\t\t\t: Roda o programa isolado, sem ler a entrada, as variáveis de ambiente, \
+              arquivos, outros programas nem as horas. Só pode escrever na saída");
+    println!("\t-D ou --desmontar\t\t\t\t: Mostra as instruções compiladas de cada função ao invés de rodar. \
+              No console interativo, \":desmontar [função]\" faz o mesmo");
+    println!("\t-C ou --compilar [arquivo.{}]\t\t: Só compila os arquivos e salva o bytecode no arquivo, \
+              sem rodar. Um arquivo .{} passado como entrada é carregado ao invés de compilado",
+             birl::bytecode::BYTECODE_EXTENSION, birl::bytecode::BYTECODE_EXTENSION);
 }
 
 /// Parameters passed through the command line
@@ -140,6 +212,24 @@ enum Param {
 	Interactive,
     /// Do not add the standard library to the code
     WithoutStdLib,
+    /// Promote overflowing integers to big integers
+    BigIntegers,
+    /// Limit for how many function calls can be active at the same time
+    MaxCallDepth(usize),
+    /// How much the compiled functions are optimized
+    OptimizationLevel(u8),
+    /// Saves the compiled program to a bytecode file instead of running it
+    CompileTo(String),
+    /// Prints the compiled instructions instead of running them
+    Disassemble,
+    /// Maximum number of instructions the program can run
+    Fuel(u64),
+    /// Maximum time the program can run for, in milliseconds
+    TimeLimit(u64),
+    /// Maximum number of bytes used by texts, lists and big integers
+    MemoryLimit(usize),
+    /// Denies everything but printing to the program
+    Sandboxed,
 }
 
 fn get_params() -> Vec<Param> {
@@ -163,6 +253,46 @@ fn get_params() -> Vec<Param> {
 					}
 				}
                 "-p" | "--sem-padrao" | "--sem-padrão" => result.push(Param::WithoutStdLib),
+                "-g" | "--inteiros-grandes" => result.push(Param::BigIntegers),
+                "-c" | "--chamadas" => {
+                    match arguments.next().map(|n| n.parse::<usize>()) {
+                        Some(Ok(depth)) => result.push(Param::MaxCallDepth(depth)),
+                        _ => println!("Erro: O argumento {} precisa de um número logo em seguida, bixo.", arg),
+                    }
+                }
+                "-O" | "--otimizar" => {
+                    match arguments.next().map(|n| n.parse::<u8>()) {
+                        Some(Ok(level)) => result.push(Param::OptimizationLevel(level)),
+                        _ => println!("Erro: O argumento {} precisa de um número logo em seguida, bixo.", arg),
+                    }
+                }
+                "-D" | "--desmontar" => result.push(Param::Disassemble),
+                "-S" | "--isolado" => result.push(Param::Sandboxed),
+                "-L" | "--instrucoes" | "--instruções" => {
+                    match arguments.next().map(|n| n.parse::<u64>()) {
+                        Some(Ok(fuel)) => result.push(Param::Fuel(fuel)),
+                        _ => println!("Erro: O argumento {} precisa de um número logo em seguida, bixo.", arg),
+                    }
+                }
+                "-M" | "--memoria" | "--memória" => {
+                    match arguments.next().map(|n| n.parse::<usize>()) {
+                        Some(Ok(bytes)) => result.push(Param::MemoryLimit(bytes)),
+                        _ => println!("Erro: O argumento {} precisa de um número logo em seguida, bixo.", arg),
+                    }
+                }
+                "-T" | "--tempo" => {
+                    match arguments.next().map(|n| n.parse::<u64>()) {
+                        Some(Ok(ms)) => result.push(Param::TimeLimit(ms)),
+                        _ => println!("Erro: O argumento {} precisa de um número logo em seguida, bixo.", arg),
+                    }
+                }
+                "-C" | "--compilar" => {
+                    if let Some(file) = arguments.next() {
+                        result.push(Param::CompileTo(file));
+                    } else {
+                        println!("Erro: O argumento {} precisa de um arquivo logo em seguida, bixo.", arg);
+                    }
+                }
 				// Push the file to the result stack
 				_ => result.push(Param::InputFile(arg))
 			}
@@ -178,11 +308,57 @@ fn main() {
 	let args = get_params();
 	let mut interactive = false;
     let mut with_stdlib = true;
+    let mut big_integers = false;
+    let mut max_call_depth = None;
+    let mut optimization_level = 0;
+    let mut compile_to = None;
+    let mut disassemble = false;
+    let mut fuel = None;
+    let mut time_limit = None;
+    let mut memory_limit = None;
+    let mut sandboxed = false;
     let mut files = vec![];
     let mut strings = vec![];
 
+	if args.len() > 0 {
+		for arg in args {
+			match arg {
+				Param::PrintHelp => print_help(),
+				Param::Interactive => interactive = true,
+				Param::PrintVersion => Context::print_version(),
+                Param::WithoutStdLib => with_stdlib = false,
+                Param::BigIntegers => big_integers = true,
+                Param::MaxCallDepth(depth) => max_call_depth = Some(depth),
+                Param::OptimizationLevel(level) => optimization_level = level,
+                Param::CompileTo(file) => compile_to = Some(file),
+                Param::Disassemble => disassemble = true,
+                Param::Fuel(f) => fuel = Some(f),
+                Param::TimeLimit(ms) => time_limit = Some(ms),
+                Param::MemoryLimit(bytes) => memory_limit = Some(bytes),
+                Param::Sandboxed => sandboxed = true,
+				Param::InputFile(file) => files.push(file),
+				Param::StringSource(source) => strings.push(source),
+			}
+		}
+	} else {
+		interactive = true;
+	}
+
 	let mut ctx = Context::new();
 
+    ctx.set_big_integer_mode(big_integers);
+
+    if let Some(depth) = max_call_depth {
+        ctx.set_max_call_depth(depth);
+    }
+
+    ctx.set_optimization_level(optimization_level);
+    ctx.set_memory_limits(birl::vm::MemoryLimits { max_bytes : memory_limit, max_items : None });
+
+    if sandboxed {
+        ctx.set_capabilities(birl::capabilities::Capabilities::sandboxed());
+    }
+
     match ctx.call_function_by_id(BIRL_GLOBAL_FUNCTION_ID, vec![]) {
         Ok(_) => {}
         Err(e) => {
@@ -192,7 +368,37 @@ fn main() {
         }
     }
 
-    if with_stdlib {
+    let extension = format!(".{}", birl::bytecode::BYTECODE_EXTENSION);
+    let (bytecode_files, files) : (Vec<String>, Vec<String>) = files.into_iter()
+        .partition(|f| f.ends_with(extension.as_str()));
+
+    if bytecode_files.len() > 1 {
+        println!("Erro : Só dá pra carregar um arquivo {} de cada vez", extension);
+        exit(-1);
+    }
+
+    if let Some(file) = bytecode_files.first() {
+        // The program already has everything from the standard library that it uses, except for the plugins
+        if with_stdlib {
+            let stdlib = birl::standard_lib::module_standard_library(ctx.get_capabilities());
+
+            for plugin in stdlib.plugin_functions {
+                if let Err(e) = ctx.add_module_plugin(plugin) {
+                    println!("Erro adicionando standard library : {}", e);
+                    exit(-1);
+                }
+            }
+        }
+
+        let result = std::fs::read(file.as_str())
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|bytes| ctx.load_bytecode(&bytes));
+
+        if let Err(e) = result {
+            println!("Ocorreu um erro ao carregar o arquivo \"{}\" : {}", file.as_str(), e);
+            exit(-1);
+        }
+    } else if with_stdlib {
         match ctx.add_standard_library() {
             Ok(_) => {}
             Err(e) => {
@@ -202,22 +408,6 @@ fn main() {
         }
     }
 
-	if args.len() > 0 {
-		for arg in args {
-			match arg {
-				Param::PrintHelp => print_help(),
-				Param::Interactive => interactive = true,
-				Param::PrintVersion => Context::print_version(),
-                Param::WithoutStdLib => with_stdlib = false,
-				Param::InputFile(file) => files.push(file),
-				Param::StringSource(source) => strings.push(source),
-			}
-		}
-	} else {
-		interactive = true;
-	}
-
-
     for file in files {
         match ctx.add_file(file.as_str()) {
             Ok(_) => {}
@@ -238,8 +428,28 @@ fn main() {
         }
     }
 
+    if disassemble {
+        println!("{}", ctx.disassemble());
+    }
+
+    if let Some(file) = compile_to {
+        let result = ctx.save_bytecode()
+            .and_then(|bytes| std::fs::write(file.as_str(), bytes).map_err(|e| format!("{:?}", e)));
+
+        if let Err(e) = result {
+            println!("Ocorreu um erro ao salvar o bytecode em \"{}\" : {}", file.as_str(), e);
+            exit(-1);
+        }
+
+        return;
+    }
+
+    if disassemble {
+        return;
+    }
+
 	if interactive {
-		start_interactive_console(with_stdlib);
+		start_interactive_console(with_stdlib, big_integers, max_call_depth, optimization_level, sandboxed, memory_limit);
 	} else {
         /* Bind the Context interpreter to standard IO */
         let _ = ctx.set_stdin({
@@ -252,6 +462,13 @@ fn main() {
             Some(Box::new(io::stdout()))
         });
 
+        ctx.set_fuel(fuel);
+
+        if let Some(ms) = time_limit {
+            use std::time::{ Instant, Duration };
+            ctx.set_deadline(Some(Instant::now() + Duration::from_millis(ms)));
+        }
+
 		match ctx.start_program() {
 			Ok(_) => {}
 			Err(e) => println!("Erro de execução : {}", e),